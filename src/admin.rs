@@ -0,0 +1,62 @@
+//! Operator endpoints that need the database rather than the OAuth state. Mounted under
+//! /admin with the rest, behind the admin token
+
+use crate::couchdb::CouchDbClient;
+use axum::{
+    Json, Router,
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Every vault's database by name, primary first
+type Vaults = Arc<Vec<(String, CouchDbClient)>>;
+
+pub fn routes(vaults: Vec<(String, CouchDbClient)>) -> Router {
+    Router::new()
+        .route("/admin/storage", get(storage_handler))
+        .with_state(Arc::new(vaults))
+}
+
+#[derive(Deserialize)]
+struct StorageQuery {
+    /// which vault to count, the primary one if not given
+    vault: Option<String>,
+}
+
+/// GET /admin/storage[?vault=name] - doc, note and chunk counts, including how many chunks
+/// look orphaned, so you can see what --chunk-gc-interval would clean up before turning it
+/// on. Reads the whole database, so not something to poll
+async fn storage_handler(
+    State(vaults): State<Vaults>,
+    Query(query): Query<StorageQuery>,
+) -> Response {
+    let db = match &query.vault {
+        None => &vaults[0].1,
+        Some(name) => match vaults.iter().find(|(vault, _)| vault == name) {
+            Some((_, db)) => db,
+            None => {
+                let names: Vec<&str> = vaults.iter().map(|(vault, _)| vault.as_str()).collect();
+                return (
+                    StatusCode::NOT_FOUND,
+                    format!(
+                        "Unknown vault '{}'. Available vaults: {}",
+                        name,
+                        names.join(", ")
+                    ),
+                )
+                    .into_response();
+            }
+        },
+    };
+    match db.storage_stats().await {
+        Ok(stats) => Json(stats).into_response(),
+        Err(e) => {
+            tracing::error!("Couldn't count storage in {}: {}", db.database(), e);
+            (StatusCode::BAD_GATEWAY, e.to_string()).into_response()
+        }
+    }
+}
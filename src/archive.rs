@@ -0,0 +1,290 @@
+//! Just enough of the tar format (ustar, plus pax headers for long/unicode paths) to move a vault
+//! in and out of yamos. Every entry is a regular file; folders are implied by the paths
+
+use anyhow::{Result, anyhow};
+
+/// Tar works in 512 byte blocks
+const BLOCK: usize = 512;
+
+/// Two empty blocks mark the end of the archive
+pub const TAR_END: [u8; BLOCK * 2] = [0; BLOCK * 2];
+
+/// One file's worth of tar: header(s), then the content padded out to a whole block
+pub fn tar_entry(path: &str, content: &[u8], mtime_secs: u64) -> Vec<u8> {
+    let mut out = Vec::with_capacity(BLOCK * 2 + padded_len(content.len()));
+
+    // ustar only has 100 bytes for a name and says nothing about encoding, so anything longer
+    // or non-ascii goes in a pax extended header that overrides it
+    let name = if path.len() <= 100 && path.is_ascii() {
+        path
+    } else {
+        let record = pax_record("path", path);
+        out.extend_from_slice(&header("PaxHeader", record.len() as u64, mtime_secs, b'x'));
+        push_padded(&mut out, record.as_bytes());
+        "pax-long-path"
+    };
+
+    out.extend_from_slice(&header(name, content.len() as u64, mtime_secs, b'0'));
+    push_padded(&mut out, content);
+    out
+}
+
+/// "<len> <key>=<value>\n", where len counts the whole record including its own digits
+fn pax_record(key: &str, value: &str) -> String {
+    let body = format!(" {}={}\n", key, value);
+    let mut len = body.len() + 1;
+    while len.to_string().len() + body.len() > len {
+        len += 1;
+    }
+    format!("{}{}", len, body)
+}
+
+fn header(name: &str, size: u64, mtime_secs: u64, typeflag: u8) -> [u8; BLOCK] {
+    let mut h = [0u8; BLOCK];
+    h[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut h[100..108], 0o644);
+    write_octal(&mut h[108..116], 0);
+    write_octal(&mut h[116..124], 0);
+    write_octal(&mut h[124..136], size);
+    write_octal(&mut h[136..148], mtime_secs);
+    h[156] = typeflag;
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+
+    // checksum is calculated with its own field full of spaces
+    h[148..156].fill(b' ');
+    let checksum: u32 = h.iter().map(|&b| b as u32).sum();
+    write_octal(&mut h[148..155], checksum as u64);
+    h[154] = 0;
+    h
+}
+
+/// zero-padded octal, leaving the last byte of the field as the NUL terminator
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+fn padded_len(len: usize) -> usize {
+    len.div_ceil(BLOCK) * BLOCK
+}
+
+fn push_padded(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(data);
+    out.resize(out.len() + padded_len(data.len()) - data.len(), 0);
+}
+
+/// A regular file pulled out of a tar
+#[derive(Debug, PartialEq, Eq)]
+pub struct TarFile {
+    pub path: String,
+    pub content: Vec<u8>,
+}
+
+/// Reads every regular file out of a tar. Directories, links and the like are skipped; pax and
+/// GNU long names are honoured so paths come out the same as they went in
+pub fn read_tar(data: &[u8]) -> Result<Vec<TarFile>> {
+    let mut files = Vec::new();
+    let mut offset = 0;
+    // a pax 'x' or GNU 'L' entry sets the path of the entry after it
+    let mut next_path: Option<String> = None;
+
+    while offset + BLOCK <= data.len() {
+        let h = &data[offset..offset + BLOCK];
+        if h.iter().all(|&b| b == 0) {
+            break;
+        }
+        verify_checksum(h).map_err(|e| anyhow!("bad tar header at byte {}: {}", offset, e))?;
+
+        let size = read_octal(&h[124..136])? as usize;
+        let body_start = offset + BLOCK;
+        let body = data
+            .get(body_start..body_start + size)
+            .ok_or_else(|| anyhow!("tar truncated in the middle of an entry"))?;
+        offset = body_start + padded_len(size);
+
+        match h[156] {
+            b'x' => next_path = pax_path(body)?.or(next_path),
+            b'L' => next_path = Some(c_str(body).to_string()),
+            b'0' | 0 => {
+                let path = match next_path.take() {
+                    Some(path) => path,
+                    None => {
+                        let (name, prefix) = (c_str(&h[0..100]), c_str(&h[345..500]));
+                        if prefix.is_empty() {
+                            name.to_string()
+                        } else {
+                            format!("{}/{}", prefix, name)
+                        }
+                    }
+                };
+                files.push(TarFile {
+                    path,
+                    content: body.to_vec(),
+                });
+            }
+            // global pax headers, dirs, links, devices... nothing a note cares about
+            _ => next_path = None,
+        }
+    }
+
+    Ok(files)
+}
+
+fn verify_checksum(h: &[u8]) -> Result<()> {
+    let expected = read_octal(&h[148..156])?;
+    let actual: u64 = h
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+        .sum();
+    if expected != actual {
+        return Err(anyhow!("checksum mismatch"));
+    }
+    Ok(())
+}
+
+fn read_octal(field: &[u8]) -> Result<u64> {
+    let digits = c_str(field).trim_matches(' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| anyhow!("bad octal field {:?}", digits))
+}
+
+/// a NUL-terminated (or NUL-padded) header field as a string
+fn c_str(field: &[u8]) -> &str {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    std::str::from_utf8(&field[..end]).unwrap_or_default()
+}
+
+/// the `path` out of a block of pax records, if there is one
+fn pax_path(records: &[u8]) -> Result<Option<String>> {
+    let mut rest = std::str::from_utf8(records).map_err(|_| anyhow!("pax header isn't utf-8"))?;
+    let mut path = None;
+    while let Some((len, _)) = rest.split_once(' ') {
+        let len: usize = len.parse().map_err(|_| anyhow!("bad pax record length"))?;
+        let record = rest
+            .get(..len)
+            .ok_or_else(|| anyhow!("pax record runs off the end"))?;
+        if let Some((_, kv)) = record.trim_end_matches('\n').split_once(' ')
+            && let Some(value) = kv.strip_prefix("path=")
+        {
+            path = Some(value.to_string());
+        }
+        rest = &rest[len..];
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn field(h: &[u8], range: std::ops::Range<usize>) -> &str {
+        std::str::from_utf8(&h[range])
+            .unwrap()
+            .trim_end_matches('\0')
+    }
+
+    #[test]
+    fn test_short_path_entry() {
+        let entry = tar_entry("Projects/todo.md", b"hello", 1_700_000_000);
+        assert_eq!(entry.len(), BLOCK * 2);
+
+        let h = &entry[..BLOCK];
+        assert_eq!(field(h, 0..100), "Projects/todo.md");
+        assert_eq!(field(h, 124..136), format!("{:011o}", 5));
+        assert_eq!(h[156], b'0');
+        assert_eq!(&h[257..263], b"ustar\0");
+        assert_eq!(&entry[BLOCK..BLOCK + 5], b"hello");
+        assert!(entry[BLOCK + 5..].iter().all(|&b| b == 0));
+
+        // the checksum is the byte sum with the checksum field as spaces
+        let mut blanked = h.to_vec();
+        blanked[148..156].fill(b' ');
+        let sum: u32 = blanked.iter().map(|&b| b as u32).sum();
+        assert_eq!(field(h, 148..155), format!("{:06o}", sum));
+    }
+
+    #[test]
+    fn test_long_and_unicode_paths_use_pax() {
+        for path in [
+            "日本語/ノート.md".to_string(),
+            format!("{}.md", "a".repeat(120)),
+        ] {
+            let entry = tar_entry(&path, b"x", 0);
+            assert_eq!(entry[156], b'x');
+            let record = pax_record("path", &path);
+            assert_eq!(&entry[BLOCK..BLOCK + record.len()], record.as_bytes());
+            assert_eq!(entry[BLOCK * 2 + 156], b'0');
+        }
+    }
+
+    #[test]
+    fn test_pax_record_length_counts_itself() {
+        for value in ["a", &"b".repeat(90), &"c".repeat(5000)] {
+            let record = pax_record("path", value);
+            let (len, _) = record.split_once(' ').unwrap();
+            assert_eq!(len.parse::<usize>().unwrap(), record.len());
+        }
+    }
+
+    #[test]
+    fn test_read_tar_round_trip() {
+        let long = format!("deep/{}.md", "a".repeat(150));
+        let files = [
+            ("Projects/todo.md", "hello".as_bytes()),
+            ("日本語/ノート.md", "content ✨".as_bytes()),
+            (long.as_str(), b"long".as_slice()),
+            ("empty.md", b"".as_slice()),
+        ];
+        let mut tar = Vec::new();
+        for (path, content) in files {
+            tar.extend(tar_entry(path, content, 0));
+        }
+        tar.extend(TAR_END);
+
+        let read = read_tar(&tar).unwrap();
+        assert_eq!(read.len(), files.len());
+        for (file, (path, content)) in read.iter().zip(files) {
+            assert_eq!(file.path, path);
+            assert_eq!(file.content, content);
+        }
+    }
+
+    #[test]
+    fn test_read_tar_ustar_prefix_and_skips() {
+        let mut tar = Vec::new();
+        // a directory entry, then a file split across prefix/name like other tools write them
+        tar.extend(header("folder/", 0, 0, b'5'));
+        let mut file = header("note.md", 2, 0, b'0');
+        file[345..351].copy_from_slice(b"folder");
+        file[148..156].fill(b' ');
+        let checksum: u32 = file.iter().map(|&b| b as u32).sum();
+        write_octal(&mut file[148..155], checksum as u64);
+        file[154] = 0;
+        tar.extend(file);
+        push_padded(&mut tar, b"hi");
+        // no end marker at all is fine too
+
+        let read = read_tar(&tar).unwrap();
+        assert_eq!(
+            read,
+            vec![TarFile {
+                path: "folder/note.md".to_string(),
+                content: b"hi".to_vec(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_read_tar_rejects_garbage() {
+        let mut tar = tar_entry("a.md", b"hello", 0);
+        tar[0] = b'b'; // checksum no longer matches
+        assert!(read_tar(&tar).is_err());
+
+        let truncated = &tar_entry("a.md", &[b'x'; 600], 0)[..BLOCK + 100];
+        assert!(read_tar(truncated).is_err());
+    }
+}
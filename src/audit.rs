@@ -0,0 +1,72 @@
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// Append-only JSONL trail of who changed what. One line per successful mutating tool call
+#[derive(Clone)]
+pub struct AuditLog {
+    file: Arc<Mutex<File>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AuditEntry<'a> {
+    pub timestamp: DateTime<Utc>,
+    /// JWT subject (client id) of the caller, if authenticated via OAuth
+    pub sub: Option<&'a str>,
+    pub tool: &'a str,
+    /// The vault the note is in, None for the primary one
+    pub vault: Option<&'a str>,
+    pub path: &'a str,
+    /// sha256 of the note content before the change (None if it didn't exist)
+    pub before: Option<String>,
+    /// sha256 of the note content after the change (None if it's gone)
+    pub after: Option<String>,
+}
+
+impl AuditLog {
+    pub async fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    /// Writes an entry. Failures are logged rather than returned - by the time we're auditing,
+    /// the change has already happened and the tool call shouldn't fail because of the log
+    pub async fn record(&self, entry: &AuditEntry<'_>) {
+        let mut line = match serde_json::to_vec(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::error!("Failed to serialise audit entry: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        let mut file = self.file.lock().await;
+        if let Err(e) = file.write_all(&line).await {
+            tracing::error!("Failed to write audit log: {}", e);
+        } else if let Err(e) = file.flush().await {
+            tracing::error!("Failed to flush audit log: {}", e);
+        }
+    }
+}
+
+/// Hex sha256 of note content, so the audit log can show what changed without storing it
+pub fn content_hash(content: &str) -> String {
+    Sha256::digest(content.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
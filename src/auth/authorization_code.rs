@@ -16,15 +16,56 @@ use tokio::sync::RwLock;
 use url::Url;
 use uuid::Uuid;
 
-/// max pending authorisations before we start evicting old ones
-const MAX_PENDING_AUTHORISATIONS: usize = 1000;
+/// default max pending authorisations before we start evicting old ones
+pub const DEFAULT_MAX_PENDING_AUTHORISATIONS: usize = 1000;
 
-/// stores pending auth requests (in-memory, doesn't persist)
-#[derive(Clone, Default)]
+/// default lifetime of a pending authorisation
+pub const DEFAULT_AUTHORISATION_TTL: std::time::Duration = std::time::Duration::from_secs(600);
+
+/// stores pending auth requests (in-memory, doesn't persist). consent sessions waiting on the
+/// user and issued authorization codes waiting to be exchanged are kept apart, so a flood of
+/// people opening the consent page can't evict a code that's already been handed out, and a
+/// code's ttl runs from when it was issued
+#[derive(Clone)]
 pub struct AuthorizationStore {
-    pending: Arc<RwLock<HashMap<String, PendingAuthorization>>>,
+    /// keyed by the temp code in the consent form
+    consents: ExpiringStore,
+    /// keyed by the authorization code sent back to the client
+    codes: ExpiringStore,
+    /// codes that have already been exchanged, so replays can be told apart from junk
+    redeemed: ExpiringStore,
+}
+
+/// why an authorization code couldn't be exchanged
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RedeemError {
+    /// never issued, or expired/evicted
+    Unknown,
+    /// already exchanged for a token once
+    Replayed { client_id: String },
+    /// a consent page temp code, which was never approved into a real code
+    ConsentCode,
+}
+
+/// capped map of code -> authorisation with LRU eviction and a ttl
+#[derive(Clone)]
+struct ExpiringStore {
+    /// what's in here, for logs
+    kind: &'static str,
+    entries: Arc<RwLock<HashMap<String, PendingAuthorization>>>,
     /// track insertion order for LRU eviction
     insertion_order: Arc<RwLock<VecDeque<String>>>,
+    max_entries: usize,
+    ttl: std::time::Duration,
+}
+
+impl Default for AuthorizationStore {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_MAX_PENDING_AUTHORISATIONS,
+            DEFAULT_AUTHORISATION_TTL,
+        )
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -35,6 +76,7 @@ pub struct PendingAuthorization {
     #[allow(dead_code)]
     pub code_challenge_method: CodeChallengeMethod,
     pub state: Option<String>,
+    pub resource: Option<String>,
     pub created_at: std::time::Instant,
 }
 
@@ -52,6 +94,8 @@ pub struct RegisteredClient {
     pub redirect_uris: Vec<String>,
     /// client secret for dynamically registered clients (None for static clients)
     pub client_secret: Option<String>,
+    /// token lifetime for this client, overriding the server default
+    pub token_lifetime: Option<std::time::Duration>,
     #[allow(dead_code)]
     pub created_at: std::time::Instant,
 }
@@ -67,6 +111,7 @@ impl ClientRegistry {
         client_id: String,
         redirect_uris: Vec<String>,
         client_secret: Option<String>,
+        token_lifetime: Option<std::time::Duration>,
     ) {
         let mut clients = self.clients.write().await;
         clients.insert(
@@ -75,11 +120,23 @@ impl ClientRegistry {
                 client_id,
                 redirect_uris,
                 client_secret,
+                token_lifetime,
                 created_at: std::time::Instant::now(),
             },
         );
     }
 
+    /// forget a dynamically registered client. returns whether it was registered
+    pub async fn deregister(&self, client_id: &str) -> bool {
+        self.clients.write().await.remove(client_id).is_some()
+    }
+
+    /// token lifetime override for a registered client, if it has one
+    pub async fn token_lifetime(&self, client_id: &str) -> Option<std::time::Duration> {
+        let clients = self.clients.read().await;
+        clients.get(client_id).and_then(|c| c.token_lifetime)
+    }
+
     /// validate client credentials against registry
     /// returns Ok(()) if valid, Err with reason if not
     pub async fn validate_credentials(
@@ -194,22 +251,28 @@ impl ClientRegistry {
     }
 }
 
-#[allow(dead_code)]
-impl AuthorizationStore {
-    pub fn new() -> Self {
-        Self::default()
+impl ExpiringStore {
+    fn new(kind: &'static str, max_entries: usize, ttl: std::time::Duration) -> Self {
+        Self {
+            kind,
+            entries: Arc::default(),
+            insertion_order: Arc::default(),
+            max_entries,
+            ttl,
+        }
     }
 
-    pub async fn store_pending(&self, code: String, auth: PendingAuthorization) {
-        let mut pending = self.pending.write().await;
+    async fn insert(&self, code: String, auth: PendingAuthorization) {
+        let mut entries = self.entries.write().await;
         let mut order = self.insertion_order.write().await;
 
         // evict oldest entries if at capacity
-        while pending.len() >= MAX_PENDING_AUTHORISATIONS {
+        while entries.len() >= self.max_entries {
             if let Some(oldest_code) = order.pop_front() {
-                pending.remove(&oldest_code);
+                entries.remove(&oldest_code);
                 tracing::debug!(
-                    "evicted oldest pending authorisation due to capacity limit: {}",
+                    "evicted oldest {} due to capacity limit: {}",
+                    self.kind,
                     oldest_code
                 );
             } else {
@@ -217,52 +280,139 @@ impl AuthorizationStore {
             }
         }
 
-        pending.insert(code.clone(), auth);
+        entries.insert(code.clone(), auth);
         order.push_back(code);
     }
 
-    pub async fn take_pending(&self, code: &str) -> Option<PendingAuthorization> {
-        let mut pending = self.pending.write().await;
+    /// removes and returns an entry, unless it's past its ttl (cleanup may not have run yet)
+    async fn take(&self, code: &str) -> Option<PendingAuthorization> {
+        let mut entries = self.entries.write().await;
         let mut order = self.insertion_order.write().await;
 
         // remove from insertion order tracking
         order.retain(|c| c != code);
 
-        pending.remove(code)
+        entries
+            .remove(code)
+            .filter(|auth| auth.created_at.elapsed() < self.ttl)
     }
 
-    /// boot out anything older than 10 mins
-    pub async fn cleanup_expired(&self) {
-        let mut pending = self.pending.write().await;
+    /// looks at an entry without removing it
+    async fn get(&self, code: &str) -> Option<PendingAuthorization> {
+        let entries = self.entries.read().await;
+        entries
+            .get(code)
+            .filter(|auth| auth.created_at.elapsed() < self.ttl)
+            .cloned()
+    }
+
+    async fn remove_client(&self, client_id: &str) -> usize {
+        let mut entries = self.entries.write().await;
+        let mut order = self.insertion_order.write().await;
+
+        let before = entries.len();
+        entries.retain(|_, auth| auth.client_id != client_id);
+        order.retain(|code| entries.contains_key(code));
+
+        before - entries.len()
+    }
+
+    async fn cleanup_expired(&self) {
+        let mut entries = self.entries.write().await;
         let mut order = self.insertion_order.write().await;
         let now = std::time::Instant::now();
 
         // collect expired codes
-        let expired: Vec<String> = pending
+        let expired: Vec<String> = entries
             .iter()
-            .filter(|(_, auth)| now.duration_since(auth.created_at).as_secs() >= 600)
+            .filter(|(_, auth)| now.duration_since(auth.created_at) >= self.ttl)
             .map(|(code, _)| code.clone())
             .collect();
 
         // remove expired entries
         for code in &expired {
-            pending.remove(code);
+            entries.remove(code);
         }
 
         // clean up insertion order
         order.retain(|code| !expired.contains(code));
 
         if !expired.is_empty() {
-            tracing::debug!(
-                "cleaned up {} expired pending authorisations",
-                expired.len()
-            );
+            tracing::debug!("cleaned up {} expired {}s", expired.len(), self.kind);
         }
     }
 
-    /// get current count of pending authorisations (for monitoring)
+    async fn len(&self) -> usize {
+        self.entries.read().await.len()
+    }
+}
+
+#[allow(dead_code)]
+impl AuthorizationStore {
+    pub fn new(max_pending: usize, ttl: std::time::Duration) -> Self {
+        Self {
+            consents: ExpiringStore::new("pending authorisation", max_pending, ttl),
+            codes: ExpiringStore::new("authorization code", max_pending, ttl),
+            redeemed: ExpiringStore::new("redeemed authorization code", max_pending, ttl),
+        }
+    }
+
+    /// stash a consent session until the user approves or denies it
+    pub async fn store_pending(&self, code: String, auth: PendingAuthorization) {
+        self.consents.insert(code, auth).await;
+    }
+
+    pub async fn take_pending(&self, code: &str) -> Option<PendingAuthorization> {
+        self.consents.take(code).await
+    }
+
+    /// stash an approved authorisation under the code we're about to give the client. its ttl
+    /// starts now, not when the consent page was shown
+    pub async fn issue_code(&self, code: String, mut auth: PendingAuthorization) {
+        auth.created_at = std::time::Instant::now();
+        self.codes.insert(code, auth).await;
+    }
+
+    /// exchange an issued authorization code. each code works exactly once, and consent temp
+    /// codes never work
+    pub async fn redeem_code(&self, code: &str) -> Result<PendingAuthorization, RedeemError> {
+        if let Some(auth) = self.codes.take(code).await {
+            // remember it for the rest of its would-be lifetime to spot replays
+            let mut redeemed = auth.clone();
+            redeemed.created_at = std::time::Instant::now();
+            self.redeemed.insert(code.to_string(), redeemed).await;
+            return Ok(auth);
+        }
+
+        if let Some(auth) = self.redeemed.get(code).await {
+            return Err(RedeemError::Replayed {
+                client_id: auth.client_id,
+            });
+        }
+
+        // someone skipped the consent step. burn the session so it can't be approved later either
+        if self.consents.take(code).await.is_some() {
+            return Err(RedeemError::ConsentCode);
+        }
+
+        Err(RedeemError::Unknown)
+    }
+
+    /// drop every pending authorisation (and unredeemed code) for a client, returning how many
+    pub async fn remove_client(&self, client_id: &str) -> usize {
+        self.consents.remove_client(client_id).await + self.codes.remove_client(client_id).await
+    }
+
+    /// boot out anything older than the ttl
+    pub async fn cleanup_expired(&self) {
+        self.consents.cleanup_expired().await;
+        self.codes.cleanup_expired().await;
+        self.redeemed.cleanup_expired().await;
+    }
+
+    /// get current count of pending authorisations and codes (for monitoring)
     pub async fn len(&self) -> usize {
-        self.pending.read().await.len()
+        self.consents.len().await + self.codes.len().await
     }
 }
 
@@ -277,7 +427,6 @@ pub struct AuthorizationRequest {
     pub state: Option<String>,
     #[allow(dead_code)]
     pub scope: Option<String>,
-    #[allow(dead_code)]
     pub resource: Option<String>,
 }
 
@@ -331,6 +480,7 @@ pub async fn authorize_handler(
         code_challenge: req.code_challenge.clone(),
         code_challenge_method: req.code_challenge_method.unwrap_or_default(),
         state: req.state.clone(),
+        resource: req.resource.clone(),
         created_at: std::time::Instant::now(),
     };
     store.store_pending(temp_code.clone(), pending).await;
@@ -410,10 +560,8 @@ pub async fn authorize_approval_handler(
     // Generate the actual authorization code
     let auth_code = Uuid::new_v4().to_string();
 
-    // Store the authorization code (reuse temp code storage)
-    store
-        .store_pending(auth_code.clone(), pending.clone())
-        .await;
+    // Store the authorization code, separately from the consent sessions
+    store.issue_code(auth_code.clone(), pending.clone()).await;
 
     // redirect back with the authorization code
     // Include iss parameter per RFC 9207 for issuer identification
@@ -557,3 +705,103 @@ fn html_escape(s: &str) -> String {
         .replace('>', "&gt;")
         .replace('"', "&quot;")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending_for(client_id: &str) -> PendingAuthorization {
+        PendingAuthorization {
+            client_id: client_id.to_string(),
+            redirect_uri: "https://example.com/callback".to_string(),
+            code_challenge: "challenge".to_string(),
+            code_challenge_method: CodeChallengeMethod::S256,
+            state: None,
+            resource: None,
+            created_at: std::time::Instant::now(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_remove_client_only_touches_that_client() {
+        let store = AuthorizationStore::default();
+        store
+            .store_pending("a1".to_string(), pending_for("a"))
+            .await;
+        store
+            .store_pending("b1".to_string(), pending_for("b"))
+            .await;
+        store
+            .store_pending("a2".to_string(), pending_for("a"))
+            .await;
+
+        assert_eq!(store.remove_client("a").await, 2);
+        assert_eq!(store.len().await, 1);
+        assert!(store.take_pending("a1").await.is_none());
+        assert!(store.take_pending("b1").await.is_some());
+        assert_eq!(store.remove_client("nobody").await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_evicts_oldest_at_configured_capacity() {
+        let store = AuthorizationStore::new(2, DEFAULT_AUTHORISATION_TTL);
+        for code in ["one", "two", "three"] {
+            store
+                .store_pending(code.to_string(), pending_for("a"))
+                .await;
+        }
+
+        assert_eq!(store.len().await, 2);
+        assert!(store.take_pending("one").await.is_none());
+        assert!(store.take_pending("two").await.is_some());
+        assert!(store.take_pending("three").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_expires_at_configured_ttl() {
+        let ttl = std::time::Duration::from_secs(30);
+        let store = AuthorizationStore::new(DEFAULT_MAX_PENDING_AUTHORISATIONS, ttl);
+
+        let mut old = pending_for("a");
+        old.created_at = std::time::Instant::now() - ttl;
+        let mut recent = pending_for("a");
+        recent.created_at = std::time::Instant::now() - ttl / 2;
+        store.store_pending("old".to_string(), old).await;
+        store.store_pending("recent".to_string(), recent).await;
+
+        store.cleanup_expired().await;
+        assert!(store.take_pending("old").await.is_none());
+        assert!(store.take_pending("recent").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_issued_codes_are_kept_apart_from_consents() {
+        let store = AuthorizationStore::new(2, DEFAULT_AUTHORISATION_TTL);
+
+        // an old consent becomes a code with a fresh ttl...
+        let mut consent = pending_for("a");
+        consent.created_at = std::time::Instant::now() - DEFAULT_AUTHORISATION_TTL;
+        store.issue_code("code".to_string(), consent).await;
+
+        // ...which a pile of new consent sessions can't evict
+        for temp in ["t1", "t2", "t3"] {
+            store
+                .store_pending(temp.to_string(), pending_for("b"))
+                .await;
+        }
+        store.cleanup_expired().await;
+
+        assert!(store.take_pending("code").await.is_none());
+        assert_eq!(
+            store.redeem_code("t2").await.unwrap_err(),
+            RedeemError::ConsentCode
+        );
+        assert!(store.redeem_code("code").await.is_ok());
+        assert_eq!(
+            store.redeem_code("code").await.unwrap_err(),
+            RedeemError::Replayed {
+                client_id: "a".to_string()
+            }
+        );
+    }
+}
@@ -1,13 +1,15 @@
 use super::OAuthService;
-use super::authorization_code::{AuthorizationStore, ClientRegistry, verify_pkce};
+use super::authorization_code::{AuthorizationStore, ClientRegistry, RedeemError, verify_pkce};
 use super::traits::GrantType;
 use axum::{
-    Form,
-    extract::State,
-    http::{HeaderMap, StatusCode},
+    body::Bytes,
+    extract::{FromRequest, Request, State},
+    http::{HeaderMap, StatusCode, header},
     response::{IntoResponse, Json, Response},
 };
-use serde::{Deserialize, Serialize};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
 use std::sync::Arc;
 
 /// Combined OAuth state for all handlers
@@ -19,6 +21,117 @@ pub struct OAuthAppState {
     pub base_url: String,
     /// Optional PIN required to approve authorization requests
     pub consent_pin: Option<String>,
+    /// Whether the client_credentials grant is allowed at all
+    pub client_credentials_enabled: bool,
+}
+
+/// A request body that's either a form or JSON, going by its Content-Type (or, without one,
+/// whether it looks like JSON). The spec says /token takes a form and /register takes JSON, but
+/// plenty of clients send the other one. A form field given more than once becomes a list
+pub struct FormOrJson<T>(pub T);
+
+impl<S: Send + Sync, T: DeserializeOwned> FromRequest<S> for FormOrJson<T> {
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let mime = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|ct| {
+                ct.split(';')
+                    .next()
+                    .unwrap_or_default()
+                    .trim()
+                    .to_ascii_lowercase()
+            });
+        let body = Bytes::from_request(req, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        let json = match mime.as_deref() {
+            Some("application/x-www-form-urlencoded") => false,
+            Some(mime) if mime == "application/json" || mime.ends_with("+json") => true,
+            None => body.trim_ascii_start().starts_with(b"{"),
+            Some(mime) => {
+                return Err(error_response(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "invalid_request",
+                    Some(&format!(
+                        "Content-Type {} isn't supported, send application/x-www-form-urlencoded or application/json",
+                        mime
+                    )),
+                ));
+            }
+        };
+        let parsed = if json {
+            serde_json::from_slice(&body).map_err(|e| e.to_string())
+        } else {
+            serde_json::from_value(form_to_json(&body)).map_err(|e| e.to_string())
+        };
+        parsed.map(FormOrJson).map_err(|e| {
+            error_response(
+                StatusCode::BAD_REQUEST,
+                "invalid_request",
+                Some(&format!("Couldn't read the request: {}", e)),
+            )
+        })
+    }
+}
+
+/// A form body as a JSON object of strings, with repeated fields as lists of them
+fn form_to_json(body: &[u8]) -> serde_json::Value {
+    let mut object = serde_json::Map::new();
+    for (key, value) in url::form_urlencoded::parse(body) {
+        let value = serde_json::Value::String(value.into_owned());
+        match object.get_mut(key.as_ref()) {
+            Some(serde_json::Value::Array(values)) => values.push(value),
+            Some(existing) => *existing = serde_json::Value::Array(vec![existing.take(), value]),
+            None => {
+                object.insert(key.into_owned(), value);
+            }
+        }
+    }
+    serde_json::Value::Object(object)
+}
+
+/// A list that a form may have sent as a single value
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        Many(Vec<T>),
+        One(T),
+    }
+    Ok(
+        Option::<OneOrMany<T>>::deserialize(deserializer)?.map(|v| match v {
+            OneOrMany::Many(values) => values,
+            OneOrMany::One(value) => vec![value],
+        }),
+    )
+}
+
+/// A number that a form will have sent as a string
+fn number_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum NumberOrString {
+        Number(u64),
+        String(String),
+    }
+    match Option::<NumberOrString>::deserialize(deserializer)? {
+        None => Ok(None),
+        Some(NumberOrString::Number(n)) => Ok(Some(n)),
+        Some(NumberOrString::String(s)) => s
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| serde::de::Error::custom(format!("expected a number, got {:?}", s))),
+    }
 }
 
 /// OAuth 2.0 token request (supports both grant types)
@@ -35,6 +148,21 @@ pub struct TokenRequest {
     pub code_verifier: Option<String>,
     /// Redirect URI (required for authorization_code grant)
     pub redirect_uri: Option<String>,
+    /// The resource the token is for (RFC 8707), defaulting to the one given to /authorize
+    #[serde(default)]
+    pub resource: Option<String>,
+}
+
+/// The `requested` resource if it's this server (`base_url` or something under it), so a
+/// token response never claims to be good for somewhere else
+fn granted_resource(requested: Option<&str>, base_url: &str) -> Option<String> {
+    let requested = requested?;
+    let base_url = base_url.trim_end_matches('/');
+    let ours = match requested.strip_prefix(base_url) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    };
+    ours.then(|| requested.to_string())
 }
 
 /// OAuth 2.0 error response
@@ -47,13 +175,21 @@ pub struct ErrorResponse {
 /// Handler for POST /token
 pub async fn oauth_token_handler(
     State(state): State<OAuthAppState>,
-    Form(req): Form<TokenRequest>,
+    headers: HeaderMap,
+    FormOrJson(req): FormOrJson<TokenRequest>,
 ) -> Response {
     tracing::info!("Token request: grant_type={}", req.grant_type);
 
     match req.grant_type {
         GrantType::AuthorizationCode => handle_authorization_code_grant(&state, &req).await,
-        GrantType::ClientCredentials => handle_client_credentials_grant(&state, &req).await,
+        GrantType::ClientCredentials if state.client_credentials_enabled => {
+            handle_client_credentials_grant(&state, &headers, &req).await
+        }
+        GrantType::ClientCredentials => error_response(
+            StatusCode::BAD_REQUEST,
+            "unauthorized_client",
+            Some("The client_credentials grant is disabled on this server"),
+        ),
         GrantType::Unsupported => error_response(
             StatusCode::BAD_REQUEST,
             "unsupported_grant_type",
@@ -90,32 +226,44 @@ async fn handle_authorization_code_grant(state: &OAuthAppState, req: &TokenReque
     };
 
     // Look up the authorization code
-    let pending = match state.auth_store.take_pending(code).await {
-        Some(p) => p,
-        None => {
-            tracing::warn!("Invalid or expired authorization code");
-            return error_response(
-                StatusCode::BAD_REQUEST,
-                "invalid_grant",
-                Some("Invalid or expired authorization code"),
-            );
+    let pending = match state.auth_store.redeem_code(code).await {
+        Ok(p) => p,
+        Err(e) => {
+            let description = match e {
+                RedeemError::Replayed { client_id } => {
+                    tracing::warn!(
+                        "Replayed authorization code for client {} - it was already exchanged",
+                        client_id
+                    );
+                    "Authorization code has already been used"
+                }
+                RedeemError::ConsentCode => {
+                    tracing::warn!("Unapproved consent session code submitted to /token");
+                    "Invalid or expired authorization code"
+                }
+                RedeemError::Unknown => {
+                    tracing::warn!("Invalid or expired authorization code");
+                    "Invalid or expired authorization code"
+                }
+            };
+            return error_response(StatusCode::BAD_REQUEST, "invalid_grant", Some(description));
         }
     };
 
     // Verify client_id matches the original authorization request
-    if let Some(request_client_id) = &req.client_id {
-        if request_client_id != &pending.client_id {
-            tracing::warn!(
-                "client_id mismatch: expected '{}', got '{}'",
-                pending.client_id,
-                request_client_id
-            );
-            return error_response(
-                StatusCode::BAD_REQUEST,
-                "invalid_grant",
-                Some("client_id mismatch"),
-            );
-        }
+    if let Some(request_client_id) = &req.client_id
+        && request_client_id != &pending.client_id
+    {
+        tracing::warn!(
+            "client_id mismatch: expected '{}', got '{}'",
+            pending.client_id,
+            request_client_id
+        );
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_grant",
+            Some("client_id mismatch"),
+        );
     }
 
     // Verify redirect_uri matches (must match the one from the authorization request)
@@ -155,8 +303,12 @@ async fn handle_authorization_code_grant(state: &OAuthAppState, req: &TokenReque
     }
 
     // Issue token
-    match state.oauth_service.issue_token(&pending.client_id) {
-        Ok(token_response) => {
+    match state.oauth_service.issue_token(&pending.client_id).await {
+        Ok(mut token_response) => {
+            token_response.resource = granted_resource(
+                req.resource.as_deref().or(pending.resource.as_deref()),
+                &state.base_url,
+            );
             tracing::info!(
                 "Issued OAuth token via authorization_code for client: {}",
                 pending.client_id
@@ -174,8 +326,33 @@ async fn handle_authorization_code_grant(state: &OAuthAppState, req: &TokenReque
     }
 }
 
-async fn handle_client_credentials_grant(state: &OAuthAppState, req: &TokenRequest) -> Response {
-    let client_id = match &req.client_id {
+/// client_secret_basic: `Authorization: Basic base64(urlencode(id):urlencode(secret))`
+fn basic_client_credentials(headers: &HeaderMap) -> Option<(String, String)> {
+    let encoded = headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (id, secret) = decoded.split_once(':')?;
+    Some((
+        urlencoding::decode(id).ok()?.into_owned(),
+        urlencoding::decode(secret).ok()?.into_owned(),
+    ))
+}
+
+async fn handle_client_credentials_grant(
+    state: &OAuthAppState,
+    headers: &HeaderMap,
+    req: &TokenRequest,
+) -> Response {
+    // client_secret_basic takes priority, falling back to client_secret_post
+    let (client_id, client_secret) = match basic_client_credentials(headers) {
+        Some((id, secret)) => (Some(id), Some(secret)),
+        None => (req.client_id.clone(), req.client_secret.clone()),
+    };
+
+    let client_id = match &client_id {
         Some(id) => id,
         None => {
             return error_response(
@@ -186,7 +363,7 @@ async fn handle_client_credentials_grant(state: &OAuthAppState, req: &TokenReque
         }
     };
 
-    let client_secret = match &req.client_secret {
+    let client_secret = match &client_secret {
         Some(secret) => secret,
         None => {
             return error_response(
@@ -205,8 +382,14 @@ async fn handle_client_credentials_grant(state: &OAuthAppState, req: &TokenReque
     {
         Ok(client_info) => {
             // Issue token
-            match state.oauth_service.issue_token(&client_info.client_id) {
-                Ok(token_response) => {
+            match state
+                .oauth_service
+                .issue_token(&client_info.client_id)
+                .await
+            {
+                Ok(mut token_response) => {
+                    token_response.resource =
+                        granted_resource(req.resource.as_deref(), &state.base_url);
                     tracing::info!(
                         "Issued OAuth token via client_credentials for client: {}",
                         client_info.client_id
@@ -278,15 +461,23 @@ pub struct AuthorizationServerMetadata {
 /// Tells clients what auth methods we support
 pub async fn metadata_handler(State(state): State<OAuthAppState>) -> Response {
     let base_url = &state.base_url;
-    // advertise authorization_code with PKCE (public clients)
-    // client_credentials is still supported but not advertised to avoid confusion
+    // authorization_code with PKCE (public clients) is always on. client_credentials is only
+    // advertised when it's enabled, along with the ways confidential clients can send secrets
+    let mut grant_types_supported = vec!["authorization_code".to_string()];
+    let mut token_endpoint_auth_methods_supported = vec!["none".to_string()];
+    if state.client_credentials_enabled {
+        grant_types_supported.push("client_credentials".to_string());
+        token_endpoint_auth_methods_supported.push("client_secret_basic".to_string());
+        token_endpoint_auth_methods_supported.push("client_secret_post".to_string());
+    }
+
     let metadata = AuthorizationServerMetadata {
         issuer: base_url.clone(),
         authorization_endpoint: Some(format!("{}/authorize", base_url)),
         token_endpoint: format!("{}/token", base_url),
         registration_endpoint: Some(format!("{}/register", base_url)),
-        grant_types_supported: vec!["authorization_code".to_string()],
-        token_endpoint_auth_methods_supported: vec!["none".to_string()],
+        grant_types_supported,
+        token_endpoint_auth_methods_supported,
         response_types_supported: vec!["code".to_string()],
         code_challenge_methods_supported: Some(vec!["S256".to_string()]),
     };
@@ -303,8 +494,13 @@ pub async fn metadata_handler(State(state): State<OAuthAppState>) -> Response {
 #[derive(Debug, Deserialize)]
 pub struct ClientRegistrationRequest {
     pub client_name: Option<String>,
+    #[serde(default, deserialize_with = "one_or_many")]
     pub grant_types: Option<Vec<GrantType>>,
+    #[serde(default, deserialize_with = "one_or_many")]
     pub redirect_uris: Option<Vec<String>>,
+    /// non-standard: token lifetime in seconds for this client, instead of the server default
+    #[serde(default, deserialize_with = "number_or_string")]
+    pub token_lifetime: Option<u64>,
 }
 
 /// Dynamic Client Registration Response (RFC 7591)
@@ -320,13 +516,15 @@ pub struct ClientRegistrationResponse {
     pub redirect_uris: Option<Vec<String>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub token_endpoint_auth_method: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_lifetime: Option<u64>,
 }
 
 /// Dynamic client registration (RFC 7591)
 /// NB: credentials aren't persisted - they won't survive a restart
 pub async fn register_handler(
     State(state): State<OAuthAppState>,
-    Json(req): Json<ClientRegistrationRequest>,
+    FormOrJson(req): FormOrJson<ClientRegistrationRequest>,
 ) -> Response {
     tracing::info!(
         "dynamic client registration request: client_name={:?}, grant_types={:?}, redirect_uris={:?}",
@@ -335,6 +533,17 @@ pub async fn register_handler(
         req.redirect_uris
     );
 
+    let token_lifetime = req.token_lifetime.map(std::time::Duration::from_secs);
+    if let Some(lifetime) = token_lifetime
+        && let Err(e) = state.oauth_service.check_token_lifetime(lifetime)
+    {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_client_metadata",
+            Some(&e.to_string()),
+        );
+    }
+
     // Generate new client credentials
     use uuid::Uuid;
     let client_id = format!("mcp-client-{}", Uuid::new_v4());
@@ -347,7 +556,11 @@ pub async fn register_handler(
         .grant_types
         .unwrap_or_else(|| vec![GrantType::AuthorizationCode])
         .into_iter()
-        .filter(|g| !matches!(g, GrantType::Unsupported))
+        .filter(|g| match g {
+            GrantType::AuthorizationCode => true,
+            GrantType::ClientCredentials => state.client_credentials_enabled,
+            GrantType::Unsupported => false,
+        })
         .collect();
     // default to authorization_code if client only requested unsupported grants
     let grant_types = if grant_types.is_empty() {
@@ -364,6 +577,7 @@ pub async fn register_handler(
             client_id.clone(),
             redirect_uris.clone(),
             Some(client_secret.clone()),
+            token_lifetime,
         )
         .await;
 
@@ -379,6 +593,7 @@ pub async fn register_handler(
             Some(redirect_uris)
         },
         token_endpoint_auth_method: Some("none".to_string()),
+        token_lifetime: req.token_lifetime,
     };
 
     tracing::info!(
@@ -388,3 +603,289 @@ pub async fn register_handler(
 
     (StatusCode::CREATED, Json(response)).into_response()
 }
+
+/// Admin request to cut a client off
+#[derive(Debug, Deserialize)]
+pub struct RevokeClientRequest {
+    pub client_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RevokeClientResponse {
+    pub client_id: String,
+    /// pending authorisations and unredeemed codes thrown away
+    pub pending_removed: usize,
+    /// whether it was a dynamically registered client (which can't come back without
+    /// registering again)
+    pub deregistered: bool,
+}
+
+/// Admin endpoint: purge a client's in-flight authorisations, deregister it, and revoke every
+/// token it's been issued so far
+pub async fn revoke_client_handler(
+    State(state): State<OAuthAppState>,
+    Json(req): Json<RevokeClientRequest>,
+) -> Response {
+    let pending_removed = state.auth_store.remove_client(&req.client_id).await;
+    let deregistered = state.client_registry.deregister(&req.client_id).await;
+    state.oauth_service.revoke_client_tokens(&req.client_id);
+
+    tracing::warn!(
+        "Revoked client '{}': {} pending authorisations removed, deregistered: {}",
+        req.client_id,
+        pending_removed,
+        deregistered
+    );
+
+    let response = RevokeClientResponse {
+        client_id: req.client_id,
+        pending_removed,
+        deregistered,
+    };
+    (StatusCode::OK, Json(response)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::authorization_code::PendingAuthorization;
+    use crate::auth::traits::CodeChallengeMethod;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use sha2::{Digest, Sha256};
+
+    const VERIFIER: &str = "a-sufficiently-long-and-random-pkce-code-verifier";
+    const REDIRECT_URI: &str = "https://example.com/callback";
+
+    fn app_state() -> OAuthAppState {
+        let client_registry = Arc::new(ClientRegistry::new());
+        OAuthAppState {
+            oauth_service: Arc::new(OAuthService::new(
+                crate::auth::test_config(),
+                client_registry.clone(),
+            )),
+            auth_store: Arc::new(AuthorizationStore::default()),
+            client_registry,
+            base_url: "https://yamos.example".to_string(),
+            consent_pin: None,
+            client_credentials_enabled: false,
+        }
+    }
+
+    fn pending() -> PendingAuthorization {
+        PendingAuthorization {
+            client_id: "static".to_string(),
+            redirect_uri: REDIRECT_URI.to_string(),
+            code_challenge: URL_SAFE_NO_PAD.encode(Sha256::digest(VERIFIER)),
+            code_challenge_method: CodeChallengeMethod::S256,
+            state: None,
+            resource: None,
+            created_at: std::time::Instant::now(),
+        }
+    }
+
+    /// POST /token with an authorization_code grant, returning the status and error (if any)
+    async fn exchange(state: &OAuthAppState, code: &str) -> (StatusCode, Option<String>) {
+        let req = TokenRequest {
+            grant_type: GrantType::AuthorizationCode,
+            client_id: Some("static".to_string()),
+            client_secret: None,
+            code: Some(code.to_string()),
+            code_verifier: Some(VERIFIER.to_string()),
+            redirect_uri: Some(REDIRECT_URI.to_string()),
+            resource: None,
+        };
+        let response =
+            oauth_token_handler(State(state.clone()), HeaderMap::new(), FormOrJson(req)).await;
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        (status, body["error"].as_str().map(|s| s.to_string()))
+    }
+
+    #[tokio::test]
+    async fn test_consent_temp_code_cannot_be_exchanged() {
+        let state = app_state();
+        state
+            .auth_store
+            .store_pending("temp".to_string(), pending())
+            .await;
+
+        let (status, error) = exchange(&state, "temp").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error.as_deref(), Some("invalid_grant"));
+        // and it's been burned, so it can't be approved afterwards either
+        assert!(state.auth_store.take_pending("temp").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_auth_code_is_single_use() {
+        let state = app_state();
+        state
+            .auth_store
+            .issue_code("code".to_string(), pending())
+            .await;
+
+        let (status, error) = exchange(&state, "code").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(error, None);
+
+        let (status, error) = exchange(&state, "code").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(error.as_deref(), Some("invalid_grant"));
+        assert_eq!(
+            state.auth_store.redeem_code("code").await.unwrap_err(),
+            RedeemError::Replayed {
+                client_id: "static".to_string()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_response_resource() {
+        let base = "https://yamos.example";
+        assert_eq!(granted_resource(None, base), None);
+        assert_eq!(granted_resource(Some(base), base).as_deref(), Some(base));
+        assert_eq!(
+            granted_resource(Some("https://yamos.example/mcp"), "https://yamos.example/")
+                .as_deref(),
+            Some("https://yamos.example/mcp")
+        );
+        assert_eq!(
+            granted_resource(Some("https://yamos.example.com/mcp"), base),
+            None
+        );
+        assert_eq!(
+            granted_resource(Some("https://elsewhere.example"), base),
+            None
+        );
+
+        let state = app_state();
+        let pending = PendingAuthorization {
+            resource: Some("https://yamos.example/mcp".to_string()),
+            ..pending()
+        };
+        state
+            .auth_store
+            .issue_code("code".to_string(), pending)
+            .await;
+        let response = oauth_token_handler(
+            State(state.clone()),
+            HeaderMap::new(),
+            FormOrJson(TokenRequest {
+                grant_type: GrantType::AuthorizationCode,
+                client_id: Some("static".to_string()),
+                client_secret: None,
+                code: Some("code".to_string()),
+                code_verifier: Some(VERIFIER.to_string()),
+                redirect_uri: Some(REDIRECT_URI.to_string()),
+                resource: None,
+            }),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let token: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        // tokens aren't scoped, so there's no scope to report
+        assert!(token.get("scope").is_none());
+        assert_eq!(token["resource"], "https://yamos.example/mcp");
+    }
+
+    fn basic(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_basic_client_credentials() {
+        // "my%3Aclient:s3cr%25t" - both halves are urlencoded before base64
+        let encoded = STANDARD.encode("my%3Aclient:s3cr%25t");
+        assert_eq!(
+            basic_client_credentials(&basic(&format!("Basic {}", encoded))),
+            Some(("my:client".to_string(), "s3cr%t".to_string()))
+        );
+
+        assert_eq!(basic_client_credentials(&HeaderMap::new()), None);
+        assert_eq!(basic_client_credentials(&basic("Bearer abc")), None);
+        assert_eq!(basic_client_credentials(&basic("Basic !!!")), None);
+        let no_colon = STANDARD.encode("justanid");
+        assert_eq!(
+            basic_client_credentials(&basic(&format!("Basic {}", no_colon))),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_form_or_json_bodies() {
+        async fn parse<T: DeserializeOwned>(
+            content_type: Option<&str>,
+            body: &str,
+        ) -> Result<T, StatusCode> {
+            let mut req = axum::http::Request::post("/token");
+            if let Some(content_type) = content_type {
+                req = req.header(header::CONTENT_TYPE, content_type);
+            }
+            let req = req.body(axum::body::Body::from(body.to_string())).unwrap();
+            FormOrJson::<T>::from_request(req, &())
+                .await
+                .map(|FormOrJson(value)| value)
+                .map_err(|response| response.status())
+        }
+
+        let form = "grant_type=client_credentials&client_id=a&client_secret=b";
+        let json =
+            r#"{"grant_type": "client_credentials", "client_id": "a", "client_secret": "b"}"#;
+        for (content_type, body) in [
+            (Some("application/x-www-form-urlencoded"), form),
+            (Some("application/json; charset=utf-8"), json),
+            (None, form),
+            (None, json),
+        ] {
+            let req: TokenRequest = parse(content_type, body).await.unwrap();
+            assert_eq!(req.grant_type, GrantType::ClientCredentials);
+            assert_eq!(req.client_secret.as_deref(), Some("b"));
+        }
+        assert_eq!(
+            parse::<TokenRequest>(Some("text/plain"), form)
+                .await
+                .unwrap_err(),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE
+        );
+        assert_eq!(
+            parse::<TokenRequest>(Some("application/json"), form)
+                .await
+                .unwrap_err(),
+            StatusCode::BAD_REQUEST
+        );
+
+        // registration as a form, with one redirect uri and then two
+        let req: ClientRegistrationRequest = parse(
+            Some("application/x-www-form-urlencoded"),
+            "client_name=cli&redirect_uris=https%3A%2F%2Fa&grant_types=authorization_code&token_lifetime=60",
+        )
+        .await
+        .unwrap();
+        assert_eq!(req.redirect_uris, Some(vec!["https://a".to_string()]));
+        assert_eq!(req.grant_types, Some(vec![GrantType::AuthorizationCode]));
+        assert_eq!(req.token_lifetime, Some(60));
+        let req: ClientRegistrationRequest = parse(
+            None,
+            "redirect_uris=https%3A%2F%2Fa&redirect_uris=https%3A%2F%2Fb",
+        )
+        .await
+        .unwrap();
+        assert_eq!(req.redirect_uris.unwrap().len(), 2);
+        let req: ClientRegistrationRequest = parse(
+            Some("application/json"),
+            r#"{"redirect_uris": ["https://a"], "token_lifetime": 60}"#,
+        )
+        .await
+        .unwrap();
+        assert_eq!(req.token_lifetime, Some(60));
+        assert_eq!(req.grant_types, None);
+    }
+}
@@ -21,7 +21,7 @@ pub struct AuthMiddlewareConfig {
 /// Returns WWW-Authenticate header on 401 as required by RFC 9728
 pub async fn jwt_auth_middleware(
     State(config): State<AuthMiddlewareConfig>,
-    req: Request,
+    mut req: Request,
     next: Next,
 ) -> Response {
     let method = req.method().clone();
@@ -45,6 +45,8 @@ pub async fn jwt_auth_middleware(
             match config.oauth_service.validate_token(token) {
                 Ok(claims) => {
                     tracing::debug!("Valid JWT token for client: {}", claims.sub);
+                    // tools pick these up (via the request parts rmcp hands them) for tracing
+                    req.extensions_mut().insert(claims);
                     next.run(req).await
                 }
                 Err(e) => {
@@ -94,11 +96,28 @@ fn unauthorized_response(base_url: &str, error: Option<&str>) -> Response {
     (StatusCode::UNAUTHORIZED, headers).into_response()
 }
 
+/// What a legacy bearer token lets its holder do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LegacyAccess {
+    /// Only tools that don't change the vault (--auth-token-ro)
+    ReadOnly,
+    /// Everything (--auth-token or --auth-token-rw)
+    ReadWrite,
+}
+
+/// Put in the request extensions by [`legacy_auth_middleware`], so tools can tell a caller
+/// that used a static bearer token from one that didn't authenticate at all, and what the
+/// token allows
+#[derive(Debug, Clone, Copy)]
+pub struct LegacyCaller {
+    pub access: LegacyAccess,
+}
+
 /// for """backward compatibility"""
 pub async fn legacy_auth_middleware(
-    req: Request,
+    mut req: Request,
     next: Next,
-    expected_token: Arc<String>,
+    tokens: Arc<Vec<(String, LegacyAccess)>>,
 ) -> Result<Response, StatusCode> {
     let auth_header = req
         .headers()
@@ -108,12 +127,23 @@ pub async fn legacy_auth_middleware(
     match auth_header {
         Some(header) if header.starts_with("Bearer ") => {
             let token = &header[7..];
-            // Use constant-time comparison to prevent timing attacks
-            if token.as_bytes().ct_eq(expected_token.as_bytes()).into() {
-                Ok(next.run(req).await)
-            } else {
-                tracing::warn!("Invalid legacy authentication token");
-                Err(StatusCode::UNAUTHORIZED)
+            // Use constant-time comparison to prevent timing attacks, and check every token so
+            // the time taken doesn't say which one matched either
+            let mut access = None;
+            for (expected, token_access) in tokens.iter() {
+                if bool::from(token.as_bytes().ct_eq(expected.as_bytes())) {
+                    access = Some(*token_access);
+                }
+            }
+            match access {
+                Some(access) => {
+                    req.extensions_mut().insert(LegacyCaller { access });
+                    Ok(next.run(req).await)
+                }
+                None => {
+                    tracing::warn!("Invalid legacy authentication token");
+                    Err(StatusCode::UNAUTHORIZED)
+                }
             }
         }
         _ => {
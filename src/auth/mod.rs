@@ -11,16 +11,19 @@ pub use authorization_code::{
 pub use client_credentials::ClientValidator;
 pub use handlers::{
     OAuthAppState, metadata_handler, oauth_token_handler, protected_resource_metadata_handler,
-    register_handler,
+    register_handler, revoke_client_handler,
+};
+pub use middleware::{
+    AuthMiddlewareConfig, LegacyAccess, LegacyCaller, jwt_auth_middleware, legacy_auth_middleware,
 };
-pub use middleware::{AuthMiddlewareConfig, jwt_auth_middleware, legacy_auth_middleware};
 pub use token::{JwtTokenIssuer, JwtTokenValidator};
 pub use traits::{
     Claims, ClientInfo, CredentialValidator, TokenIssuer, TokenResponse, TokenValidator,
 };
 
-use anyhow::Result;
-use std::sync::Arc;
+use anyhow::{Result, anyhow};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 use std::time::Duration;
 
 /// Configuration for the authentication system
@@ -30,6 +33,20 @@ pub struct AuthConfig {
     pub client_id: String,
     pub client_secret: String,
     pub token_expiration: Option<Duration>,
+    /// token lifetime for the static client, overriding `token_expiration`
+    pub client_token_expiration: Option<Duration>,
+    /// longest lifetime a client may ask for
+    pub max_token_lifetime: Option<Duration>,
+    /// `iss` claim minted into tokens and required when validating them
+    pub issuer: String,
+    /// How much clock skew to forgive when checking exp/nbf
+    pub clock_skew: Duration,
+    /// Allow (and advertise) the client_credentials grant
+    pub client_credentials_enabled: bool,
+    /// How many authorisations can be in flight before the oldest get evicted
+    pub max_pending_authorisations: usize,
+    /// How long an authorisation (consent screen or unredeemed code) stays valid
+    pub auth_code_ttl: Duration,
 }
 
 /// Complete OAuth service that combines validation, issuing, and verification
@@ -38,6 +55,13 @@ pub struct OAuthService {
     credential_validator: Arc<dyn CredentialValidator + Send + Sync>,
     token_issuer: Arc<dyn TokenIssuer + Send + Sync>,
     token_validator: Arc<dyn TokenValidator + Send + Sync>,
+    client_registry: Arc<ClientRegistry>,
+    static_client_id: String,
+    static_client_lifetime: Option<Duration>,
+    max_token_lifetime: Option<Duration>,
+    /// client_id -> unix time it was revoked; tokens it got before then, or in that same second,
+    /// are dead
+    revoked_clients: Arc<RwLock<HashMap<String, i64>>>,
 }
 
 impl OAuthService {
@@ -45,20 +69,30 @@ impl OAuthService {
         let credential_validator = Arc::new(ClientValidator::new(
             config.client_id.clone(),
             config.client_secret.clone(),
-            client_registry,
+            client_registry.clone(),
         ));
 
         let token_issuer = Arc::new(JwtTokenIssuer::new(
             config.jwt_secret.clone(),
+            config.issuer.clone(),
             config.token_expiration,
         ));
 
-        let token_validator = Arc::new(JwtTokenValidator::new(config.jwt_secret.clone()));
+        let token_validator = Arc::new(JwtTokenValidator::new(
+            config.jwt_secret.clone(),
+            &config.issuer,
+            config.clock_skew,
+        ));
 
         Self {
             credential_validator,
             token_issuer,
             token_validator,
+            client_registry,
+            static_client_id: config.client_id,
+            static_client_lifetime: config.client_token_expiration,
+            max_token_lifetime: config.max_token_lifetime,
+            revoked_clients: Arc::default(),
         }
     }
 
@@ -73,11 +107,166 @@ impl OAuthService {
             .await
     }
 
-    pub fn issue_token(&self, client_id: &str) -> Result<TokenResponse> {
-        self.token_issuer.issue_token(client_id, None)
+    /// rejects a per-client token lifetime longer than the server allows, or one of 0s, whose
+    /// tokens would be expired as soon as they're issued
+    pub fn check_token_lifetime(&self, lifetime: Duration) -> Result<()> {
+        if lifetime.is_zero() {
+            anyhow::bail!("token lifetime must be at least 1s");
+        }
+        match self.max_token_lifetime {
+            Some(max) if lifetime > max => Err(anyhow::anyhow!(
+                "token lifetime of {}s exceeds the maximum of {}s",
+                lifetime.as_secs(),
+                max.as_secs()
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// issues a token using the client's own lifetime if it has one, else the server default
+    pub async fn issue_token(&self, client_id: &str) -> Result<TokenResponse> {
+        let lifetime = match self.client_registry.token_lifetime(client_id).await {
+            Some(lifetime) => Some(lifetime),
+            None if client_id == self.static_client_id => self.static_client_lifetime,
+            None => None,
+        };
+        if let Some(lifetime) = lifetime {
+            self.check_token_lifetime(lifetime)?;
+        }
+        self.token_issuer.issue_token(client_id, lifetime)
     }
 
     pub fn validate_token(&self, token: &str) -> Result<Claims> {
-        self.token_validator.validate_token(token)
+        let claims = self.token_validator.validate_token(token)?;
+
+        let revoked = self
+            .revoked_clients
+            .read()
+            .expect("revoked clients lock poisoned");
+        if let Some(&revoked_at) = revoked.get(&claims.sub)
+            && claims.iat <= revoked_at
+        {
+            return Err(anyhow!("Token for client {} has been revoked", claims.sub));
+        }
+
+        Ok(claims)
+    }
+
+    /// kills every token issued to a client so far. new ones can still be issued afterwards if
+    /// the client is still allowed to get them
+    pub fn revoke_client_tokens(&self, client_id: &str) {
+        self.revoked_clients
+            .write()
+            .expect("revoked clients lock poisoned")
+            .insert(client_id.to_string(), chrono::Utc::now().timestamp());
+    }
+}
+
+/// Config with a static client "static" / "static-secret", for tests
+#[cfg(test)]
+pub(crate) fn test_config() -> AuthConfig {
+    AuthConfig {
+        jwt_secret: "secret".to_string(),
+        client_id: "static".to_string(),
+        client_secret: "static-secret".to_string(),
+        token_expiration: Some(Duration::from_secs(3600)),
+        client_token_expiration: None,
+        max_token_lifetime: Some(Duration::from_secs(86400)),
+        issuer: "yamos".to_string(),
+        clock_skew: Duration::from_secs(60),
+        client_credentials_enabled: false,
+        max_pending_authorisations: authorization_code::DEFAULT_MAX_PENDING_AUTHORISATIONS,
+        auth_code_ttl: authorization_code::DEFAULT_AUTHORISATION_TTL,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn service(static_lifetime: Option<Duration>) -> (OAuthService, Arc<ClientRegistry>) {
+        let registry = Arc::new(ClientRegistry::new());
+        let config = AuthConfig {
+            client_token_expiration: static_lifetime,
+            ..test_config()
+        };
+        (OAuthService::new(config, registry.clone()), registry)
+    }
+
+    #[tokio::test]
+    async fn test_per_client_token_lifetimes() {
+        let (service, registry) = service(Some(Duration::from_secs(7200)));
+        registry
+            .register(
+                "ci".to_string(),
+                vec![],
+                None,
+                Some(Duration::from_secs(300)),
+            )
+            .await;
+        registry
+            .register("plain".to_string(), vec![], None, None)
+            .await;
+
+        let expires_in = |client: &'static str| {
+            let service = &service;
+            async move { service.issue_token(client).await.unwrap().expires_in }
+        };
+        assert_eq!(expires_in("ci").await, Some(300));
+        assert_eq!(expires_in("static").await, Some(7200));
+        assert_eq!(expires_in("plain").await, Some(3600));
+    }
+
+    #[tokio::test]
+    async fn test_token_lifetime_over_max_rejected() {
+        let (service, registry) = service(None);
+        assert!(
+            service
+                .check_token_lifetime(Duration::from_secs(86400))
+                .is_ok()
+        );
+        assert!(
+            service
+                .check_token_lifetime(Duration::from_secs(86401))
+                .is_err()
+        );
+        assert!(service.check_token_lifetime(Duration::ZERO).is_err());
+
+        registry
+            .register(
+                "greedy".to_string(),
+                vec![],
+                None,
+                Some(Duration::from_secs(999999)),
+            )
+            .await;
+        assert!(service.issue_token("greedy").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_revoked_client_tokens_rejected() {
+        let (service, _registry) = service(None);
+        let token = service.issue_token("static").await.unwrap().access_token;
+        let other = service.issue_token("other").await.unwrap().access_token;
+        assert!(service.validate_token(&token).is_ok());
+
+        service.revoke_client_tokens("static");
+        assert!(service.validate_token(&token).is_err());
+        assert!(service.validate_token(&other).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_revocation_covers_tokens_from_the_same_second() {
+        let (service, _registry) = service(None);
+        let token = service.issue_token("static").await.unwrap().access_token;
+        let iat = service.validate_token(&token).unwrap().iat;
+
+        // iat only has whole seconds, so a token from the second of the revocation is dropped too
+        service
+            .revoked_clients
+            .write()
+            .unwrap()
+            .insert("static".to_string(), iat);
+        assert!(service.validate_token(&token).is_err());
     }
 }
@@ -6,13 +6,19 @@ use uuid::Uuid;
 
 pub struct JwtTokenIssuer {
     encoding_key: EncodingKey,
+    issuer: String,
     default_expiration: Option<std::time::Duration>,
 }
 
 impl JwtTokenIssuer {
-    pub fn new(secret: String, default_expiration: Option<std::time::Duration>) -> Self {
+    pub fn new(
+        secret: String,
+        issuer: String,
+        default_expiration: Option<std::time::Duration>,
+    ) -> Self {
         Self {
             encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            issuer,
             default_expiration,
         }
     }
@@ -34,8 +40,9 @@ impl TokenIssuer for JwtTokenIssuer {
                 (now + Duration::from_std(d).expect("token expiration duration out of range"))
                     .timestamp()
             }),
+            nbf: Some(now.timestamp()),
             jti: Uuid::new_v4().to_string(),
-            iss: "yamos".to_string(),
+            iss: self.issuer.clone(),
         };
 
         let token = encode(&Header::default(), &claims, &self.encoding_key)
@@ -45,6 +52,7 @@ impl TokenIssuer for JwtTokenIssuer {
             access_token: token,
             token_type: TokenType::Bearer,
             expires_in: duration.map(|d| d.as_secs()),
+            resource: None,
         })
     }
 }
@@ -55,10 +63,14 @@ pub struct JwtTokenValidator {
 }
 
 impl JwtTokenValidator {
-    pub fn new(secret: String) -> Self {
+    /// `leeway` is how far out exp/nbf can be before we care, to paper over clock skew between
+    /// us and whoever's holding the token
+    pub fn new(secret: String, issuer: &str, leeway: std::time::Duration) -> Self {
         let mut validation = Validation::new(Algorithm::HS256);
-        validation.set_issuer(&["yamos"]);
+        validation.set_issuer(&[issuer]);
         validation.validate_exp = true; // Will validate if exp claim exists
+        validation.validate_nbf = true; // ditto nbf
+        validation.leeway = leeway.as_secs();
         validation.required_spec_claims = vec!["sub".to_string(), "iat".to_string()]
             .into_iter()
             .collect();
@@ -78,3 +90,65 @@ impl TokenValidator for JwtTokenValidator {
         Ok(token_data.claims)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "test-secret";
+    const LEEWAY: std::time::Duration = std::time::Duration::from_secs(60);
+
+    fn mint(issuer: &str) -> String {
+        JwtTokenIssuer::new(SECRET.to_string(), issuer.to_string(), None)
+            .issue_token("client", None)
+            .unwrap()
+            .access_token
+    }
+
+    #[test]
+    fn test_custom_issuer_validates() {
+        let validator = JwtTokenValidator::new(SECRET.to_string(), "vault-a", LEEWAY);
+        let claims = validator.validate_token(&mint("vault-a")).unwrap();
+        assert_eq!(claims.iss, "vault-a");
+        assert_eq!(claims.sub, "client");
+    }
+
+    #[test]
+    fn test_mismatched_issuer_rejected() {
+        let validator = JwtTokenValidator::new(SECRET.to_string(), "vault-a", LEEWAY);
+        assert!(validator.validate_token(&mint("vault-b")).is_err());
+        assert!(validator.validate_token(&mint("yamos")).is_err());
+    }
+
+    /// Token whose exp/nbf are offset from now by the given number of seconds
+    fn mint_skewed(exp: i64, nbf: i64) -> String {
+        let now = Utc::now().timestamp();
+        let claims = Claims {
+            sub: "client".to_string(),
+            iat: now,
+            exp: Some(now + exp),
+            nbf: Some(now + nbf),
+            jti: Uuid::new_v4().to_string(),
+            iss: "yamos".to_string(),
+        };
+        let key = EncodingKey::from_secret(SECRET.as_bytes());
+        encode(&Header::default(), &claims, &key).unwrap()
+    }
+
+    #[test]
+    fn test_leeway_tolerates_small_skew() {
+        let strict = JwtTokenValidator::new(SECRET.to_string(), "yamos", Default::default());
+        let lenient = JwtTokenValidator::new(SECRET.to_string(), "yamos", LEEWAY);
+
+        // just expired, and not valid for a few more seconds
+        for token in [mint_skewed(-30, -3600), mint_skewed(3600, 30)] {
+            assert!(strict.validate_token(&token).is_err());
+            assert!(lenient.validate_token(&token).is_ok());
+        }
+
+        // way out is still way out
+        for token in [mint_skewed(-300, -3600), mint_skewed(3600, 300)] {
+            assert!(lenient.validate_token(&token).is_err());
+        }
+    }
+}
@@ -101,6 +101,9 @@ pub struct TokenResponse {
     pub token_type: TokenType,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub expires_in: Option<u64>, // seconds
+    /// The resource the token is for, echoed from the request when it's this server (RFC 8707)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -109,6 +112,8 @@ pub struct Claims {
     pub iat: i64,    // Issued at
     #[serde(skip_serializing_if = "Option::is_none")]
     pub exp: Option<i64>, // Expiration time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<i64>, // Not valid before
     pub jti: String, // JWT ID (unique identifier)
     pub iss: String, // Issuer
 }
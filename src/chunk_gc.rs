@@ -0,0 +1,89 @@
+//! Cleans up orphaned chunks. A save deletes the note's old chunks once the new version is
+//! written, but if that fails (or yamos dies in between) they're left behind for good. Every
+//! so often this looks for chunks no note refers to, and deletes the ones that have looked
+//! orphaned for longer than the grace period, which gives any save still writing its chunks
+//! (or a device that's been offline a while) plenty of time to catch up
+
+use crate::couchdb::{CouchDbClient, CouchResult};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// Shortest interval allowed, since every sweep lists all the chunks in the database
+pub const MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct ChunkSweeper {
+    db: CouchDbClient,
+    interval: Duration,
+    grace: Duration,
+}
+
+impl ChunkSweeper {
+    pub fn new(db: CouchDbClient, interval: Duration, grace: Duration) -> Self {
+        Self {
+            db,
+            interval,
+            grace,
+        }
+    }
+
+    pub async fn run(self, cancel: CancellationToken) {
+        tracing::info!(
+            "Sweeping orphaned chunks in {} every {}s, once they've been orphaned for {}h",
+            self.db.database(),
+            self.interval.as_secs(),
+            self.grace.as_secs() / 3600
+        );
+        // when each orphan was first seen. A restart starts the clock again, which only ever
+        // keeps a chunk for longer
+        let mut first_seen = HashMap::new();
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(self.interval) => {}
+                _ = cancel.cancelled() => return,
+            }
+            match self.sweep(&first_seen, Instant::now()).await {
+                Ok(orphans) => first_seen = orphans,
+                Err(e) => tracing::warn!("Chunk sweep of {} failed: {}", self.db.database(), e),
+            }
+        }
+    }
+
+    /// Deletes the orphans `first_seen` before the grace period, and returns when each one
+    /// that's left was first seen, for next time
+    async fn sweep(
+        &self,
+        first_seen: &HashMap<String, Instant>,
+        now: Instant,
+    ) -> CouchResult<HashMap<String, Instant>> {
+        let mut orphans = HashMap::new();
+        let mut deleted = 0;
+        for (id, rev) in self.db.orphaned_chunks().await? {
+            let seen = first_seen.get(&id).copied().unwrap_or(now);
+            if now.duration_since(seen) < self.grace {
+                orphans.insert(id, seen);
+                continue;
+            }
+            match self.db.delete_chunk(&id, &rev).await {
+                Ok(true) => deleted += 1,
+                Ok(false) => {}
+                Err(e) => {
+                    tracing::warn!("Couldn't delete orphaned chunk {}: {}", id, e);
+                    orphans.insert(id, seen);
+                }
+            }
+        }
+        if deleted > 0 {
+            tracing::info!(
+                "Deleted {} orphaned chunks from {}",
+                deleted,
+                self.db.database()
+            );
+        }
+        tracing::debug!(
+            "{} chunks look orphaned but are still within the grace period",
+            orphans.len()
+        );
+        Ok(orphans)
+    }
+}
@@ -0,0 +1,233 @@
+//! MCP's logging capability: clients pick a level with `logging/setLevel`, then get yamos's own
+//! log events at that level or above as `notifications/message`. Handy when stderr goes somewhere
+//! nobody looks, like a desktop app running yamos over stdio. Only events RUST_LOG lets through
+//! get this far, so a client can't ask for more detail than the server logs anyway. A client
+//! only gets the events logged while handling its own tool calls, never another client's notes
+//! or anything from the background tasks
+
+use rmcp::model::{LoggingLevel, LoggingMessageNotificationParam};
+use rmcp::service::{Peer, RoleServer};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+/// The span field that says which client a span's work is for, see [`client_key`]
+const CLIENT_FIELD: &str = "client";
+
+/// The clients that asked for log messages, shared between the tracing layer and the server
+#[derive(Clone, Default)]
+pub struct ClientLog {
+    clients: Arc<Mutex<Vec<Client>>>,
+}
+
+struct Client {
+    peer: Peer<RoleServer>,
+    key: Option<u64>,
+    /// The least severe messages it wants
+    level: LoggingLevel,
+}
+
+/// Identifies the connection `peer` is on. Two handles on the same connection share its client
+/// info, so that's what identifies one. Tool call spans record it in their [`CLIENT_FIELD`]
+pub fn client_key(peer: &Peer<RoleServer>) -> Option<u64> {
+    peer.peer_info()
+        .map(|info| std::ptr::from_ref(info) as usize as u64)
+}
+
+fn to_mcp_level(level: &Level) -> LoggingLevel {
+    match *level {
+        Level::ERROR => LoggingLevel::Error,
+        Level::WARN => LoggingLevel::Warning,
+        Level::INFO => LoggingLevel::Info,
+        _ => LoggingLevel::Debug,
+    }
+}
+
+impl ClientLog {
+    /// Send a client log messages at `level` and above from now on (replacing any earlier level)
+    pub fn set_level(&self, peer: Peer<RoleServer>, level: LoggingLevel) {
+        let key = client_key(&peer);
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        clients.retain(|c| !c.peer.is_transport_closed() && (key.is_none() || c.key != key));
+        clients.push(Client { peer, key, level });
+    }
+
+    /// Sends a message to the client with `key`, if it asked for ones at `level`
+    fn send(&self, key: u64, level: LoggingLevel, logger: &str, message: String) {
+        // tracing events can come from outside the runtime (or during shutdown), nothing to do then
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let mut clients = self.clients.lock().unwrap_or_else(|e| e.into_inner());
+        clients.retain(|c| !c.peer.is_transport_closed());
+        for client in clients.iter() {
+            if client.key != Some(key) || (level as u8) < (client.level as u8) {
+                continue;
+            }
+            let peer = client.peer.clone();
+            let param = LoggingMessageNotificationParam {
+                level,
+                logger: Some(logger.to_string()),
+                data: message.clone().into(),
+            };
+            // a client that's gone away isn't worth logging about (and that would loop)
+            runtime.spawn(async move {
+                let _ = peer.notify_logging_message(param).await;
+            });
+        }
+    }
+}
+
+/// Turns an event's fields into "message key=value ..."
+#[derive(Default)]
+struct MessageVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for MessageVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            let _ = write!(self.fields, " {}={}", field.name(), value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Picks the client key out of a span's fields
+#[derive(Default)]
+struct ClientVisitor(Option<u64>);
+
+impl Visit for ClientVisitor {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == CLIENT_FIELD {
+            self.0 = Some(value);
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+/// Kept in a span's extensions: the client its work is for
+struct SpanClient(u64);
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for ClientLog {
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = ClientVisitor::default();
+        attrs.record(&mut visitor);
+        if let Some(key) = visitor.0
+            && let Some(span) = ctx.span(id)
+        {
+            span.extensions_mut().insert(SpanClient(key));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        // just ours: rmcp and hyper logging about sending the notification would never end
+        if !metadata.target().starts_with("yamos") {
+            return;
+        }
+        if self
+            .clients
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_empty()
+        {
+            return;
+        }
+        // only events from inside a client's own request go anywhere
+        let Some(key) = ctx.event_scope(event).and_then(|scope| {
+            scope
+                .into_iter()
+                .find_map(|span| span.extensions().get::<SpanClient>().map(|c| c.0))
+        }) else {
+            return;
+        };
+        let mut visitor = MessageVisitor::default();
+        event.record(&mut visitor);
+        self.send(
+            key,
+            to_mcp_level(metadata.level()),
+            metadata.target(),
+            visitor.message + &visitor.fields,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmcp::model::LoggingMessageNotificationParam;
+    use rmcp::service::{NotificationContext, RoleClient, RunningService};
+    use rmcp::{ClientHandler, ServerHandler, ServiceExt};
+    use tokio::sync::mpsc;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    struct Quiet;
+    impl ServerHandler for Quiet {}
+
+    /// A client that passes on every log message it's sent
+    struct Collector(mpsc::UnboundedSender<String>);
+    impl ClientHandler for Collector {
+        async fn on_logging_message(
+            &self,
+            params: LoggingMessageNotificationParam,
+            _context: NotificationContext<RoleClient>,
+        ) {
+            let _ = self
+                .0
+                .send(params.data.as_str().unwrap_or_default().to_string());
+        }
+    }
+
+    /// A connected client, and the server's side of the connection
+    async fn connect() -> (
+        RunningService<RoleClient, Collector>,
+        RunningService<RoleServer, Quiet>,
+        mpsc::UnboundedReceiver<String>,
+    ) {
+        let (client_io, server_io) = tokio::io::duplex(4096);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (client, server) = tokio::join!(Collector(tx).serve(client_io), Quiet.serve(server_io));
+        (client.unwrap(), server.unwrap(), rx)
+    }
+
+    #[tokio::test]
+    async fn test_clients_only_get_their_own_events() {
+        let (_a, server_a, mut a_log) = connect().await;
+        let (_b, server_b, mut b_log) = connect().await;
+        let log = ClientLog::default();
+        log.set_level(server_a.peer().clone(), LoggingLevel::Debug);
+        log.set_level(server_b.peer().clone(), LoggingLevel::Debug);
+        let a = client_key(server_a.peer()).unwrap();
+
+        let subscriber = tracing_subscriber::registry().with(log);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("tool_call", client = a);
+            span.in_scope(|| tracing::info!("reading a.md"));
+            tracing::info!("background work");
+        });
+
+        let message = tokio::time::timeout(std::time::Duration::from_secs(5), a_log.recv())
+            .await
+            .unwrap();
+        assert_eq!(message.as_deref(), Some("reading a.md"));
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(a_log.try_recv().is_err());
+        assert!(b_log.try_recv().is_err());
+    }
+}
@@ -1,20 +1,203 @@
+use crate::e2ee::{self, Encryption};
 use anyhow::{Result, anyhow};
 use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use futures::{Stream, StreamExt};
 use rand::Rng;
-use reqwest::Client;
+use reqwest::{
+    Certificate, Client, Identity, NoProxy, Proxy, RequestBuilder, Response, StatusCode,
+};
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+use url::Url;
 use urlencoding::encode as urlencode;
 
 // livesync chunks at ~32 bytes. or so i think
 const CHUNK_SIZE: usize = 32;
 
+/// What LiveSync starts every chunk id with
+const LIVESYNC_CHUNK_PREFIX: &str = "h:";
+
+/// How many times save_note goes again with the latest rev after a 409, e.g. when LiveSync
+/// pushed the same note from another device in between
+const CONFLICT_RETRIES: usize = 3;
+
+/// How many times a request is tried again after a 429, and the longest Retry-After that's
+/// waited out. Anything longer is better reported than sat on
+const RATE_LIMIT_RETRIES: usize = 3;
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// Design doc holding the notes view, see [`CouchDbClient::ensure_notes_view`]
+const NOTES_VIEW_DOC: &str = "_design/yamos";
+const NOTES_VIEW: &str = "_design/yamos/_view/notes";
+
+/// Docs asked for per `_find` request, following the bookmark for more
+const FIND_PAGE_SIZE: usize = 500;
+
+/// How often couchdb sends a blank line down an idle _changes feed, unless configured otherwise
+const DEFAULT_CHANGES_HEARTBEAT: Duration = Duration::from_secs(30);
+
+/// How long a 429 response asks us to wait, from its Retry-After header (seconds or a date)
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim();
+    if let Ok(secs) = value.parse() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    // a date in the past means go ahead now
+    Some(
+        (at.to_utc() - chrono::Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// Checks the couchdb url parses and strips any trailing slash, so that
+/// "https://host/couchdb/" and "https://host/couchdb" both end up as the latter
+fn normalise_base_url(url: &str) -> Result<String> {
+    let parsed = Url::parse(url).map_err(|e| anyhow!("Invalid CouchDB URL {}: {}", url, e))?;
+
+    if parsed.cannot_be_a_base() {
+        return Err(anyhow!("Invalid CouchDB URL {}: not a base URL", url));
+    }
+    if parsed.query().is_some() || parsed.fragment().is_some() {
+        return Err(anyhow!(
+            "Invalid CouchDB URL {}: query strings and fragments aren't supported",
+            url
+        ));
+    }
+
+    Ok(parsed.as_str().trim_end_matches('/').to_string())
+}
+
+/// How we authenticate against couchdb
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum CouchAuthMode {
+    /// HTTP Basic auth on every request
+    #[default]
+    Basic,
+    /// POST to /_session once and send the AuthSession cookie afterwards
+    Session,
+}
+
+/// How a note's path maps to its doc id. LiveSync stores the real path in the doc's `path` field
+/// either way, and always puts a `/` in front of a path starting with `_`, since CouchDB keeps
+/// ids like that for itself. Otherwise the id is the vault-relative path, `.md` and all
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum PathStyle {
+    /// The id is the path, as LiveSync does with "Handle files as case-sensitive" on
+    #[default]
+    Exact,
+    /// The id is the lowercased path, as LiveSync does with that setting off
+    Lowercase,
+}
+
+impl PathStyle {
+    /// The doc id for a note path
+    pub fn doc_id(self, path: &str) -> String {
+        let id = match self {
+            Self::Exact => path.to_string(),
+            Self::Lowercase => path.to_lowercase(),
+        };
+        if id.starts_with('_') {
+            format!("/{}", id)
+        } else {
+            id
+        }
+    }
+
+    /// The path of the note with this doc id. A lowercased id has lost the path's real case,
+    /// so that comes from the doc's own `path` field when there is one
+    pub fn note_path(self, id: &str, doc_path: Option<&str>) -> String {
+        match (self, doc_path) {
+            (Self::Lowercase, Some(path)) if !path.is_empty() => path.to_string(),
+            _ => id
+                .strip_prefix('/')
+                .filter(|p| p.starts_with('_'))
+                .unwrap_or(id)
+                .to_string(),
+        }
+    }
+}
+
+/// Connection settings for CouchDB
+#[derive(Debug, Clone, Default)]
+pub struct CouchDbConfig {
+    /// Server URL. May include a path prefix when couchdb sits behind a reverse proxy
+    /// (e.g. https://host/couchdb), but not a query string or fragment
+    pub url: String,
+    pub database: String,
+    pub username: String,
+    pub password: String,
+    pub auth_mode: CouchAuthMode,
+    /// Extra PEM root certificate(s) to trust, for a private CA
+    pub ca_cert: Option<PathBuf>,
+    /// PEM client certificate and key, for mutual TLS
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    /// Skip certificate verification entirely. Dev only!
+    pub danger_accept_invalid_certs: bool,
+    /// Explicit proxy for all CouchDB traffic. Without this, reqwest picks up HTTPS_PROXY and
+    /// friends from the environment
+    pub proxy: Option<String>,
+    /// Ignore any proxy config (including the env vars) and connect directly
+    pub no_proxy: bool,
+    /// Refuse to save notes bigger than this many bytes. Since chunks are ~32 bytes, this is
+    /// also what keeps the number of chunk docs per note bounded. None = unlimited
+    pub max_note_size: Option<usize>,
+    /// Refuse to save notes that would split into more chunk docs than this, checked before
+    /// anything is written. None = unlimited
+    pub max_chunks_per_note: Option<usize>,
+    /// LiveSync end-to-end encryption passphrase, for reading and writing encrypted vaults
+    pub passphrase: Option<String>,
+    /// Log a warning for any request that takes longer than this. None = never
+    pub slow_request_threshold: Option<Duration>,
+    pub path_style: PathStyle,
+    /// How often couchdb should send a heartbeat down an idle _changes feed, to keep proxies
+    /// from dropping the connection. None = every 30s
+    pub changes_heartbeat: Option<Duration>,
+    /// What the ids of chunks we write start with. None = "h:", like LiveSync's. Anything else
+    /// makes notes we save unreadable to LiveSync
+    pub chunk_id_prefix: Option<String>,
+}
+
 #[derive(Clone)]
 pub struct CouchDbClient {
     client: Client,
     base_url: String,
     database: String,
-    auth_header: String,
+    auth: Arc<CouchAuth>,
+    max_note_size: Option<usize>,
+    max_chunks_per_note: Option<usize>,
+    encryption: Option<Arc<Encryption>>,
+    slow_request_threshold: Option<Duration>,
+    path_style: PathStyle,
+    changes_heartbeat: Duration,
+    /// Whether to list notes with the notes view rather than _all_docs
+    notes_view: Arc<AtomicBool>,
+    /// Whether [`CouchDbClient::detect_encryption`] found the vault to be encrypted, which is
+    /// when chunks that only look encrypted are taken to be
+    encrypted_vault: Arc<AtomicBool>,
+    chunk_prefix: String,
+}
+
+struct CouchAuth {
+    mode: CouchAuthMode,
+    username: String,
+    password: String,
+    basic_header: String,
+    /// "AuthSession=..." cookie, only used in session mode
+    session_cookie: RwLock<Option<String>>,
 }
 
 // i tried to get "notes" working but it kept corrupting my database. i've left it in, in case
@@ -42,6 +225,16 @@ pub struct NoteDoc {
     pub eden: serde_json::Value,
 }
 
+/// The parts of a note doc a `_find` asks for: enough to list the note, without its chunk list
+#[derive(Debug, Clone, Deserialize)]
+pub struct NoteSummary {
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub path: String,
+    pub mtime: u64,
+    pub size: u64,
+}
+
 /// Chunk document - contains raw string data (not base64!)
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct LeafDoc {
@@ -52,6 +245,9 @@ pub struct LeafDoc {
     pub data: String,
     #[serde(rename = "type")]
     pub doc_type: String,
+    /// Set by LiveSync on end-to-end encrypted chunks
+    #[serde(rename = "e_", default, skip_serializing_if = "Option::is_none")]
+    pub encrypted: Option<bool>,
 }
 
 #[allow(dead_code)]
@@ -62,6 +258,61 @@ pub struct SaveResponse {
     pub rev: String,
 }
 
+/// How many notes [`NoteLoader::next_batch`] fetches the chunks of at once
+const LOAD_BATCH_SIZE: usize = 200;
+
+/// Hands out the notes from [`CouchDbClient::load_notes`] with their content, a batch at a
+/// time
+pub struct NoteLoader {
+    db: CouchDbClient,
+    notes: std::vec::IntoIter<NoteDoc>,
+}
+
+impl NoteLoader {
+    /// The next batch of notes as (path, content, mtime), or None once they're all out
+    pub async fn next_batch(&mut self) -> CouchResult<Option<Vec<(String, String, u64)>>> {
+        let batch: Vec<NoteDoc> = self.notes.by_ref().take(LOAD_BATCH_SIZE).collect();
+        if batch.is_empty() {
+            return Ok(None);
+        }
+        let chunk_ids: Vec<&str> = batch
+            .iter()
+            .filter(|note| note.doc_type != "notes")
+            .flat_map(|note| note.children.iter().map(String::as_str))
+            .collect();
+        let chunks = self.db.leaves(&chunk_ids).await?;
+
+        // Reassemble notes from chunks (or decode legacy format)
+        let mut results = Vec::with_capacity(batch.len());
+        for note in &batch {
+            let content = if note.doc_type == "notes" {
+                // Legacy format: base64 encoded data in document
+                match BASE64.decode(&note.data) {
+                    Ok(bytes) => String::from_utf8(bytes).unwrap_or_default(),
+                    Err(e) => {
+                        tracing::warn!("Failed to decode legacy note {}: {}", note.id, e);
+                        String::new()
+                    }
+                }
+            } else {
+                let mut content = String::new();
+                for chunk_id in &note.children {
+                    if let Some(chunk_data) = chunks.get(chunk_id) {
+                        content.push_str(chunk_data);
+                    } else {
+                        tracing::warn!("Missing chunk {} for note {}", chunk_id, note.id);
+                    }
+                }
+                content
+            };
+
+            let path = self.db.path_style.note_path(&note.id, Some(&note.path));
+            results.push((path, content, note.mtime));
+        }
+        Ok(Some(results))
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct AllDocsRow {
     pub id: String,
@@ -73,6 +324,34 @@ pub struct AllDocsRow {
     pub doc: Option<serde_json::Value>,
 }
 
+/// A row of the notes view
+#[derive(Debug, Deserialize)]
+struct NotesViewRow {
+    id: String,
+    value: NotesViewValue,
+}
+
+#[derive(Debug, Deserialize)]
+struct NotesViewValue {
+    path: Option<String>,
+    mtime: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NotesViewResponse {
+    rows: Vec<NotesViewRow>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FindResponse {
+    docs: Vec<serde_json::Value>,
+    #[serde(default)]
+    bookmark: Option<String>,
+    /// Set when no index covers the selector, so couchdb scanned every doc
+    #[serde(default)]
+    warning: Option<String>,
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct AllDocsValue {
@@ -81,6 +360,240 @@ pub struct AllDocsValue {
     pub deleted: bool,
 }
 
+/// Change event from CouchDB _changes feed
+#[derive(Debug, Deserialize)]
+pub struct ChangeEvent {
+    pub seq: String,
+    pub id: String,
+    #[serde(default)]
+    pub deleted: bool,
+    pub doc: Option<serde_json::Value>,
+}
+
+/// Everything that can go wrong in a [`CouchDbClient`] call, kept apart so callers can tell a
+/// missing note or a conflicting write from CouchDB being unreachable
+#[derive(Debug, thiserror::Error)]
+pub enum CouchDbError {
+    #[error("Note not found: {0}")]
+    NotFound(String),
+    /// Someone else updated the doc between our read and write
+    #[error("{0} changed since it was read - read it again and redo the change")]
+    Conflict(String),
+    #[error("Note already exists: {0} (set overwrite to replace it)")]
+    AlreadyExists(String),
+    #[error("Note {path} is too large: {size} bytes (max {max} bytes)")]
+    TooLarge {
+        path: String,
+        size: usize,
+        max: usize,
+    },
+    #[error(
+        "Note {path} would be split into {chunks} chunks of at most {CHUNK_SIZE} bytes (max \
+         {max} per note). Split it into smaller notes, or raise --max-chunks-per-note if it \
+         really is meant to be this big"
+    )]
+    TooManyChunks {
+        path: String,
+        chunks: usize,
+        max: usize,
+    },
+    /// An old revision that CouchDB no longer has, or whose chunks are gone
+    #[error("Revision {rev} of {path} can't be read: {reason}")]
+    RevisionUnavailable {
+        path: String,
+        rev: String,
+        reason: &'static str,
+    },
+    /// A `_find` sorted by a field that no index covers
+    #[error("CouchDB has no index to sort by: {0}")]
+    NoIndex(String),
+    /// The _changes feed rejected our `since` seq (too old, compacted away, etc)
+    #[error("changes feed rejected since={0}")]
+    InvalidSeq(String),
+    #[error("CouchDB rejected our credentials: {0}")]
+    Unauthorized(String),
+    /// A 413, from CouchDB's max_document_size/max_http_request_size or a proxy's body limit
+    #[error(
+        "Content too large for CouchDB's configured max (max_document_size or \
+         max_http_request_size, or a body size limit on a proxy in front of it): {0}"
+    )]
+    PayloadTooLarge(String),
+    /// A 429 that was still coming back after waiting out Retry-After a few times
+    #[error("CouchDB is rate limiting requests, try again in a bit: {0}")]
+    RateLimited(String),
+    /// Any other unsuccessful response
+    #[error("CouchDB returned {status}: {body}")]
+    Http { status: StatusCode, body: String },
+    /// Never got a response: connection refused, timeout, TLS...
+    #[error("Couldn't talk to CouchDB: {0}")]
+    Transport(reqwest::Error),
+    /// Got a response, but not one we could make sense of
+    #[error("Unexpected data from CouchDB: {0}")]
+    Decode(String),
+}
+
+impl CouchDbError {
+    /// The same error with the note path in it passed through `f`
+    pub fn map_path(self, f: impl FnOnce(String) -> String) -> Self {
+        match self {
+            Self::NotFound(path) => Self::NotFound(f(path)),
+            Self::Conflict(path) => Self::Conflict(f(path)),
+            Self::AlreadyExists(path) => Self::AlreadyExists(f(path)),
+            Self::TooLarge { path, size, max } => Self::TooLarge {
+                path: f(path),
+                size,
+                max,
+            },
+            Self::TooManyChunks { path, chunks, max } => Self::TooManyChunks {
+                path: f(path),
+                chunks,
+                max,
+            },
+            Self::RevisionUnavailable { path, rev, reason } => Self::RevisionUnavailable {
+                path: f(path),
+                rev,
+                reason,
+            },
+            e => e,
+        }
+    }
+
+    /// Turns an unsuccessful response into an error, reading the body for the message
+    async fn from_response(response: Response) -> Self {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        match status {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Self::Unauthorized(body),
+            StatusCode::PAYLOAD_TOO_LARGE => Self::PayloadTooLarge(body),
+            StatusCode::TOO_MANY_REQUESTS => Self::RateLimited(body),
+            status => Self::Http { status, body },
+        }
+    }
+}
+
+impl From<reqwest::Error> for CouchDbError {
+    fn from(e: reqwest::Error) -> Self {
+        // reqwest reports bad response json as its own error, but it's not a transport problem
+        if e.is_decode() {
+            Self::Decode(e.to_string())
+        } else {
+            Self::Transport(e)
+        }
+    }
+}
+
+impl From<serde_json::Error> for CouchDbError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Decode(e.to_string())
+    }
+}
+
+pub type CouchResult<T> = std::result::Result<T, CouchDbError>;
+
+/// Docs LiveSync keeps for its own bookkeeping, which aren't notes or chunks but are a good sign
+/// we're looking at a LiveSync database
+const LIVESYNC_META_DOCS: &[&str] = &["obsydian_livesync_version", "syncinfo"];
+
+/// Docs sampled from the start of the database by [`CouchDbClient::sample_doc_format`]
+const FORMAT_SAMPLE_SIZE: usize = 20;
+
+/// Where LiveSync keeps each device's sync settings, including whether the vault is encrypted
+const MILESTONE_DOC: &str = "_local/obsidian_livesync_milestone";
+
+/// Whether any device's settings in the milestone doc have end-to-end encryption turned on
+fn milestone_says_encrypted(milestone: &serde_json::Value) -> bool {
+    milestone
+        .get("tweak_values")
+        .and_then(|t| t.as_object())
+        .is_some_and(|devices| {
+            devices
+                .values()
+                .any(|settings| settings.get("encrypt") == Some(&serde_json::Value::Bool(true)))
+        })
+}
+
+/// Why [`CouchDbClient::detect_encryption`] thinks a vault is end-to-end encrypted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionSign {
+    /// The LiveSync settings have encryption turned on
+    Settings,
+    /// The chunks we looked at were all encrypted
+    Chunks,
+    /// Note ids are hashes rather than paths
+    ObfuscatedPaths,
+}
+
+impl std::fmt::Display for EncryptionSign {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Settings => "the LiveSync settings have encryption turned on",
+            Self::Chunks => "the note chunks are encrypted",
+            Self::ObfuscatedPaths => "the note paths are obfuscated",
+        })
+    }
+}
+
+/// What a sample of the database's docs looked like
+#[derive(Debug, Default)]
+pub struct FormatSample {
+    /// Non-system docs looked at
+    pub sampled: usize,
+    /// How many of them were LiveSync notes, chunks or bookkeeping
+    pub livesync: usize,
+    /// A few ids of the ones that weren't, for the warning
+    pub others: Vec<String>,
+}
+
+impl FormatSample {
+    /// Nothing in the sample looked like LiveSync, so this is probably the wrong database
+    pub fn looks_wrong(&self) -> bool {
+        self.sampled > 0 && self.livesync == 0
+    }
+}
+
+/// Whether a doc has the shape of a LiveSync note (`type` plain/newnote/notes with `children`
+/// or `data`) or chunk (`type` leaf with `data`)
+fn looks_like_livesync(id: &str, doc: &serde_json::Value) -> bool {
+    if LIVESYNC_META_DOCS.contains(&id) {
+        return true;
+    }
+    let has = |field: &str| doc.get(field).is_some();
+    match doc.get("type").and_then(|t| t.as_str()) {
+        Some("plain" | "newnote" | "notes") => has("children") || has("data"),
+        Some("leaf") => has("data"),
+        _ => false,
+    }
+}
+
+/// Doc counts from [`CouchDbClient::storage_stats`]
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct StorageStats {
+    /// Everything in `_all_docs`, system docs included
+    pub total_docs: usize,
+    pub notes: usize,
+    /// Soft-deleted notes, whose chunks are kept so they can be restored
+    pub deleted_notes: usize,
+    pub chunks: usize,
+    /// Chunks no note refers to. A save in progress can add a few that aren't really orphans
+    pub orphaned_chunks: usize,
+}
+
+/// One page of note paths from [`CouchDbClient::list_notes`]
+#[derive(Debug)]
+pub struct NotesPage<T = String> {
+    pub notes: Vec<T>,
+    /// Where the next page starts, if there is one
+    pub next_cursor: Option<String>,
+}
+
+/// A soft-deleted note from [`CouchDbClient::list_deleted_notes`]
+#[derive(Debug, PartialEq)]
+pub struct DeletedNote {
+    pub path: String,
+    /// When it was last touched, i.e. when it was deleted (ms since the epoch)
+    pub mtime: u64,
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 pub struct AllDocsResponse {
@@ -90,234 +603,851 @@ pub struct AllDocsResponse {
 }
 
 impl CouchDbClient {
-    pub fn new(url: &str, database: &str, username: &str, password: &str) -> Result<Self> {
-        let auth = format!("{}:{}", username, password);
-        let auth_header = format!("Basic {}", BASE64.encode(auth.as_bytes()));
+    pub fn new(config: CouchDbConfig) -> Result<Self> {
+        let credentials = format!("{}:{}", config.username, config.password);
+        let basic_header = format!("Basic {}", BASE64.encode(credentials.as_bytes()));
+
+        let base_url = normalise_base_url(&config.url)?;
+
+        let chunk_prefix = config
+            .chunk_id_prefix
+            .unwrap_or_else(|| LIVESYNC_CHUNK_PREFIX.to_string());
+        // obsidian won't have a : in a file name, so no note's id can start with one of these
+        if !chunk_prefix.contains(':') || chunk_prefix.starts_with('_') {
+            return Err(anyhow!(
+                "--chunk-id-prefix has to contain a : (so no note's id can start with it) and can't start with _ (couchdb keeps those ids for itself)"
+            ));
+        }
+
+        // couchdb (or whatever's in front of it) can compress the big _all_docs responses
+        let mut builder = Client::builder().gzip(true).brotli(true).deflate(true);
+
+        if let Some(path) = &config.ca_cert {
+            let pem = std::fs::read(path)
+                .map_err(|e| anyhow!("Failed to read CA cert {}: {}", path.display(), e))?;
+            for cert in Certificate::from_pem_bundle(&pem)? {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+
+        match (&config.client_cert, &config.client_key) {
+            (Some(cert_path), Some(key_path)) => {
+                // rustls wants the cert and key in one PEM buffer
+                let mut pem = std::fs::read(cert_path).map_err(|e| {
+                    anyhow!("Failed to read client cert {}: {}", cert_path.display(), e)
+                })?;
+                pem.push(b'\n');
+                pem.extend(std::fs::read(key_path).map_err(|e| {
+                    anyhow!("Failed to read client key {}: {}", key_path.display(), e)
+                })?);
+                builder = builder.identity(Identity::from_pem(&pem)?);
+            }
+            (None, None) => {}
+            _ => {
+                return Err(anyhow!(
+                    "--couchdb-client-cert and --couchdb-client-key must be set together"
+                ));
+            }
+        }
+
+        if config.danger_accept_invalid_certs {
+            tracing::warn!(
+                "WARNING: CouchDB TLS certificate verification is disabled. Don't do this in production!"
+            );
+            builder = builder.tls_danger_accept_invalid_certs(true);
+        }
 
-        let base_url = url.trim_end_matches('/').to_string();
+        if config.no_proxy {
+            builder = builder.no_proxy();
+        } else if let Some(proxy) = &config.proxy {
+            // still honour NO_PROXY so local hosts can skip the explicit proxy
+            builder = builder.proxy(Proxy::all(proxy)?.no_proxy(NoProxy::from_env()));
+        }
+
+        let client = builder.build()?;
 
         Ok(Self {
-            client: Client::new(),
+            client,
             base_url,
-            database: database.to_string(),
-            auth_header,
+            database: config.database,
+            auth: Arc::new(CouchAuth {
+                mode: config.auth_mode,
+                username: config.username,
+                password: config.password,
+                basic_header,
+                session_cookie: RwLock::new(None),
+            }),
+            max_note_size: config.max_note_size,
+            max_chunks_per_note: config.max_chunks_per_note,
+            encryption: config
+                .passphrase
+                .as_deref()
+                .map(|p| Arc::new(Encryption::new(p))),
+            slow_request_threshold: config.slow_request_threshold,
+            path_style: config.path_style,
+            changes_heartbeat: config
+                .changes_heartbeat
+                .unwrap_or(DEFAULT_CHANGES_HEARTBEAT),
+            notes_view: Arc::new(AtomicBool::new(false)),
+            encrypted_vault: Arc::new(AtomicBool::new(false)),
+            chunk_prefix,
         })
     }
 
-    /// Get the full database URL (for changes feed, etc.)
-    pub fn db_url(&self) -> String {
-        format!("{}/{}", self.base_url, self.database)
+    /// Whether a doc id is a chunk's: LiveSync's, or ours if they start differently
+    pub fn is_chunk_id(&self, id: &str) -> bool {
+        id.starts_with(LIVESYNC_CHUNK_PREFIX) || id.starts_with(&self.chunk_prefix)
     }
 
-    /// Make an authenticated GET request
-    pub async fn get(&self, url: &str) -> Result<reqwest::Response> {
-        Ok(self
-            .client
-            .get(url)
-            .header("Authorization", &self.auth_header)
-            .send()
-            .await?)
+    /// The id ranges chunks live in, as (first, just past the last), in order. Chunks are
+    /// contiguous in id order and there are loads of them, so listings skip straight past
+    fn chunk_ranges(&self) -> Vec<(String, String)> {
+        let mut prefixes = vec![LIVESYNC_CHUNK_PREFIX, self.chunk_prefix.as_str()];
+        prefixes.sort();
+        prefixes.dedup();
+        // one prefix inside another's range (e.g. "h:y:") is covered by that one already
+        prefixes.dedup_by(|b, a| b.starts_with(*a));
+        prefixes
+            .into_iter()
+            .map(|p| (p.to_string(), after_prefix(p)))
+            .collect()
     }
 
-    fn doc_url(&self, doc_id: &str) -> String {
-        format!("{}/{}/{}", self.base_url, self.database, urlencode(doc_id))
+    /// The notes view's map function. Every note (not chunks, system docs or LiveSync's
+    /// bookkeeping), keyed by whether it's soft-deleted and then its id, so a page of either
+    /// kind is just a key range
+    fn notes_view_map(&self) -> String {
+        let chunk_checks: String = self
+            .chunk_ranges()
+            .iter()
+            .map(|(prefix, _)| format!("doc._id.indexOf({}) === 0 || ", serde_json::json!(prefix)))
+            .collect();
+        format!(
+            r#"function (doc) {{
+  if ({}doc._id.charAt(0) === "_") return;
+  if (doc.type !== "plain" && doc.type !== "newnote" && doc.type !== "notes") return;
+  emit([doc.deleted === true, doc._id], {{path: doc.path, mtime: doc.mtime}});
+}}"#,
+            chunk_checks
+        )
     }
 
-    /// lists notes, filtering out chunks (h:*), system docs (_*), and soft-deleted notes
-    pub async fn list_notes(&self) -> Result<Vec<String>> {
-        let url = format!(
-            "{}/{}/_all_docs?include_docs=true",
-            self.base_url, self.database
-        );
+    /// How note paths map to doc ids in this database
+    pub fn path_style(&self) -> PathStyle {
+        self.path_style
+    }
+
+    /// The CouchDB database this client talks to
+    pub fn database(&self) -> &str {
+        &self.database
+    }
+
+    /// Log in via /_session and store the AuthSession cookie. No-op in basic mode
+    pub async fn start_session(&self) -> CouchResult<()> {
+        if self.auth.mode != CouchAuthMode::Session {
+            return Ok(());
+        }
 
+        let url = self.server_url("_session");
         let response = self
             .client
-            .get(&url)
-            .header("Authorization", &self.auth_header)
+            .post(&url)
+            .json(&serde_json::json!({
+                "name": self.auth.username,
+                "password": self.auth.password,
+            }))
             .send()
             .await?;
 
         if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            return Err(anyhow!("Failed to list documents: {} - {}", status, body));
+            return Err(CouchDbError::from_response(response).await);
         }
 
-        let all_docs: AllDocsResponse = response.json().await?;
-
-        // filter out chunk documents (h:*), system docs (_*), tombstones, and soft-deleted
-        let notes: Vec<String> = all_docs
-            .rows
-            .into_iter()
-            .filter(|row| {
-                !row.id.starts_with("h:")
-                    && !row.id.starts_with("_")
-                    && !row.value.deleted
-                    && !row
-                        .doc
-                        .as_ref()
-                        .is_some_and(|d| d.get("deleted") == Some(&serde_json::Value::Bool(true)))
-            })
-            .map(|row| row.id)
-            .collect();
-
-        Ok(notes)
+        // Set-Cookie: AuthSession=abc; Version=1; Path=/; HttpOnly
+        let cookie = response
+            .headers()
+            .get_all(reqwest::header::SET_COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .filter_map(|v| v.split(';').next())
+            .find(|v| v.starts_with("AuthSession="))
+            .map(|v| v.to_string())
+            .ok_or_else(|| {
+                CouchDbError::Decode("/_session response had no AuthSession cookie".to_string())
+            })?;
+
+        *self.auth.session_cookie.write().await = Some(cookie);
+        tracing::debug!("Started CouchDB session for {}", self.auth.username);
+        Ok(())
     }
 
-    pub async fn get_note(&self, id: &str) -> Result<NoteDoc> {
-        let url = self.doc_url(id);
-
-        let response = self
-            .client
-            .get(&url)
-            .header("Authorization", &self.auth_header)
-            .send()
-            .await?;
+    async fn with_auth(&self, request: RequestBuilder) -> CouchResult<RequestBuilder> {
+        match self.auth.mode {
+            CouchAuthMode::Basic => Ok(request.header("Authorization", &self.auth.basic_header)),
+            CouchAuthMode::Session => {
+                if self.auth.session_cookie.read().await.is_none() {
+                    self.start_session().await?;
+                }
+                let cookie = self.auth.session_cookie.read().await.clone();
+                Ok(request.header(reqwest::header::COOKIE, cookie.unwrap_or_default()))
+            }
+        }
+    }
 
-        if response.status() == reqwest::StatusCode::NOT_FOUND {
-            return Err(anyhow!("Note not found: {}", id));
+    /// Send a request with auth attached. `build` gets called again if we need to retry, since
+    /// a RequestBuilder can't be reused once sent. In session mode a 401 means the cookie
+    /// expired, so we log in again and retry once
+    async fn send(&self, build: impl Fn() -> RequestBuilder) -> CouchResult<Response> {
+        let mut response = self.execute(build()).await?;
+
+        if response.status() == StatusCode::UNAUTHORIZED && self.auth.mode == CouchAuthMode::Session
+        {
+            tracing::debug!("CouchDB session expired, logging in again");
+            self.start_session().await?;
+            response = self.execute(build()).await?;
         }
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            return Err(anyhow!("Failed to get note: {} - {}", status, body));
+        for _ in 0..RATE_LIMIT_RETRIES {
+            if response.status() != StatusCode::TOO_MANY_REQUESTS {
+                break;
+            }
+            let wait = retry_after(&response).unwrap_or(Duration::from_secs(1));
+            if wait > MAX_RETRY_AFTER {
+                break;
+            }
+            tracing::warn!(
+                "CouchDB is rate limiting us, retrying in {}ms",
+                wait.as_millis()
+            );
+            tokio::time::sleep(wait).await;
+            response = self.execute(build()).await?;
         }
 
-        let doc: NoteDoc = response.json().await?;
-        Ok(doc)
+        Ok(response)
     }
 
-    /// fetches chunks for "plain", decodes base64 for legacy "notes"
-    pub async fn decode_content(&self, doc: &NoteDoc) -> Result<String> {
-        if doc.doc_type == "notes" {
-            // legacy format: base64 encoded data in document
-            let bytes = BASE64.decode(&doc.data)?;
-            let content = String::from_utf8(bytes)?;
-            Ok(content)
-        } else {
-            // chunked format: fetch all leaf documents
-            let mut content = String::new();
-            for chunk_id in &doc.children {
-                let chunk_content = self.get_leaf(chunk_id).await?;
-                content.push_str(&chunk_content);
-            }
-            Ok(content)
+    /// Sends one request with auth attached, warning if it's slower than the configured
+    /// threshold. Only the time to the response headers counts, not reading the body
+    async fn execute(&self, request: RequestBuilder) -> CouchResult<Response> {
+        let request = self.with_auth(request).await?.build()?;
+        let (method, path) = (request.method().clone(), request.url().path().to_string());
+
+        let started = Instant::now();
+        let response = self.client.execute(request).await?;
+        let elapsed = started.elapsed();
+        if self.slow_request_threshold.is_some_and(|t| elapsed >= t) {
+            tracing::warn!(
+                "Slow CouchDB request: {} {} took {}ms ({})",
+                method,
+                path,
+                elapsed.as_millis(),
+                response.status()
+            );
         }
+        Ok(response)
     }
 
-    async fn get_leaf(&self, chunk_id: &str) -> Result<String> {
-        let url = self.doc_url(chunk_id);
+    // all URLs get built from these. base_url never has a trailing slash (see
+    // normalise_base_url), so joining with "/" works whether or not it has a path prefix
+
+    /// `{base_url}/{path}`, for server-level endpoints like /_session
+    fn server_url(&self, path: &str) -> String {
+        format!("{}/{}", self.base_url, path)
+    }
+
+    /// Get the full database URL
+    fn db_url(&self) -> String {
+        // database names can contain '/', which couchdb wants encoded
+        self.server_url(&urlencode(&self.database))
+    }
 
+    /// `{db_url}/{endpoint}`, e.g. "_all_docs?include_docs=true"
+    fn db_endpoint(&self, endpoint: &str) -> String {
+        format!("{}/{}", self.db_url(), endpoint)
+    }
+
+    /// Opens the continuous _changes feed from `since` and yields one event per change.
+    /// The stream ends when the server closes the connection or `cancel` fires, and yields an
+    /// error (then ends) if the connection drops. Fails with [`CouchDbError::InvalidSeq`] if couchdb rejects
+    /// `since`, e.g. because it's from before a compaction
+    pub async fn changes_stream(
+        &self,
+        since: &str,
+        cancel: CancellationToken,
+    ) -> CouchResult<impl Stream<Item = CouchResult<ChangeEvent>> + Send + use<>> {
+        let url = self.db_endpoint(&format!(
+            "_changes?feed=continuous&include_docs=true&since={}&heartbeat={}",
+            urlencode(since),
+            self.changes_heartbeat.as_millis()
+        ));
+
+        // compression is disabled here, otherwise heartbeats and individual change lines can
+        // sit in the decoder's buffer instead of reaching us as they arrive
         let response = self
-            .client
-            .get(&url)
-            .header("Authorization", &self.auth_header)
-            .send()
+            .send(|| self.client.get(&url).header("Accept-Encoding", "identity"))
             .await?;
 
         if !response.status().is_success() {
             let status = response.status();
             let body = response.text().await.unwrap_or_default();
-            return Err(anyhow!(
-                "Failed to get chunk {}: {} - {}",
-                chunk_id,
-                status,
-                body
-            ));
+
+            if body.contains("since") || status == StatusCode::BAD_REQUEST {
+                return Err(CouchDbError::InvalidSeq(since.to_string()));
+            }
+
+            return Err(CouchDbError::Http { status, body });
         }
 
-        let leaf: LeafDoc = response.json().await?;
-        Ok(leaf.data)
+        let bytes = response.bytes_stream().boxed();
+
+        // couchdb sends one JSON object per line, with blank lines as heartbeats. buffer raw
+        // bytes rather than strings so a multi-byte character split across chunks survives
+        Ok(futures::stream::unfold(
+            (bytes, Vec::new(), cancel, false),
+            |(mut bytes, mut buffer, cancel, failed)| async move {
+                if failed {
+                    return None;
+                }
+
+                loop {
+                    while let Some(pos) = buffer.iter().position(|&b| b == b'\n') {
+                        let line: Vec<u8> = buffer.drain(..=pos).collect();
+                        if line.trim_ascii().is_empty() {
+                            continue;
+                        }
+                        match serde_json::from_slice::<ChangeEvent>(&line) {
+                            Ok(change) => {
+                                return Some((Ok(change), (bytes, buffer, cancel, false)));
+                            }
+                            Err(e) => tracing::warn!("Error parsing change: {}", e),
+                        }
+                    }
+
+                    tokio::select! {
+                        chunk = bytes.next() => match chunk {
+                            Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+                            Some(Err(e)) => {
+                                let err = CouchDbError::Transport(e);
+                                return Some((Err(err), (bytes, buffer, cancel, true)));
+                            }
+                            None => {
+                                tracing::debug!("Changes stream ended");
+                                return None;
+                            }
+                        },
+                        _ = cancel.cancelled() => return None,
+                    }
+                }
+            },
+        ))
     }
 
-    fn now_ms() -> u64 {
-        SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map(|d| d.as_millis() as u64)
-            .unwrap_or(0)
+    fn doc_url(&self, doc_id: &str) -> String {
+        self.db_endpoint(&urlencode(doc_id))
     }
 
-    // inb4 "there's a crate for this" shut up
-    fn generate_chunk_id() -> String {
-        const CHARSET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
-        let mut rng = rand::rng();
-        let id: String = (0..13)
-            .map(|_| {
-                let idx = rng.random_range(0..CHARSET.len());
-                CHARSET[idx] as char
-            })
-            .collect();
-        format!("h:{}", id)
+    /// lists a page of notes (optionally under a path prefix), filtering out chunks (h:*),
+    /// system docs (_*), and soft-deleted notes. Pass the returned `next_cursor` back in as
+    /// `cursor` to get the next page
+    pub async fn list_notes(
+        &self,
+        prefix: Option<&str>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> CouchResult<NotesPage> {
+        let page = self.list_page(prefix, cursor, limit, false).await?;
+        Ok(NotesPage {
+            notes: page.notes.into_iter().map(|(path, _)| path).collect(),
+            next_cursor: page.next_cursor,
+        })
     }
 
-    fn split_into_chunks(content: &str) -> Vec<(String, String)> {
-        let mut chunks = Vec::new();
-        let mut current_chunk = String::new();
-        let mut current_size = 0;
+    /// Like [`Self::list_notes`], but only the soft-deleted notes it would leave out
+    pub async fn list_deleted_notes(
+        &self,
+        prefix: Option<&str>,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> CouchResult<NotesPage<DeletedNote>> {
+        let page = self.list_page(prefix, cursor, limit, true).await?;
+        Ok(NotesPage {
+            notes: page
+                .notes
+                .into_iter()
+                .map(|(path, mtime)| DeletedNote { path, mtime })
+                .collect(),
+            next_cursor: page.next_cursor,
+        })
+    }
 
-        // split on character boundaries to avoid corrupting multi-byte UTF-8
-        for ch in content.chars() {
-            let ch_len = ch.len_utf8();
-            if current_size + ch_len > CHUNK_SIZE && !current_chunk.is_empty() {
-                chunks.push((Self::generate_chunk_id(), current_chunk));
-                current_chunk = String::new();
-                current_size = 0;
+    /// Creates the `_design/yamos` doc (or updates it, if its view is out of date) and lists
+    /// notes with its view from now on, so couchdb does the filtering and chunks never come
+    /// over the wire. Writing a design doc needs a database admin
+    pub async fn ensure_notes_view(&self) -> CouchResult<()> {
+        let url = self.db_endpoint(NOTES_VIEW_DOC);
+        let map = self.notes_view_map();
+        let response = self.send(|| self.client.get(&url)).await?;
+        let mut doc = match response.status() {
+            StatusCode::NOT_FOUND => serde_json::json!({
+                "_id": NOTES_VIEW_DOC,
+                "language": "javascript",
+            }),
+            status if status.is_success() => {
+                let doc: serde_json::Value = response.json().await?;
+                if doc["views"]["notes"]["map"] == map.as_str() {
+                    self.notes_view.store(true, Ordering::Relaxed);
+                    return Ok(());
+                }
+                doc
+            }
+            _ => return Err(CouchDbError::from_response(response).await),
+        };
+
+        // only the notes view is ours, anything else someone put in the doc stays
+        if !doc["views"].is_object() {
+            doc["views"] = serde_json::json!({});
+        }
+        doc["views"]["notes"] = serde_json::json!({ "map": map });
+        let response = self.send(|| self.client.put(&url).json(&doc)).await?;
+        if !response.status().is_success() {
+            return Err(CouchDbError::from_response(response).await);
+        }
+        self.notes_view.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// A page of notes that are (or aren't) soft-deleted, with their mtimes
+    async fn list_page(
+        &self,
+        prefix: Option<&str>,
+        cursor: Option<&str>,
+        limit: usize,
+        deleted: bool,
+    ) -> CouchResult<NotesPage<(String, u64)>> {
+        if self.notes_view.load(Ordering::Relaxed) {
+            match self.list_view_page(prefix, cursor, limit, deleted).await {
+                Err(CouchDbError::NotFound(_)) => {
+                    // someone deleted the design doc, so back to doing it the slow way
+                    tracing::warn!(
+                        "{} is gone from {}, listing notes with _all_docs instead",
+                        NOTES_VIEW_DOC,
+                        self.database
+                    );
+                    self.notes_view.store(false, Ordering::Relaxed);
+                }
+                result => return result,
+            }
+        }
+
+        let mut notes = Vec::new();
+        // _all_docs is sorted by raw id, so a prefix is just a key range
+        let prefix = prefix.map(|p| self.path_style.doc_id(p));
+        let mut start = cursor.map(str::to_string).or(prefix.clone());
+        let end = prefix.map(|p| format!("{}\u{fff0}", p));
+
+        loop {
+            // one extra row so we know where the next page starts
+            let mut endpoint = format!(
+                "_all_docs?include_docs=true&limit={}",
+                limit - notes.len() + 1
+            );
+            if let Some(start) = &start {
+                endpoint.push_str(&format!(
+                    "&startkey={}",
+                    urlencode(&serde_json::to_string(start)?)
+                ));
+            }
+            if let Some(end) = &end {
+                endpoint.push_str(&format!(
+                    "&endkey={}",
+                    urlencode(&serde_json::to_string(end)?)
+                ));
+            }
+            let url = self.db_endpoint(&endpoint);
+
+            let response = self.send(|| self.client.get(&url)).await?;
+
+            if !response.status().is_success() {
+                return Err(CouchDbError::from_response(response).await);
+            }
+
+            let all_docs: AllDocsResponse = response.json().await?;
+            let exhausted = all_docs.rows.len() <= limit - notes.len();
+            let mut last_id = None;
+            let mut hit_chunks = None;
+
+            let chunk_ranges = self.chunk_ranges();
+            for row in all_docs.rows {
+                if let Some((_, end)) = chunk_ranges
+                    .iter()
+                    .find(|(prefix, _)| row.id.starts_with(prefix.as_str()))
+                {
+                    hit_chunks = Some(end.clone());
+                    break;
+                }
+
+                // filter out system docs (_*), and soft-deleted notes or everything else
+                let soft_deleted = row
+                    .doc
+                    .as_ref()
+                    .is_some_and(|d| d.get("deleted") == Some(&serde_json::Value::Bool(true)));
+                if !row.id.starts_with('_') && !row.value.deleted && soft_deleted == deleted {
+                    if notes.len() == limit {
+                        return Ok(NotesPage {
+                            notes,
+                            next_cursor: Some(row.id),
+                        });
+                    }
+                    let mtime = row.doc.as_ref().and_then(|d| d["mtime"].as_u64());
+                    let doc_path = row.doc.as_ref().and_then(|d| d["path"].as_str());
+                    let path = self.path_style.note_path(&row.id, doc_path);
+                    notes.push((path, mtime.unwrap_or(0)));
+                }
+                last_id = Some(row.id);
+            }
+
+            if let Some(end) = hit_chunks {
+                // chunks are contiguous in id order and there are loads of them, so jump
+                // straight past the lot
+                start = Some(end);
+            } else if let Some(last_id) = last_id {
+                if exhausted {
+                    return Ok(NotesPage {
+                        notes,
+                        next_cursor: None,
+                    });
+                }
+                // startkey is inclusive, so nudge it just past the last row we saw
+                start = Some(format!("{}\u{0}", last_id));
+            } else {
+                return Ok(NotesPage {
+                    notes,
+                    next_cursor: None,
+                });
+            }
+        }
+    }
+
+    /// [`Self::list_page`] using the notes view, where the notes wanted are exactly one key
+    /// range. NotFound if the view isn't there
+    async fn list_view_page(
+        &self,
+        prefix: Option<&str>,
+        cursor: Option<&str>,
+        limit: usize,
+        deleted: bool,
+    ) -> CouchResult<NotesPage<(String, u64)>> {
+        let prefix = prefix.map(|p| self.path_style.doc_id(p));
+        let start = cursor.map(str::to_string).or(prefix.clone());
+        let start = serde_json::json!([deleted, start.unwrap_or_default()]);
+        // {} sorts after every string
+        let end = match prefix {
+            Some(prefix) => serde_json::json!([deleted, format!("{}\u{fff0}", prefix)]),
+            None => serde_json::json!([deleted, {}]),
+        };
+        // one extra row so we know where the next page starts
+        let url = self.db_endpoint(&format!(
+            "{}?limit={}&startkey={}&endkey={}",
+            NOTES_VIEW,
+            limit + 1,
+            urlencode(&start.to_string()),
+            urlencode(&end.to_string())
+        ));
+
+        let response = self.send(|| self.client.get(&url)).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(CouchDbError::NotFound(NOTES_VIEW.to_string()));
+        }
+        if !response.status().is_success() {
+            return Err(CouchDbError::from_response(response).await);
+        }
+
+        let mut rows = response.json::<NotesViewResponse>().await?.rows;
+        let next_cursor = (rows.len() > limit).then(|| rows.remove(limit).id);
+        rows.truncate(limit);
+        Ok(NotesPage {
+            notes: rows
+                .into_iter()
+                .map(|row| {
+                    let path = self
+                        .path_style
+                        .note_path(&row.id, row.value.path.as_deref());
+                    (path, row.value.mtime.unwrap_or(0))
+                })
+                .collect(),
+            next_cursor,
+        })
+    }
+
+    /// Up to `limit` notes matching a Mango `selector`, via `_find`, so couchdb does the
+    /// filtering, in the order of `sort` if there is one. Matches that aren't notes (chunks,
+    /// say) are left out. Without an index on the selector's fields couchdb has to read every
+    /// doc in the database to answer, which it grumbles about in the log here. Sorting needs an
+    /// index on the fields sorted by, and is NoIndex without one
+    pub async fn find(
+        &self,
+        selector: serde_json::Value,
+        sort: Option<serde_json::Value>,
+        limit: usize,
+    ) -> CouchResult<Vec<NoteSummary>> {
+        let url = self.db_endpoint("_find");
+        let mut notes = Vec::new();
+        let mut bookmark: Option<String> = None;
+        let mut warned = false;
+
+        while notes.len() < limit {
+            let mut body = serde_json::json!({
+                "selector": selector,
+                "fields": ["_id", "path", "mtime", "size"],
+                "limit": FIND_PAGE_SIZE.min(limit - notes.len()),
+            });
+            if let Some(sort) = &sort {
+                body["sort"] = sort.clone();
+            }
+            if let Some(bookmark) = &bookmark {
+                body["bookmark"] = bookmark.clone().into();
+            }
+            let response = self.send(|| self.client.post(&url).json(&body)).await?;
+            if response.status() == StatusCode::BAD_REQUEST && sort.is_some() {
+                let body = response.text().await.unwrap_or_default();
+                if body.contains("no_usable_index") {
+                    return Err(CouchDbError::NoIndex(body));
+                }
+                return Err(CouchDbError::Http {
+                    status: StatusCode::BAD_REQUEST,
+                    body,
+                });
+            }
+            if !response.status().is_success() {
+                return Err(CouchDbError::from_response(response).await);
+            }
+
+            let page: FindResponse = response.json().await?;
+            if let Some(warning) = page.warning
+                && !warned
+            {
+                tracing::debug!("_find in {}: {}", self.database, warning);
+                warned = true;
+            }
+            if page.docs.is_empty() {
+                break;
+            }
+            notes.extend(
+                page.docs
+                    .into_iter()
+                    .filter_map(|doc| serde_json::from_value::<NoteSummary>(doc).ok()),
+            );
+            match page.bookmark {
+                Some(next) if bookmark.as_ref() != Some(&next) => bookmark = Some(next),
+                _ => break,
+            }
+        }
+        notes.truncate(limit);
+        Ok(notes)
+    }
+
+    pub async fn get_note(&self, path: &str) -> CouchResult<NoteDoc> {
+        let url = self.doc_url(&self.path_style.doc_id(path));
+
+        let response = self.send(|| self.client.get(&url)).await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(CouchDbError::NotFound(path.to_string()));
+        }
+
+        if !response.status().is_success() {
+            return Err(CouchDbError::from_response(response).await);
+        }
+
+        let doc: NoteDoc = response.json().await?;
+        Ok(doc)
+    }
+
+    /// A note as it was at `rev`, and its content then. Old revisions only last until the
+    /// database is compacted, and their chunks usually go sooner: saving a note deletes the
+    /// chunks of the version it replaces
+    pub async fn get_note_revision(&self, path: &str, rev: &str) -> CouchResult<(NoteDoc, String)> {
+        let unavailable = |reason| CouchDbError::RevisionUnavailable {
+            path: path.to_string(),
+            rev: rev.to_string(),
+            reason,
+        };
+        let url = format!(
+            "{}?rev={}",
+            self.doc_url(&self.path_style.doc_id(path)),
+            urlencode(rev)
+        );
+
+        let response = self.send(|| self.client.get(&url)).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(unavailable(
+                "CouchDB doesn't have it (compaction throws old revisions away)",
+            ));
+        }
+        if !response.status().is_success() {
+            return Err(CouchDbError::from_response(response).await);
+        }
+        let doc: NoteDoc = response.json().await?;
+
+        match self.decode_content(&doc).await {
+            Ok(content) => Ok((doc, content)),
+            Err(CouchDbError::Http { status, .. }) if status == StatusCode::NOT_FOUND => {
+                Err(unavailable(
+                    "its chunks have been deleted since, which happens when a note is saved",
+                ))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// fetches chunks for "plain", decodes base64 for legacy "notes"
+    pub async fn decode_content(&self, doc: &NoteDoc) -> CouchResult<String> {
+        if doc.doc_type == "notes" {
+            // legacy format: base64 encoded data in document
+            let bytes = BASE64
+                .decode(&doc.data)
+                .map_err(|e| CouchDbError::Decode(format!("{}: {}", doc.id, e)))?;
+            String::from_utf8(bytes)
+                .map_err(|_| CouchDbError::Decode(format!("{} isn't utf-8", doc.id)))
+        } else {
+            // chunked format: fetch all leaf documents
+            let mut content = String::new();
+            for chunk_id in &doc.children {
+                let chunk_content = self.get_leaf(chunk_id).await?;
+                content.push_str(&chunk_content);
+            }
+            Ok(content)
+        }
+    }
+
+    async fn get_leaf(&self, chunk_id: &str) -> CouchResult<String> {
+        let url = self.doc_url(chunk_id);
+
+        let response = self.send(|| self.client.get(&url)).await?;
+
+        // a missing chunk means a broken note, not a missing one, so no NotFound here
+        if !response.status().is_success() {
+            return Err(CouchDbError::from_response(response).await);
+        }
+
+        let leaf: LeafDoc = response.json().await?;
+        self.leaf_text(leaf)
+    }
+
+    /// A chunk's text, decrypted if it needs to be. `e_` says so for certain; data that just
+    /// looks encrypted only counts in a vault known to be, since a plain note can start with a
+    /// % and a hash too, and even then it's kept as it is if it doesn't decrypt
+    fn leaf_text(&self, leaf: LeafDoc) -> CouchResult<String> {
+        let flagged = leaf.encrypted == Some(true);
+        let looks_encrypted =
+            self.encrypted_vault.load(Ordering::Relaxed) && e2ee::is_encrypted(&leaf.data);
+        if !flagged && !looks_encrypted {
+            return Ok(leaf.data);
+        }
+        let Some(encryption) = &self.encryption else {
+            return Err(CouchDbError::Decode(format!(
+                "chunk {} is end-to-end encrypted - set --livesync-passphrase to read it",
+                leaf.id
+            )));
+        };
+        match encryption.decrypt(&leaf.data) {
+            Ok(text) => Ok(text),
+            Err(_) if !flagged => Ok(leaf.data),
+            Err(e) => Err(CouchDbError::Decode(format!("chunk {} {}", leaf.id, e))),
+        }
+    }
+
+    /// size in bytes of each of a note's chunks, in order, without reassembling the content.
+    /// a chunk that can't be fetched comes back as the error instead of a size
+    pub async fn chunk_sizes(&self, doc: &NoteDoc) -> Vec<(String, CouchResult<usize>)> {
+        let mut sizes = Vec::with_capacity(doc.children.len());
+        for chunk_id in &doc.children {
+            let size = self.get_leaf(chunk_id).await.map(|data| data.len());
+            sizes.push((chunk_id.clone(), size));
+        }
+        sizes
+    }
+
+    fn now_ms() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+
+    // inb4 "there's a crate for this" shut up
+    fn generate_chunk_id(&self) -> String {
+        const CHARSET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+        let mut rng = rand::rng();
+        let id: String = (0..13)
+            .map(|_| {
+                let idx = rng.random_range(0..CHARSET.len());
+                CHARSET[idx] as char
+            })
+            .collect();
+        format!("{}{}", self.chunk_prefix, id)
+    }
+
+    fn split_into_chunks(&self, content: &str) -> Vec<(String, String)> {
+        let mut chunks = Vec::new();
+        let mut current_chunk = String::new();
+        let mut current_size = 0;
+
+        // split on character boundaries to avoid corrupting multi-byte UTF-8
+        for ch in content.chars() {
+            let ch_len = ch.len_utf8();
+            if current_size + ch_len > CHUNK_SIZE && !current_chunk.is_empty() {
+                chunks.push((self.generate_chunk_id(), current_chunk));
+                current_chunk = String::new();
+                current_size = 0;
             }
             current_chunk.push(ch);
             current_size += ch_len;
         }
 
         if !current_chunk.is_empty() || chunks.is_empty() {
-            chunks.push((Self::generate_chunk_id(), current_chunk));
+            chunks.push((self.generate_chunk_id(), current_chunk));
         }
 
         chunks
     }
 
-    async fn save_leaf(&self, chunk_id: &str, data: &str) -> Result<()> {
-        let leaf = LeafDoc {
-            id: chunk_id.to_string(),
-            rev: None,
-            data: data.to_string(),
-            doc_type: "leaf".to_string(),
+    async fn save_leaf(&self, chunk_id: &str, data: &str) -> CouchResult<()> {
+        let leaf = match &self.encryption {
+            Some(encryption) => LeafDoc {
+                id: chunk_id.to_string(),
+                rev: None,
+                data: encryption
+                    .encrypt(data)
+                    .map_err(|e| CouchDbError::Decode(e.to_string()))?,
+                doc_type: "leaf".to_string(),
+                encrypted: Some(true),
+            },
+            None => LeafDoc {
+                id: chunk_id.to_string(),
+                rev: None,
+                data: data.to_string(),
+                doc_type: "leaf".to_string(),
+                encrypted: None,
+            },
         };
 
         let url = self.doc_url(chunk_id);
 
-        let response = self
-            .client
-            .put(&url)
-            .header("Authorization", &self.auth_header)
-            .header("Content-Type", "application/json")
-            .json(&leaf)
-            .send()
-            .await?;
+        let response = self.send(|| self.client.put(&url).json(&leaf)).await?;
 
         if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            return Err(anyhow!(
-                "Failed to save chunk {}: {} - {}",
-                chunk_id,
-                status,
-                body
-            ));
+            return Err(CouchDbError::from_response(response).await);
         }
 
         Ok(())
     }
 
-    async fn delete_leaf(&self, chunk_id: &str) -> Result<()> {
+    async fn delete_leaf(&self, chunk_id: &str) -> CouchResult<()> {
         let url = self.doc_url(chunk_id);
 
         // get current rev first
-        let response = self
-            .client
-            .get(&url)
-            .header("Authorization", &self.auth_header)
-            .send()
-            .await?;
+        let response = self.send(|| self.client.get(&url)).await?;
 
         if !response.status().is_success() {
             // already gone or never existed, that's fine
@@ -330,14 +1460,9 @@ impl CouchDbClient {
         };
 
         let delete_url = format!("{}?rev={}", url, urlencode(&rev));
-        let response = self
-            .client
-            .delete(&delete_url)
-            .header("Authorization", &self.auth_header)
-            .send()
-            .await?;
+        let response = self.send(|| self.client.delete(&delete_url)).await?;
 
-        if !response.status().is_success() && response.status() != reqwest::StatusCode::NOT_FOUND {
+        if !response.status().is_success() && response.status() != StatusCode::NOT_FOUND {
             let status = response.status();
             let body = response.text().await.unwrap_or_default();
             tracing::warn!("Failed to delete chunk {}: {} - {}", chunk_id, status, body);
@@ -346,11 +1471,62 @@ impl CouchDbClient {
         Ok(())
     }
 
-    pub async fn save_note(&self, id: &str, content: &str) -> Result<SaveResponse> {
-        let existing = self.get_note(id).await.ok();
-        let now = Self::now_ms();
+    /// Overwrites a note with `content`. Whatever's there gets replaced regardless, so a 409
+    /// from someone else saving in between is retried against the latest rev
+    pub async fn save_note(&self, id: &str, content: &str) -> CouchResult<SaveResponse> {
+        self.put_note(id, content, None, None).await
+    }
+
+    /// Saves `content` that was worked out from `read`, the note as it was read (an edit, an
+    /// append), against that doc's rev. If the note has changed since, that's a
+    /// [`CouchDbError::Conflict`] rather than a retry, since a retry would throw away whatever
+    /// changed - the caller has to read the note again and redo its change
+    pub async fn update_note(
+        &self,
+        id: &str,
+        content: &str,
+        read: &NoteDoc,
+    ) -> CouchResult<SaveResponse> {
+        self.put_note(id, content, None, Some(read)).await
+    }
+
+    /// Writes a note's chunks and then its main doc. The doc gets `ctime` if given, otherwise
+    /// it keeps the existing doc's (or now, for a new note). Saved against `read`'s rev if
+    /// given, otherwise the latest, with a conflicting doc write retried a few times
+    async fn put_note(
+        &self,
+        id: &str,
+        content: &str,
+        ctime: Option<u64>,
+        read: Option<&NoteDoc>,
+    ) -> CouchResult<SaveResponse> {
+        // check before touching the db, so an enormous note doesn't leave thousands of chunks
+        if let Some(max) = self.max_note_size
+            && content.len() > max
+        {
+            return Err(CouchDbError::TooLarge {
+                path: id.to_string(),
+                size: content.len(),
+                max,
+            });
+        }
+
+        let chunks = self.split_into_chunks(content);
+        if let Some(max) = self.max_chunks_per_note
+            && chunks.len() > max
+        {
+            return Err(CouchDbError::TooManyChunks {
+                path: id.to_string(),
+                chunks: chunks.len(),
+                max,
+            });
+        }
 
-        let chunks = Self::split_into_chunks(content);
+        let (mut existing, retries) = match read {
+            Some(doc) => (Some(doc.clone()), 0),
+            None => (self.get_note(id).await.ok(), CONFLICT_RETRIES),
+        };
+        let now = Self::now_ms();
         let chunk_ids: Vec<String> = chunks.iter().map(|(id, _)| id.clone()).collect();
 
         tracing::debug!(
@@ -366,42 +1542,57 @@ impl CouchDbClient {
             tracing::debug!("Saved chunk {} ({} bytes)", chunk_id, chunk_data.len());
         }
 
-        let doc = NoteDoc {
-            id: id.to_string(),
-            rev: existing.as_ref().and_then(|d| d.rev.clone()),
-            path: id.to_string(),
-            data: String::new(),
-            ctime: existing.as_ref().map(|d| d.ctime).unwrap_or(now),
-            mtime: now,
-            size: content.len() as u64,
-            doc_type: "plain".to_string(),
-            children: chunk_ids,
-            deleted: None,
-            eden: serde_json::json!({}),
-        };
-
-        let url = self.doc_url(id);
-
-        if let Ok(json) = serde_json::to_string_pretty(&doc) {
-            tracing::debug!("Saving main document:\n{}", json);
-        }
+        let doc_id = self.path_style.doc_id(id);
+        let url = self.doc_url(&doc_id);
+        let mut attempt = 0;
+
+        let save_response: SaveResponse = loop {
+            let doc = NoteDoc {
+                id: doc_id.clone(),
+                rev: existing.as_ref().and_then(|d| d.rev.clone()),
+                // with lowercased ids, `id` might be the same note spelt another way, and the
+                // note keeps the case it already has
+                path: existing
+                    .as_ref()
+                    .filter(|d| d.deleted != Some(true) && !d.path.is_empty())
+                    .map_or(id, |d| d.path.as_str())
+                    .to_string(),
+                data: String::new(),
+                ctime: ctime.or(existing.as_ref().map(|d| d.ctime)).unwrap_or(now),
+                mtime: now,
+                size: content.len() as u64,
+                doc_type: "plain".to_string(),
+                children: chunk_ids.clone(),
+                deleted: None,
+                eden: serde_json::json!({}),
+            };
 
-        let response = self
-            .client
-            .put(&url)
-            .header("Authorization", &self.auth_header)
-            .header("Content-Type", "application/json")
-            .json(&doc)
-            .send()
-            .await?;
+            if let Ok(json) = serde_json::to_string_pretty(&doc) {
+                tracing::debug!("Saving main document:\n{}", json);
+            }
 
-        if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            return Err(anyhow!("Failed to save note: {} - {}", status, body));
-        }
+            let response = self.send(|| self.client.put(&url).json(&doc)).await?;
 
-        let save_response: SaveResponse = response.json().await?;
+            if response.status() == StatusCode::CONFLICT {
+                if attempt == retries {
+                    return Err(CouchDbError::Conflict(id.to_string()));
+                }
+                attempt += 1;
+                tracing::info!(
+                    "Conflict saving {}, retrying with the latest rev ({}/{})",
+                    id,
+                    attempt,
+                    retries
+                );
+                // the chunks are already saved, only the main doc needs the new rev
+                existing = self.get_note(id).await.ok();
+                continue;
+            }
+            if !response.status().is_success() {
+                return Err(CouchDbError::from_response(response).await);
+            }
+            break response.json().await?;
+        };
 
         // only delete old chunks AFTER parent doc is saved successfully
         // (orphaned chunks are better than dangling references)
@@ -419,18 +1610,97 @@ impl CouchDbClient {
         Ok(save_response)
     }
 
-    pub async fn append_to_note(&self, id: &str, content: &str) -> Result<SaveResponse> {
+    /// Copies a note to a new path, with a fresh ctime unless `keep_ctime` is set (for moves).
+    /// Refuses to replace an existing (non-deleted) note at `to` unless `overwrite` is set.
+    // couchdb's native COPY would be quicker, but it copies the children array as-is, so both
+    // notes would share chunks - and the next save of either would delete the other's content
+    pub async fn copy_note(
+        &self,
+        from: &str,
+        to: &str,
+        overwrite: bool,
+        keep_ctime: bool,
+    ) -> CouchResult<SaveResponse> {
+        let source = self.get_note(from).await?;
+        if source.deleted == Some(true) {
+            return Err(CouchDbError::NotFound(from.to_string()));
+        }
+
+        if !overwrite
+            && let Ok(existing) = self.get_note(to).await
+            && existing.deleted != Some(true)
+        {
+            return Err(CouchDbError::AlreadyExists(to.to_string()));
+        }
+
+        let content = self.decode_content(&source).await?;
+        let ctime = if keep_ctime {
+            source.ctime
+        } else {
+            Self::now_ms()
+        };
+        self.put_note(to, &content, Some(ctime), None).await
+    }
+
+    /// Adds `content` to the end of a note, after `separator`
+    pub async fn append_to_note(
+        &self,
+        id: &str,
+        content: &str,
+        separator: &str,
+    ) -> CouchResult<SaveResponse> {
+        let existing = self.get_note(id).await?;
+        let current_content = self.decode_content(&existing).await?;
+        let new_content = format!("{}{}{}", current_content, separator, content);
+        self.update_note(id, &new_content, &existing).await
+    }
+
+    /// appends `line` on a line of its own, unless the note already has a line that's the same
+    /// once both are trimmed. true if it was appended
+    pub async fn append_unique(
+        &self,
+        id: &str,
+        line: &str,
+        case_sensitive: bool,
+    ) -> CouchResult<bool> {
         let existing = self.get_note(id).await?;
         let current_content = self.decode_content(&existing).await?;
-        let new_content = format!("{}\n{}", current_content, content);
-        self.save_note(id, &new_content).await
+        if has_line(&current_content, line, case_sensitive) {
+            return Ok(false);
+        }
+        let separator = if current_content.is_empty() || current_content.ends_with('\n') {
+            ""
+        } else {
+            "\n"
+        };
+        let new_content = format!("{}{}{}", current_content, separator, line);
+        self.update_note(id, &new_content, &existing).await?;
+        Ok(true)
     }
 
     /// soft-deletes a note by setting deleted: true (livesync expects this, not couchDB tombstones)
     #[allow(dead_code)] // Kept for potential admin use, but not exposed to AI agents
-    pub async fn delete_note(&self, id: &str) -> Result<()> {
+    pub async fn delete_note(&self, id: &str) -> CouchResult<()> {
+        let existing = self.get_note(id).await?;
+        self.set_deleted(existing, Some(true)).await?;
+        tracing::info!("Soft-deleted note {}", id);
+        Ok(())
+    }
+
+    /// brings back a soft-deleted note by clearing its deleted flag. false if it wasn't deleted
+    pub async fn restore_note(&self, id: &str) -> CouchResult<bool> {
         let existing = self.get_note(id).await?;
+        if existing.deleted != Some(true) {
+            return Ok(false);
+        }
+        self.set_deleted(existing, None).await?;
+        tracing::info!("Restored note {}", id);
+        Ok(true)
+    }
 
+    /// rewrites a note's main doc with a new deleted flag and mtime, leaving its chunks alone
+    async fn set_deleted(&self, existing: NoteDoc, deleted: Option<bool>) -> CouchResult<()> {
+        let id = existing.id.clone();
         let doc = NoteDoc {
             id: existing.id,
             rev: existing.rev,
@@ -441,71 +1711,173 @@ impl CouchDbClient {
             size: existing.size,
             doc_type: existing.doc_type,
             children: existing.children,
-            deleted: Some(true),
+            deleted,
             eden: existing.eden,
         };
 
-        let url = self.doc_url(id);
+        let url = self.doc_url(&id);
 
-        let response = self
-            .client
-            .put(&url)
-            .header("Authorization", &self.auth_header)
-            .header("Content-Type", "application/json")
-            .json(&doc)
-            .send()
-            .await?;
+        let response = self.send(|| self.client.put(&url).json(&doc)).await?;
 
+        if response.status() == StatusCode::CONFLICT {
+            return Err(CouchDbError::Conflict(id));
+        }
         if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            return Err(anyhow!("Failed to delete note: {} - {}", status, body));
+            return Err(CouchDbError::from_response(response).await);
         }
-
-        tracing::info!("Soft-deleted note {}", id);
         Ok(())
     }
 
-    pub async fn test_connection(&self) -> Result<()> {
-        let url = format!("{}/{}", self.base_url, self.database);
-
-        let response = self
-            .client
-            .get(&url)
-            .header("Authorization", &self.auth_header)
-            .send()
-            .await?;
+    /// Looks at the first few non-system docs to check this is a LiveSync database at all, so
+    /// pointing yamos at the wrong one can be flagged at startup instead of showing up later as
+    /// confusing "note not found" or decode errors
+    pub async fn sample_doc_format(&self) -> CouchResult<FormatSample> {
+        let url = self.db_endpoint(&format!(
+            "_all_docs?include_docs=true&limit={}",
+            FORMAT_SAMPLE_SIZE
+        ));
 
+        let response = self.send(|| self.client.get(&url)).await?;
         if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            return Err(anyhow!(
-                "Failed to connect to CouchDB: {} - {}",
-                status,
-                body
-            ));
+            return Err(CouchDbError::from_response(response).await);
         }
 
-        Ok(())
+        let all_docs: AllDocsResponse = response.json().await?;
+        let mut sample = FormatSample::default();
+        for row in all_docs.rows {
+            let Some(doc) = row.doc.filter(|_| !row.id.starts_with('_')) else {
+                continue;
+            };
+            sample.sampled += 1;
+            if looks_like_livesync(&row.id, &doc) {
+                sample.livesync += 1;
+            } else if sample.others.len() < 3 {
+                sample.others.push(row.id);
+            }
+        }
+        Ok(sample)
     }
 
-    /// Fetch all notes with their content in a single bulk operation.
-    /// Returns (path, content, mtime) tuples and the last sequence number.
-    pub async fn get_all_notes_with_content(
-        &self,
-    ) -> Result<(Vec<(String, String, u64)>, Option<String>)> {
-        // First, get the current update seq
-        let db_info_url = format!("{}/{}", self.base_url, self.database);
-        let db_info_response = self
-            .client
-            .get(&db_info_url)
-            .header("Authorization", &self.auth_header)
-            .send()
-            .await?;
+    /// The first few chunks in the database
+    async fn sample_chunks(&self, limit: usize) -> CouchResult<Vec<LeafDoc>> {
+        let url = self.db_endpoint(&format!(
+            "_all_docs?include_docs=true&limit={}&startkey={}&endkey={}",
+            limit,
+            urlencode("\"h:\""),
+            urlencode("\"h;\"")
+        ));
+        let response = self.send(|| self.client.get(&url)).await?;
+        if !response.status().is_success() {
+            return Err(CouchDbError::from_response(response).await);
+        }
+        Ok(response
+            .json::<AllDocsResponse>()
+            .await?
+            .rows
+            .into_iter()
+            .filter_map(|row| serde_json::from_value::<LeafDoc>(row.doc?).ok())
+            .collect())
+    }
 
-        let db_info: serde_json::Value = db_info_response.json().await?;
-        let last_seq = db_info
-            .get("update_seq")
+    /// Looks for signs the vault uses LiveSync's end-to-end encryption: the sync settings saying
+    /// so, encrypted chunks, or obfuscated paths
+    pub async fn detect_encryption(&self) -> CouchResult<Option<EncryptionSign>> {
+        // obfuscation goes first since there's no reading those vaults even with a passphrase.
+        // note ids are "f:" and a hash instead of the path
+        let url = self.db_endpoint(&format!(
+            "_all_docs?limit=1&startkey={}&endkey={}",
+            urlencode("\"f:\""),
+            urlencode("\"f;\"")
+        ));
+        let response = self.send(|| self.client.get(&url)).await?;
+        if !response.status().is_success() {
+            return Err(CouchDbError::from_response(response).await);
+        }
+        if !response.json::<AllDocsResponse>().await?.rows.is_empty() {
+            self.encrypted_vault.store(true, Ordering::Relaxed);
+            return Ok(Some(EncryptionSign::ObfuscatedPaths));
+        }
+
+        // not doc_url: the '/' in a _local id has to stay as it is
+        let url = self.db_endpoint(MILESTONE_DOC);
+        let response = self.send(|| self.client.get(&url)).await?;
+        if response.status().is_success() {
+            let milestone: serde_json::Value = response.json().await?;
+            if milestone_says_encrypted(&milestone) {
+                self.encrypted_vault.store(true, Ordering::Relaxed);
+                return Ok(Some(EncryptionSign::Settings));
+            }
+        }
+
+        // a few chunks, all of them encrypted. one odd chunk isn't enough to go on
+        let chunks = self.sample_chunks(5).await?;
+        if !chunks.is_empty() && chunks.iter().all(|c| e2ee::is_encrypted(&c.data)) {
+            self.encrypted_vault.store(true, Ordering::Relaxed);
+            return Ok(Some(EncryptionSign::Chunks));
+        }
+
+        Ok(None)
+    }
+
+    /// Decrypts a sample chunk with the passphrase, so a wrong one (or an unsupported format)
+    /// shows up at startup rather than on every read. Fine if there's nothing to decrypt yet
+    pub async fn check_passphrase(&self) -> CouchResult<()> {
+        let chunks = self.sample_chunks(20).await?;
+        // straight to decrypt: leaf_text would let a chunk that only looks encrypted through
+        let (Some(chunk), Some(encryption)) = (
+            chunks.into_iter().find(|c| e2ee::is_encrypted(&c.data)),
+            &self.encryption,
+        ) else {
+            return Ok(());
+        };
+        encryption
+            .decrypt(&chunk.data)
+            .map(|_| ())
+            .map_err(|e| CouchDbError::Decode(format!("chunk {} {}", chunk.id, e)))
+    }
+
+    pub async fn test_connection(&self) -> CouchResult<()> {
+        let url = self.db_url();
+
+        let response = self.send(|| self.client.get(&url)).await?;
+
+        if !response.status().is_success() {
+            return Err(CouchDbError::from_response(response).await);
+        }
+
+        Ok(())
+    }
+
+    /// Creates the database if it doesn't exist yet. Returns true if we created it, false if it
+    /// was already there
+    pub async fn create_database_if_missing(&self) -> CouchResult<bool> {
+        let url = self.db_url();
+
+        let response = self.send(|| self.client.head(&url)).await?;
+        if response.status().is_success() {
+            return Ok(false);
+        }
+        if response.status() != StatusCode::NOT_FOUND {
+            return Err(CouchDbError::from_response(response).await);
+        }
+
+        let response = self.send(|| self.client.put(&url)).await?;
+        match response.status() {
+            status if status.is_success() => Ok(true),
+            // someone else created it between our HEAD and PUT, that's fine
+            StatusCode::PRECONDITION_FAILED => Ok(false),
+            _ => Err(CouchDbError::from_response(response).await),
+        }
+    }
+
+    /// The database's current update seq, for watching changes from this point on
+    pub async fn update_seq(&self) -> CouchResult<Option<String>> {
+        let db_info_url = self.db_url();
+        let db_info_response = self.send(|| self.client.get(&db_info_url)).await?;
+
+        let db_info: serde_json::Value = db_info_response.json().await?;
+        Ok(db_info
+            .get("update_seq")
             .and_then(|v| v.as_str())
             .map(|s| s.to_string())
             .or_else(|| {
@@ -513,93 +1885,1347 @@ impl CouchDbClient {
                     .get("update_seq")
                     .and_then(|v| v.as_u64())
                     .map(|n| n.to_string())
-            });
+            }))
+    }
 
-        // Fetch all documents
-        let url = format!(
-            "{}/{}/_all_docs?include_docs=true",
-            self.base_url, self.database
-        );
+    /// Every note in the database, for loading the search index, and the seq to watch for
+    /// changes from. The notes come out of the [`NoteLoader`] a batch at a time, so only one
+    /// batch's worth of chunks is ever in memory
+    pub async fn load_notes(&self) -> CouchResult<(NoteLoader, Option<String>)> {
+        // First, get the current update seq
+        let last_seq = self.update_seq().await?;
 
-        let response = self
-            .client
-            .get(&url)
-            .header("Authorization", &self.auth_header)
-            .send()
-            .await?;
+        let notes: Vec<NoteDoc> = self
+            .docs_between_chunks()
+            .await?
+            .into_iter()
+            .filter(|doc| {
+                !doc.get("_id")
+                    .and_then(|id| id.as_str())
+                    .is_none_or(|id| id.starts_with('_'))
+            })
+            .filter_map(|doc| serde_json::from_value::<NoteDoc>(doc).ok())
+            // Skip soft-deleted notes
+            .filter(|note| note.deleted != Some(true))
+            .collect();
 
+        Ok((
+            NoteLoader {
+                db: self.clone(),
+                notes: notes.into_iter(),
+            },
+            last_seq,
+        ))
+    }
+
+    /// The text of each of these chunks that could be read, by id, fetched in one go
+    async fn leaves(&self, ids: &[&str]) -> CouchResult<std::collections::HashMap<String, String>> {
+        let mut leaves = std::collections::HashMap::new();
+        if ids.is_empty() {
+            return Ok(leaves);
+        }
+        let url = self.db_endpoint("_all_docs?include_docs=true");
+        let body = serde_json::json!({ "keys": ids });
+        let response = self.send(|| self.client.post(&url).json(&body)).await?;
         if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-            return Err(anyhow!(
-                "Failed to fetch all documents: {} - {}",
-                status,
-                body
+            return Err(CouchDbError::from_response(response).await);
+        }
+
+        // missing chunks come back as rows with an error instead of a doc
+        let rows: serde_json::Value = response.json().await?;
+        for doc in rows["rows"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|row| row.get("doc"))
+        {
+            if let Ok(leaf) = serde_json::from_value::<LeafDoc>(doc.clone()) {
+                let id = leaf.id.clone();
+                match self.leaf_text(leaf) {
+                    Ok(text) => {
+                        leaves.insert(id, text);
+                    }
+                    Err(e) => tracing::warn!("{}", e),
+                }
+            }
+        }
+        Ok(leaves)
+    }
+
+    /// Rows of `_all_docs` between two ids, with or without the docs (and their `_conflicts`)
+    async fn all_docs_range(
+        &self,
+        start: Option<&str>,
+        end: Option<&str>,
+        include_docs: bool,
+    ) -> CouchResult<Vec<AllDocsRow>> {
+        let mut endpoint = format!(
+            "_all_docs?include_docs={}&conflicts={}",
+            include_docs, include_docs
+        );
+        // as JSON, so a chunk prefix with a " or \ in it still makes a valid key
+        if let Some(start) = start {
+            endpoint.push_str(&format!(
+                "&startkey={}",
+                urlencode(&serde_json::to_string(start)?)
+            ));
+        }
+        if let Some(end) = end {
+            endpoint.push_str(&format!(
+                "&endkey={}",
+                urlencode(&serde_json::to_string(end)?)
             ));
         }
+        let url = self.db_endpoint(&endpoint);
+        let response = self.send(|| self.client.get(&url)).await?;
+        if !response.status().is_success() {
+            return Err(CouchDbError::from_response(response).await);
+        }
+        Ok(response.json::<AllDocsResponse>().await?.rows)
+    }
 
-        let all_docs: AllDocsResponse = response.json().await?;
+    /// Every chunk row, and the docs of everything else. Chunks go first: one saved after
+    /// that can't then be mistaken for an orphan
+    async fn chunks_and_docs(&self) -> CouchResult<(Vec<AllDocsRow>, Vec<serde_json::Value>)> {
+        let mut chunks = Vec::new();
+        for (start, end) in &self.chunk_ranges() {
+            chunks.extend(self.all_docs_range(Some(start), Some(end), false).await?);
+        }
+        Ok((chunks, self.docs_between_chunks().await?))
+    }
+
+    /// The revisions of these docs that lost a conflict. LiveSync can still bring one back, so
+    /// its chunks aren't orphans
+    async fn conflicting_revisions(
+        &self,
+        docs: &[serde_json::Value],
+    ) -> CouchResult<Vec<serde_json::Value>> {
+        let mut revisions = Vec::new();
+        for doc in docs {
+            let Some(id) = doc["_id"].as_str() else {
+                continue;
+            };
+            let revs = doc["_conflicts"].as_array().into_iter().flatten();
+            for rev in revs.filter_map(|r| r.as_str()) {
+                let url = format!("{}?rev={}", self.doc_url(id), urlencode(rev));
+                let response = self.send(|| self.client.get(&url)).await?;
+                match response.status() {
+                    status if status.is_success() => revisions.push(response.json().await?),
+                    // resolved since the listing
+                    StatusCode::NOT_FOUND => {}
+                    _ => return Err(CouchDbError::from_response(response).await),
+                }
+            }
+        }
+        Ok(revisions)
+    }
+
+    /// The docs of everything that isn't a chunk, skipping past the chunks' id ranges
+    async fn docs_between_chunks(&self) -> CouchResult<Vec<serde_json::Value>> {
+        // endkey is inclusive, but nothing has a chunk prefix as its whole id
+        let mut docs = Vec::new();
+        let mut from = None;
+        let ranges = self.chunk_ranges();
+        for (start, end) in &ranges {
+            docs.extend(self.all_docs_range(from, Some(start), true).await?);
+            from = Some(end.as_str());
+        }
+        docs.extend(self.all_docs_range(from, None, true).await?);
+        Ok(docs.into_iter().filter_map(|row| row.doc).collect())
+    }
 
-        // Separate notes from chunks
-        let mut notes: Vec<NoteDoc> = Vec::new();
-        let mut chunks: std::collections::HashMap<String, String> =
-            std::collections::HashMap::new();
+    /// Chunks (id and rev) out of `chunks` that none of the `docs` have in their `children`
+    fn unreferenced(chunks: Vec<AllDocsRow>, docs: &[serde_json::Value]) -> Vec<(String, String)> {
+        // not going through NoteDoc, so nothing with children gets missed for a bad field
+        let referenced: HashSet<&str> = docs
+            .iter()
+            .filter_map(|doc| doc.get("children")?.as_array())
+            .flatten()
+            .filter_map(|c| c.as_str())
+            .collect();
+        chunks
+            .into_iter()
+            .filter(|row| !row.value.deleted && !referenced.contains(row.id.as_str()))
+            .map(|row| (row.id, row.value.rev))
+            .collect()
+    }
 
-        for row in all_docs.rows {
-            // Skip deleted docs
-            if row.value.deleted {
+    /// Chunks (id and rev) that no doc's `children` mentions. Soft-deleted notes and the
+    /// losing sides of conflicts still count, since restoring either needs its chunks. A save
+    /// that's written its chunks but not its note yet looks just the same, so don't delete
+    /// these without giving that time to finish
+    pub async fn orphaned_chunks(&self) -> CouchResult<Vec<(String, String)>> {
+        let (chunks, mut docs) = self.chunks_and_docs().await?;
+        docs.extend(self.conflicting_revisions(&docs).await?);
+        Ok(Self::unreferenced(chunks, &docs))
+    }
+
+    /// What the database is made of, for working out where the space went
+    pub async fn storage_stats(&self) -> CouchResult<StorageStats> {
+        let (chunks, docs) = self.chunks_and_docs().await?;
+        let mut stats = StorageStats {
+            total_docs: chunks.len() + docs.len(),
+            chunks: chunks.len(),
+            ..Default::default()
+        };
+        for doc in &docs {
+            if !matches!(
+                doc.get("type").and_then(|t| t.as_str()),
+                Some("plain" | "newnote" | "notes")
+            ) {
                 continue;
             }
+            if doc.get("deleted").and_then(|d| d.as_bool()) == Some(true) {
+                stats.deleted_notes += 1;
+            } else {
+                stats.notes += 1;
+            }
+        }
+        let conflicts = self.conflicting_revisions(&docs).await?;
+        let referencing: Vec<_> = docs.into_iter().chain(conflicts).collect();
+        stats.orphaned_chunks = Self::unreferenced(chunks, &referencing).len();
+        Ok(stats)
+    }
 
-            if row.id.starts_with("h:") {
-                // This is a chunk
-                if let Some(doc) = row.doc
-                    && let Ok(leaf) = serde_json::from_value::<LeafDoc>(doc)
-                {
-                    chunks.insert(leaf.id.clone(), leaf.data);
-                }
-            } else if !row.id.starts_with('_') {
-                // This is a note (not a system doc)
-                if let Some(doc) = row.doc
-                    && let Ok(note) = serde_json::from_value::<NoteDoc>(doc)
-                {
-                    // Skip soft-deleted notes
-                    if note.deleted != Some(true) {
-                        notes.push(note);
-                    }
+    /// Deletes a chunk at `rev`, and says whether it did. If it's gone or changed since, or
+    /// isn't a leaf after all (just a doc with a chunk's id), it's left as it is
+    pub async fn delete_chunk(&self, id: &str, rev: &str) -> CouchResult<bool> {
+        let url = format!("{}?rev={}", self.doc_url(id), urlencode(rev));
+        let response = self.send(|| self.client.get(&url)).await?;
+        match response.status() {
+            status if status.is_success() => {}
+            StatusCode::NOT_FOUND => return Ok(false),
+            _ => return Err(CouchDbError::from_response(response).await),
+        }
+        let doc: serde_json::Value = response.json().await?;
+        if doc["type"] != "leaf" {
+            tracing::warn!("{} has a chunk's id but isn't one, leaving it", id);
+            return Ok(false);
+        }
+
+        let response = self.send(|| self.client.delete(&url)).await?;
+        match response.status() {
+            status if status.is_success() => Ok(true),
+            StatusCode::NOT_FOUND | StatusCode::CONFLICT => Ok(false),
+            _ => Err(CouchDbError::from_response(response).await),
+        }
+    }
+}
+
+/// The smallest id that sorts after every id starting with `prefix`, by bumping its last
+/// character ("h:" -> "h;")
+fn after_prefix(prefix: &str) -> String {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    if let Some(last) = chars.pop() {
+        chars.push(char::from_u32(last as u32 + 1).unwrap_or(char::MAX));
+    }
+    chars.into_iter().collect()
+}
+
+/// Whether any line of `content` matches `line`, ignoring leading and trailing whitespace
+fn has_line(content: &str, line: &str, case_sensitive: bool) -> bool {
+    let line = line.trim();
+    content.lines().map(str::trim).any(|l| {
+        if case_sensitive {
+            l == line
+        } else {
+            l.to_lowercase() == line.to_lowercase()
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use wiremock::matchers::{method, path, path_regex};
+    use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+    /// Just enough of couchdb to save and read notes: GET/PUT/DELETE on single docs, with revs
+    /// and 409s on stale writes. Point a CouchDbClient at a MockServer using this and the rest of
+    /// the client runs for real
+    #[derive(Clone, Default)]
+    struct FakeCouch {
+        docs: Arc<Mutex<HashMap<String, serde_json::Value>>>,
+        /// Every revision that's been replaced, by id and rev, for GETs with ?rev=
+        old_revs: Arc<Mutex<HashMap<(String, String), serde_json::Value>>>,
+    }
+
+    impl Respond for FakeCouch {
+        fn respond(&self, req: &Request) -> ResponseTemplate {
+            // path is /{db}/{encoded id}
+            let raw_id = req.url.path().splitn(3, '/').nth(2).unwrap_or_default();
+            let id = urlencoding::decode(raw_id).unwrap().into_owned();
+            let mut docs = self.docs.lock().unwrap();
+            let current_rev = docs
+                .get(&id)
+                .and_then(|d| d["_rev"].as_str())
+                .map(|r| r.to_string());
+            let not_found =
+                ResponseTemplate::new(404).set_body_json(serde_json::json!({"error": "not_found"}));
+
+            if id == "_all_docs" {
+                return Self::all_docs(&docs, req);
+            }
+            if id == NOTES_VIEW {
+                if !docs.contains_key(NOTES_VIEW_DOC) {
+                    return not_found;
                 }
+                return Self::notes_view(&docs, req);
             }
-        }
 
-        // Reassemble notes from chunks (or decode legacy format)
-        let mut results = Vec::with_capacity(notes.len());
+            let rev_param = req
+                .url
+                .query_pairs()
+                .find(|(k, _)| k == "rev")
+                .map(|(_, v)| v.into_owned());
 
-        for note in notes {
-            let content = if note.doc_type == "notes" {
-                // Legacy format: base64 encoded data in document
-                match BASE64.decode(&note.data) {
-                    Ok(bytes) => String::from_utf8(bytes).unwrap_or_default(),
-                    Err(e) => {
-                        tracing::warn!("Failed to decode legacy note {}: {}", note.id, e);
-                        String::new()
+            match req.method.as_str() {
+                "GET" => match docs.get(&id) {
+                    Some(doc) if rev_param.is_none() || rev_param == current_rev => {
+                        ResponseTemplate::new(200).set_body_json(doc)
                     }
-                }
-            } else {
-                // Chunked format: reassemble from chunks
-                let mut content = String::new();
-                for chunk_id in &note.children {
-                    if let Some(chunk_data) = chunks.get(chunk_id) {
-                        content.push_str(chunk_data);
-                    } else {
-                        tracing::warn!("Missing chunk {} for note {}", chunk_id, note.id);
+                    _ => match rev_param
+                        .and_then(|rev| self.old_revs.lock().unwrap().get(&(id, rev)).cloned())
+                    {
+                        Some(doc) => ResponseTemplate::new(200).set_body_json(doc),
+                        None => not_found,
+                    },
+                },
+                "PUT" => {
+                    let mut doc: serde_json::Value = req.body_json().unwrap();
+                    if doc["_rev"].as_str().map(|r| r.to_string()) != current_rev {
+                        return ResponseTemplate::new(409)
+                            .set_body_json(serde_json::json!({"error": "conflict"}));
+                    }
+                    let current_rev_for_history = current_rev.clone();
+                    let generation = current_rev
+                        .and_then(|r| r.split('-').next()?.parse::<u32>().ok())
+                        .unwrap_or(0);
+                    let rev = format!("{}-fake", generation + 1);
+                    doc["_rev"] = rev.clone().into();
+                    if let Some(old) = docs.insert(id.clone(), doc)
+                        && let Some(old_rev) = current_rev_for_history
+                    {
+                        self.old_revs
+                            .lock()
+                            .unwrap()
+                            .insert((id.clone(), old_rev), old);
                     }
+                    ResponseTemplate::new(201)
+                        .set_body_json(serde_json::json!({"ok": true, "id": id, "rev": rev}))
                 }
-                content
+                "DELETE" => match docs.remove(&id) {
+                    Some(_) => ResponseTemplate::new(200)
+                        .set_body_json(serde_json::json!({"ok": true, "id": id, "rev": "x"})),
+                    None => not_found,
+                },
+                _ => ResponseTemplate::new(405),
+            }
+        }
+    }
+
+    impl FakeCouch {
+        /// _all_docs with startkey/endkey/limit, sorted by raw id like the real thing, or a
+        /// POST of the keys to look up
+        fn all_docs(docs: &HashMap<String, serde_json::Value>, req: &Request) -> ResponseTemplate {
+            let param = |name: &str| {
+                req.url
+                    .query_pairs()
+                    .find(|(k, _)| k == name)
+                    .map(|(_, v)| v.into_owned())
             };
+            let key = |name: &str| -> Option<String> {
+                param(name).map(|v| serde_json::from_str(&v).unwrap())
+            };
+            if req.method.as_str() == "POST" {
+                let body: serde_json::Value = req.body_json().unwrap();
+                let rows: Vec<_> = body["keys"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|id| match docs.get(id.as_str().unwrap()) {
+                        Some(doc) => serde_json::json!({
+                            "id": id,
+                            "key": id,
+                            "value": {"rev": doc["_rev"]},
+                            "doc": doc,
+                        }),
+                        None => serde_json::json!({"key": id, "error": "not_found"}),
+                    })
+                    .collect();
+                return ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({"total_rows": docs.len(), "rows": rows}));
+            }
+            let (start, end) = (key("startkey"), key("endkey"));
+            let limit = param("limit").map_or(usize::MAX, |l| l.parse().unwrap());
+
+            let mut ids: Vec<_> = docs.keys().collect();
+            ids.sort();
+            let rows: Vec<_> = ids
+                .into_iter()
+                .filter(|id| start.as_ref().is_none_or(|s| *id >= s))
+                .filter(|id| end.as_ref().is_none_or(|e| *id <= e))
+                .take(limit)
+                .map(|id| {
+                    serde_json::json!({
+                        "id": id,
+                        "key": id,
+                        "value": {"rev": docs[id]["_rev"]},
+                        "doc": docs[id],
+                    })
+                })
+                .collect();
+
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "total_rows": docs.len(),
+                "offset": 0,
+                "rows": rows,
+            }))
+        }
+    }
 
-            results.push((note.id, content, note.mtime));
+    impl FakeCouch {
+        /// The notes view, with its map for the default chunk prefix done in rust. Keys are [deleted, id], and the
+        /// only non-string id in a range is {} at the end
+        fn notes_view(
+            docs: &HashMap<String, serde_json::Value>,
+            req: &Request,
+        ) -> ResponseTemplate {
+            let param = |name: &str| {
+                req.url
+                    .query_pairs()
+                    .find(|(k, _)| k == name)
+                    .map(|(_, v)| serde_json::from_str::<serde_json::Value>(&v).unwrap())
+            };
+            let key = |value: serde_json::Value| {
+                (
+                    value[0].as_bool().unwrap(),
+                    value[1].as_str().map(str::to_string),
+                )
+            };
+            let (start, end) = (
+                key(param("startkey").unwrap()),
+                key(param("endkey").unwrap()),
+            );
+            let limit = param("limit").map_or(usize::MAX, |l| l.as_u64().unwrap() as usize);
+            // None is {}, which goes after every id
+            let in_range = |k: &(bool, Option<String>)| {
+                let (deleted, id) = (k.0, k.1.as_deref().unwrap());
+                let after_start = (deleted, Some(id)) >= (start.0, start.1.as_deref());
+                let before_end = match &end.1 {
+                    Some(end_id) => (deleted, id) <= (end.0, end_id.as_str()),
+                    None => deleted <= end.0,
+                };
+                after_start && before_end
+            };
+
+            let mut rows: Vec<_> = docs
+                .iter()
+                .filter(|(id, doc)| {
+                    !id.starts_with("h:")
+                        && !id.starts_with('_')
+                        && matches!(doc["type"].as_str(), Some("plain" | "newnote" | "notes"))
+                })
+                .map(|(id, doc)| {
+                    let key = (doc["deleted"] == true, Some(id.clone()));
+                    (key, id, doc)
+                })
+                .filter(|(key, _, _)| in_range(key))
+                .collect();
+            rows.sort_by(|a, b| a.0.cmp(&b.0));
+            let rows: Vec<_> = rows
+                .into_iter()
+                .take(limit)
+                .map(|((deleted, _), id, doc)| {
+                    serde_json::json!({
+                        "id": id,
+                        "key": [deleted, id],
+                        "value": {"path": doc["path"], "mtime": doc["mtime"]},
+                    })
+                })
+                .collect();
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({ "rows": rows }))
+        }
+    }
+
+    async fn fake_couch() -> (MockServer, FakeCouch, CouchDbClient) {
+        let server = MockServer::start().await;
+        let couch = FakeCouch::default();
+        Mock::given(path_regex("^/obsidian/.+"))
+            .respond_with(couch.clone())
+            .mount(&server)
+            .await;
+        let db = client(&server.uri(), "obsidian");
+        (server, couch, db)
+    }
+
+    fn client(url: &str, database: &str) -> CouchDbClient {
+        CouchDbClient::new(CouchDbConfig {
+            url: url.to_string(),
+            database: database.to_string(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_urls_without_prefix() {
+        let db = client("http://localhost:5984", "obsidian");
+        assert_eq!(db.db_url(), "http://localhost:5984/obsidian");
+        assert_eq!(
+            db.doc_url("Projects/todo.md"),
+            "http://localhost:5984/obsidian/Projects%2Ftodo.md"
+        );
+        assert_eq!(db.server_url("_session"), "http://localhost:5984/_session");
+    }
+
+    #[test]
+    fn test_urls_with_path_prefix() {
+        for url in ["https://host/couchdb", "https://host/couchdb/"] {
+            let db = client(url, "obsidian");
+            assert_eq!(db.db_url(), "https://host/couchdb/obsidian");
+            assert_eq!(
+                db.db_endpoint("_all_docs?include_docs=true"),
+                "https://host/couchdb/obsidian/_all_docs?include_docs=true"
+            );
+            assert_eq!(db.doc_url("h:abc"), "https://host/couchdb/obsidian/h%3Aabc");
+            assert_eq!(db.server_url("_session"), "https://host/couchdb/_session");
+        }
+    }
+
+    #[test]
+    fn test_has_line() {
+        let content = "# Inbox\n- buy milk  \n  - Call Mum\n";
+        assert!(has_line(content, "- buy milk", true));
+        assert!(has_line(content, "  - Call Mum  ", true));
+        assert!(!has_line(content, "- call mum", true));
+        assert!(has_line(content, "- call mum", false));
+        assert!(!has_line(content, "- buy", false));
+        assert!(!has_line("", "- buy milk", false));
+    }
+
+    #[test]
+    fn test_database_name_is_encoded() {
+        let db = client("http://localhost:5984", "vaults/mine");
+        assert_eq!(db.db_url(), "http://localhost:5984/vaults%2Fmine");
+    }
+
+    #[test]
+    fn test_invalid_base_urls() {
+        assert!(normalise_base_url("not a url").is_err());
+        assert!(normalise_base_url("http://host/couchdb?x=1").is_err());
+        assert!(normalise_base_url("http://host/couchdb#frag").is_err());
+    }
+
+    #[test]
+    fn test_split_into_chunks_boundaries() {
+        let chunks = |content: &str| -> Vec<String> {
+            client("http://localhost:5984", "obsidian")
+                .split_into_chunks(content)
+                .into_iter()
+                .map(|(_, data)| data)
+                .collect()
+        };
+
+        // empty content still gets one (empty) chunk so the note has children
+        assert_eq!(chunks(""), vec![""]);
+
+        let exact = "a".repeat(CHUNK_SIZE);
+        assert_eq!(chunks(&exact), vec![exact.clone()]);
+
+        let over = "a".repeat(CHUNK_SIZE + 1);
+        assert_eq!(chunks(&over), vec![exact.clone(), "a".to_string()]);
+
+        // a 2-byte char that would straddle the boundary moves to the next chunk whole
+        let straddle = format!("{}é", "a".repeat(CHUNK_SIZE - 1));
+        assert_eq!(
+            chunks(&straddle),
+            vec!["a".repeat(CHUNK_SIZE - 1), "é".to_string()]
+        );
+
+        let emoji = "🦀".repeat(20);
+        let split = chunks(&emoji);
+        assert!(split.iter().all(|c| c.len() <= CHUNK_SIZE));
+        assert_eq!(split.concat(), emoji);
+    }
+
+    #[tokio::test]
+    async fn test_save_and_read_round_trip() {
+        let (_server, _couch, db) = fake_couch().await;
+        let content = "# Title\n\nsome content that is definitely longer than one chunk ✨";
+
+        db.save_note("notes/test.md", content).await.unwrap();
+        let doc = db.get_note("notes/test.md").await.unwrap();
+
+        assert_eq!(doc.doc_type, "plain");
+        assert_eq!(doc.size, content.len() as u64);
+        assert!(doc.children.len() > 1);
+        assert_eq!(db.decode_content(&doc).await.unwrap(), content);
+
+        // overwriting keeps ctime and bumps the rev
+        db.save_note("notes/test.md", "new").await.unwrap();
+        let updated = db.get_note("notes/test.md").await.unwrap();
+        assert_eq!(updated.ctime, doc.ctime);
+        assert_ne!(updated.rev, doc.rev);
+        assert_eq!(db.decode_content(&updated).await.unwrap(), "new");
+    }
+
+    #[tokio::test]
+    async fn test_append_and_soft_delete() {
+        let (_server, couch, db) = fake_couch().await;
+
+        db.save_note("log.md", "one").await.unwrap();
+        db.append_to_note("log.md", "two", "\n").await.unwrap();
+        let doc = db.get_note("log.md").await.unwrap();
+        assert_eq!(db.decode_content(&doc).await.unwrap(), "one\ntwo");
+
+        db.delete_note("log.md").await.unwrap();
+        let stored = couch.docs.lock().unwrap()["log.md"].clone();
+        assert_eq!(stored["deleted"], true);
+        // chunks stay put so livesync can still sync the deletion
+        assert!(couch.docs.lock().unwrap().contains_key(&doc.children[0]));
+    }
+
+    #[tokio::test]
+    async fn test_old_chunks_deleted_after_parent_saved() {
+        let (server, couch, db) = fake_couch().await;
+
+        db.save_note("a.md", "first version").await.unwrap();
+        let old_children = db.get_note("a.md").await.unwrap().children;
+        let first_request = server.received_requests().await.unwrap().len();
+
+        db.save_note("a.md", "second version").await.unwrap();
+
+        let requests = server.received_requests().await.unwrap();
+        let ops: Vec<(String, String)> = requests[first_request..]
+            .iter()
+            .map(|r| {
+                let id = r.url.path().splitn(3, '/').nth(2).unwrap_or_default();
+                let id = urlencoding::decode(id).unwrap().into_owned();
+                (r.method.to_string(), id)
+            })
+            .collect();
+
+        let parent_put = ops
+            .iter()
+            .position(|op| *op == ("PUT".to_string(), "a.md".to_string()))
+            .unwrap();
+        let first_delete = ops.iter().position(|(m, _)| m == "DELETE").unwrap();
+        let last_chunk_put = ops
+            .iter()
+            .rposition(|(m, id)| m == "PUT" && id.starts_with("h:"))
+            .unwrap();
+
+        // new chunks, then the parent, and only then are the old chunks removed
+        assert!(last_chunk_put < parent_put);
+        assert!(parent_put < first_delete);
+
+        let docs = couch.docs.lock().unwrap();
+        assert!(old_children.iter().all(|id| !docs.contains_key(id)));
+    }
+
+    #[tokio::test]
+    async fn test_save_note_rejects_oversized_content() {
+        let server = MockServer::start().await;
+        let db = CouchDbClient::new(CouchDbConfig {
+            url: server.uri(),
+            database: "obsidian".to_string(),
+            max_note_size: Some(10),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let err = db.save_note("big.md", &"a".repeat(11)).await.unwrap_err();
+        assert!(matches!(err, CouchDbError::TooLarge { size: 11, .. }));
+        // nothing was written
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_save_note_rejects_too_many_chunks() {
+        let server = MockServer::start().await;
+        let db = CouchDbClient::new(CouchDbConfig {
+            url: server.uri(),
+            database: "obsidian".to_string(),
+            max_chunks_per_note: Some(2),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let err = db
+            .save_note("big.md", &"a".repeat(CHUNK_SIZE * 2 + 1))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, CouchDbError::TooManyChunks { chunks: 3, .. }));
+        assert!(err.to_string().contains("--max-chunks-per-note"));
+        // found out before the existing doc was even fetched
+        assert!(server.received_requests().await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_looks_like_livesync() {
+        use serde_json::json;
+        let yes = [
+            ("a.md", json!({"type": "plain", "children": ["h:1"]})),
+            ("b.md", json!({"type": "newnote", "children": []})),
+            ("c.md", json!({"type": "notes", "data": "aGk="})),
+            ("h:1", json!({"type": "leaf", "data": "hi"})),
+            ("obsydian_livesync_version", json!({"version": 10})),
+        ];
+        for (id, doc) in &yes {
+            assert!(looks_like_livesync(id, doc), "{id}");
+        }
+        let no = [
+            ("user:1", json!({"name": "bob", "email": "bob@example.com"})),
+            ("a.md", json!({"type": "plain"})),
+            ("h:1", json!({"type": "leaf"})),
+        ];
+        for (id, doc) in &no {
+            assert!(!looks_like_livesync(id, doc), "{id}");
         }
 
-        Ok((results, last_seq))
+        let empty = FormatSample::default();
+        assert!(!empty.looks_wrong());
+        let wrong = FormatSample {
+            sampled: 3,
+            livesync: 0,
+            others: vec![],
+        };
+        assert!(wrong.looks_wrong());
+    }
+
+    #[test]
+    fn test_encryption_detection() {
+        use serde_json::json;
+        assert!(milestone_says_encrypted(&json!({
+            "tweak_values": {"laptop": {"encrypt": false}, "phone": {"encrypt": true}}
+        })));
+        assert!(!milestone_says_encrypted(&json!({
+            "tweak_values": {"laptop": {"encrypt": false}}
+        })));
+        assert!(!milestone_says_encrypted(&json!({"locked": false})));
+    }
+
+    #[test]
+    fn test_chunks_that_only_look_encrypted() {
+        // a note that starts with a % and a sha256, in a vault that isn't encrypted
+        let data = format!("%{} is the checksum", "ab12".repeat(16));
+        assert!(e2ee::is_encrypted(&data));
+        let leaf = |encrypted| LeafDoc {
+            id: "h:1".to_string(),
+            rev: None,
+            data: data.clone(),
+            doc_type: "leaf".to_string(),
+            encrypted,
+        };
+        let plain = client("http://127.0.0.1:9", "obsidian");
+        assert_eq!(plain.leaf_text(leaf(None)).unwrap(), data);
+        assert!(plain.leaf_text(leaf(Some(true))).is_err());
+
+        // with a passphrase, in a vault that is encrypted, it still doesn't decrypt
+        let encrypted = CouchDbClient::new(CouchDbConfig {
+            url: "http://127.0.0.1:9".to_string(),
+            database: "obsidian".to_string(),
+            passphrase: Some("hunter2".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        encrypted.encrypted_vault.store(true, Ordering::Relaxed);
+        assert_eq!(encrypted.leaf_text(leaf(None)).unwrap(), data);
+        assert!(encrypted.leaf_text(leaf(Some(true))).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_doc_ids() {
+        let (server, couch, db) = fake_couch().await;
+
+        // the doc id is the vault-relative path, .md and all, with a / in front of a leading _
+        db.save_note("Projects/Note.md", "a").await.unwrap();
+        db.save_note("_templates/Daily.md", "b").await.unwrap();
+        {
+            let docs = couch.docs.lock().unwrap();
+            assert_eq!(docs["Projects/Note.md"]["path"], "Projects/Note.md");
+            assert_eq!(docs["/_templates/Daily.md"]["path"], "_templates/Daily.md");
+        }
+        assert_eq!(
+            db.list_notes(None, None, 10).await.unwrap().notes,
+            vec!["_templates/Daily.md", "Projects/Note.md"]
+        );
+        assert!(db.get_note("_templates/Daily.md").await.is_ok());
+
+        let lowercase = CouchDbClient::new(CouchDbConfig {
+            url: server.uri(),
+            database: "obsidian".to_string(),
+            path_style: PathStyle::Lowercase,
+            ..Default::default()
+        })
+        .unwrap();
+        lowercase.save_note("Ideas/Big Plan.md", "c").await.unwrap();
+        assert_eq!(
+            couch.docs.lock().unwrap()["ideas/big plan.md"]["path"],
+            "Ideas/Big Plan.md"
+        );
+        let listed = lowercase
+            .list_notes(Some("Ideas/"), None, 10)
+            .await
+            .unwrap();
+        assert_eq!(listed.notes, vec!["Ideas/Big Plan.md"]);
+        assert!(lowercase.get_note("ideas/BIG plan.md").await.is_ok());
+        // saving it under another case keeps the path it has
+        lowercase.save_note("ideas/BIG plan.md", "d").await.unwrap();
+        assert_eq!(
+            couch.docs.lock().unwrap()["ideas/big plan.md"]["path"],
+            "Ideas/Big Plan.md"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_conflict_retry() {
+        let (server, _couch, db) = fake_couch().await;
+        db.save_note("race.md", "first version").await.unwrap();
+
+        // a 409 as if another device saved in between, then the fake couch as normal
+        let conflict_once = || {
+            Mock::given(method("PUT"))
+                .and(path("/obsidian/race.md"))
+                .respond_with(ResponseTemplate::new(409))
+                .up_to_n_times(1)
+                .with_priority(1)
+        };
+
+        conflict_once().mount(&server).await;
+        db.save_note("race.md", "overwritten").await.unwrap();
+        let doc = db.get_note("race.md").await.unwrap();
+        assert_eq!(db.decode_content(&doc).await.unwrap(), "overwritten");
+    }
+
+    #[tokio::test]
+    async fn test_edit_conflict() {
+        let (_server, _couch, db) = fake_couch().await;
+        db.save_note("race.md", "first version").await.unwrap();
+        let read = db.get_note("race.md").await.unwrap();
+
+        // obsidian saves in between the read and the edit's save
+        db.save_note("race.md", "from obsidian").await.unwrap();
+        assert!(matches!(
+            db.update_note("race.md", "first version, edited", &read)
+                .await,
+            Err(CouchDbError::Conflict(_))
+        ));
+        let doc = db.get_note("race.md").await.unwrap();
+        assert_eq!(db.decode_content(&doc).await.unwrap(), "from obsidian");
+
+        // against what's there now it goes through
+        db.update_note("race.md", "edited", &doc).await.unwrap();
+        let doc = db.get_note("race.md").await.unwrap();
+        assert_eq!(db.decode_content(&doc).await.unwrap(), "edited");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_and_payload_too_large() {
+        let (server, _couch, db) = fake_couch().await;
+
+        // waited out, then the request goes through
+        Mock::given(method("PUT"))
+            .and(path("/obsidian/busy.md"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(2)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        db.save_note("busy.md", "eventually").await.unwrap();
+
+        // a wait that's too long to sit through is reported instead
+        Mock::given(method("GET"))
+            .and(path("/obsidian/slow.md"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "3600"))
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        assert!(matches!(
+            db.get_note("slow.md").await,
+            Err(CouchDbError::RateLimited(_))
+        ));
+
+        Mock::given(method("PUT"))
+            .and(path("/obsidian/huge.md"))
+            .respond_with(ResponseTemplate::new(413).set_body_string("document too large"))
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        let err = db.save_note("huge.md", "big").await.unwrap_err();
+        assert!(matches!(err, CouchDbError::PayloadTooLarge(_)));
+        assert!(err.to_string().contains("max_document_size"));
+    }
+
+    #[tokio::test]
+    async fn test_copy_note() {
+        let (_server, _couch, db) = fake_couch().await;
+        db.save_note("src.md", "copy me please, i am long enough for chunks")
+            .await
+            .unwrap();
+        db.save_note("dst.md", "already here").await.unwrap();
+
+        assert!(matches!(
+            db.copy_note("src.md", "dst.md", false, false).await,
+            Err(CouchDbError::AlreadyExists(_))
+        ));
+        assert!(matches!(
+            db.copy_note("missing.md", "x.md", false, false).await,
+            Err(CouchDbError::NotFound(_))
+        ));
+        db.copy_note("src.md", "dst.md", true, false).await.unwrap();
+        db.copy_note("src.md", "new.md", false, false)
+            .await
+            .unwrap();
+        db.copy_note("src.md", "moved.md", false, true)
+            .await
+            .unwrap();
+
+        let src = db.get_note("src.md").await.unwrap();
+        assert_eq!(db.get_note("moved.md").await.unwrap().ctime, src.ctime);
+        for path in ["dst.md", "new.md", "moved.md"] {
+            let copy = db.get_note(path).await.unwrap();
+            assert_eq!(
+                db.decode_content(&copy).await.unwrap(),
+                "copy me please, i am long enough for chunks"
+            );
+            // copies get their own chunks
+            assert!(copy.children.iter().all(|c| !src.children.contains(c)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_list_notes_pages() {
+        let (_server, _couch, db) = fake_couch().await;
+        for path in [
+            "a.md",
+            "b/one.md",
+            "b/two.md",
+            "b/three.md",
+            "c.md",
+            "gone.md",
+        ] {
+            db.save_note(path, "some content that spans more than one chunk, honest")
+                .await
+                .unwrap();
+        }
+        db.delete_note("gone.md").await.unwrap();
+
+        // walk the whole vault two at a time, chunks and soft-deleted notes never show up
+        let mut all = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = db.list_notes(None, cursor.as_deref(), 2).await.unwrap();
+            assert!(page.notes.len() <= 2);
+            all.extend(page.notes);
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                break;
+            }
+        }
+        assert_eq!(all, ["a.md", "b/one.md", "b/three.md", "b/two.md", "c.md"]);
+
+        let page = db.list_notes(Some("b/"), None, 2).await.unwrap();
+        assert_eq!(page.notes, ["b/one.md", "b/three.md"]);
+        assert_eq!(page.next_cursor.as_deref(), Some("b/two.md"));
+        let page = db
+            .list_notes(Some("b/"), page.next_cursor.as_deref(), 2)
+            .await
+            .unwrap();
+        assert_eq!(page.notes, ["b/two.md"]);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[tokio::test]
+    async fn test_restore_note() {
+        let (_server, _couch, db) = fake_couch().await;
+        db.save_note("kept.md", "still here").await.unwrap();
+        db.save_note("gone.md", "back from the dead").await.unwrap();
+        db.delete_note("gone.md").await.unwrap();
+
+        let trash = db.list_deleted_notes(None, None, 10).await.unwrap();
+        let gone = db.get_note("gone.md").await.unwrap();
+        assert_eq!(
+            trash.notes,
+            [DeletedNote {
+                path: "gone.md".to_string(),
+                mtime: gone.mtime,
+            }]
+        );
+
+        assert!(db.restore_note("gone.md").await.unwrap());
+        assert!(!db.restore_note("kept.md").await.unwrap());
+        let restored = db.get_note("gone.md").await.unwrap();
+        assert_eq!(restored.deleted, None);
+        assert_eq!(
+            db.decode_content(&restored).await.unwrap(),
+            "back from the dead"
+        );
+        let trash = db.list_deleted_notes(None, None, 10).await.unwrap();
+        assert!(trash.notes.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_chunk_sizes_reports_missing_chunks() {
+        let (_server, couch, db) = fake_couch().await;
+        let content = "a".repeat(CHUNK_SIZE + 5);
+        db.save_note("chunky.md", &content).await.unwrap();
+        let doc = db.get_note("chunky.md").await.unwrap();
+
+        couch.docs.lock().unwrap().remove(&doc.children[1]);
+
+        let sizes = db.chunk_sizes(&doc).await;
+        assert_eq!(sizes.len(), 2);
+        assert_eq!(sizes[0].0, doc.children[0]);
+        assert_eq!(*sizes[0].1.as_ref().unwrap(), CHUNK_SIZE);
+        assert!(sizes[1].1.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_orphaned_chunks() {
+        let (_server, couch, db) = fake_couch().await;
+        db.save_note("a.md", "first").await.unwrap();
+        db.save_note("z.md", "last").await.unwrap();
+        db.save_note("trash.md", "deleted but restorable")
+            .await
+            .unwrap();
+        db.delete_note("trash.md").await.unwrap();
+        couch.docs.lock().unwrap().insert(
+            "h:orphan".to_string(),
+            serde_json::json!({"_id": "h:orphan", "_rev": "1-x", "type": "leaf", "data": "?"}),
+        );
+
+        let orphans = db.orphaned_chunks().await.unwrap();
+        assert_eq!(orphans, [("h:orphan".to_string(), "1-x".to_string())]);
+
+        assert_eq!(
+            db.storage_stats().await.unwrap(),
+            StorageStats {
+                total_docs: 7,
+                notes: 2,
+                deleted_notes: 1,
+                chunks: 4,
+                orphaned_chunks: 1,
+            }
+        );
+
+        assert!(db.delete_chunk("h:orphan", "1-x").await.unwrap());
+        // already gone is fine
+        assert!(!db.delete_chunk("h:orphan", "1-x").await.unwrap());
+        assert!(db.orphaned_chunks().await.unwrap().is_empty());
+        let doc = db.get_note("z.md").await.unwrap();
+        assert_eq!(db.decode_content(&doc).await.unwrap(), "last");
+        // something else that happens to have a chunk's id is left alone
+        couch.docs.lock().unwrap().insert(
+            "h:odd".to_string(),
+            serde_json::json!({"_id": "h:odd", "_rev": "1-x", "type": "plain"}),
+        );
+        assert!(!db.delete_chunk("h:odd", "1-x").await.unwrap());
+        assert!(couch.docs.lock().unwrap().contains_key("h:odd"));
+    }
+
+    #[tokio::test]
+    async fn test_conflicted_chunks_arent_orphans() {
+        let (_server, couch, db) = fake_couch().await;
+        db.save_note("a.md", "ours").await.unwrap();
+        let mut theirs = couch.docs.lock().unwrap()["a.md"].clone();
+        theirs["_rev"] = "1-theirs".into();
+        theirs["children"] = serde_json::json!(["h:theirs"]);
+        {
+            let mut docs = couch.docs.lock().unwrap();
+            docs.insert(
+                "h:theirs".to_string(),
+                serde_json::json!({"_id": "h:theirs", "_rev": "1-x", "type": "leaf", "data": "?"}),
+            );
+            docs.get_mut("a.md").unwrap()["_conflicts"] = serde_json::json!(["1-theirs"]);
+        }
+        couch
+            .old_revs
+            .lock()
+            .unwrap()
+            .insert(("a.md".to_string(), "1-theirs".to_string()), theirs);
+
+        assert!(db.orphaned_chunks().await.unwrap().is_empty());
+        assert_eq!(db.storage_stats().await.unwrap().orphaned_chunks, 0);
+
+        // once the conflict's resolved, its chunks are fair game
+        couch.docs.lock().unwrap().get_mut("a.md").unwrap()["_conflicts"] = serde_json::json!([]);
+        assert_eq!(
+            db.orphaned_chunks().await.unwrap(),
+            [("h:theirs".to_string(), "1-x".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_load_notes() {
+        let (server, couch, db) = fake_couch().await;
+        Mock::given(method("GET"))
+            .and(path("/obsidian"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({"update_seq": "9-x"})),
+            )
+            .mount(&server)
+            .await;
+        for i in 0..=LOAD_BATCH_SIZE {
+            db.save_note(&format!("n{:03}.md", i), &format!("note {}", i))
+                .await
+                .unwrap();
+        }
+        db.delete_note("n000.md").await.unwrap();
+        couch.docs.lock().unwrap().insert(
+            "broken.md".to_string(),
+            serde_json::json!({
+                "_id": "broken.md", "_rev": "1-x", "path": "broken.md", "type": "plain",
+                "children": ["h:gone"], "ctime": 0, "mtime": 5, "size": 0,
+            }),
+        );
+
+        let (mut loader, last_seq) = db.load_notes().await.unwrap();
+        assert_eq!(last_seq.as_deref(), Some("9-x"));
+        let mut batches = Vec::new();
+        while let Some(batch) = loader.next_batch().await.unwrap() {
+            batches.push(batch);
+        }
+        assert_eq!(
+            batches.iter().map(Vec::len).collect::<Vec<_>>(),
+            [LOAD_BATCH_SIZE, 1]
+        );
+        let notes: Vec<_> = batches.into_iter().flatten().collect();
+        assert_eq!(notes[0], ("broken.md".to_string(), String::new(), 5));
+        assert_eq!(notes[1].0, "n001.md");
+        assert_eq!(notes[1].1, "note 1");
+        assert!(!notes.iter().any(|(path, ..)| path == "n000.md"));
+    }
+
+    #[tokio::test]
+    async fn test_get_note_revision() {
+        let (_server, couch, db) = fake_couch().await;
+        db.save_note("a.md", "first version").await.unwrap();
+        let first = db.get_note("a.md").await.unwrap();
+        let first_rev = first.rev.clone().unwrap();
+        // put the first version's chunks back, as if nothing had cleaned them up yet
+        let chunks: Vec<_> = first
+            .children
+            .iter()
+            .map(|id| (id.clone(), couch.docs.lock().unwrap()[id].clone()))
+            .collect();
+        db.save_note("a.md", "second version").await.unwrap();
+
+        let err = db.get_note_revision("a.md", &first_rev).await.unwrap_err();
+        assert!(
+            err.to_string().contains("chunks have been deleted"),
+            "{}",
+            err
+        );
+
+        couch.docs.lock().unwrap().extend(chunks);
+        let (doc, content) = db.get_note_revision("a.md", &first_rev).await.unwrap();
+        assert_eq!(doc.rev.as_deref(), Some(first_rev.as_str()));
+        assert_eq!(content, "first version");
+
+        let err = db.get_note_revision("a.md", "9-nope").await.unwrap_err();
+        assert!(matches!(err, CouchDbError::RevisionUnavailable { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_notes_view() {
+        let (_server, couch, db) = fake_couch().await;
+        for path in ["a.md", "b/one.md", "b/two.md", "c.md", "gone.md"] {
+            db.save_note(path, "x").await.unwrap();
+        }
+        db.delete_note("gone.md").await.unwrap();
+        couch.docs.lock().unwrap().insert(
+            "obsydian_livesync_version".to_string(),
+            serde_json::json!({"_id": "obsydian_livesync_version", "version": 12}),
+        );
+
+        couch.docs.lock().unwrap().insert(
+            NOTES_VIEW_DOC.to_string(),
+            serde_json::json!({
+                "_id": NOTES_VIEW_DOC, "_rev": "1-theirs",
+                "views": {"notes": {"map": "old"}, "by_tag": {"map": "theirs"}},
+            }),
+        );
+        db.ensure_notes_view().await.unwrap();
+        let design = couch.docs.lock().unwrap()[NOTES_VIEW_DOC].clone();
+        assert_eq!(design["views"]["notes"]["map"], db.notes_view_map());
+        // other views in the doc are kept
+        assert_eq!(design["views"]["by_tag"]["map"], "theirs");
+        // already up to date, so it's left alone
+        db.ensure_notes_view().await.unwrap();
+        assert_eq!(couch.docs.lock().unwrap()[NOTES_VIEW_DOC]["_rev"], "2-fake");
+
+        let page = db.list_notes(None, None, 2).await.unwrap();
+        assert_eq!(page.notes, ["a.md", "b/one.md"]);
+        let page = db
+            .list_notes(None, page.next_cursor.as_deref(), 10)
+            .await
+            .unwrap();
+        assert_eq!(page.notes, ["b/two.md", "c.md"]);
+        assert_eq!(page.next_cursor, None);
+        let page = db.list_notes(Some("b/"), None, 10).await.unwrap();
+        assert_eq!(page.notes, ["b/one.md", "b/two.md"]);
+        let trash = db.list_deleted_notes(None, None, 10).await.unwrap();
+        assert_eq!(trash.notes.len(), 1);
+        assert_eq!(trash.notes[0].path, "gone.md");
+
+        // without the design doc it falls back to _all_docs
+        couch.docs.lock().unwrap().remove(NOTES_VIEW_DOC);
+        let page = db.list_notes(Some("b/"), None, 10).await.unwrap();
+        assert_eq!(page.notes, ["b/one.md", "b/two.md"]);
+    }
+
+    #[tokio::test]
+    async fn test_find() {
+        let server = MockServer::start().await;
+        let note = |id: &str, mtime: u64| {
+            serde_json::json!({
+                "_id": id, "_rev": "1-a", "path": id, "ctime": 1, "mtime": mtime, "size": 1,
+                "type": "plain", "children": [],
+            })
+        };
+        Mock::given(method("POST"))
+            .and(path("/obsidian/_find"))
+            .and(wiremock::matchers::body_partial_json(
+                serde_json::json!({"bookmark": "page2"}),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "docs": [note("c.md", 30)],
+                "bookmark": "page3",
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/obsidian/_find"))
+            .and(wiremock::matchers::body_partial_json(
+                serde_json::json!({"bookmark": "page3"}),
+            ))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({"docs": [], "bookmark": "page3"})),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/obsidian/_find"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "docs": [note("a.md", 10), {"_id": "h:x", "type": "leaf", "data": ""}, note("b.md", 20)],
+                "bookmark": "page2",
+                "warning": "No matching index found, create an index to optimize query time.",
+            })))
+            .mount(&server)
+            .await;
+        let db = client(&server.uri(), "obsidian");
+
+        let selector = serde_json::json!({"mtime": {"$gt": 5}});
+        let found = db.find(selector.clone(), None, 100).await.unwrap();
+        let ids: Vec<_> = found.iter().map(|n| n.id.as_str()).collect();
+        assert_eq!(ids, ["a.md", "b.md", "c.md"]);
+
+        let found = db.find(selector, None, 1).await.unwrap();
+        assert_eq!(found.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_find_sorted() {
+        let server = MockServer::start().await;
+        let newest_first = serde_json::json!([{"mtime": "desc"}]);
+        Mock::given(method("POST"))
+            .and(path("/indexed/_find"))
+            .and(wiremock::matchers::body_partial_json(serde_json::json!({
+                "sort": newest_first,
+                "fields": ["_id", "path", "mtime", "size"],
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "docs": [{"_id": "b.md", "path": "b.md", "mtime": 20, "size": 1}],
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/plain/_find"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                "error": "no_usable_index",
+                "reason": "No index exists for this sort, try indexing by the sort fields.",
+            })))
+            .mount(&server)
+            .await;
+
+        let selector = serde_json::json!({"mtime": {"$gt": 5}});
+        let found = client(&server.uri(), "indexed")
+            .find(selector.clone(), Some(newest_first.clone()), 10)
+            .await
+            .unwrap();
+        assert_eq!(found[0].mtime, 20);
+        let err = client(&server.uri(), "plain")
+            .find(selector, Some(newest_first), 10)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, CouchDbError::NoIndex(_)));
+    }
+
+    #[tokio::test]
+    async fn test_chunk_id_prefix() {
+        let server = MockServer::start().await;
+        let couch = FakeCouch::default();
+        Mock::given(path_regex("^/obsidian/.+"))
+            .respond_with(couch.clone())
+            .mount(&server)
+            .await;
+        let db = CouchDbClient::new(CouchDbConfig {
+            url: server.uri(),
+            database: "obsidian".to_string(),
+            chunk_id_prefix: Some("yamos:".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(after_prefix("h:"), "h;");
+
+        db.save_note("a.md", "mine").await.unwrap();
+        db.save_note("z.md", "also mine").await.unwrap();
+        let doc = db.get_note("a.md").await.unwrap();
+        assert!(doc.children.iter().all(|id| id.starts_with("yamos:")));
+        // LiveSync's chunks are still chunks
+        couch.docs.lock().unwrap().insert(
+            "h:livesync".to_string(),
+            serde_json::json!({"_id": "h:livesync", "_rev": "1-x", "type": "leaf", "data": "?"}),
+        );
+
+        assert!(db.is_chunk_id("h:livesync") && db.is_chunk_id(&doc.children[0]));
+        let map = db.notes_view_map();
+        assert!(map.contains(r#"doc._id.indexOf("h:") === 0 || doc._id.indexOf("yamos:") === 0"#));
+        let page = db.list_notes(None, None, 10).await.unwrap();
+        assert_eq!(page.notes, ["a.md", "z.md"]);
+        let orphans = db.orphaned_chunks().await.unwrap();
+        assert_eq!(orphans, [("h:livesync".to_string(), "1-x".to_string())]);
+
+        // quotes and backslashes have to survive being made into _all_docs keys
+        let db = CouchDbClient::new(CouchDbConfig {
+            url: server.uri(),
+            database: "obsidian".to_string(),
+            chunk_id_prefix: Some(r#"q"\:"#.to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        db.save_note("b.md", "quoted").await.unwrap();
+        let doc = db.get_note("b.md").await.unwrap();
+        assert!(doc.children.iter().all(|id| id.starts_with(r#"q"\:"#)));
+        assert_eq!(
+            db.orphaned_chunks().await.unwrap(),
+            [("h:livesync".to_string(), "1-x".to_string())]
+        );
+        assert_eq!(db.storage_stats().await.unwrap().notes, 3);
+
+        for bad in ["", "_h:", "chunk", "notes/"] {
+            let config = CouchDbConfig {
+                url: server.uri(),
+                database: "obsidian".to_string(),
+                chunk_id_prefix: Some(bad.to_string()),
+                ..Default::default()
+            };
+            assert!(CouchDbClient::new(config).is_err());
+        }
     }
 }
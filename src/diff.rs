@@ -0,0 +1,158 @@
+//! Line-based unified diffs, like `diff -u`. A plain longest-common-subsequence over whatever is
+//! left once the common start and end are trimmed off, which is fine at note sizes
+
+/// Past this many line pairs to compare, the changed middle is shown as removed then re-added
+/// rather than spending the memory to line it up
+const MAX_COMPARISONS: usize = 4_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// Edit script turning `old` into `new`, as each line's op and text
+fn ops<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<(Op, &'a str)> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (a, b) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+
+    let mut ops: Vec<(Op, &str)> = old[..prefix].iter().map(|l| (Op::Equal, *l)).collect();
+    if a.len() * b.len() > MAX_COMPARISONS {
+        ops.extend(a.iter().map(|l| (Op::Delete, *l)));
+        ops.extend(b.iter().map(|l| (Op::Insert, *l)));
+    } else {
+        // lcs[i][j] is the LCS length of a[i..] and b[j..]
+        let mut lcs = vec![vec![0u32; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                lcs[i][j] = if a[i] == b[j] {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    lcs[i + 1][j].max(lcs[i][j + 1])
+                };
+            }
+        }
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() || j < b.len() {
+            if i < a.len() && j < b.len() && a[i] == b[j] {
+                ops.push((Op::Equal, a[i]));
+                i += 1;
+                j += 1;
+            } else if j == b.len() || (i < a.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+                ops.push((Op::Delete, a[i]));
+                i += 1;
+            } else {
+                ops.push((Op::Insert, b[j]));
+                j += 1;
+            }
+        }
+    }
+    ops.extend(old[old.len() - suffix..].iter().map(|l| (Op::Equal, *l)));
+    ops
+}
+
+/// `diff -u` style output going from `old` to `new`, with `context` unchanged lines around each
+/// change. Empty if they're the same
+pub fn unified(old: &str, new: &str, old_name: &str, new_name: &str, context: usize) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let ops = ops(&old_lines, &new_lines);
+
+    let changes: Vec<usize> = (0..ops.len()).filter(|&i| ops[i].0 != Op::Equal).collect();
+    if changes.is_empty() {
+        return String::new();
+    }
+
+    // where each op starts in the old and new text
+    let mut positions = Vec::with_capacity(ops.len());
+    let (mut old_pos, mut new_pos) = (0, 0);
+    for (op, _) in &ops {
+        positions.push((old_pos, new_pos));
+        match op {
+            Op::Equal => {
+                old_pos += 1;
+                new_pos += 1;
+            }
+            Op::Delete => old_pos += 1,
+            Op::Insert => new_pos += 1,
+        }
+    }
+
+    // changes close enough together share a hunk, so their context doesn't overlap
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for &change in &changes {
+        let start = change.saturating_sub(context);
+        let end = (change + context + 1).min(ops.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", old_name, new_name);
+    for (start, end) in hunks {
+        let hunk = &ops[start..end];
+        let old_len = hunk.iter().filter(|(op, _)| *op != Op::Insert).count();
+        let new_len = hunk.iter().filter(|(op, _)| *op != Op::Delete).count();
+        let (old_start, new_start) = positions[start];
+        // an empty range is numbered by the line before it, as diff does
+        let line_no = |pos: usize, len: usize| if len == 0 { pos } else { pos + 1 };
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            line_no(old_start, old_len),
+            old_len,
+            line_no(new_start, new_len),
+            new_len
+        ));
+        for (op, line) in hunk {
+            let sign = match op {
+                Op::Equal => ' ',
+                Op::Delete => '-',
+                Op::Insert => '+',
+            };
+            out.push(sign);
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unified() {
+        assert_eq!(unified("a\nb\n", "a\nb\n", "x", "y", 3), "");
+
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n";
+        let new = "1\n2\nthree\n4\n5\n6\n7\n8\n9\n10\neleven\n";
+        assert_eq!(
+            unified(old, new, "a.md", "b.md", 1),
+            "--- a.md\n+++ b.md\n\
+             @@ -2,3 +2,3 @@\n 2\n-3\n+three\n 4\n\
+             @@ -10,1 +10,2 @@\n 10\n+eleven\n"
+        );
+
+        // one side missing is all added or all removed
+        assert_eq!(
+            unified("", "# Hi\nthere", "/dev/null", "b.md", 3),
+            "--- /dev/null\n+++ b.md\n@@ -0,0 +1,2 @@\n+# Hi\n+there\n"
+        );
+        assert_eq!(
+            unified("gone", "", "a.md", "/dev/null", 3),
+            "--- a.md\n+++ /dev/null\n@@ -1,1 +0,0 @@\n-gone\n"
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! LiveSync's end-to-end encryption, for vaults that have it turned on. Chunk data is stored as
+//! `%<iv hex><salt hex><base64 ciphertext>`: AES-256-GCM with a 16-byte iv, keyed by PBKDF2 over
+//! the SHA-256 of the passphrase, and the plaintext is the chunk's text JSON-encoded as a string.
+//! The newer "%=" format and obfuscated paths aren't supported
+
+use aes_gcm::AesGcm;
+use aes_gcm::aead::consts::U16;
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::aes::Aes256;
+use anyhow::{Result, anyhow, bail};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// LiveSync's PBKDF2 rounds, unless "use dynamic iteration count" is on (not supported)
+const ITERATIONS: u32 = 100_000;
+
+/// Derived keys kept around for decrypting. LiveSync reuses a salt across many chunks, and
+/// PBKDF2 is slow on purpose
+const MAX_CACHED_KEYS: usize = 256;
+
+type Cipher = AesGcm<Aes256, U16>;
+
+/// Whether chunk data is in one of LiveSync's encrypted formats: '%' then a hex iv and salt, or
+/// "%=" then base64. Plain chunks can start with '%' too (hello `%% comments %%`), hence the
+/// closer look
+pub fn is_encrypted(data: &str) -> bool {
+    if data.starts_with("%=") {
+        return true;
+    }
+    data.strip_prefix('%').is_some_and(|rest| {
+        rest.len() > 64 && rest.as_bytes()[..64].iter().all(u8::is_ascii_hexdigit)
+    })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex<const N: usize>(hex: &str) -> Result<[u8; N]> {
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        bail!("expected {} hex characters", N * 2);
+    }
+    let mut bytes = [0; N];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(bytes)
+}
+
+pub struct Encryption {
+    /// SHA-256 of the passphrase, which is what LiveSync feeds to PBKDF2
+    secret: [u8; 32],
+    /// Keys by salt, for decrypting
+    keys: Mutex<HashMap<[u8; 16], Cipher>>,
+    /// Salt and key for everything we encrypt, derived once per run
+    write_key: ([u8; 16], Cipher),
+}
+
+impl Encryption {
+    pub fn new(passphrase: &str) -> Self {
+        let secret: [u8; 32] = Sha256::digest(passphrase.as_bytes()).into();
+        let mut salt = [0; 16];
+        rand::rng().fill(&mut salt);
+        let write_key = (salt, derive_key(&secret, &salt));
+        Self {
+            secret,
+            keys: Mutex::new(HashMap::new()),
+            write_key,
+        }
+    }
+
+    fn key_for(&self, salt: [u8; 16]) -> Cipher {
+        if salt == self.write_key.0 {
+            return self.write_key.1.clone();
+        }
+        let mut keys = self.keys.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(key) = keys.get(&salt) {
+            return key.clone();
+        }
+        if keys.len() >= MAX_CACHED_KEYS {
+            keys.clear();
+        }
+        keys.entry(salt)
+            .or_insert_with(|| derive_key(&self.secret, &salt))
+            .clone()
+    }
+
+    /// The plaintext of an encrypted chunk
+    pub fn decrypt(&self, data: &str) -> Result<String> {
+        if data.starts_with("%=") {
+            bail!("uses LiveSync's newer encryption format, which yamos doesn't support yet");
+        }
+        let rest = data
+            .strip_prefix('%')
+            .filter(|_| is_encrypted(data))
+            .ok_or_else(|| anyhow!("isn't encrypted chunk data"))?;
+
+        let iv: [u8; 16] = from_hex(&rest[..32])?;
+        let salt: [u8; 16] = from_hex(&rest[32..64])?;
+        let ciphertext = BASE64.decode(&rest[64..])?;
+
+        let plain = self
+            .key_for(salt)
+            .decrypt((&iv).into(), ciphertext.as_slice())
+            .map_err(|_| anyhow!("couldn't be decrypted - is the passphrase right?"))?;
+        Ok(serde_json::from_slice(&plain)?)
+    }
+
+    /// Chunk text encrypted the way LiveSync does it
+    pub fn encrypt(&self, plain: &str) -> Result<String> {
+        let mut iv = [0; 16];
+        rand::rng().fill(&mut iv);
+        let (salt, key) = &self.write_key;
+        let json = serde_json::to_string(plain)?;
+        let ciphertext = key
+            .encrypt((&iv).into(), json.as_bytes())
+            .map_err(|_| anyhow!("encryption failed"))?;
+        Ok(format!(
+            "%{}{}{}",
+            to_hex(&iv),
+            to_hex(salt),
+            BASE64.encode(ciphertext)
+        ))
+    }
+}
+
+fn derive_key(secret: &[u8; 32], salt: &[u8; 16]) -> Cipher {
+    let key = pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(secret, salt, ITERATIONS);
+    Cipher::new(&key.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let encryption = Encryption::new("correct horse battery staple");
+        let plain = "# hello\n\"quoted\" and ünïcödé %%";
+        let data = encryption.encrypt(plain).unwrap();
+        assert!(is_encrypted(&data));
+        assert_eq!(encryption.decrypt(&data).unwrap(), plain);
+        // a fresh iv every time
+        assert_ne!(encryption.encrypt(plain).unwrap(), data);
+
+        // another run with the same passphrase has its own salt but can still read it
+        let other = Encryption::new("correct horse battery staple");
+        assert_eq!(other.decrypt(&data).unwrap(), plain);
+
+        let wrong = Encryption::new("hunter2");
+        assert!(wrong.decrypt(&data).is_err());
+        assert!(encryption.decrypt("%=abc").is_err());
+        assert!(encryption.decrypt("plain text").is_err());
+    }
+
+    #[test]
+    fn test_is_encrypted() {
+        assert!(is_encrypted(&format!("%{}{}", "0a".repeat(32), "c2VjcmV0")));
+        assert!(is_encrypted("%=c2VjcmV0"));
+        assert!(!is_encrypted("%% an obsidian comment %%"));
+        assert!(!is_encrypted("just some text"));
+    }
+}
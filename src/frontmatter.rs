@@ -0,0 +1,518 @@
+//! Obsidian properties - the YAML frontmatter block at the top of a note. There's no YAML crate
+//! here, so this handles the subset Obsidian itself writes: one `key: value` per line, with
+//! scalar values or lists. Anything fancier (nested maps, block scalars) is rejected rather than
+//! guessed at, so a round trip never mangles someone's frontmatter
+
+use anyhow::{Result, anyhow, bail};
+use serde_json::{Map, Value};
+
+/// Properties in the order they appear in the note
+pub type Properties = Vec<(String, Value)>;
+
+/// Splits a note into its frontmatter (the YAML between the `---` fences) and the body after
+/// the closing fence. None if the note doesn't open with a frontmatter block
+pub fn split(content: &str) -> Option<(&str, &str)> {
+    let mut lines = content.split_inclusive('\n');
+    let first = lines.next()?;
+    if first.trim() != "---" {
+        return None;
+    }
+
+    let yaml_start = first.len();
+    let mut offset = yaml_start;
+    for line in lines {
+        if line.trim() == "---" {
+            return Some((
+                &content[yaml_start..offset],
+                &content[offset + line.len()..],
+            ));
+        }
+        offset += line.len();
+    }
+    // never closed, so it's just a horizontal rule at the top of the note
+    None
+}
+
+/// Parses a note's frontmatter. A note without any comes back empty
+pub fn get(content: &str) -> Result<Properties> {
+    match split(content) {
+        Some((yaml, _)) => parse(yaml),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Updates a note's frontmatter, leaving the body as it was. Keys in `updates` are set in
+/// place (new ones go on the end) and null values remove the key. With `replace` the whole
+/// block becomes `updates` instead. Creates the block if there isn't one, and drops it if it
+/// ends up empty
+pub fn set(content: &str, updates: Map<String, Value>, replace: bool) -> Result<String> {
+    let (mut props, body) = match split(content) {
+        Some((yaml, body)) => (parse(yaml)?, body),
+        None => (Vec::new(), content),
+    };
+
+    if replace {
+        props.clear();
+    }
+    for (key, value) in updates {
+        let existing = props.iter().position(|(k, _)| *k == key);
+        match (existing, value) {
+            (Some(i), Value::Null) => {
+                props.remove(i);
+            }
+            (None, Value::Null) => {}
+            (Some(i), value) => props[i].1 = value,
+            (None, value) => props.push((key, value)),
+        }
+    }
+
+    if props.is_empty() {
+        return Ok(body.to_string());
+    }
+    Ok(format!("---\n{}---\n{}", serialize(&props)?, body))
+}
+
+fn parse(yaml: &str) -> Result<Properties> {
+    let mut props: Properties = Vec::new();
+    let lines: Vec<&str> = yaml.lines().collect();
+    let mut i = 0;
+
+    while i < lines.len() {
+        let line = lines[i].trim_end();
+        let line_no = i + 1;
+        i += 1;
+
+        if is_blank(line) {
+            continue;
+        }
+        if line.starts_with([' ', '\t']) {
+            bail!(
+                "malformed frontmatter on line {}: unexpected indentation (nested values aren't supported)",
+                line_no
+            );
+        }
+
+        let (key, rest) = split_key(line).ok_or_else(|| {
+            anyhow!(
+                "malformed frontmatter on line {}: expected 'key: value'",
+                line_no
+            )
+        })?;
+        if props.iter().any(|(k, _)| *k == key) {
+            bail!(
+                "malformed frontmatter on line {}: duplicate key '{}'",
+                line_no,
+                key
+            );
+        }
+
+        let value = if rest.is_empty() {
+            // either nothing (an empty property) or an indented block list underneath
+            let mut items = Vec::new();
+            while i < lines.len() {
+                let next = lines[i].trim_end();
+                if is_blank(next) {
+                    i += 1;
+                    continue;
+                }
+                let trimmed = next.trim_start();
+                let is_item = trimmed == "-" || trimmed.starts_with("- ");
+                if !is_item {
+                    if next.starts_with([' ', '\t']) {
+                        bail!(
+                            "malformed frontmatter on line {}: nested values aren't supported",
+                            i + 1
+                        );
+                    }
+                    break;
+                }
+                items.push(
+                    scalar(trimmed[1..].trim())
+                        .map_err(|e| anyhow!("malformed frontmatter on line {}: {}", i + 1, e))?,
+                );
+                i += 1;
+            }
+            if items.is_empty() {
+                Value::Null
+            } else {
+                Value::Array(items)
+            }
+        } else {
+            value(rest).map_err(|e| anyhow!("malformed frontmatter on line {}: {}", line_no, e))?
+        };
+
+        props.push((key, value));
+    }
+
+    Ok(props)
+}
+
+fn is_blank(line: &str) -> bool {
+    let trimmed = line.trim();
+    trimmed.is_empty() || trimmed.starts_with('#')
+}
+
+/// `key: rest`, with the key optionally quoted
+fn split_key(line: &str) -> Option<(String, &str)> {
+    if line.starts_with(['"', '\'']) {
+        let (key, len) = quoted(line).ok()?;
+        let rest = line[len..].trim_start().strip_prefix(':')?;
+        if !rest.is_empty() && !rest.starts_with([' ', '\t']) {
+            return None;
+        }
+        return Some((key, rest.trim()));
+    }
+
+    // the first colon followed by whitespace or the end of the line ends the key, so
+    // `time: 12:30` and `url: https://...` both work
+    let bytes = line.as_bytes();
+    let colon = (0..bytes.len()).find(|&i| {
+        bytes[i] == b':' && bytes.get(i + 1).is_none_or(|b| *b == b' ' || *b == b'\t')
+    })?;
+    let key = line[..colon].trim();
+    if key.is_empty() || key.starts_with(['-', '[', '{', '?']) {
+        return None;
+    }
+    Some((key.to_string(), line[colon + 1..].trim()))
+}
+
+/// The value after a key on the same line: a flow list or a scalar
+fn value(s: &str) -> Result<Value> {
+    if let Some(inner) = s.strip_prefix('[') {
+        let inner = strip_comment(inner);
+        let inner = inner
+            .strip_suffix(']')
+            .ok_or_else(|| anyhow!("unclosed '['"))?
+            .trim();
+        if inner.is_empty() {
+            return Ok(Value::Array(Vec::new()));
+        }
+        return split_flow(inner)?
+            .into_iter()
+            .map(scalar)
+            .collect::<Result<Vec<_>>>()
+            .map(Value::Array);
+    }
+    if s.starts_with('{') {
+        bail!("nested values aren't supported");
+    }
+    if s.starts_with(['|', '>']) {
+        bail!("multi-line block values aren't supported");
+    }
+    scalar(s)
+}
+
+/// Splits the inside of `[a, "b, c", d]` on the commas that aren't in quotes
+fn split_flow(s: &str) -> Result<Vec<&str>> {
+    let mut items = Vec::new();
+    let mut start = 0;
+    let mut quote = None;
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (Some('"'), '\\') => {
+                chars.next();
+            }
+            (Some(q), c) if c == q => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[' | '{') => bail!("nested values aren't supported"),
+            (None, ',') => {
+                items.push(s[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if quote.is_some() {
+        bail!("unterminated quoted string");
+    }
+    items.push(s[start..].trim());
+    Ok(items)
+}
+
+/// Drops a trailing ` # comment` that isn't inside quotes
+fn strip_comment(s: &str) -> &str {
+    let mut quote = None;
+    let mut prev = ' ';
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') if prev == ' ' || prev == '\t' => return s[..i].trim_end(),
+            _ => {}
+        }
+        prev = c;
+    }
+    s
+}
+
+fn scalar(s: &str) -> Result<Value> {
+    if s.starts_with(['"', '\'']) {
+        let (text, len) = quoted(s)?;
+        if !strip_comment(&s[len..]).trim().is_empty() {
+            bail!("unexpected text after quoted string");
+        }
+        return Ok(Value::String(text));
+    }
+    if s.starts_with(['[', '{']) {
+        bail!("nested values aren't supported");
+    }
+
+    let s = strip_comment(s);
+    Ok(plain_scalar(s).unwrap_or_else(|| Value::String(s.to_string())))
+}
+
+/// What an unquoted scalar means if it isn't just a string
+fn plain_scalar(s: &str) -> Option<Value> {
+    match s {
+        "" | "~" | "null" | "Null" | "NULL" => return Some(Value::Null),
+        "true" | "True" | "TRUE" => return Some(Value::Bool(true)),
+        "false" | "False" | "FALSE" => return Some(Value::Bool(false)),
+        _ => {}
+    }
+    if let Ok(n) = s.parse::<i64>() {
+        return Some(n.into());
+    }
+    // f64 parsing also takes "inf" and "nan", which YAML spells differently
+    let numeric = s
+        .chars()
+        .all(|c| c.is_ascii_digit() || matches!(c, '.' | '-' | '+' | 'e' | 'E'));
+    if numeric
+        && s.chars().any(|c| c.is_ascii_digit())
+        && let Ok(f) = s.parse::<f64>()
+    {
+        return serde_json::Number::from_f64(f).map(Value::Number);
+    }
+    None
+}
+
+/// A quoted string at the start of `s`, and how many bytes it took up
+fn quoted(s: &str) -> Result<(String, usize)> {
+    let mut chars = s.char_indices();
+    let (_, q) = chars
+        .next()
+        .ok_or_else(|| anyhow!("expected a quoted string"))?;
+    let mut out = String::new();
+
+    while let Some((i, c)) = chars.next() {
+        match c {
+            // single quotes escape themselves by doubling up
+            '\'' if q == '\'' => {
+                if s[i + 1..].starts_with('\'') {
+                    chars.next();
+                    out.push('\'');
+                } else {
+                    return Ok((out, i + 1));
+                }
+            }
+            '"' if q == '"' => return Ok((out, i + 1)),
+            '\\' if q == '"' => match chars.next().map(|(_, c)| c) {
+                Some('n') => out.push('\n'),
+                Some('t') => out.push('\t'),
+                Some(c @ ('"' | '\\' | '/')) => out.push(c),
+                Some(c) => bail!("unsupported escape '\\{}'", c),
+                None => break,
+            },
+            c => out.push(c),
+        }
+    }
+    bail!("unterminated quoted string")
+}
+
+fn serialize(props: &[(String, Value)]) -> Result<String> {
+    let mut out = String::new();
+    for (key, value) in props {
+        if key.trim().is_empty() {
+            bail!("property names can't be empty");
+        }
+        let key = if key.contains(':') || key.contains('#') || needs_quotes(key) {
+            quote(key)
+        } else {
+            key.clone()
+        };
+
+        match value {
+            Value::Null => out.push_str(&format!("{}:\n", key)),
+            Value::Array(items) if items.is_empty() => out.push_str(&format!("{}: []\n", key)),
+            Value::Array(items) => {
+                out.push_str(&format!("{}:\n", key));
+                for item in items {
+                    out.push_str(&format!("  - {}\n", scalar_text(item)?));
+                }
+            }
+            value => out.push_str(&format!("{}: {}\n", key, scalar_text(value)?)),
+        }
+    }
+    Ok(out)
+}
+
+fn scalar_text(value: &Value) -> Result<String> {
+    Ok(match value {
+        Value::Null => "null".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => n.to_string(),
+        Value::String(s) if needs_quotes(s) => quote(s),
+        Value::String(s) => s.clone(),
+        Value::Array(_) | Value::Object(_) => bail!("nested values aren't supported"),
+    })
+}
+
+/// Whether a string would read back as something else (or not at all) without quotes
+fn needs_quotes(s: &str) -> bool {
+    s.is_empty()
+        || plain_scalar(s).is_some()
+        || s.starts_with([
+            '-', '?', ':', ',', '[', ']', '{', '}', '#', '&', '*', '!', '|', '>', '\'', '"', '%',
+            '@', '`', ' ', '\t',
+        ])
+        || s.ends_with([' ', '\t', ':'])
+        || s.contains(": ")
+        || s.contains(" #")
+        || s.contains(['\n', '\r', '\t'])
+}
+
+fn quote(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn props(value: Value) -> Map<String, Value> {
+        value.as_object().unwrap().clone()
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(
+            split("---\ntags: [a]\n---\n# Title\n"),
+            Some(("tags: [a]\n", "# Title\n"))
+        );
+        assert_eq!(split("---\n---\nbody"), Some(("", "body")));
+        assert_eq!(
+            split("---\r\na: 1\r\n---\r\nbody"),
+            Some(("a: 1\r\n", "body"))
+        );
+        assert_eq!(split("no frontmatter\n---\nhere"), None);
+        // an unclosed fence is just a horizontal rule
+        assert_eq!(split("---\nsome text"), None);
+    }
+
+    #[test]
+    fn test_get_obsidian_properties() {
+        let note = "---\n\
+            title: \"My: Note\"\n\
+            tags:\n  - project\n  - 'it''s'\n\
+            aliases: [one, \"two, three\"]\n\
+            rating: 4\n\
+            score: 2.5\n\
+            done: false\n\
+            due: 2024-01-15\n\
+            time: 12:30\n\
+            url: https://example.com/a#b\n\
+            empty:\n\
+            # a comment\n\
+            note: plain text # trailing comment\n\
+            ---\nbody";
+
+        let parsed: Map<String, Value> = get(note).unwrap().into_iter().collect();
+        assert_eq!(
+            Value::Object(parsed),
+            json!({
+                "title": "My: Note",
+                "tags": ["project", "it's"],
+                "aliases": ["one", "two, three"],
+                "rating": 4,
+                "score": 2.5,
+                "done": false,
+                "due": "2024-01-15",
+                "time": "12:30",
+                "url": "https://example.com/a#b",
+                "empty": null,
+                "note": "plain text",
+            })
+        );
+        assert!(get("no frontmatter").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_malformed_frontmatter_errors() {
+        for yaml in [
+            "just some words",
+            "a: 1\na: 2",
+            "parent:\n  child: 1",
+            "a: {b: 1}",
+            "a: [1, [2]]",
+            "a: [1, 2",
+            "a: \"unterminated",
+            "a: |\n  text",
+            "  indented: 1",
+        ] {
+            let note = format!("---\n{}\n---\nbody", yaml);
+            let err = get(&note).unwrap_err().to_string();
+            assert!(err.contains("malformed frontmatter"), "{}: {}", yaml, err);
+        }
+    }
+
+    #[test]
+    fn test_set_merges_and_keeps_body() {
+        let note = "---\ntitle: Old\ntags: [a]\nstatus: draft\n---\n# Heading\n\nbody text\n";
+        let updated = set(
+            note,
+            props(json!({"title": "New", "status": null, "rating": 5})),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            updated,
+            "---\ntitle: New\ntags:\n  - a\nrating: 5\n---\n# Heading\n\nbody text\n"
+        );
+    }
+
+    #[test]
+    fn test_set_replace_and_create() {
+        let note = "---\ntitle: Old\n---\nbody";
+        assert_eq!(
+            set(note, props(json!({"tags": []})), true).unwrap(),
+            "---\ntags: []\n---\nbody"
+        );
+        // no block yet, so one gets made
+        assert_eq!(
+            set("body", props(json!({"done": true})), false).unwrap(),
+            "---\ndone: true\n---\nbody"
+        );
+        // and removing the last key drops it again
+        assert_eq!(
+            set(note, props(json!({"title": null})), false).unwrap(),
+            "body"
+        );
+        assert!(set(note, props(json!({"a": {"b": 1}})), false).is_err());
+    }
+
+    #[test]
+    fn test_serialize_round_trips() {
+        let original = json!({
+            "plain": "hello world",
+            "looks_like_bool": "true",
+            "looks_like_number": "42",
+            "empty": "",
+            "colon": "a: b",
+            "hash": "a #b",
+            "leading_dash": "- x",
+            "quotes": "say \"hi\"",
+            "multiline": "one\ntwo",
+            "list": ["x", "null", 3, false],
+            "float": 1.5,
+        });
+        let written = set("", props(original.clone()), false).unwrap();
+        let read: Map<String, Value> = get(&written).unwrap().into_iter().collect();
+        assert_eq!(Value::Object(read), original);
+    }
+}
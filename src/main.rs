@@ -1,13 +1,26 @@
+mod admin;
+mod archive;
+mod audit;
 mod auth;
+mod chunk_gc;
+mod client_log;
 mod couchdb;
+mod diff;
+mod e2ee;
+mod frontmatter;
+mod markdown;
 mod search;
+mod search_api;
 mod server;
+mod transfer;
+mod websocket;
 
 use anyhow::Result;
 use clap::{Parser, ValueEnum};
 use rmcp::ServiceExt;
-use search::{ChangesWatcher, NoteEntry, SearchIndex, extract_title};
-use server::YamosServer;
+use search::{ChangesWatcher, SearchIndex, note_entries};
+use server::{ServerConfig, YamosServer};
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::RwLock;
 use tokio_util::sync::CancellationToken;
@@ -17,6 +30,16 @@ use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 enum TransportMode {
     Stdio,
     Sse,
+    /// The HTTP server plus MCP over a WebSocket at /ws
+    Ws,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum LogFormat {
+    /// Human-readable lines
+    Pretty,
+    /// One JSON object per line, for shipping to loki/elk/etc
+    Json,
 }
 
 // could this use enums/groups so that we're not offering sse-only flags when using stdio transport? yep.
@@ -45,6 +68,11 @@ struct Args {
     #[arg(long, env = "COUCHDB_DATABASE", default_value = "obsidian")]
     couchdb_database: String,
 
+    /// Another LiveSync database to serve, as name=database. Tools take a `vault` argument to
+    /// pick it; without one they use COUCHDB_DATABASE. Repeatable
+    #[arg(long = "vault", env = "VAULTS", value_delimiter = ',', value_parser = parse_vault)]
+    vaults: Vec<(String, String)>,
+
     /// CouchDB username
     #[arg(long, env = "COUCHDB_USER")]
     couchdb_user: String,
@@ -53,6 +81,124 @@ struct Args {
     #[arg(long, env = "COUCHDB_PASSWORD")]
     couchdb_password: String,
 
+    /// How to authenticate against CouchDB: basic auth, or a /_session cookie
+    #[arg(long, value_enum, env = "COUCHDB_AUTH", default_value = "basic")]
+    couchdb_auth: couchdb::CouchAuthMode,
+
+    /// How LiveSync turns note paths into doc ids: `exact`, or `lowercase` if LiveSync's
+    /// "Handle files as case-sensitive" setting is off
+    #[arg(long, value_enum, env = "OBSIDIAN_PATH_STYLE", default_value = "exact")]
+    obsidian_path_style: couchdb::PathStyle,
+
+    /// PEM file with extra root certificate(s) to trust for CouchDB (e.g. a private CA)
+    #[arg(long, env = "COUCHDB_CA_CERT")]
+    couchdb_ca_cert: Option<PathBuf>,
+
+    /// PEM client certificate for CouchDB mutual TLS (requires --couchdb-client-key)
+    #[arg(long, env = "COUCHDB_CLIENT_CERT")]
+    couchdb_client_cert: Option<PathBuf>,
+
+    /// PEM private key for the CouchDB client certificate
+    #[arg(long, env = "COUCHDB_CLIENT_KEY")]
+    couchdb_client_key: Option<PathBuf>,
+
+    /// DANGEROUS: accept invalid/self-signed CouchDB certificates. Dev environments only!
+    #[arg(
+        long,
+        env = "COUCHDB_DANGER_ACCEPT_INVALID_CERTS",
+        default_value = "false"
+    )]
+    couchdb_danger_accept_invalid_certs: bool,
+
+    /// HTTP(S) proxy for reaching CouchDB. HTTPS_PROXY/HTTP_PROXY/NO_PROXY are respected if unset
+    #[arg(long, env = "COUCHDB_PROXY", conflicts_with = "couchdb_no_proxy")]
+    couchdb_proxy: Option<String>,
+
+    /// Connect to CouchDB directly, ignoring any proxy settings from the environment
+    #[arg(long, env = "COUCHDB_NO_PROXY", default_value = "false")]
+    couchdb_no_proxy: bool,
+
+    /// LiveSync end-to-end encryption passphrase, for vaults with encryption turned on
+    #[arg(long, env = "LIVESYNC_PASSPHRASE", hide_env_values = true)]
+    livesync_passphrase: Option<String>,
+
+    /// Maximum note size in bytes (0 = unlimited)
+    #[arg(long, env = "MAX_NOTE_SIZE", default_value = "1048576")]
+    max_note_size: usize,
+
+    /// Most chunk docs a single note may be split into (0 = unlimited). A guard against a
+    /// runaway write creating thousands of docs
+    #[arg(long, env = "MAX_CHUNKS_PER_NOTE", default_value = "40000")]
+    max_chunks_per_note: usize,
+
+    /// What the ids of chunks yamos writes start with. LiveSync only reads "h:" chunks, so
+    /// anything else leaves notes yamos saves unreadable in Obsidian - only change it if nothing
+    /// but yamos reads this database. Has to contain a ":", so no note's id can start with it
+    #[arg(long, env = "CHUNK_ID_PREFIX", default_value = "h:")]
+    chunk_id_prefix: String,
+
+    /// Create (or update) a _design/yamos view of just the notes and list notes with it, so
+    /// CouchDB skips the chunks instead of sending them all over. Needs a database admin
+    #[arg(long, env = "COUCHDB_NOTES_VIEW", default_value = "false")]
+    couchdb_notes_view: bool,
+
+    /// Create the CouchDB database on startup if it doesn't exist yet
+    #[arg(long, env = "CREATE_DATABASE", default_value = "false")]
+    create_database: bool,
+
+    /// OpenAI-compatible embeddings endpoint (e.g. http://localhost:11434/v1/embeddings) to
+    /// enable semantic search
+    #[cfg(feature = "embeddings")]
+    #[arg(long, env = "EMBEDDINGS_URL")]
+    embeddings_url: Option<String>,
+
+    /// Embedding model to ask the endpoint for
+    #[cfg(feature = "embeddings")]
+    #[arg(
+        long,
+        env = "EMBEDDINGS_MODEL",
+        default_value = "text-embedding-3-small"
+    )]
+    embeddings_model: String,
+
+    /// API key for the embeddings endpoint, sent as a bearer token
+    #[cfg(feature = "embeddings")]
+    #[arg(long, env = "EMBEDDINGS_API_KEY")]
+    embeddings_api_key: Option<String>,
+
+    /// Build the search index by replaying the entire changes feed from the start instead of
+    /// loading a snapshot. Slower, but goes through the same path as live updates (diagnostics)
+    #[arg(long, env = "WATCH_FROM_ZERO", default_value = "false")]
+    watch_from_zero: bool,
+
+    /// Keep every note's content in memory for content search and fast reads. Set to false on
+    /// small hosts with big vaults to index only titles, tags and metadata
+    #[arg(long, env = "INDEX_CONTENT", default_value = "true", action = clap::ArgAction::Set)]
+    index_content: bool,
+
+    /// How many results search_notes returns when the call doesn't say
+    #[arg(long, env = "SEARCH_DEFAULT_LIMIT", default_value = "20", value_parser = clap::value_parser!(u64).range(1..))]
+    search_default_limit: u64,
+
+    /// Whether search_notes looks in note content (not just titles) when the call doesn't say
+    #[arg(long, env = "SEARCH_DEFAULT_CONTENT", default_value = "true", action = clap::ArgAction::Set)]
+    search_default_content: bool,
+
+    /// Load every note into the search index before serving. Set to false (or pass
+    /// --no-startup-resync) to start right away and load it in the background instead
+    #[arg(
+        long,
+        env = "STARTUP_RESYNC",
+        default_value = "true",
+        action = clap::ArgAction::Set,
+        overrides_with = "no_startup_resync"
+    )]
+    startup_resync: bool,
+
+    /// Shorthand for --startup-resync=false
+    #[arg(long, overrides_with = "startup_resync")]
+    no_startup_resync: bool,
+
     /// Enable OAuth 2.0 authentication (disables legacy bearer token auth)
     #[arg(long, env = "OAUTH_ENABLED", default_value = "false")]
     oauth_enabled: bool,
@@ -65,6 +211,40 @@ struct Args {
     #[arg(long, env = "OAUTH_TOKEN_EXPIRATION", default_value = "3600")]
     oauth_token_expiration: u64,
 
+    /// Issuer (`iss` claim) for OAuth tokens; tokens from any other issuer are rejected
+    #[arg(long, env = "OAUTH_ISSUER", default_value = "yamos")]
+    oauth_issuer: String,
+
+    /// Token lifetime in seconds for the static OAuth client, overriding --oauth-token-expiration
+    #[arg(long, env = "OAUTH_CLIENT_TOKEN_EXPIRATION", value_parser = clap::value_parser!(u64).range(1..))]
+    oauth_client_token_expiration: Option<u64>,
+
+    /// Longest token lifetime in seconds a client may be given (0 = no limit)
+    #[arg(long, env = "OAUTH_MAX_TOKEN_LIFETIME", default_value = "86400")]
+    oauth_max_token_lifetime: u64,
+
+    /// Seconds of clock skew to tolerate when checking OAuth token expiry/not-before
+    #[arg(long, env = "OAUTH_CLOCK_SKEW_SECS", default_value = "60")]
+    oauth_clock_skew_secs: u64,
+
+    /// Allow machine clients to get tokens with just a client id and secret (client_credentials
+    /// grant), skipping the browser consent flow
+    #[arg(long, env = "OAUTH_ENABLE_CLIENT_CREDENTIALS", default_value = "false")]
+    oauth_enable_client_credentials: bool,
+
+    /// Bearer token for the /admin endpoints (storage stats, revoking an OAuth client), in any
+    /// auth mode; they're off without it
+    #[arg(long, env = "ADMIN_TOKEN")]
+    admin_token: Option<String>,
+
+    /// Most OAuth authorisations that can be in flight at once before the oldest get evicted
+    #[arg(long, env = "OAUTH_MAX_PENDING", default_value = "1000")]
+    oauth_max_pending: usize,
+
+    /// Seconds an OAuth authorisation (consent screen or unredeemed code) stays valid
+    #[arg(long, env = "OAUTH_AUTH_CODE_TTL_SECS", default_value = "600")]
+    oauth_auth_code_ttl_secs: u64,
+
     /// OAuth client ID
     #[arg(long, env = "OAUTH_CLIENT_ID")]
     oauth_client_id: Option<String>,
@@ -81,11 +261,37 @@ struct Args {
     #[arg(long, env = "MCP_AUTH_TOKEN")]
     auth_token: Option<String>,
 
+    /// Extra bearer tokens that can only use tools which don't change the vault
+    #[arg(long, env = "MCP_AUTH_TOKEN_RO", value_delimiter = ',')]
+    auth_token_ro: Vec<String>,
+
+    /// Extra bearer tokens with full access, same as --auth-token
+    #[arg(long, env = "MCP_AUTH_TOKEN_RW", value_delimiter = ',')]
+    auth_token_rw: Vec<String>,
+
+    /// Serve without any authentication. Without this, starting in SSE mode with neither OAuth
+    /// nor a bearer token set is an error, rather than quietly exposing the vault
+    #[arg(long, env = "ALLOW_NO_AUTH", default_value = "false")]
+    allow_no_auth: bool,
+
     /// Public base URL for OAuth metadata (e.g., https://your-domain.com)
     /// If not set, defaults to http://HOST:PORT
     #[arg(long, env = "PUBLIC_URL")]
     public_url: Option<String>,
 
+    /// Address to listen on as addr:port (SSE mode only). Repeat for several, e.g.
+    /// --listen 0.0.0.0:3000 --listen [::]:3000. Overrides --host/--port for binding
+    #[arg(long, env = "MCP_LISTEN", value_delimiter = ',')]
+    listen: Vec<String>,
+
+    /// Biggest MCP request body accepted over HTTP, in bytes (0 = unlimited). Bigger ones get a 413
+    #[arg(long, env = "MAX_REQUEST_BODY", default_value = "16777216")]
+    max_request_body: usize,
+
+    /// Gzip/brotli HTTP responses for clients that accept it. SSE streams are never compressed
+    #[arg(long, env = "COMPRESS_RESPONSES", default_value = "true", action = clap::ArgAction::Set)]
+    compress_responses: bool,
+
     /// Rate limit: requests per second per IP
     #[arg(long, env = "RATE_LIMIT_PER_SECOND", default_value = "10")]
     rate_limit_per_second: u64,
@@ -97,6 +303,103 @@ struct Args {
     /// Base path for all routes, for hosting at a subpath
     #[arg(long, env = "BASE_PATH", default_value = "")]
     base_path: String,
+
+    /// Append a JSONL audit record of every successful note change to this file
+    #[arg(long, env = "AUDIT_LOG")]
+    audit_log: Option<PathBuf>,
+
+    /// Maximum number of notes in a single batch tool call
+    #[arg(long, env = "MAX_BATCH_SIZE", default_value = "100")]
+    max_batch_size: usize,
+
+    /// Characters to allow in note paths that are rejected by default (any of \:*?"<>|)
+    #[arg(long, env = "PATH_ALLOW_CHARS", default_value = "")]
+    path_allow_chars: String,
+
+    /// Confine every tool to this vault folder: paths are relative to it and nothing outside
+    /// it can be listed, read or written (e.g. "Shared/")
+    #[arg(long, env = "VAULT_ROOT")]
+    vault_root: Option<String>,
+
+    /// Folder that archive_note moves notes into, relative to the vault root
+    #[arg(long, env = "ARCHIVE_FOLDER", default_value = "Archive/")]
+    archive_folder: String,
+
+    /// How append_to_log stamps entries unless the call says otherwise: a line like "## {{time}}"
+    /// or a per-entry one with {{content}}, like "- [{{time}}] {{content}}"
+    #[arg(long, env = "LOG_ENTRY_FORMAT", default_value = server::DEFAULT_LOG_FORMAT)]
+    log_entry_format: String,
+
+    /// Instructions sent to the model about how to use this server (replaces the default)
+    #[arg(long, env = "MCP_INSTRUCTIONS", conflicts_with = "instructions_file")]
+    instructions: Option<String>,
+
+    /// Read the instructions from this file instead
+    #[arg(long, env = "MCP_INSTRUCTIONS_FILE")]
+    instructions_file: Option<PathBuf>,
+
+    /// Server name advertised to MCP clients
+    #[arg(long, env = "MCP_SERVER_NAME")]
+    server_name: Option<String>,
+
+    /// Server version advertised to MCP clients
+    #[arg(long, env = "MCP_SERVER_VERSION")]
+    server_version: Option<String>,
+
+    /// Seconds before a tool call is abandoned with an error (0 = never). Keep it generous
+    /// enough for big batches
+    #[arg(long, env = "TOOL_TIMEOUT_SECS", default_value = "300")]
+    tool_timeout_secs: u64,
+
+    /// Log a warning for any tool call or CouchDB request slower than this many milliseconds
+    /// (0 = never)
+    #[arg(long, env = "SLOW_OP_THRESHOLD_MS", default_value = "0")]
+    slow_op_threshold_ms: u64,
+
+    /// How often (in milliseconds) CouchDB sends a heartbeat down the changes feed when nothing's
+    /// changing. Lower it if a proxy drops connections that sit idle
+    #[arg(long, env = "CHANGES_HEARTBEAT_MS", default_value = "30000", value_parser = clap::value_parser!(u64).range(1..))]
+    changes_heartbeat_ms: u64,
+
+    /// Seconds to wait before reconnecting a dropped changes feed
+    #[arg(long, env = "CHANGES_RECONNECT_BACKOFF", default_value = "5")]
+    changes_reconnect_backoff: u64,
+
+    /// Double the reconnect wait after each failure in a row, up to this many seconds. The
+    /// default keeps it flat
+    #[arg(long, env = "CHANGES_RECONNECT_BACKOFF_MAX")]
+    changes_reconnect_backoff_max: Option<u64>,
+
+    /// Every this many seconds, delete chunks that no note refers to any more, such as ones a
+    /// failed save left behind (0 = never). At least 60
+    #[arg(long, env = "CHUNK_GC_INTERVAL", default_value = "0")]
+    chunk_gc_interval: u64,
+
+    /// How many hours a chunk has to have looked orphaned before a sweep deletes it, so a save
+    /// in progress or a device syncing after a while offline isn't caught out. At least 1
+    #[arg(long, env = "CHUNK_GC_GRACE_HOURS", default_value = "24")]
+    chunk_gc_grace_hours: u64,
+
+    /// Expose debugging tools (debug_note_chunks) to MCP clients
+    #[arg(long, env = "DEBUG_TOOLS", default_value = "false")]
+    debug_tools: bool,
+
+    /// Only offer these tools (comma-separated names), e.g. read_note,search_notes
+    #[arg(long = "enable-tool", env = "ENABLE_TOOLS", value_delimiter = ',')]
+    enable_tools: Vec<String>,
+
+    /// Never offer these tools (comma-separated names), e.g. delete_folder
+    #[arg(long = "disable-tool", env = "DISABLE_TOOLS", value_delimiter = ',')]
+    disable_tools: Vec<String>,
+
+    /// When read_note gets a path that doesn't exist but clearly meant one that does, read that
+    /// instead. Without this the near misses are only suggested in the error
+    #[arg(long, env = "AUTO_RESOLVE_PATHS", default_value = "false")]
+    auto_resolve_paths: bool,
+
+    /// Log output format
+    #[arg(long, value_enum, env = "LOG_FORMAT", default_value = "pretty")]
+    log_format: LogFormat,
 }
 
 #[tokio::main]
@@ -107,70 +410,194 @@ async fn main() -> Result<()> {
     let args = Args::parse();
 
     // Initialise logging to stderr (so it doesn't interfere with stdio transport)
-    tracing_subscriber::registry()
-        .with(
-            tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "yamos=info".into()),
-        )
-        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
-        .init();
-
-    tracing::info!(
-        "Connecting to CouchDB at {}/{}",
-        args.couchdb_url,
-        args.couchdb_database
+    let registry = tracing_subscriber::registry().with(
+        tracing_subscriber::EnvFilter::try_from_default_env()
+            .unwrap_or_else(|_| "yamos=info".into()),
     );
+    // also forwarded to any MCP client that asks for them
+    let client_log = client_log::ClientLog::default();
+    let registry = registry.with(client_log.clone());
+    let fmt_layer = tracing_subscriber::fmt::layer().with_writer(std::io::stderr);
+    match args.log_format {
+        LogFormat::Pretty => registry.with(fmt_layer).init(),
+        LogFormat::Json => registry.with(fmt_layer.json()).init(),
+    }
 
-    // Create CouchDB client
-    let db = couchdb::CouchDbClient::new(
-        &args.couchdb_url,
-        &args.couchdb_database,
-        &args.couchdb_user,
-        &args.couchdb_password,
-    )?;
+    let (db, search_index) = open_vault(&args, &args.couchdb_database).await?;
+    let mut vaults = Vec::new();
+    for (name, database) in &args.vaults {
+        if *name == args.couchdb_database || vaults.iter().any(|(n, _, _)| n == name) {
+            anyhow::bail!("--vault {} is given more than once", name);
+        }
+        let (db, search_index) = open_vault(&args, database).await?;
+        tracing::info!("Vault {} is database {}", name, database);
+        vaults.push((name.clone(), db, search_index));
+    }
 
-    // Test connection
-    db.test_connection().await?;
-    tracing::info!("Successfully connected to CouchDB");
+    let chunk_sweeps = match args.chunk_gc_interval {
+        0 => None,
+        secs => {
+            let interval = std::time::Duration::from_secs(secs);
+            if interval < chunk_gc::MIN_INTERVAL {
+                anyhow::bail!(
+                    "--chunk-gc-interval must be at least {}s, every sweep lists all the chunks",
+                    chunk_gc::MIN_INTERVAL.as_secs()
+                );
+            }
+            if args.chunk_gc_grace_hours == 0 {
+                anyhow::bail!("--chunk-gc-grace-hours must be at least 1");
+            }
+            Some((
+                interval,
+                std::time::Duration::from_secs(args.chunk_gc_grace_hours * 3600),
+            ))
+        }
+    };
 
-    // Initialize search index
-    tracing::info!("Loading search index...");
-    let search_index = Arc::new(RwLock::new(SearchIndex::new()));
+    let backoff = search::Backoff {
+        initial: std::time::Duration::from_secs(args.changes_reconnect_backoff),
+        max: std::time::Duration::from_secs(
+            args.changes_reconnect_backoff_max
+                .unwrap_or(args.changes_reconnect_backoff),
+        ),
+    };
 
-    // Initial load of all notes
-    {
-        let (notes, last_seq) = db.get_all_notes_with_content().await?;
-        let mut index = search_index.write().await;
+    // Start a changes watcher per vault in background
+    let cancel_token = CancellationToken::new();
+    let mut watcher_handles = Vec::new();
+    let all_vaults = std::iter::once((&db, &search_index))
+        .chain(vaults.iter().map(|(_, db, index)| (db, index)));
+    for (db, search_index) in all_vaults {
+        if let Some((interval, grace)) = chunk_sweeps {
+            let sweeper = chunk_gc::ChunkSweeper::new(db.clone(), interval, grace);
+            let sweeper_cancel = cancel_token.clone();
+            tokio::spawn(async move { sweeper.run(sweeper_cancel).await });
+        }
+        let watcher = ChangesWatcher::new(db.clone(), search_index.clone()).with_backoff(backoff);
+        if !search_index.read().await.is_ready() {
+            let backfill = watcher.clone();
+            let backfill_cancel = cancel_token.clone();
+            tokio::spawn(async move { backfill.backfill(backfill_cancel).await });
+        }
+        let watcher_cancel = cancel_token.clone();
+        watcher_handles.push(tokio::spawn(watcher.supervise(watcher_cancel)));
+    }
 
-        for (path, content, mtime) in notes {
-            let title = extract_title(&path, &content);
-            index.upsert(
-                path.clone(),
-                NoteEntry {
-                    path,
-                    title,
-                    content,
-                    mtime,
-                },
-            );
+    #[cfg(feature = "embeddings")]
+    let embedder: Option<Arc<dyn search::Embedder>> = match &args.embeddings_url {
+        Some(_) if !args.index_content => {
+            anyhow::bail!("Semantic search needs note content indexed, drop --index-content=false")
+        }
+        Some(url) => {
+            tracing::info!("Semantic search enabled, embedding notes with {}", url);
+            let embedder: Arc<dyn search::Embedder> = Arc::new(search::HttpEmbedder::new(
+                url,
+                &args.embeddings_model,
+                args.embeddings_api_key.clone(),
+            )?);
+            let worker = search::EmbeddingsWorker::new(embedder.clone(), search_index.clone());
+            let worker_cancel = cancel_token.clone();
+            tokio::spawn(async move { worker.run(worker_cancel).await });
+            Some(embedder)
         }
+        None => None,
+    };
 
-        index.last_seq = last_seq;
-        tracing::info!("Search index loaded with {} notes", index.len());
-    }
+    let audit = match &args.audit_log {
+        Some(path) => {
+            tracing::info!("Audit logging note changes to {}", path.display());
+            Some(audit::AuditLog::open(path).await?)
+        }
+        None => None,
+    };
 
-    // Start changes watcher in background
-    let cancel_token = CancellationToken::new();
-    let watcher = ChangesWatcher::new(db.clone(), search_index.clone());
-    let watcher_cancel = cancel_token.clone();
-    let watcher_handle = tokio::spawn(async move {
-        if let Err(e) = watcher.run(watcher_cancel).await {
-            tracing::error!("Changes watcher error: {}", e);
+    let instructions = match &args.instructions_file {
+        Some(path) => Some(
+            tokio::fs::read_to_string(path)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?,
+        ),
+        None => args.instructions.clone(),
+    };
+
+    let vault_root = match &args.vault_root {
+        Some(root) => {
+            let folder = format!("{}/", root.trim().trim_end_matches('/'));
+            let root = server::normalize_folder_prefix(&folder, &args.path_allow_chars)
+                .map_err(|e| anyhow::anyhow!("Invalid --vault-root {:?}: {}", root, e.message))?;
+            tracing::info!("Tools are confined to {}", root);
+            Some(root)
         }
-    });
+        None => None,
+    };
+
+    let archive_folder = format!("{}/", args.archive_folder.trim().trim_end_matches('/'));
+    let archive_folder = server::normalize_folder_prefix(&archive_folder, &args.path_allow_chars)
+        .map_err(|e| {
+        anyhow::anyhow!(
+            "Invalid --archive-folder {:?}: {}",
+            args.archive_folder,
+            e.message
+        )
+    })?;
+
+    let tool_names = YamosServer::tool_names();
+    if let Some(unknown) = args
+        .enable_tools
+        .iter()
+        .chain(&args.disable_tools)
+        .find(|name| !tool_names.contains(name))
+    {
+        anyhow::bail!(
+            "--enable-tool/--disable-tool: there's no tool called {:?}. The tools are {}",
+            unknown,
+            tool_names.join(", ")
+        );
+    }
 
     // Create the MCP server
-    let server = YamosServer::new(db, search_index);
+    let server = YamosServer::new(
+        db,
+        search_index,
+        audit,
+        ServerConfig {
+            max_batch_size: args.max_batch_size,
+            debug_tools: args.debug_tools,
+            path_allow_chars: args.path_allow_chars.clone(),
+            instructions,
+            server_name: args.server_name.clone(),
+            server_version: args.server_version.clone(),
+            tool_timeout: match args.tool_timeout_secs {
+                0 => None,
+                secs => Some(std::time::Duration::from_secs(secs)),
+            },
+            slow_op_threshold: slow_op_threshold(&args),
+            vault_root,
+            archive_folder,
+            auto_resolve_paths: args.auto_resolve_paths,
+            enabled_tools: args.enable_tools.clone(),
+            disabled_tools: args.disable_tools.clone(),
+            log_entry_format: args.log_entry_format.clone(),
+            search_defaults: search::SearchOptions {
+                limit: args.search_default_limit as usize,
+                search_content: args.search_default_content,
+                ..Default::default()
+            },
+        },
+    );
+
+    let server = vaults
+        .into_iter()
+        .fold(server, |server, (name, db, search_index)| {
+            server.with_vault(name, db, search_index)
+        })
+        .with_client_log(client_log);
+
+    #[cfg(feature = "embeddings")]
+    let server = match embedder {
+        Some(embedder) => server.with_embedder(embedder),
+        None => server,
+    };
 
     match args.transport {
         TransportMode::Stdio => {
@@ -178,8 +605,13 @@ async fn main() -> Result<()> {
             let service = server.serve(rmcp::transport::stdio()).await?;
             service.waiting().await?;
         }
-        TransportMode::Sse => {
-            tracing::info!("Starting in SSE mode on {}:{}", args.host, args.port);
+        TransportMode::Sse | TransportMode::Ws => {
+            tracing::info!(
+                "Starting in {:?} mode on {}:{}",
+                args.transport,
+                args.host,
+                args.port
+            );
 
             let auth_mode = determine_auth_mode(&args)?;
 
@@ -196,57 +628,264 @@ async fn main() -> Result<()> {
                 format!("/{}", p)
             };
 
-            match auth_mode {
-                AuthMode::OAuth(config) => {
-                    tracing::info!("OAuth 2.0 authentication enabled");
-                    run_sse_server_with_oauth(
-                        server,
-                        &args.host,
-                        args.port,
-                        config,
-                        args.public_url.as_deref(),
-                        &rate_limit,
-                        &base_path,
-                        args.consent_pin.clone(),
-                    )
-                    .await?;
-                }
-                AuthMode::Legacy(token) => {
-                    tracing::info!(
-                        "Bearer token authentication enabled (consider migrating to OAuth)"
-                    );
-                    run_sse_server_legacy(
-                        server,
-                        &args.host,
-                        args.port,
-                        token,
-                        &rate_limit,
-                        &base_path,
-                    )
-                    .await?;
-                }
-                AuthMode::None => {
-                    tracing::warn!(
-                        "WARNING: No authentication enabled. Server is publicly accessible!"
-                    );
-                    run_sse_server_no_auth(server, &args.host, args.port, &rate_limit, &base_path)
-                        .await?;
-                }
-            }
+            run_sse_server(server, auth_mode, &args, &rate_limit, &base_path).await?;
         }
     }
 
     // Shutdown: cancel the changes watcher
     tracing::info!("Shutting down changes watcher...");
     cancel_token.cancel();
-    let _ = watcher_handle.await;
+    for handle in watcher_handles {
+        let _ = handle.await;
+    }
 
     Ok(())
 }
 
+/// Gives every HTTP request an x-request-id (keeping one the client sent), logs it in a span
+/// around the request, and echoes it back in the response
+fn with_request_tracing(router: axum::Router) -> axum::Router {
+    use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+    use tower_http::trace::TraceLayer;
+
+    router
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(
+            TraceLayer::new_for_http().make_span_with(|req: &axum::http::Request<_>| {
+                let request_id = req
+                    .headers()
+                    .get("x-request-id")
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("-");
+                tracing::info_span!(
+                    "http_request",
+                    method = %req.method(),
+                    uri = %req.uri(),
+                    request_id,
+                )
+            }),
+        )
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+}
+
+/// Serves the app on every --listen address, or just host:port if there aren't any. Each
+/// listener gets its own axum::serve, and we bail as soon as any of them stops
+async fn serve_app(app: axum::Router, bind_addr: &str, listen: &[String]) -> Result<()> {
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+
+    let mut listeners = Vec::new();
+    if listen.is_empty() {
+        listeners.push(TcpListener::bind(bind_addr).await?);
+    } else {
+        for addr in listen {
+            let resolved: Vec<SocketAddr> = tokio::net::lookup_host(addr).await?.collect();
+            if resolved.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "--listen {} didn't resolve to anything",
+                    addr
+                ));
+            }
+            for socket_addr in resolved {
+                listeners.push(bind_listener(socket_addr).map_err(|e| {
+                    anyhow::anyhow!("Failed to bind {} ({}): {}", addr, socket_addr, e)
+                })?);
+            }
+        }
+    }
+
+    let mut servers = tokio::task::JoinSet::new();
+    for listener in listeners {
+        tracing::info!("MCP server listening on {}", listener.local_addr()?);
+        // into_make_service_with_connect_info gives us the peer ip for rate limiting fallback
+        // (SmartIpKeyExtractor checks headers first, but falls back to this if no proxy headers)
+        let service = app
+            .clone()
+            .into_make_service_with_connect_info::<SocketAddr>();
+        servers.spawn(async move { axum::serve(listener, service).await });
+    }
+
+    while let Some(result) = servers.join_next().await {
+        result??;
+    }
+
+    Ok(())
+}
+
+/// Binds a listener for one --listen address. IPv6 sockets are v6-only, otherwise [::]:3000
+/// would also grab 0.0.0.0:3000 and clash with an explicit IPv4 listener
+fn bind_listener(addr: std::net::SocketAddr) -> Result<tokio::net::TcpListener> {
+    use socket2::{Domain, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(tokio::net::TcpListener::from_std(socket.into())?)
+}
+
+/// --slow-op-threshold-ms as a duration, None if it's off
+fn slow_op_threshold(args: &Args) -> Option<std::time::Duration> {
+    (args.slow_op_threshold_ms > 0)
+        .then(|| std::time::Duration::from_millis(args.slow_op_threshold_ms))
+}
+
+/// Parses a --vault name=database
+fn parse_vault(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, database)) if !name.trim().is_empty() && !database.trim().is_empty() => {
+            Ok((name.trim().to_string(), database.trim().to_string()))
+        }
+        _ => Err(format!("expected name=database, got {:?}", s)),
+    }
+}
+
+/// Connects to one LiveSync database, checks it's one yamos can work with, and loads its
+/// search index
+async fn open_vault(
+    args: &Args,
+    database: &str,
+) -> Result<(couchdb::CouchDbClient, Arc<RwLock<SearchIndex>>)> {
+    tracing::info!("Connecting to CouchDB at {}/{}", args.couchdb_url, database);
+
+    // Create CouchDB client
+    let db = couchdb::CouchDbClient::new(couchdb::CouchDbConfig {
+        url: args.couchdb_url.clone(),
+        database: database.to_string(),
+        username: args.couchdb_user.clone(),
+        password: args.couchdb_password.clone(),
+        auth_mode: args.couchdb_auth,
+        ca_cert: args.couchdb_ca_cert.clone(),
+        client_cert: args.couchdb_client_cert.clone(),
+        client_key: args.couchdb_client_key.clone(),
+        danger_accept_invalid_certs: args.couchdb_danger_accept_invalid_certs,
+        proxy: args.couchdb_proxy.clone(),
+        no_proxy: args.couchdb_no_proxy,
+        max_note_size: (args.max_note_size > 0).then_some(args.max_note_size),
+        max_chunks_per_note: (args.max_chunks_per_note > 0).then_some(args.max_chunks_per_note),
+        passphrase: args.livesync_passphrase.clone(),
+        slow_request_threshold: slow_op_threshold(args),
+        path_style: args.obsidian_path_style,
+        changes_heartbeat: Some(std::time::Duration::from_millis(args.changes_heartbeat_ms)),
+        chunk_id_prefix: Some(args.chunk_id_prefix.clone()),
+    })?;
+
+    if args.couchdb_auth == couchdb::CouchAuthMode::Session {
+        db.start_session().await?;
+        tracing::info!("Authenticated to CouchDB with a session cookie");
+    }
+
+    if args.create_database {
+        if db.create_database_if_missing().await? {
+            tracing::info!("Created CouchDB database {}", database);
+        } else {
+            tracing::info!("Found existing CouchDB database {}", database);
+        }
+    }
+
+    // Test connection
+    db.test_connection().await?;
+    tracing::info!("Successfully connected to CouchDB database {}", database);
+
+    if args.couchdb_notes_view {
+        match db.ensure_notes_view().await {
+            Ok(()) => tracing::info!("Listing notes in {} with the _design/yamos view", database),
+            Err(e) => tracing::warn!(
+                "Couldn't set up the _design/yamos view in {} (that needs a database admin), \
+                 listing notes with _all_docs instead: {}",
+                database,
+                e
+            ),
+        }
+    }
+
+    match db.sample_doc_format().await {
+        Ok(sample) if sample.looks_wrong() => tracing::warn!(
+            "WARNING: none of the first {} docs in database {} look like Obsidian LiveSync notes \
+             (e.g. {}). Is COUCHDB_DATABASE pointing at your LiveSync database? Expect \
+             \"note not found\" and decode errors if not",
+            sample.sampled,
+            database,
+            sample.others.join(", ")
+        ),
+        Ok(sample) => tracing::debug!(
+            "{} of {} sampled docs look like LiveSync",
+            sample.livesync,
+            sample.sampled
+        ),
+        Err(e) => tracing::warn!("Couldn't check the database looks like LiveSync: {}", e),
+    }
+
+    match (db.detect_encryption().await, &args.livesync_passphrase) {
+        (Ok(Some(couchdb::EncryptionSign::ObfuscatedPaths)), _) => anyhow::bail!(
+            "This vault uses LiveSync's path obfuscation, which yamos does not support yet - \
+             turn it off in LiveSync and rebuild the remote database to use yamos with it"
+        ),
+        (Ok(Some(sign)), None) => anyhow::bail!(
+            "This vault uses LiveSync's end-to-end encryption ({}), so every note would read \
+             back as garbage. Pass the vault's passphrase with --livesync-passphrase",
+            sign
+        ),
+        (Ok(Some(_)), Some(_)) => {
+            db.check_passphrase().await.map_err(|e| {
+                anyhow::anyhow!(
+                    "Couldn't decrypt the vault with --livesync-passphrase: {}",
+                    e
+                )
+            })?;
+            tracing::info!("Vault is end-to-end encrypted, passphrase checks out");
+        }
+        (Ok(None), Some(_)) => anyhow::bail!(
+            "--livesync-passphrase is set but this vault isn't end-to-end encrypted. Writing \
+             encrypted chunks into it would leave them unreadable to your other devices"
+        ),
+        (Ok(None), None) => {}
+        (Err(e), _) => tracing::warn!("Couldn't check whether the vault is encrypted: {}", e),
+    }
+
+    // Initialize search index
+    tracing::info!("Loading search index for {}...", database);
+    let search_index = if args.index_content {
+        SearchIndex::new()
+    } else {
+        SearchIndex::without_content()
+    };
+    let search_index = Arc::new(RwLock::new(search_index.with_path_style(db.path_style())));
+
+    if args.watch_from_zero {
+        // no snapshot: the watcher builds the index up from the very first change instead
+        tracing::info!("Replaying the whole changes feed from seq 0 to build the search index");
+        search_index.write().await.last_seq = Some("0".to_string());
+    } else if args.no_startup_resync || !args.startup_resync {
+        // watch from here on, and let ChangesWatcher::backfill load the rest behind it
+        tracing::info!("Skipping the startup resync, the search index will load in the background");
+        let mut index = search_index.write().await;
+        index.last_seq = db.update_seq().await?;
+        index.start_loading();
+    } else {
+        // Initial load of all notes
+        let (mut loader, last_seq) = db.load_notes().await?;
+        let mut index = search_index.write().await;
+        while let Some(batch) = loader.next_batch().await? {
+            for note in note_entries(batch) {
+                index.upsert(note.path.clone(), note);
+            }
+        }
+
+        index.last_seq = last_seq;
+        tracing::info!("Search index loaded with {} notes", index.len());
+    }
+
+    Ok((db, search_index))
+}
+
 enum AuthMode {
     OAuth(auth::AuthConfig),
-    Legacy(String),
+    Legacy(Vec<(String, auth::LegacyAccess)>),
     None,
 }
 
@@ -272,6 +911,17 @@ fn determine_auth_mode(args: &Args) -> Result<AuthMode> {
             .as_ref()
             .ok_or_else(|| anyhow::anyhow!("OAUTH_CLIENT_SECRET required when OAuth is enabled"))?;
 
+        if let Some(lifetime) = args.oauth_client_token_expiration
+            && args.oauth_max_token_lifetime != 0
+            && lifetime > args.oauth_max_token_lifetime
+        {
+            anyhow::bail!(
+                "OAUTH_CLIENT_TOKEN_EXPIRATION ({}s) exceeds OAUTH_MAX_TOKEN_LIFETIME ({}s)",
+                lifetime,
+                args.oauth_max_token_lifetime
+            );
+        }
+
         Ok(AuthMode::OAuth(auth::AuthConfig {
             jwt_secret: jwt_secret.clone(),
             client_id: client_id.clone(),
@@ -281,62 +931,126 @@ fn determine_auth_mode(args: &Args) -> Result<AuthMode> {
             } else {
                 Some(std::time::Duration::from_secs(args.oauth_token_expiration))
             },
+            client_token_expiration: args
+                .oauth_client_token_expiration
+                .map(std::time::Duration::from_secs),
+            max_token_lifetime: match args.oauth_max_token_lifetime {
+                0 => None,
+                secs => Some(std::time::Duration::from_secs(secs)),
+            },
+            issuer: args.oauth_issuer.clone(),
+            clock_skew: std::time::Duration::from_secs(args.oauth_clock_skew_secs),
+            client_credentials_enabled: args.oauth_enable_client_credentials,
+            max_pending_authorisations: args.oauth_max_pending,
+            auth_code_ttl: std::time::Duration::from_secs(args.oauth_auth_code_ttl_secs),
         }))
-    } else if let Some(token) = &args.auth_token {
-        Ok(AuthMode::Legacy(token.clone()))
-    } else {
+    } else if args.auth_token.is_some()
+        || !args.auth_token_rw.is_empty()
+        || !args.auth_token_ro.is_empty()
+    {
+        let full = args.auth_token.iter().chain(&args.auth_token_rw);
+        let mut tokens: Vec<_> = full
+            .map(|t| (t.clone(), auth::LegacyAccess::ReadWrite))
+            .collect();
+        for token in &args.auth_token_ro {
+            if tokens.iter().any(|(t, _)| t == token) {
+                anyhow::bail!("The same bearer token can't be both read-only and read-write");
+            }
+            tokens.push((token.clone(), auth::LegacyAccess::ReadOnly));
+        }
+        if tokens.iter().any(|(t, _)| t.is_empty()) {
+            anyhow::bail!("Bearer tokens can't be empty");
+        }
+        Ok(AuthMode::Legacy(tokens))
+    } else if args.allow_no_auth {
         Ok(AuthMode::None)
+    } else {
+        anyhow::bail!(
+            "No authentication configured, so anyone who can reach the server could read and \
+             write your vault. Set OAUTH_ENABLED=true with OAUTH_JWT_SECRET, OAUTH_CLIENT_ID and \
+             OAUTH_CLIENT_SECRET (recommended), or MCP_AUTH_TOKEN for a static bearer token. \
+             If you really want no auth (e.g. it's only reachable from localhost), pass \
+             --allow-no-auth"
+        )
     }
 }
 
-async fn run_sse_server_with_oauth(
+/// Serves MCP (and the vault transfer endpoints) over streamable HTTP. Everything but the auth
+/// is the same whichever AuthMode is in play, so the mode just decides which middleware guards
+/// the protected routes and whether the OAuth endpoints exist
+async fn run_sse_server(
     server: YamosServer,
-    host: &str,
-    port: u16,
-    config: auth::AuthConfig,
-    public_url: Option<&str>,
+    auth_mode: AuthMode,
+    args: &Args,
     rate_limit: &RateLimitConfig,
     base_path: &str,
-    consent_pin: Option<String>,
 ) -> Result<()> {
-    use axum::{
-        Router, middleware,
-        routing::{get, post},
-    };
+    use axum::{Router, middleware};
     use rmcp::transport::streamable_http_server::session::local::LocalSessionManager;
     use rmcp::transport::streamable_http_server::tower::{
         StreamableHttpServerConfig, StreamableHttpService,
     };
-    use std::net::SocketAddr;
     use tower_governor::{
         GovernorLayer, governor::GovernorConfigBuilder, key_extractor::SmartIpKeyExtractor,
     };
+    use tower_http::compression::CompressionLayer;
     use tower_http::cors::{Any, CorsLayer};
+    use tower_http::limit::RequestBodyLimitLayer;
 
-    let bind_addr = format!("{}:{}", host, port);
+    let bind_addr = format!("{}:{}", args.host, args.port);
 
     // base_url includes the base_path for OAuth metadata URLs
-    let base_url = public_url
+    let base_url = args
+        .public_url
+        .as_deref()
         .map(|url| format!("{}{}", url.trim_end_matches('/'), base_path))
-        .unwrap_or_else(|| format!("http://{}:{}{}", host, port, base_path));
+        .unwrap_or_else(|| format!("http://{}:{}{}", args.host, args.port, base_path));
 
-    tracing::info!("MCP server listening on {}", bind_addr);
-    if let Some(public) = public_url {
+    if let Some(public) = &args.public_url {
         tracing::info!("Public URL: {}", public);
     }
     tracing::info!("MCP endpoint: {}/", base_url);
+
+    // Rate limiting - configurable via RATE_LIMIT_PER_SECOND and RATE_LIMIT_BURST
+    // SmartIpKeyExtractor checks x-forwarded-for and friends before falling back to peer ip,
+    // so this works both behind cloudflare/nginx/whatever and when running locally
     tracing::info!(
-        "Protected resource metadata: {}/.well-known/oauth-protected-resource",
-        base_url
+        "Rate limiting: {} req/sec, burst size {}",
+        rate_limit.per_second,
+        rate_limit.burst
     );
-    tracing::info!(
-        "Authorization server metadata: {}/.well-known/oauth-authorization-server",
-        base_url
+    let governor_conf = Arc::new(
+        GovernorConfigBuilder::default()
+            .key_extractor(SmartIpKeyExtractor)
+            .per_second(rate_limit.per_second)
+            .burst_size(rate_limit.burst)
+            .finish()
+            .expect("Failed to build rate limiter config"),
     );
-    tracing::info!("Token endpoint: {}/token", base_url);
-    tracing::info!("Registration endpoint: {}/register", base_url);
+    let governor_limiter = governor_conf.limiter().clone();
+    let rate_limit_layer = GovernorLayer::new(governor_conf);
+
+    // Start background task to clean up rate limiter state
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            governor_limiter.retain_recent();
+        }
+    });
 
     let session_manager = Arc::new(LocalSessionManager::default());
+    tracing::info!("Search endpoint: {}/search?q=", base_url);
+    let mut extra_routes =
+        transfer::routes(server.clone()).merge(search_api::routes(server.clone()));
+    if matches!(args.transport, TransportMode::Ws) {
+        tracing::info!(
+            "WebSocket endpoint: {}/ws (subprotocol \"{}\")",
+            base_url.replacen("http", "ws", 1),
+            websocket::SUBPROTOCOL
+        );
+        extra_routes = extra_routes.merge(websocket::routes(server.clone()));
+    }
+    let mut admin_routes = admin::routes(server.databases());
 
     let http_service = StreamableHttpService::new(
         move || Ok(server.clone()),
@@ -344,38 +1058,137 @@ async fn run_sse_server_with_oauth(
         StreamableHttpServerConfig::default(),
     );
 
-    let auth_store = Arc::new(auth::AuthorizationStore::new());
+    // Mount at both "/" and "/sse" for compatibility with different MCP clients
+    // (some clients like poke.com expect /sse, others use root)
+    let mut mcp_routes = Router::new()
+        .route_service("/", http_service.clone())
+        .route_service("/sse", http_service);
+    // rmcp buffers the whole body before parsing it. /import has its own, bigger limit
+    if args.max_request_body > 0 {
+        mcp_routes = mcp_routes.layer(RequestBodyLimitLayer::new(args.max_request_body));
+    }
+    // the plain HTTP endpoints (search, export/import, websocket) go in with MCP, so they sit
+    // behind the same auth and rate limiting
+    let mcp_routes = mcp_routes.merge(extra_routes);
+
+    let oauth = matches!(auth_mode, AuthMode::OAuth(_));
+    let (protected_routes, public_routes) = match auth_mode {
+        AuthMode::OAuth(config) => {
+            tracing::info!("OAuth 2.0 authentication enabled");
+            let (auth_config, oauth_routes, oauth_admin_routes) =
+                oauth_routes(config, &base_url, args.consent_pin.clone(), rate_limit);
+            admin_routes = admin_routes.merge(oauth_admin_routes);
+            let protected = mcp_routes.layer(middleware::from_fn_with_state(
+                auth_config,
+                auth::jwt_auth_middleware,
+            ));
+            (protected, oauth_routes)
+        }
+        AuthMode::Legacy(tokens) => {
+            tracing::info!("Bearer token authentication enabled (consider migrating to OAuth)");
+            let tokens = Arc::new(tokens);
+            let protected = mcp_routes.layer(middleware::from_fn(move |req, next| {
+                auth::legacy_auth_middleware(req, next, tokens.clone())
+            }));
+            (protected, Router::new())
+        }
+        AuthMode::None => {
+            tracing::warn!("WARNING: No authentication enabled. Server is publicly accessible!");
+            (mcp_routes, Router::new())
+        }
+    };
+
+    // operator-only endpoints, behind their own static token whatever clients authenticate with
+    let admin_routes = match &args.admin_token {
+        Some(token) => {
+            tracing::info!("Admin endpoints enabled under {}/admin", base_url);
+            let token = Arc::new(vec![(token.clone(), auth::LegacyAccess::ReadWrite)]);
+            admin_routes
+                .layer(middleware::from_fn(move |req, next| {
+                    auth::legacy_auth_middleware(req, next, token.clone())
+                }))
+                .layer(rate_limit_layer.clone())
+        }
+        None => Router::new(),
+    };
+
+    let mut all_routes = public_routes
+        .merge(protected_routes.layer(rate_limit_layer))
+        .merge(admin_routes);
+    // the default predicate already leaves text/event-stream alone, so MCP's SSE responses still
+    // reach the client event by event instead of sitting in a compressor's buffer
+    if args.compress_responses {
+        all_routes = all_routes.layer(CompressionLayer::new());
+    }
+
+    // nest under base_path if set
+    let mut app = if base_path.is_empty() {
+        all_routes
+    } else {
+        Router::new().nest(base_path, all_routes)
+    };
+    // CORS layer - permissive for MCP clients like poke.com. Only with OAuth, a static bearer
+    // token or no auth at all shouldn't be usable from any page a browser happens to open
+    if oauth {
+        app = app.layer(
+            CorsLayer::new()
+                .allow_origin(Any)
+                .allow_methods(Any)
+                .allow_headers(Any)
+                .expose_headers(Any),
+        );
+    }
+    let app = with_request_tracing(app);
+
+    tracing::info!("Server ready at {}", base_url);
+    serve_app(app, &bind_addr, &args.listen).await
+}
+
+/// The public OAuth endpoints, the config for the JWT middleware that guards everything else,
+/// and OAuth's admin endpoints (for the caller to put behind the admin token)
+fn oauth_routes(
+    config: auth::AuthConfig,
+    base_url: &str,
+    consent_pin: Option<String>,
+    rate_limit: &RateLimitConfig,
+) -> (auth::AuthMiddlewareConfig, axum::Router, axum::Router) {
+    use axum::{
+        Router,
+        routing::{get, post},
+    };
+    use tower_governor::{
+        GovernorLayer, governor::GovernorConfigBuilder, key_extractor::SmartIpKeyExtractor,
+    };
+
+    tracing::info!(
+        "Protected resource metadata: {}/.well-known/oauth-protected-resource",
+        base_url
+    );
+    tracing::info!(
+        "Authorization server metadata: {}/.well-known/oauth-authorization-server",
+        base_url
+    );
+    tracing::info!("Token endpoint: {}/token", base_url);
+    tracing::info!("Registration endpoint: {}/register", base_url);
+
+    let auth_store = Arc::new(auth::AuthorizationStore::new(
+        config.max_pending_authorisations,
+        config.auth_code_ttl,
+    ));
     let client_registry = Arc::new(auth::ClientRegistry::new());
+    let client_credentials_enabled = config.client_credentials_enabled;
     let oauth_service = Arc::new(auth::OAuthService::new(config, client_registry.clone()));
 
     // Combined OAuth state for all handlers
     let oauth_state = auth::OAuthAppState {
         oauth_service: oauth_service.clone(),
-        auth_store: auth_store.clone(),
-        client_registry: client_registry.clone(),
-        base_url: base_url.clone(),
+        auth_store,
+        client_registry,
+        base_url: base_url.to_string(),
         consent_pin,
+        client_credentials_enabled,
     };
 
-    // Rate limiting - configurable via RATE_LIMIT_PER_SECOND and RATE_LIMIT_BURST
-    // SmartIpKeyExtractor checks x-forwarded-for and friends before falling back to peer ip,
-    // so this works both behind cloudflare/nginx/whatever and when running locally
-    tracing::info!(
-        "Rate limiting: {} req/sec, burst size {}",
-        rate_limit.per_second,
-        rate_limit.burst
-    );
-    let governor_conf = Arc::new(
-        GovernorConfigBuilder::default()
-            .key_extractor(SmartIpKeyExtractor)
-            .per_second(rate_limit.per_second)
-            .burst_size(rate_limit.burst)
-            .finish()
-            .expect("Failed to build rate limiter config"),
-    );
-    let governor_limiter = governor_conf.limiter().clone();
-    let rate_limit_layer = GovernorLayer::new(governor_conf);
-
     // Stricter rate limiting for auth endpoints: half the normal rate
     let auth_governor_conf = Arc::new(
         GovernorConfigBuilder::default()
@@ -392,7 +1205,7 @@ async fn run_sse_server_with_oauth(
     let rate_limited_auth_routes = Router::new()
         .route("/token", post(auth::oauth_token_handler))
         .route("/register", post(auth::register_handler))
-        .layer(auth_rate_limit_layer)
+        .layer(auth_rate_limit_layer.clone())
         .with_state(oauth_state.clone());
 
     // Standard rate limiting for other OAuth endpoints
@@ -414,205 +1227,21 @@ async fn run_sse_server_with_oauth(
             "/authorize/callback",
             post(auth::authorize_approval_handler),
         )
-        .with_state(oauth_state);
+        .with_state(oauth_state.clone());
 
-    // Start background task to clean up rate limiter state
-    tokio::spawn({
-        let limiter = governor_limiter;
-        async move {
-            loop {
-                tokio::time::sleep(std::time::Duration::from_secs(60)).await;
-                limiter.retain_recent();
-            }
-        }
-    });
+    let admin_routes = Router::new()
+        .route("/admin/revoke-client", post(auth::revoke_client_handler))
+        .layer(auth_rate_limit_layer)
+        .with_state(oauth_state);
 
     let auth_config = auth::AuthMiddlewareConfig {
-        oauth_service: oauth_service.clone(),
-        base_url: base_url.clone(),
-    };
-
-    // protected routes - jwt required, with rate limiting
-    // Mount at both "/" and "/sse" for compatibility with different MCP clients
-    // (some clients like poke.com expect /sse, others use root)
-    let protected_routes = Router::new()
-        .route_service("/", http_service.clone())
-        .route_service("/sse", http_service)
-        .layer(middleware::from_fn_with_state(
-            auth_config,
-            auth::jwt_auth_middleware,
-        ))
-        .layer(rate_limit_layer);
-
-    let all_routes = oauth_routes
-        .merge(rate_limited_auth_routes)
-        .merge(protected_routes);
-
-    // CORS layer - permissive for MCP clients like poke.com
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any)
-        .expose_headers(Any);
-
-    // nest under base_path if set
-    let app = if base_path.is_empty() {
-        all_routes.layer(cors)
-    } else {
-        Router::new().nest(base_path, all_routes).layer(cors)
-    };
-
-    let listener = tokio::net::TcpListener::bind(&bind_addr).await?;
-    tracing::info!("Server ready at {}", base_url);
-
-    // into_make_service_with_connect_info gives us the peer ip for rate limiting fallback
-    // (SmartIpKeyExtractor checks headers first, but falls back to this if no proxy headers)
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await?;
-
-    Ok(())
-}
-
-async fn run_sse_server_legacy(
-    server: YamosServer,
-    host: &str,
-    port: u16,
-    token: String,
-    rate_limit: &RateLimitConfig,
-    base_path: &str,
-) -> Result<()> {
-    use axum::{Router, middleware};
-    use rmcp::transport::streamable_http_server::session::local::LocalSessionManager;
-    use rmcp::transport::streamable_http_server::tower::{
-        StreamableHttpServerConfig, StreamableHttpService,
+        oauth_service,
+        base_url: base_url.to_string(),
     };
-    use std::net::SocketAddr;
-    use tower_governor::{
-        GovernorLayer, governor::GovernorConfigBuilder, key_extractor::SmartIpKeyExtractor,
-    };
-
-    let bind_addr = format!("{}:{}", host, port);
-    let base_url = format!("http://{}:{}{}", host, port, base_path);
-
-    tracing::info!("MCP server listening on {}", bind_addr);
-    tracing::info!(
-        "Rate limiting: {} req/sec, burst size {}",
-        rate_limit.per_second,
-        rate_limit.burst
-    );
-
-    let governor_conf = Arc::new(
-        GovernorConfigBuilder::default()
-            .key_extractor(SmartIpKeyExtractor)
-            .per_second(rate_limit.per_second)
-            .burst_size(rate_limit.burst)
-            .finish()
-            .expect("Failed to build rate limiter config"),
-    );
-    let rate_limit_layer = GovernorLayer::new(governor_conf);
-
-    let session_manager = Arc::new(LocalSessionManager::default());
-
-    let http_service = StreamableHttpService::new(
-        move || Ok(server.clone()),
-        session_manager,
-        StreamableHttpServerConfig::default(),
-    );
-
-    let token_arc = Arc::new(token);
-    let routes = Router::new()
-        .route_service("/", http_service.clone())
-        .route_service("/sse", http_service)
-        .layer(middleware::from_fn(move |req, next| {
-            auth::legacy_auth_middleware(req, next, token_arc.clone())
-        }))
-        .layer(rate_limit_layer);
-
-    let app = if base_path.is_empty() {
-        routes
-    } else {
-        Router::new().nest(base_path, routes)
-    };
-
-    let listener = tokio::net::TcpListener::bind(&bind_addr).await?;
-    tracing::info!("Server ready at {}", base_url);
-
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
-    )
-    .await?;
-
-    Ok(())
-}
-
-async fn run_sse_server_no_auth(
-    server: YamosServer,
-    host: &str,
-    port: u16,
-    rate_limit: &RateLimitConfig,
-    base_path: &str,
-) -> Result<()> {
-    use axum::Router;
-    use rmcp::transport::streamable_http_server::session::local::LocalSessionManager;
-    use rmcp::transport::streamable_http_server::tower::{
-        StreamableHttpServerConfig, StreamableHttpService,
-    };
-    use std::net::SocketAddr;
-    use tower_governor::{
-        GovernorLayer, governor::GovernorConfigBuilder, key_extractor::SmartIpKeyExtractor,
-    };
-
-    let bind_addr = format!("{}:{}", host, port);
-    let base_url = format!("http://{}:{}{}", host, port, base_path);
 
-    tracing::info!("MCP server listening on {}", bind_addr);
-    tracing::info!(
-        "Rate limiting: {} req/sec, burst size {}",
-        rate_limit.per_second,
-        rate_limit.burst
-    );
-
-    let governor_conf = Arc::new(
-        GovernorConfigBuilder::default()
-            .key_extractor(SmartIpKeyExtractor)
-            .per_second(rate_limit.per_second)
-            .burst_size(rate_limit.burst)
-            .finish()
-            .expect("Failed to build rate limiter config"),
-    );
-    let rate_limit_layer = GovernorLayer::new(governor_conf);
-
-    let session_manager = Arc::new(LocalSessionManager::default());
-
-    let http_service = StreamableHttpService::new(
-        move || Ok(server.clone()),
-        session_manager,
-        StreamableHttpServerConfig::default(),
-    );
-
-    let routes = Router::new()
-        .route_service("/", http_service.clone())
-        .route_service("/sse", http_service)
-        .layer(rate_limit_layer);
-
-    let app = if base_path.is_empty() {
-        routes
-    } else {
-        Router::new().nest(base_path, routes)
-    };
-
-    let listener = tokio::net::TcpListener::bind(&bind_addr).await?;
-    tracing::info!("Server ready at {}", base_url);
-
-    axum::serve(
-        listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
+    (
+        auth_config,
+        oauth_routes.merge(rate_limited_auth_routes),
+        admin_routes,
     )
-    .await?;
-
-    Ok(())
 }
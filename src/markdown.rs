@@ -0,0 +1,851 @@
+//! Just enough markdown structure for the heading-aware tools: where the headings are, and which
+//! part of a note belongs to each one. Fenced code blocks are skipped so a `# comment` in a
+//! shell snippet isn't mistaken for a heading
+
+use anyhow::{Result, anyhow, bail};
+use serde::Serialize;
+
+/// An ATX heading (`## Like this`) somewhere in a note
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heading {
+    pub level: usize,
+    pub text: String,
+    /// 1-based line number
+    pub line: usize,
+    /// Byte range of the whole heading line, including its newline
+    pub start: usize,
+    pub end: usize,
+}
+
+/// The lines of a note that are actual markdown text, i.e. not frontmatter or inside a fenced
+/// code block, as (0-based line number, byte offset, line including its newline)
+fn prose_lines(content: &str) -> Vec<(usize, usize, &str)> {
+    let mut lines = Vec::new();
+    let mut fence: Option<(char, usize)> = None;
+    let mut offset = 0;
+    let frontmatter_end =
+        crate::frontmatter::split(content).map_or(0, |(_, body)| content.len() - body.len());
+
+    for (number, line) in content.split_inclusive('\n').enumerate() {
+        let start = offset;
+        offset += line.len();
+        if start < frontmatter_end {
+            continue;
+        }
+        let text = line.trim_end_matches(['\n', '\r']);
+
+        if let Some(marker) = fence_marker(text) {
+            match fence {
+                None => fence = Some(marker),
+                // a closing fence is at least as long as the opening one and has no info string
+                Some((c, len)) if marker.0 == c && marker.1 >= len && is_bare_fence(text) => {
+                    fence = None
+                }
+                Some(_) => {}
+            }
+            continue;
+        }
+        if fence.is_none() {
+            lines.push((number, start, line));
+        }
+    }
+
+    lines
+}
+
+/// Every heading in a note, in order. Frontmatter and fenced code blocks are skipped
+pub fn headings(content: &str) -> Vec<Heading> {
+    prose_lines(content)
+        .into_iter()
+        .filter_map(|(number, start, line)| {
+            let (level, text) = parse_heading(line.trim_end_matches(['\n', '\r']))?;
+            Some(Heading {
+                level,
+                text,
+                line: number + 1,
+                start,
+                end: start + line.len(),
+            })
+        })
+        .collect()
+}
+
+/// A heading in a note's outline, with the headings under it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OutlineEntry {
+    pub level: usize,
+    pub text: String,
+    pub line: usize,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<OutlineEntry>,
+}
+
+/// A note's headings as a tree: each one holds the deeper headings that follow it, up to the
+/// next one at its level or above. A skipped level (# then ###) still nests
+pub fn outline(content: &str) -> Vec<OutlineEntry> {
+    // the path of open headings, each waiting for its children to be finished
+    let mut open: Vec<OutlineEntry> = Vec::new();
+    let mut top = Vec::new();
+
+    let close = |open: &mut Vec<OutlineEntry>, top: &mut Vec<OutlineEntry>| {
+        let done = open.pop().expect("only called with an open heading");
+        match open.last_mut() {
+            Some(parent) => parent.children.push(done),
+            None => top.push(done),
+        }
+    };
+
+    for heading in headings(content) {
+        while open.last().is_some_and(|h| h.level >= heading.level) {
+            close(&mut open, &mut top);
+        }
+        open.push(OutlineEntry {
+            level: heading.level,
+            text: heading.text,
+            line: heading.line,
+            children: Vec::new(),
+        });
+    }
+    while !open.is_empty() {
+        close(&mut open, &mut top);
+    }
+    top
+}
+
+/// ``` or ~~~ (three or more) at the start of a line, indented by at most three spaces
+fn fence_marker(line: &str) -> Option<(char, usize)> {
+    let trimmed = line.trim_start_matches(' ');
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+    let c = trimmed.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = trimmed.chars().take_while(|x| *x == c).count();
+    (len >= 3).then_some((c, len))
+}
+
+fn is_bare_fence(line: &str) -> bool {
+    line.trim().chars().all(|c| c == '`' || c == '~')
+}
+
+/// `## Heading text ##` -> (2, "Heading text")
+fn parse_heading(line: &str) -> Option<(usize, String)> {
+    let trimmed = line.trim_start_matches(' ');
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+    let level = trimmed.chars().take_while(|c| *c == '#').count();
+    if !(1..=6).contains(&level) {
+        return None;
+    }
+    let rest = &trimmed[level..];
+    if !rest.is_empty() && !rest.starts_with([' ', '\t']) {
+        // #hashtag, not a heading
+        return None;
+    }
+
+    // an optional closing run of #s, as long as it's separated by a space
+    let mut text = rest.trim();
+    let without_closing = text.trim_end_matches('#');
+    if without_closing.is_empty() || without_closing.ends_with([' ', '\t']) {
+        text = without_closing.trim_end();
+    }
+    Some((level, text.to_string()))
+}
+
+/// The part of a note under one heading
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub heading: Heading,
+    /// Byte offset where the section's content ends: the next heading of the same or a higher
+    /// level, or the end of the note. Content starts at `heading.end`
+    pub end: usize,
+}
+
+/// Finds the section under a heading. The heading can be given with or without its #s, and
+/// matching ignores case and surrounding whitespace. Errors if there's no such heading, or more
+/// than one so it's unclear which was meant
+pub fn find_section(content: &str, heading: &str) -> Result<Section> {
+    let wanted = match parse_heading(heading.trim()) {
+        Some((_, text)) => text,
+        None => heading.trim().to_string(),
+    };
+    if wanted.is_empty() {
+        bail!("heading cannot be empty");
+    }
+
+    let all = headings(content);
+    let matches: Vec<_> = all
+        .iter()
+        .enumerate()
+        .filter(|(_, h)| h.text.to_lowercase() == wanted.to_lowercase())
+        .collect();
+
+    let (index, found) = match matches.as_slice() {
+        [] => bail!("heading '{}' not found in note", wanted),
+        [single] => *single,
+        many => bail!(
+            "heading '{}' appears {} times in the note - rename one or edit by line instead",
+            wanted,
+            many.len()
+        ),
+    };
+
+    let end = all[index + 1..]
+        .iter()
+        .find(|h| h.level <= found.level)
+        .map_or(content.len(), |h| h.start);
+
+    Ok(Section {
+        heading: found.clone(),
+        end,
+    })
+}
+
+/// Swaps out the content under a heading, keeping the heading line itself
+pub fn replace_section(content: &str, heading: &str, new_content: &str) -> Result<String> {
+    let section = find_section(content, heading)?;
+    let mut out = String::with_capacity(content.len() + new_content.len());
+    out.push_str(&content[..section.heading.end]);
+    // a heading on the last line of the note has no newline of its own yet
+    if !out.ends_with('\n') && !new_content.is_empty() {
+        out.push('\n');
+    }
+    out.push_str(new_content);
+    // keep whatever comes next on its own line
+    if section.end < content.len() && !new_content.is_empty() && !new_content.ends_with('\n') {
+        out.push('\n');
+    }
+    out.push_str(&content[section.end..]);
+    Ok(out)
+}
+
+/// Changes a heading's text, keeping its level. Returns the new content and the heading as it
+/// was. Errors like [`find_section`], or if another heading already has the new text
+pub fn rename_heading(content: &str, heading: &str, new_text: &str) -> Result<(String, Heading)> {
+    let old = find_section(content, heading)?.heading;
+    let new_text = new_text.trim();
+    if headings(content)
+        .iter()
+        .any(|h| h.start != old.start && h.text.to_lowercase() == new_text.to_lowercase())
+    {
+        bail!(
+            "the note already has a heading '{}', links to it would be ambiguous",
+            new_text
+        );
+    }
+    let line = &content[old.start..old.end];
+    let ending = &line[line.trim_end_matches(['\n', '\r']).len()..];
+    let mut out = String::with_capacity(content.len() + new_text.len());
+    out.push_str(&content[..old.start]);
+    out.push_str(&format!("{} {}{}", "#".repeat(old.level), new_text, ending));
+    out.push_str(&content[old.end..]);
+    Ok((out, old))
+}
+
+/// Inserts text into the section under a heading: straight after the heading line, or with
+/// `at_end` after the section's last non-blank line (so the gap before the next heading stays).
+/// Returns the new content and the 1-based line the inserted text starts on
+pub fn insert_in_section(
+    content: &str,
+    heading: &str,
+    text: &str,
+    at_end: bool,
+) -> Result<(String, usize)> {
+    let section = find_section(content, heading)?;
+    let mut at = section.heading.end;
+    if at_end {
+        let body = &content[at..section.end];
+        let last = body.trim_end_matches(['\n', '\r', ' ', '\t']).len();
+        if last > 0 {
+            at += body[last..].find('\n').map_or(body.len(), |i| last + i + 1);
+        }
+    }
+
+    let mut out = String::with_capacity(content.len() + text.len() + 2);
+    out.push_str(&content[..at]);
+    // the line we're inserting after might be the last one, with no newline yet
+    if !out.ends_with('\n') {
+        out.push('\n');
+    }
+    let line = out.matches('\n').count() + 1;
+    out.push_str(text);
+    if at < content.len() && !text.ends_with('\n') {
+        out.push('\n');
+    }
+    out.push_str(&content[at..]);
+    Ok((out, line))
+}
+
+/// A checkbox list item, `- [ ] like this`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Task {
+    /// 1-based, counting every line of the note
+    pub line: usize,
+    /// What's between the brackets: ' ' for open, 'x' for done, or one of the custom statuses
+    /// themes use ('/' in progress, '-' cancelled, '>' deferred...)
+    pub status: char,
+    pub text: String,
+}
+
+impl Task {
+    pub fn is_done(&self) -> bool {
+        matches!(self.status, 'x' | 'X')
+    }
+}
+
+/// Every task in a note, skipping frontmatter and code blocks
+pub fn tasks(content: &str) -> Vec<Task> {
+    prose_lines(content)
+        .into_iter()
+        .filter_map(|(number, _, line)| {
+            let (status_at, text) = parse_task(line.trim_end_matches(['\n', '\r']))?;
+            Some(Task {
+                line: number + 1,
+                status: line[status_at..].chars().next()?,
+                text: text.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// For a task line, the byte offset of its status character and the task text
+fn parse_task(line: &str) -> Option<(usize, &str)> {
+    let trimmed = line.trim_start_matches([' ', '\t']);
+    let indent = line.len() - trimmed.len();
+
+    // bullet (- * +) or ordered (1. or 1)) list marker, then a space
+    let marker_len = match trimmed.chars().next()? {
+        '-' | '*' | '+' => 1,
+        c if c.is_ascii_digit() => {
+            let digits = trimmed.chars().take_while(|c| c.is_ascii_digit()).count();
+            if !trimmed[digits..].starts_with(['.', ')']) {
+                return None;
+            }
+            digits + 1
+        }
+        _ => return None,
+    };
+    let after_marker = trimmed[marker_len..].strip_prefix([' ', '\t'])?;
+    let after_marker = after_marker.trim_start_matches([' ', '\t']);
+
+    let inside = after_marker.strip_prefix('[')?;
+    let status = inside.chars().next()?;
+    let rest = inside[status.len_utf8()..].strip_prefix(']')?;
+    if status == '[' || status == ']' || (!rest.is_empty() && !rest.starts_with([' ', '\t'])) {
+        return None;
+    }
+
+    let status_at = indent + (trimmed.len() - inside.len());
+    Some((status_at, rest.trim()))
+}
+
+/// Sets the status of the task on a (1-based) line
+pub fn set_task_status(content: &str, line: usize, status: char) -> Result<String> {
+    let (start, status_at) = prose_lines(content)
+        .into_iter()
+        .find(|(number, _, _)| number + 1 == line)
+        .and_then(|(_, start, text)| {
+            let (status_at, _) = parse_task(text.trim_end_matches(['\n', '\r']))?;
+            Some((start, status_at))
+        })
+        .ok_or_else(|| anyhow!("line {} isn't a task", line))?;
+    let at = start + status_at;
+    let old_len = content[at..].chars().next().map_or(0, char::len_utf8);
+
+    let mut out = String::with_capacity(content.len() + 4);
+    out.push_str(&content[..at]);
+    out.push(status);
+    out.push_str(&content[at + old_len..]);
+    Ok(out)
+}
+
+/// The tags on a note, from its `tags` property and inline `#tags` in the text, lowercased
+/// (Obsidian treats them case-insensitively), without the `#`, and each only once
+pub fn tags(content: &str) -> Vec<String> {
+    let mut tags: Vec<String> = Vec::new();
+    let mut add = |tag: &str| {
+        let tag = tag
+            .trim()
+            .trim_start_matches('#')
+            .trim_end_matches('/')
+            .to_lowercase();
+        if is_valid_tag(&tag) && !tags.contains(&tag) {
+            tags.push(tag);
+        }
+    };
+
+    // a note with broken frontmatter still has its inline tags counted
+    for (key, value) in crate::frontmatter::get(content).unwrap_or_default() {
+        if !matches!(key.to_lowercase().as_str(), "tags" | "tag") {
+            continue;
+        }
+        match value {
+            serde_json::Value::Array(items) => {
+                items.iter().filter_map(|v| v.as_str()).for_each(&mut add)
+            }
+            // older notes use `tags: one, two` or `tags: one two`
+            serde_json::Value::String(list) => list
+                .split([',', ' '])
+                .filter(|t| !t.is_empty())
+                .for_each(&mut add),
+            _ => {}
+        }
+    }
+
+    for (_, _, line) in prose_lines(content) {
+        let mut in_code = false;
+        let mut prev = ' ';
+        for (i, c) in line.char_indices() {
+            match c {
+                '`' => in_code = !in_code,
+                '#' if !in_code && prev.is_whitespace() => {
+                    let rest = &line[i + 1..];
+                    let end = rest.find(|c: char| !is_tag_char(c)).unwrap_or(rest.len());
+                    add(&rest[..end]);
+                }
+                _ => {}
+            }
+            prev = c;
+        }
+    }
+
+    tags
+}
+
+fn is_tag_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '/')
+}
+
+/// Tags need at least one character that isn't a number, so `#1` and `#2024` aren't tags
+fn is_valid_tag(tag: &str) -> bool {
+    !tag.is_empty()
+        && tag.chars().all(is_tag_char)
+        && tag.chars().any(|c| !c.is_numeric() && c != '/')
+}
+
+/// A `[[wikilink]]` (or `![[embed]]`) in a note
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    /// 1-based line number
+    pub line: usize,
+    /// The note it points at, without any `#heading`, `^block` or `|alias`
+    pub target: String,
+}
+
+/// Every wikilink and embed in a note that points at another note. Links to a heading in the
+/// same note (`[[#Heading]]`) are left out, as is anything in frontmatter, code blocks or
+/// `inline code`
+pub fn links(content: &str) -> Vec<Link> {
+    link_targets(content)
+        .into_iter()
+        .map(|(line, range)| Link {
+            line,
+            target: content[range].to_string(),
+        })
+        .collect()
+}
+
+/// Rewrites the target of every link [`links`] would find, keeping any `#heading`, `^block` or
+/// `|alias` after it. `retarget` gets each target and returns its replacement, or None to leave
+/// it alone
+pub fn retarget_links(content: &str, retarget: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(content.len());
+    let mut copied = 0;
+    for (_, range) in link_targets(content) {
+        if let Some(new) = retarget(&content[range.clone()]) {
+            out.push_str(&content[copied..range.start]);
+            out.push_str(&new);
+            copied = range.end;
+        }
+    }
+    out.push_str(&content[copied..]);
+    out
+}
+
+/// Renames heading `old` to `new` in every link to it, like `[[Note#Old]]`,
+/// `[[Note#Parent#Old|alias]]` or `[[#Old]]`. `in_note` gets each link's target (empty for a
+/// link within the same note) and says whether that's the note with the heading. Headings match
+/// ignoring case, as in [`find_section`]. Returns the new content and how many links changed
+pub fn rename_heading_links(
+    content: &str,
+    in_note: impl Fn(&str) -> bool,
+    old: &str,
+    new: &str,
+) -> (String, usize) {
+    let old = old.trim().to_lowercase();
+    let mut out = String::with_capacity(content.len());
+    let (mut copied, mut renamed) = (0, 0);
+    for (_, inner) in wikilinks(content) {
+        let link = &content[inner.clone()];
+        let link = link.split('|').next().unwrap_or_default();
+        let Some(hash) = link.find('#') else {
+            continue;
+        };
+        if !in_note(link[..hash].trim()) {
+            continue;
+        }
+        let mut offset = inner.start + hash + 1;
+        let mut changed = false;
+        for part in link[hash + 1..].split('#') {
+            if part.trim().to_lowercase() == old {
+                out.push_str(&content[copied..offset]);
+                out.push_str(new);
+                copied = offset + part.len();
+                changed = true;
+            }
+            offset += part.len() + 1;
+        }
+        renamed += changed as usize;
+    }
+    out.push_str(&content[copied..]);
+    (out, renamed)
+}
+
+/// Where the target of each link is, as (1-based line number, byte range in the content)
+fn link_targets(content: &str) -> Vec<(usize, std::ops::Range<usize>)> {
+    wikilinks(content)
+        .into_iter()
+        .filter_map(|(number, inner)| {
+            let raw = content[inner.clone()]
+                .split(['|', '#', '^'])
+                .next()
+                .unwrap_or_default();
+            let target = raw.trim();
+            let start = inner.start + (raw.len() - raw.trim_start().len());
+            (!target.is_empty()).then(|| (number, start..start + target.len()))
+        })
+        .collect()
+}
+
+/// Where everything between `[[` and `]]` is, as (1-based line number, byte range in the
+/// content). Frontmatter, code blocks and `inline code` are skipped
+fn wikilinks(content: &str) -> Vec<(usize, std::ops::Range<usize>)> {
+    let mut links = Vec::new();
+    for (number, line_start, line) in prose_lines(content) {
+        let mut pos = 0;
+        let mut in_code = false;
+        while let Some(i) = line[pos..].find(['`', '[']).map(|i| pos + i) {
+            pos = i + 1;
+            if line[i..].starts_with('`') {
+                in_code = !in_code;
+                continue;
+            }
+            if in_code || !line[pos..].starts_with('[') {
+                continue;
+            }
+            let inner_start = pos + 1;
+            let Some(len) = line[inner_start..].find("]]") else {
+                break;
+            };
+            let start = line_start + inner_start;
+            links.push((number + 1, start..start + len));
+            pos = inner_start + len + 2;
+        }
+    }
+    links
+}
+
+/// The first paragraph of a note's text: its first run of lines that aren't blank or headings,
+/// joined with spaces. Frontmatter and code blocks are skipped
+pub fn first_paragraph(content: &str) -> Option<String> {
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut last_line = None;
+    for (number, _, line) in prose_lines(content) {
+        let text = line.trim();
+        // a code block in between ends the paragraph as much as a blank line does
+        let contiguous = last_line.is_none_or(|last| number == last + 1);
+        if text.is_empty() || parse_heading(text).is_some() || !contiguous {
+            if !paragraph.is_empty() {
+                break;
+            }
+            last_line = None;
+            continue;
+        }
+        paragraph.push(text);
+        last_line = Some(number);
+    }
+    (!paragraph.is_empty()).then(|| paragraph.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOTE: &str = "---\ntitle: x\n# not a heading\n---\n# Project\n\nintro\n\n## Tasks\n\n- [ ] one\n\n```sh\n# a comment\n## also not\n```\n\n### Subtasks\n\n- [ ] two\n\n## Notes ##\n\nend\n";
+
+    #[test]
+    fn test_headings_skip_code_and_frontmatter() {
+        let found: Vec<_> = headings(NOTE)
+            .into_iter()
+            .map(|h| (h.level, h.text))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (1, "Project".to_string()),
+                (2, "Tasks".to_string()),
+                (3, "Subtasks".to_string()),
+                (2, "Notes".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_outline() {
+        let tree = outline(NOTE);
+        assert_eq!(tree.len(), 1);
+        let project = &tree[0];
+        assert_eq!((project.level, project.text.as_str()), (1, "Project"));
+        let children: Vec<_> = project
+            .children
+            .iter()
+            .map(|h| (h.text.as_str(), h.children.len()))
+            .collect();
+        assert_eq!(children, vec![("Tasks", 1), ("Notes", 0)]);
+        assert_eq!(project.children[0].children[0].text, "Subtasks");
+        assert_eq!(
+            NOTE.lines().nth(project.children[1].line - 1),
+            Some("## Notes ##")
+        );
+
+        // skipped levels still nest, and a shallower heading later starts a new branch
+        let tree = outline(
+            "### deep
+# top
+### under top
+## mid
+",
+        );
+        let shape: Vec<_> = tree
+            .iter()
+            .map(|h| (h.text.as_str(), h.children.len()))
+            .collect();
+        assert_eq!(shape, vec![("deep", 0), ("top", 2)]);
+    }
+
+    #[test]
+    fn test_parse_heading() {
+        assert_eq!(parse_heading("# Title"), Some((1, "Title".to_string())));
+        assert_eq!(
+            parse_heading("   ### Three ###"),
+            Some((3, "Three".to_string()))
+        );
+        assert_eq!(parse_heading("## C#"), Some((2, "C#".to_string())));
+        assert_eq!(parse_heading("#"), Some((1, String::new())));
+        assert_eq!(parse_heading("#tag"), None);
+        assert_eq!(parse_heading("####### seven"), None);
+        assert_eq!(parse_heading("    # indented code"), None);
+    }
+
+    #[test]
+    fn test_find_section_includes_subsections() {
+        let section = find_section(NOTE, "## tasks").unwrap();
+        let body = &NOTE[section.heading.end..section.end];
+        assert!(body.starts_with("\n- [ ] one"));
+        assert!(body.contains("### Subtasks"));
+        assert!(!body.contains("## Notes"));
+
+        // the last section runs to the end of the note
+        let notes = find_section(NOTE, "Notes").unwrap();
+        assert_eq!(&NOTE[notes.heading.end..notes.end], "\nend\n");
+    }
+
+    #[test]
+    fn test_find_section_errors() {
+        assert!(find_section(NOTE, "Missing").is_err());
+        assert!(find_section(NOTE, "also not").is_err());
+        let dupes = "## A\none\n## A\ntwo\n";
+        let err = find_section(dupes, "A").unwrap_err().to_string();
+        assert!(err.contains("2 times"), "{}", err);
+    }
+
+    #[test]
+    fn test_replace_section() {
+        let note = "# Title\n\n## Tasks\nold\n### Sub\nsub\n## Done\nfin";
+        assert_eq!(
+            replace_section(note, "Tasks", "new").unwrap(),
+            "# Title\n\n## Tasks\nnew\n## Done\nfin"
+        );
+        assert_eq!(
+            replace_section(note, "Done", "all of it\n").unwrap(),
+            "# Title\n\n## Tasks\nold\n### Sub\nsub\n## Done\nall of it\n"
+        );
+        assert_eq!(
+            replace_section("# Only", "Only", "body").unwrap(),
+            "# Only\nbody"
+        );
+        assert_eq!(
+            replace_section(note, "Sub", "").unwrap(),
+            "# Title\n\n## Tasks\nold\n### Sub\n## Done\nfin"
+        );
+    }
+
+    #[test]
+    fn test_retarget_links() {
+        let note = "[[Old]] [[old#Part|see here]] ![[ Old ]] `[[Old]]` [[Other]]\n";
+        let new = retarget_links(note, |t| {
+            (t.eq_ignore_ascii_case("old")).then(|| "Archive/New".to_string())
+        });
+        assert_eq!(
+            new,
+            "[[Archive/New]] [[Archive/New#Part|see here]] ![[ Archive/New ]] `[[Old]]` [[Other]]\n"
+        );
+        assert_eq!(retarget_links(note, |_| None), note);
+    }
+
+    #[test]
+    fn test_rename_heading() {
+        let note = "# Plan\r\n## Goals ##\r\nship it\n### Later\n";
+        let (new, old) = rename_heading(note, "goals", " Aims ").unwrap();
+        assert_eq!(new, "# Plan\r\n## Aims\r\nship it\n### Later\n");
+        assert_eq!((old.level, old.text.as_str(), old.line), (2, "Goals", 2));
+        assert!(rename_heading(note, "Missing", "Aims").is_err());
+        assert!(rename_heading(note, "Goals", "later").is_err());
+        // just changing the case is fine
+        assert!(rename_heading(note, "Goals", "GOALS").is_ok());
+    }
+
+    #[test]
+    fn test_rename_heading_links() {
+        let note = "[[Plan#Goals]] [[plan#goals|why]] [[Plan#Q1#Goals]] [[#Goals]]\n\
+                    [[Other#Goals]] [[Plan#Goals later]] [[Plan#^goals]] `[[Plan#Goals]]`\n";
+        let in_plan = |t: &str| t.is_empty() || t.eq_ignore_ascii_case("plan");
+        let (new, renamed) = rename_heading_links(note, in_plan, "Goals", "Aims");
+        assert_eq!(renamed, 4);
+        assert_eq!(
+            new,
+            "[[Plan#Aims]] [[plan#Aims|why]] [[Plan#Q1#Aims]] [[#Aims]]\n\
+             [[Other#Goals]] [[Plan#Goals later]] [[Plan#^goals]] `[[Plan#Goals]]`\n"
+        );
+        // from another note, [[#Goals]] is about that note's own heading
+        let (new, renamed) = rename_heading_links(note, |t| t == "Plan", "Goals", "Aims");
+        assert_eq!(renamed, 2);
+        assert!(new.contains("[[#Goals]]"));
+    }
+
+    #[test]
+    fn test_insert_in_section() {
+        let note = "# Title\n\n## Log\nfirst\n### Sub\nsub\n\n## Done\nfin";
+        assert_eq!(
+            insert_in_section(note, "Log", "new", false).unwrap(),
+            (
+                "# Title\n\n## Log\nnew\nfirst\n### Sub\nsub\n\n## Done\nfin".to_string(),
+                4
+            )
+        );
+        // the end of the section includes its subsections, but not the blank line after them
+        assert_eq!(
+            insert_in_section(note, "## Log", "new\n", true).unwrap(),
+            (
+                "# Title\n\n## Log\nfirst\n### Sub\nsub\nnew\n\n## Done\nfin".to_string(),
+                7
+            )
+        );
+        assert_eq!(
+            insert_in_section(note, "Done", "more", true).unwrap().0,
+            "# Title\n\n## Log\nfirst\n### Sub\nsub\n\n## Done\nfin\nmore"
+        );
+        assert_eq!(
+            insert_in_section("# Empty\n\n# Next", "Empty", "x", true).unwrap(),
+            ("# Empty\nx\n\n# Next".to_string(), 2)
+        );
+        assert_eq!(
+            insert_in_section("# Only", "Only", "x", false).unwrap(),
+            ("# Only\nx".to_string(), 2)
+        );
+        assert!(insert_in_section(note, "Missing", "x", false).is_err());
+    }
+
+    #[test]
+    fn test_tasks() {
+        let note = "---\ntags: [x]\n---\n- [ ] open\n  - [x] done nested\n* [/] in progress\n1. [-] cancelled\n- not a task\n- [ ]\n- [x]no space\n```\n- [ ] in code\n```\n- [X] Shouted\n";
+        let found: Vec<_> = tasks(note)
+            .into_iter()
+            .map(|t| (t.line, t.status, t.text))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (4, ' ', "open".to_string()),
+                (5, 'x', "done nested".to_string()),
+                (6, '/', "in progress".to_string()),
+                (7, '-', "cancelled".to_string()),
+                (9, ' ', String::new()),
+                (14, 'X', "Shouted".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_task_status() {
+        let note = "# Todo\n- [ ] one\n  - [x] two\n";
+        assert_eq!(
+            set_task_status(note, 2, 'x').unwrap(),
+            "# Todo\n- [x] one\n  - [x] two\n"
+        );
+        assert_eq!(
+            set_task_status(note, 3, ' ').unwrap(),
+            "# Todo\n- [ ] one\n  - [ ] two\n"
+        );
+        assert!(set_task_status(note, 1, 'x').is_err());
+        assert!(set_task_status(note, 0, 'x').is_err());
+        assert!(set_task_status(note, 9, 'x').is_err());
+    }
+
+    #[test]
+    fn test_tags() {
+        let note = "---\ntags:\n  - Project\n  - '#area/work'\n---\n# Heading #notatag\n\nSome #idea and #Project again, #2024 isn't one but #y2024 is.\nurl.com/#anchor and `#code` skip, [[Note#Section]] too\n```\n#in-code\n```\n#nested/tag/ at the start\n";
+        assert_eq!(
+            tags(note),
+            vec![
+                "project",
+                "area/work",
+                "notatag",
+                "idea",
+                "y2024",
+                "nested/tag"
+            ]
+        );
+        // the old comma separated style
+        assert_eq!(tags("---\ntags: a, b\n---\n"), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_links() {
+        let note = "---\nup: \"[[Frontmatter]]\"\n---\n# [[Heading Link]]\nsee [[Note]], [[Folder/Other|alias]] and ![[Pic.png]]\n[[Note#Section]] [[#Local]] [[Note^block]] `[[Code]]` [[ Spaced ]]\n```\n[[Fenced]]\n```\n[[unclosed\n";
+        let found: Vec<_> = links(note)
+            .into_iter()
+            .map(|l| (l.line, l.target))
+            .collect();
+        let expected = [
+            (4, "Heading Link"),
+            (5, "Note"),
+            (5, "Folder/Other"),
+            (5, "Pic.png"),
+            (6, "Note"),
+            (6, "Note"),
+            (6, "Spaced"),
+        ];
+        assert_eq!(
+            found,
+            expected
+                .iter()
+                .map(|(l, t)| (*l, t.to_string()))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_first_paragraph() {
+        let note = "---\ntitle: x\n---\n# Title\n\nFirst line\nsecond line\n\nMore\n";
+        assert_eq!(
+            first_paragraph(note).as_deref(),
+            Some("First line second line")
+        );
+        assert_eq!(
+            first_paragraph("```\ncode\n```\nText\n```\nmore code\n```\nAfter").as_deref(),
+            Some("Text")
+        );
+        assert_eq!(first_paragraph("# Only a heading\n\n"), None);
+    }
+}
@@ -0,0 +1,375 @@
+//! Optional semantic search. Notes are embedded in the background by whatever [`Embedder`] is
+//! configured, and search_notes can rank by cosine similarity to the embedded query instead of
+//! fuzzy matching. Only built with the `embeddings` cargo feature
+
+use super::{SearchIndex, SearchResult, extract_snippet};
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+
+/// Notes sent to the embedder per request
+const BATCH_SIZE: usize = 32;
+
+/// Embedding models have an input limit, and the start of a note says most about it anyway
+const MAX_INPUT_CHARS: usize = 8000;
+
+/// How often to look for new or changed notes once everything is embedded
+const IDLE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long to wait before retrying after the embedder fails
+const ERROR_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Turns text into vectors
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    /// One vector per input, in the same order
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>>;
+}
+
+/// An OpenAI-compatible `/v1/embeddings` endpoint (OpenAI itself, Ollama, llama.cpp, vLLM...)
+pub struct HttpEmbedder {
+    client: reqwest::Client,
+    url: String,
+    model: String,
+    api_key: Option<String>,
+}
+
+#[derive(Serialize)]
+struct EmbeddingsRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingsResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+impl HttpEmbedder {
+    pub fn new(url: &str, model: &str, api_key: Option<String>) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(60))
+            .build()?;
+        Ok(Self {
+            client,
+            url: url.to_string(),
+            model: model.to_string(),
+            api_key,
+        })
+    }
+}
+
+#[async_trait]
+impl Embedder for HttpEmbedder {
+    async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        let mut request = self.client.post(&self.url).json(&EmbeddingsRequest {
+            model: &self.model,
+            input: texts,
+        });
+        if let Some(key) = &self.api_key {
+            request = request.bearer_auth(key);
+        }
+
+        let response = request.send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow!("embeddings request failed: {} - {}", status, body));
+        }
+
+        let mut data = response.json::<EmbeddingsResponse>().await?.data;
+        if data.len() != texts.len() {
+            return Err(anyhow!(
+                "asked for {} embeddings but got {}",
+                texts.len(),
+                data.len()
+            ));
+        }
+        data.sort_by_key(|d| d.index);
+        Ok(data.into_iter().map(|d| d.embedding).collect())
+    }
+}
+
+/// Cheap fingerprint of a note's content, to tell whether its vector is out of date
+fn content_hash(content: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 { 0.0 } else { dot / norms }
+}
+
+impl SearchIndex {
+    /// Whether any notes have been embedded yet
+    pub fn has_embeddings(&self) -> bool {
+        !self.vectors.is_empty()
+    }
+
+    /// Notes with no vector, or one from before their content last changed, as
+    /// (path, content hash, text to embed)
+    fn stale_embeddings(&self, limit: usize) -> Vec<(String, u64, String)> {
+        self.notes
+            .values()
+            .filter_map(|note| {
+                let hash = content_hash(&note.content);
+                if self
+                    .vectors
+                    .get(&note.path)
+                    .is_some_and(|(h, _)| *h == hash)
+                {
+                    return None;
+                }
+                let text: String = format!("{}\n\n{}", note.title, note.content)
+                    .chars()
+                    .take(MAX_INPUT_CHARS)
+                    .collect();
+                Some((note.path.clone(), hash, text))
+            })
+            .take(limit)
+            .collect()
+    }
+
+    /// Stores a note's vector, unless the note has changed again since it was embedded
+    fn set_embedding(&mut self, path: String, hash: u64, vector: Vec<f32>) {
+        if self
+            .notes
+            .get(&path)
+            .is_some_and(|note| content_hash(&note.content) == hash)
+        {
+            self.vectors.insert(path, (hash, vector));
+        }
+    }
+
+    /// Whether there are vectors left over for notes that have gone, e.g. after a resync
+    fn has_orphaned_embeddings(&self) -> bool {
+        self.vectors
+            .keys()
+            .any(|path| !self.notes.contains_key(path))
+    }
+
+    fn prune_embeddings(&mut self) {
+        let notes = &self.notes;
+        self.vectors.retain(|path, _| notes.contains_key(path));
+    }
+
+    /// Notes under `prefix` ranked by cosine similarity to an embedded query. Scores are the
+    /// similarity scaled to 0-1000 so they look like the fuzzy ones
+    pub fn semantic_search(
+        &self,
+        query: &str,
+        vector: &[f32],
+        limit: usize,
+        prefix: &str,
+    ) -> Vec<SearchResult> {
+        let mut scored: Vec<(f32, &str)> = self
+            .vectors
+            .iter()
+            .filter(|(path, _)| path.starts_with(prefix))
+            .map(|(path, (_, v))| (cosine(vector, v), path.as_str()))
+            .filter(|(score, _)| *score > 0.0)
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        scored
+            .into_iter()
+            .filter_map(|(score, path)| {
+                let note = self.notes.get(path)?;
+                Some(SearchResult {
+                    path: note.path.clone(),
+                    title: note.title.clone(),
+                    score: (score * 1000.0) as u32,
+                    snippet: extract_snippet(&note.content, query),
+                })
+            })
+            .take(limit)
+            .collect()
+    }
+}
+
+/// Keeps the index's vectors up to date: embeds anything new or changed, a batch at a time,
+/// without holding the index lock while the embedder works
+pub struct EmbeddingsWorker {
+    embedder: Arc<dyn Embedder>,
+    index: Arc<RwLock<SearchIndex>>,
+}
+
+impl EmbeddingsWorker {
+    pub fn new(embedder: Arc<dyn Embedder>, index: Arc<RwLock<SearchIndex>>) -> Self {
+        Self { embedder, index }
+    }
+
+    pub async fn run(&self, cancel: CancellationToken) {
+        loop {
+            let wait = match self.embed_batch().await {
+                Ok(0) => IDLE_INTERVAL,
+                Ok(_) => Duration::ZERO,
+                Err(e) => {
+                    tracing::warn!(
+                        "Embedding notes failed, retrying in {:?}: {}",
+                        ERROR_BACKOFF,
+                        e
+                    );
+                    ERROR_BACKOFF
+                }
+            };
+
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = cancel.cancelled() => break,
+            }
+        }
+        tracing::info!("Embeddings worker stopped");
+    }
+
+    /// Embeds one batch of stale notes, returning how many there were
+    async fn embed_batch(&self) -> Result<usize> {
+        let (stale, orphaned) = {
+            let index = self.index.read().await;
+            (
+                index.stale_embeddings(BATCH_SIZE),
+                index.has_orphaned_embeddings(),
+            )
+        };
+        if orphaned {
+            self.index.write().await.prune_embeddings();
+        }
+        if stale.is_empty() {
+            return Ok(0);
+        }
+
+        let texts: Vec<String> = stale.iter().map(|(_, _, text)| text.clone()).collect();
+        let vectors = self.embedder.embed(&texts).await?;
+
+        let mut index = self.index.write().await;
+        for ((path, hash, _), vector) in stale.into_iter().zip(vectors) {
+            index.set_embedding(path, hash, vector);
+        }
+        tracing::debug!("Embedded {} notes", texts.len());
+        Ok(texts.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::NoteEntry;
+    use wiremock::matchers::{body_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    /// Embeds by counting a few keywords, so similarity is predictable
+    struct KeywordEmbedder;
+
+    #[async_trait]
+    impl Embedder for KeywordEmbedder {
+        async fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+            Ok(texts
+                .iter()
+                .map(|t| {
+                    ["cat", "dog", "car"]
+                        .iter()
+                        .map(|k| t.matches(k).count() as f32)
+                        .collect()
+                })
+                .collect())
+        }
+    }
+
+    fn entry(path: &str, content: &str) -> NoteEntry {
+        NoteEntry {
+            path: path.to_string(),
+            title: path.to_string(),
+            content: content.to_string(),
+            mtime: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_http_embedder() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/v1/embeddings"))
+            .and(header("authorization", "Bearer key"))
+            .and(body_json(
+                serde_json::json!({"model": "m", "input": ["a", "b"]}),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                // out of order on purpose
+                "data": [
+                    {"index": 1, "embedding": [0.0, 1.0]},
+                    {"index": 0, "embedding": [1.0, 0.0]},
+                ]
+            })))
+            .mount(&server)
+            .await;
+
+        let embedder = HttpEmbedder::new(
+            &format!("{}/v1/embeddings", server.uri()),
+            "m",
+            Some("key".to_string()),
+        )
+        .unwrap();
+        let vectors = embedder
+            .embed(&["a".to_string(), "b".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(vectors, vec![vec![1.0, 0.0], vec![0.0, 1.0]]);
+    }
+
+    #[tokio::test]
+    async fn test_worker_embeds_and_search_ranks() {
+        let index = Arc::new(RwLock::new(SearchIndex::new()));
+        {
+            let mut index = index.write().await;
+            index.upsert("cats.md".to_string(), entry("cats.md", "cat cat cat"));
+            index.upsert("pets.md".to_string(), entry("pets.md", "cat dog"));
+            index.upsert("cars.md".to_string(), entry("cars.md", "car car"));
+        }
+
+        let worker = EmbeddingsWorker::new(Arc::new(KeywordEmbedder), index.clone());
+        assert_eq!(worker.embed_batch().await.unwrap(), 3);
+        assert_eq!(worker.embed_batch().await.unwrap(), 0);
+
+        let index_read = index.read().await;
+        let results = index_read.semantic_search("kitty", &[1.0, 0.0, 0.0], 10, "");
+        let paths: Vec<_> = results.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, vec!["cats.md", "pets.md"]);
+        drop(index_read);
+
+        // an edited note is picked up again, a removed one is dropped
+        index
+            .write()
+            .await
+            .upsert("cars.md".to_string(), entry("cars.md", "car dog"));
+        index.write().await.remove("pets.md");
+        assert_eq!(worker.embed_batch().await.unwrap(), 1);
+        assert_eq!(index.read().await.vectors.len(), 2);
+
+        // a resync drops everything but the vectors, which get tidied up afterwards
+        {
+            let mut index = index.write().await;
+            index.clear();
+            index.upsert("cats.md".to_string(), entry("cats.md", "cat cat cat"));
+        }
+        assert_eq!(worker.embed_batch().await.unwrap(), 0);
+        assert_eq!(index.read().await.vectors.len(), 1);
+    }
+}
@@ -0,0 +1,354 @@
+//! Where `[[wikilinks]]` point, worked out from the index the way Obsidian resolves them: by
+//! file name anywhere in the vault, or by the end of the path if the link has folders in it,
+//! ignoring case and the `.md`. Links are parsed from the indexed content on each call, so
+//! there's nothing extra to keep up to date
+
+use super::{NoteEntry, SearchIndex};
+use crate::markdown::{self, Link};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// Every note path in the index, by lowercased file name (with and without `.md`)
+struct LinkTargets<'a> {
+    by_name: HashMap<String, Vec<&'a str>>,
+}
+
+impl<'a> LinkTargets<'a> {
+    fn new(paths: impl Iterator<Item = &'a str>) -> Self {
+        let mut by_name: HashMap<String, Vec<&str>> = HashMap::new();
+        for path in paths {
+            let name = path.rsplit('/').next().unwrap_or(path).to_lowercase();
+            if let Some(stem) = name.strip_suffix(".md") {
+                by_name.entry(stem.to_string()).or_default().push(path);
+            }
+            by_name.entry(name).or_default().push(path);
+        }
+        // when a name is ambiguous, the shortest path wins, which is close enough to Obsidian
+        for paths in by_name.values_mut() {
+            paths.sort_by_key(|p| (p.len(), *p));
+        }
+        Self { by_name }
+    }
+
+    /// The note a link target points at, if there is one
+    fn resolve(&self, target: &str) -> Option<&'a str> {
+        let target = target.trim().trim_start_matches("./").to_lowercase();
+        let name = target.rsplit('/').next().unwrap_or(&target);
+        let candidates = self.by_name.get(name)?;
+        if !target.contains('/') {
+            return candidates.first().copied();
+        }
+        candidates.iter().copied().find(|path| {
+            let path = path.to_lowercase();
+            let stem = path.strip_suffix(".md");
+            [Some(path.as_str()), stem]
+                .into_iter()
+                .flatten()
+                .any(|p| p == target || p.ends_with(&format!("/{}", target)))
+        })
+    }
+}
+
+/// A link that needs a new target because the note it points at is moving
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MovedLink {
+    /// The path of the note being moved, so the link is only rewritten if the move happens
+    pub from: String,
+    /// What the link's target should become
+    pub target: String,
+}
+
+fn file_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+impl SearchIndex {
+    /// Every link that would stop pointing at the right note after the `moves` (from -> to),
+    /// by the path of the note it's in, then by its current target text. A link by name stays
+    /// a name if the new one is unique in the vault, otherwise it becomes the note's full path
+    pub fn moved_links(
+        &self,
+        moves: &HashMap<String, String>,
+    ) -> BTreeMap<String, HashMap<String, MovedLink>> {
+        let targets = LinkTargets::new(self.notes.keys().map(String::as_str));
+        let mut moved: BTreeMap<String, HashMap<String, MovedLink>> = BTreeMap::new();
+        for note in self.notes.values() {
+            for link in markdown::links(&note.content) {
+                let Some(from) = targets.resolve(&link.target) else {
+                    continue;
+                };
+                let Some(to) = moves.get(from) else {
+                    continue;
+                };
+                let by_name = !link.target.contains('/');
+                let name_unique = targets
+                    .by_name
+                    .get(&file_name(to).to_lowercase())
+                    .is_none_or(|paths| paths.iter().all(|p| *p == from));
+                if by_name && file_name(from).eq_ignore_ascii_case(file_name(to)) {
+                    continue;
+                }
+                let mut target = if by_name && name_unique {
+                    file_name(to)
+                } else {
+                    to.as_str()
+                };
+                if !link.target.to_lowercase().ends_with(".md") {
+                    target = target.strip_suffix(".md").unwrap_or(target);
+                }
+                moved.entry(note.path.clone()).or_default().insert(
+                    link.target,
+                    MovedLink {
+                        from: from.to_string(),
+                        target: target.to_string(),
+                    },
+                );
+            }
+        }
+        moved
+    }
+
+    /// Every note with links to `path`, and the link targets in it that point there (which can
+    /// be spelt more than one way), by the path of the note they're in
+    pub fn links_to(&self, path: &str) -> BTreeMap<String, HashSet<String>> {
+        let path = self.indexed_path(path);
+        let targets = LinkTargets::new(self.notes.keys().map(String::as_str));
+        let mut linking: BTreeMap<String, HashSet<String>> = BTreeMap::new();
+        for note in self.notes.values() {
+            for link in markdown::links(&note.content) {
+                if path.is_some() && targets.resolve(&link.target) == path {
+                    linking
+                        .entry(note.path.clone())
+                        .or_default()
+                        .insert(link.target);
+                }
+            }
+        }
+        linking
+    }
+
+    /// The notes `content` links to, each once, in the order they're first linked, plus the
+    /// targets of any links that don't resolve. Links back to `path` itself are left out
+    pub fn linked_notes(&self, path: &str, content: &str) -> (Vec<&NoteEntry>, Vec<String>) {
+        let targets = LinkTargets::new(self.notes.keys().map(String::as_str));
+        let path = self.indexed_path(path);
+        let mut seen = HashSet::new();
+        let (mut linked, mut unresolved) = (Vec::new(), Vec::new());
+        for link in markdown::links(content) {
+            match targets.resolve(&link.target) {
+                Some(target) if Some(target) == path => {}
+                Some(target) => {
+                    if seen.insert(target) {
+                        linked.extend(self.notes.get(target));
+                    }
+                }
+                None => {
+                    if !unresolved.contains(&link.target) {
+                        unresolved.push(link.target);
+                    }
+                }
+            }
+        }
+        (linked, unresolved)
+    }
+
+    /// Links in notes under `prefix` that don't point at any note in the index, by source
+    /// note, sorted by path
+    pub fn broken_links(&self, prefix: &str) -> Vec<(String, Vec<Link>)> {
+        let targets = LinkTargets::new(self.notes.keys().map(String::as_str));
+        let mut broken: Vec<(String, Vec<Link>)> = self
+            .notes
+            .values()
+            .filter(|note| note.path.starts_with(prefix))
+            .filter_map(|note| {
+                let links: Vec<Link> = markdown::links(&note.content)
+                    .into_iter()
+                    .filter(|link| targets.resolve(&link.target).is_none())
+                    .collect();
+                (!links.is_empty()).then(|| (note.path.clone(), links))
+            })
+            .collect();
+        broken.sort_by(|a, b| a.0.cmp(&b.0));
+        broken
+    }
+
+    /// Markdown notes under `prefix` that no other note links to, as (path, title) sorted by
+    /// path. Notes under any of the `exclude` prefixes are left out, but their links still count
+    pub fn orphans(&self, prefix: &str, exclude: &[String]) -> Vec<(String, String)> {
+        let targets = LinkTargets::new(self.notes.keys().map(String::as_str));
+        let linked: HashSet<&str> = self
+            .notes
+            .values()
+            .flat_map(|note| {
+                markdown::links(&note.content)
+                    .into_iter()
+                    .filter_map(|link| targets.resolve(&link.target))
+                    .filter(|target| *target != note.path)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        let mut orphans: Vec<(String, String)> = self
+            .notes
+            .values()
+            .filter(|note| {
+                note.path.ends_with(".md")
+                    && note.path.starts_with(prefix)
+                    && !exclude.iter().any(|e| note.path.starts_with(e.as_str()))
+                    && !linked.contains(note.path.as_str())
+            })
+            .map(|note| (note.path.clone(), note.title.clone()))
+            .collect();
+        orphans.sort();
+        orphans
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::search::{NoteEntry, SearchIndex};
+    use std::collections::HashMap;
+
+    fn index(notes: &[(&str, &str)]) -> SearchIndex {
+        let mut index = SearchIndex::new();
+        for (path, content) in notes {
+            index.upsert(
+                path.to_string(),
+                NoteEntry {
+                    path: path.to_string(),
+                    title: path.to_string(),
+                    content: content.to_string(),
+                    mtime: 0,
+                },
+            );
+        }
+        index
+    }
+
+    #[test]
+    fn test_broken_links() {
+        let index = index(&[
+            (
+                "Home.md",
+                "[[projects/alpha]] [[Alpha.md|a]] [[beta]]\n![[pic.png]] [[Inbox/Beta]]",
+            ),
+            ("Projects/Alpha.md", "back to [[home#Top]]\nand [[Gone]]"),
+            ("Archive/Beta.md", "fine"),
+            ("pic.png", ""),
+            ("Other/Notes.md", "[[Nope]]"),
+        ]);
+
+        let broken: Vec<_> = index
+            .broken_links("")
+            .into_iter()
+            .map(|(path, links)| {
+                let links: Vec<_> = links.into_iter().map(|l| (l.line, l.target)).collect();
+                (path, links)
+            })
+            .collect();
+        assert_eq!(
+            broken,
+            vec![
+                ("Home.md".to_string(), vec![(2, "Inbox/Beta".to_string())]),
+                ("Other/Notes.md".to_string(), vec![(1, "Nope".to_string())]),
+                (
+                    "Projects/Alpha.md".to_string(),
+                    vec![(2, "Gone".to_string())]
+                ),
+            ]
+        );
+
+        assert_eq!(index.broken_links("Projects/").len(), 1);
+    }
+
+    #[test]
+    fn test_orphans() {
+        let index = index(&[
+            ("Home.md", "[[Projects/Alpha]] and [[Home]]"),
+            ("Projects/Alpha.md", "![[pic.png]]"),
+            ("Projects/Beta.md", "[[Daily/2024-01-01]]"),
+            ("Daily/2024-01-02.md", ""),
+            ("Daily/2024-01-01.md", ""),
+            ("pic.png", ""),
+        ]);
+
+        let paths = |orphans: Vec<(String, String)>| -> Vec<String> {
+            orphans.into_iter().map(|(path, _)| path).collect()
+        };
+        // linking to yourself doesn't count, and attachments aren't notes
+        assert_eq!(
+            paths(index.orphans("", &[])),
+            vec!["Daily/2024-01-02.md", "Home.md", "Projects/Beta.md"]
+        );
+        assert_eq!(
+            paths(index.orphans("", &["Daily/".to_string()])),
+            vec!["Home.md", "Projects/Beta.md"]
+        );
+        assert_eq!(
+            paths(index.orphans("Projects/", &[])),
+            vec!["Projects/Beta.md"]
+        );
+    }
+
+    #[test]
+    fn test_moved_links() {
+        let index = index(&[
+            (
+                "Home.md",
+                "[[Alpha]] [[Projects/Alpha|a]] [[beta.md]] [[Gamma]]",
+            ),
+            ("Projects/Alpha.md", "[[Home]]"),
+            ("Beta.md", ""),
+            ("Gamma.md", ""),
+            ("Other/Delta.md", ""),
+        ]);
+        let moves: HashMap<String, String> = [
+            ("Projects/Alpha.md", "Archive/Alpha.md"),
+            ("Beta.md", "Ideas/Beta2.md"),
+            ("Gamma.md", "Notes/Delta.md"),
+        ]
+        .into_iter()
+        .map(|(a, b)| (a.to_string(), b.to_string()))
+        .collect();
+
+        let moved = index.moved_links(&moves);
+        assert_eq!(moved.keys().collect::<Vec<_>>(), vec!["Home.md"]);
+        let home = &moved["Home.md"];
+        // a name that still works after the move is left alone
+        assert!(!home.contains_key("Alpha"));
+        assert_eq!(home["Projects/Alpha"].target, "Archive/Alpha");
+        assert_eq!(home["Projects/Alpha"].from, "Projects/Alpha.md");
+        assert_eq!(home["beta.md"].target, "Beta2.md");
+        // there's already a Delta, so the name alone would be ambiguous
+        assert_eq!(home["Gamma"].target, "Notes/Delta");
+    }
+
+    #[test]
+    fn test_links_to() {
+        let index = index(&[
+            ("Home.md", "[[Plan#Goals]] [[projects/plan]] [[Other]]"),
+            ("Projects/Plan.md", "[[#Goals]] [[Plan]]"),
+            ("Other.md", "[[Home]]"),
+        ]);
+        let linking = index.links_to("Projects/Plan.md");
+        assert_eq!(
+            linking.keys().collect::<Vec<_>>(),
+            vec!["Home.md", "Projects/Plan.md"]
+        );
+        let mut home: Vec<_> = linking["Home.md"].iter().collect();
+        home.sort();
+        assert_eq!(home, vec!["Plan", "projects/plan"]);
+        assert!(index.links_to("Nowhere.md").is_empty());
+    }
+
+    #[test]
+    fn test_linked_notes() {
+        let index = index(&[("Home.md", ""), ("Projects/Alpha.md", ""), ("Beta.md", "")]);
+        let (linked, unresolved) = index.linked_notes(
+            "Home.md",
+            "[[Beta]] [[alpha|a]] [[Beta#Part]] [[Home]] [[Nope]] [[Nope]]",
+        );
+        let paths: Vec<_> = linked.iter().map(|n| n.path.as_str()).collect();
+        assert_eq!(paths, vec!["Beta.md", "Projects/Alpha.md"]);
+        assert_eq!(unresolved, vec!["Nope"]);
+    }
+}
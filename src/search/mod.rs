@@ -1,12 +1,23 @@
+#[cfg(feature = "embeddings")]
+mod embeddings;
+mod links;
+mod properties;
+mod related;
+mod stats;
 mod watcher;
 
-pub use watcher::ChangesWatcher;
+#[cfg(feature = "embeddings")]
+pub use embeddings::{Embedder, EmbeddingsWorker, HttpEmbedder};
+pub use properties::Comparison;
+pub use watcher::{Backoff, ChangesWatcher, note_entries};
 
+use crate::couchdb::PathStyle;
 use nucleo_matcher::{
     Config, Matcher, Utf32Str,
     pattern::{CaseMatching, Normalization, Pattern},
 };
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use unicode_segmentation::UnicodeSegmentation;
 
 /// A single note's indexed content
 #[derive(Debug, Clone)]
@@ -14,7 +25,6 @@ pub struct NoteEntry {
     pub path: String,
     pub title: String,
     pub content: String,
-    #[allow(dead_code)] // Kept for potential future use (e.g., sorting by recency)
     pub mtime: u64,
 }
 
@@ -28,9 +38,12 @@ pub struct SearchResult {
 }
 
 /// Options for search queries
+#[derive(Debug, Clone)]
 pub struct SearchOptions {
     pub limit: usize,
     pub search_content: bool,
+    /// Only notes whose path starts with this
+    pub prefix: String,
 }
 
 impl Default for SearchOptions {
@@ -38,6 +51,7 @@ impl Default for SearchOptions {
         Self {
             limit: 20,
             search_content: true,
+            prefix: String::new(),
         }
     }
 }
@@ -45,17 +59,125 @@ impl Default for SearchOptions {
 /// In-memory search index for all notes
 pub struct SearchIndex {
     notes: HashMap<String, NoteEntry>,
+    /// How paths map to doc ids. Two paths with the same doc id are the same note
+    path_style: PathStyle,
+    /// The path each note is indexed under, by its doc id, so a path spelt in another case
+    /// (or a hard delete, which only has the id) finds the same entry
+    paths_by_id: HashMap<String, String>,
+    /// Each note's tags, and how many notes use each tag, kept up to date on every upsert so
+    /// listing tags doesn't mean re-parsing the vault
+    note_tags: HashMap<String, Vec<String>>,
+    tag_counts: HashMap<String, usize>,
+    /// Each note's frontmatter properties, for querying them without re-parsing every note.
+    /// Notes without any (or with frontmatter we can't parse) aren't in here
+    note_properties: HashMap<String, crate::frontmatter::Properties>,
+    /// How often each term comes up in each note, and how many notes each term is in, for
+    /// related_notes. Empty without content, there are no terms to count then
+    note_terms: HashMap<String, HashMap<String, f64>>,
+    term_doc_counts: HashMap<String, usize>,
+    /// Embedding of each note, with a hash of the content it was made from
+    #[cfg(feature = "embeddings")]
+    vectors: HashMap<String, (u64, Vec<f32>)>,
+    /// Whether note bodies are kept, or just titles, tags and metadata (--index-content=false)
+    index_content: bool,
+    /// Notes removed by the watcher while the initial load runs in the background
+    /// (--no-startup-resync). None once the index has everything in it
+    loading: Option<HashSet<String>>,
     pub last_seq: Option<String>,
+    /// Whether the changes watcher is connected and keeping the index up to date
+    pub watcher_connected: bool,
 }
 
 impl SearchIndex {
     pub fn new() -> Self {
         Self {
             notes: HashMap::new(),
+            path_style: PathStyle::default(),
+            paths_by_id: HashMap::new(),
+            note_tags: HashMap::new(),
+            tag_counts: HashMap::new(),
+            note_properties: HashMap::new(),
+            note_terms: HashMap::new(),
+            term_doc_counts: HashMap::new(),
+            #[cfg(feature = "embeddings")]
+            vectors: HashMap::new(),
+            index_content: true,
+            loading: None,
             last_seq: None,
+            watcher_connected: false,
+        }
+    }
+
+    /// An index that throws away note bodies once it has their tags, to save memory on big
+    /// vaults. Searching only matches titles, and nothing that needs the content will work
+    pub fn without_content() -> Self {
+        Self {
+            index_content: false,
+            ..Self::new()
+        }
+    }
+
+    /// Tells notes apart by their doc id under `style`, e.g. ignoring case with lowercased ids
+    pub fn with_path_style(self, style: PathStyle) -> Self {
+        Self {
+            path_style: style,
+            ..self
+        }
+    }
+
+    /// The path a note is indexed under, however `path` is spelt
+    fn indexed_path(&self, path: &str) -> Option<&str> {
+        self.paths_by_id
+            .get(&self.path_style.doc_id(path))
+            .map(String::as_str)
+    }
+
+    /// A note's entry, however `path` is spelt
+    fn entry(&self, path: &str) -> Option<&NoteEntry> {
+        self.notes.get(self.indexed_path(path)?)
+    }
+
+    /// Whether note bodies are in the index, for the things that need them
+    pub fn has_content(&self) -> bool {
+        self.index_content
+    }
+
+    /// Mark the index as still waiting for its initial load, which
+    /// [`SearchIndex::finish_loading`] finishes. Until then it only has what the watcher has seen change
+    pub fn start_loading(&mut self) {
+        self.loading = Some(HashSet::new());
+    }
+
+    /// Whether the initial load is done, so searches see the whole vault
+    pub fn is_ready(&self) -> bool {
+        self.loading.is_none()
+    }
+
+    /// Add part of a snapshot of the vault during the initial load. Anything the watcher
+    /// upserted or removed since loading started is newer than the snapshot, so it's left as
+    /// it is. Does nothing if a full resync has loaded everything in the meantime
+    pub fn backfill(&mut self, notes: Vec<NoteEntry>) {
+        let Some(removed) = &self.loading else {
+            return;
+        };
+        let notes: Vec<NoteEntry> = notes
+            .into_iter()
+            .filter(|note| {
+                let id = self.path_style.doc_id(&note.path);
+                !self.paths_by_id.contains_key(&id) && !removed.contains(&id)
+            })
+            .collect();
+        for note in notes {
+            self.upsert(note.path.clone(), note);
         }
     }
 
+    /// Finish the initial load once the whole snapshot has gone through
+    /// [`SearchIndex::backfill`]
+    pub fn finish_loading(&mut self) {
+        self.loading = None;
+    }
+
     pub fn len(&self) -> usize {
         self.notes.len()
     }
@@ -66,18 +188,117 @@ impl SearchIndex {
     }
 
     /// Insert or update a note in the index
-    pub fn upsert(&mut self, path: String, entry: NoteEntry) {
+    pub fn upsert(&mut self, path: String, mut entry: NoteEntry) {
+        let id = self.path_style.doc_id(&path);
+        // the same note under a path spelt differently, which is going away
+        if let Some(old) = self.paths_by_id.get(&id).filter(|old| **old != path) {
+            let old = old.clone();
+            self.forget(&old);
+        }
+        self.paths_by_id.insert(id, path.clone());
+        self.forget_tags(&path);
+        let tags = crate::markdown::tags(&entry.content);
+        for tag in &tags {
+            *self.tag_counts.entry(tag.clone()).or_default() += 1;
+        }
+        self.note_tags.insert(path.clone(), tags);
+        match crate::frontmatter::get(&entry.content) {
+            Ok(props) if !props.is_empty() => {
+                self.note_properties.insert(path.clone(), props);
+            }
+            _ => {
+                self.note_properties.remove(&path);
+            }
+        }
+        if self.index_content {
+            self.count_terms(&path, &entry.content);
+        } else {
+            entry.content = String::new();
+        }
         self.notes.insert(path, entry);
     }
 
+    /// Every indexed note, in no particular order
+    pub fn notes(&self) -> impl Iterator<Item = &NoteEntry> {
+        self.notes.values()
+    }
+
+    /// A note's indexed content, if it's from the same version of the note as the given mtime
+    /// and size (both from its doc in CouchDB). None means read it from CouchDB instead
+    pub fn cached_content(&self, path: &str, mtime: u64, size: u64) -> Option<&str> {
+        self.entry(path)
+            .filter(|_| self.index_content)
+            .filter(|note| note.mtime == mtime && note.content.len() as u64 == size)
+            .map(|note| note.content.as_str())
+    }
+
     /// Remove a note from the index
     pub fn remove(&mut self, path: &str) {
+        let id = self.path_style.doc_id(path);
+        if let Some(removed) = &mut self.loading {
+            removed.insert(id.clone());
+        }
+        if let Some(path) = self.paths_by_id.remove(&id) {
+            self.forget(&path);
+        }
+    }
+
+    /// Drops everything indexed under exactly `path`
+    fn forget(&mut self, path: &str) {
+        self.forget_tags(path);
+        self.forget_terms(path);
+        self.note_properties.remove(path);
+        #[cfg(feature = "embeddings")]
+        self.vectors.remove(path);
         self.notes.remove(path);
     }
 
+    fn forget_tags(&mut self, path: &str) {
+        for tag in self.note_tags.remove(path).unwrap_or_default() {
+            if let Some(count) = self.tag_counts.get_mut(&tag) {
+                *count -= 1;
+                if *count == 0 {
+                    self.tag_counts.remove(&tag);
+                }
+            }
+        }
+    }
+
+    /// Every tag in the vault with the number of notes using it, most used first
+    pub fn tag_counts(&self, prefix: &str) -> Vec<(String, usize)> {
+        let mut counts: Vec<_> = if prefix.is_empty() {
+            self.tag_counts
+                .iter()
+                .map(|(tag, count)| (tag.clone(), *count))
+                .collect()
+        } else {
+            // not cached, only a vault root asks for this
+            let mut under: HashMap<&str, usize> = HashMap::new();
+            for (_, tags) in self.note_tags.iter().filter(|(p, _)| p.starts_with(prefix)) {
+                for tag in tags {
+                    *under.entry(tag).or_default() += 1;
+                }
+            }
+            under
+                .into_iter()
+                .map(|(tag, count)| (tag.to_string(), count))
+                .collect()
+        };
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts
+    }
+
     /// Clear the index (for full resync)
     pub fn clear(&mut self) {
         self.notes.clear();
+        self.paths_by_id.clear();
+        self.note_tags.clear();
+        self.tag_counts.clear();
+        self.note_properties.clear();
+        self.note_terms.clear();
+        self.term_doc_counts.clear();
+        // vectors are left alone, anything unchanged after the resync doesn't need re-embedding
+        self.loading = None;
         self.last_seq = None;
     }
 
@@ -93,6 +314,7 @@ impl SearchIndex {
         let mut results: Vec<SearchResult> = self
             .notes
             .values()
+            .filter(|note| note.path.starts_with(&opts.prefix))
             .filter_map(|note| {
                 // Convert strings to Utf32Str for nucleo
                 let mut title_buf = Vec::new();
@@ -102,7 +324,7 @@ impl SearchIndex {
                 let title_score = pattern.score(title_str, &mut matcher);
 
                 // Score content match if enabled
-                let (content_score, snippet) = if opts.search_content {
+                let (content_score, snippet) = if opts.search_content && self.index_content {
                     let mut content_buf = Vec::new();
                     let content_str = Utf32Str::new(&note.content, &mut content_buf);
                     let score = pattern.score(content_str, &mut matcher);
@@ -136,12 +358,76 @@ impl SearchIndex {
             .collect();
 
         // Sort by score descending
-        results.sort_by(|a, b| b.score.cmp(&a.score));
+        results.sort_by_key(|r| std::cmp::Reverse(r.score));
         results.truncate(opts.limit);
         results
     }
 }
 
+impl SearchIndex {
+    /// Notes under `prefix` whose path fuzzily matches `path` (e.g. `Projects/myproj.md` for
+    /// `Projects/my-project.md`), best first, for telling a caller what they probably meant
+    pub fn similar_paths(&self, path: &str, limit: usize, prefix: &str) -> Vec<(String, u32)> {
+        let needle = path.trim_end_matches(".md");
+        if needle.is_empty() {
+            return vec![];
+        }
+
+        let mut matcher = Matcher::new(Config::DEFAULT.match_paths());
+        let pattern = Pattern::parse(needle, CaseMatching::Ignore, Normalization::Smart);
+
+        let mut matches: Vec<(String, u32)> = self
+            .notes
+            .keys()
+            .filter(|p| p.starts_with(prefix))
+            .filter_map(|p| {
+                let mut buf = Vec::new();
+                let score =
+                    pattern.score(Utf32Str::new(&p[prefix.len()..], &mut buf), &mut matcher)?;
+                Some((p.clone(), score))
+            })
+            .collect();
+        matches.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        matches.truncate(limit);
+        matches
+    }
+}
+
+impl SearchIndex {
+    /// Notes under `prefix` for completing a path that's been typed so far: ones whose path
+    /// starts with it, then whose file name does, then any that contain it, ignoring case.
+    /// Up to `limit` of them, plus how many there are in all
+    pub fn complete_paths(&self, typed: &str, prefix: &str, limit: usize) -> (Vec<String>, usize) {
+        let typed = typed.to_lowercase();
+        let mut matches: Vec<(u8, &str)> = self
+            .notes
+            .keys()
+            .filter_map(|p| {
+                let path = p.strip_prefix(prefix)?.to_lowercase();
+                let name = path.rsplit('/').next().unwrap_or(&path);
+                let rank = if path.starts_with(&typed) {
+                    0
+                } else if name.starts_with(&typed) {
+                    1
+                } else if path.contains(&typed) {
+                    2
+                } else {
+                    return None;
+                };
+                Some((rank, p.as_str()))
+            })
+            .collect();
+        matches.sort();
+        let total = matches.len();
+        let paths = matches
+            .into_iter()
+            .take(limit)
+            .map(|(_, p)| p.to_string())
+            .collect();
+        (paths, total)
+    }
+}
+
 impl Default for SearchIndex {
     fn default() -> Self {
         Self::new()
@@ -150,37 +436,21 @@ impl Default for SearchIndex {
 
 /// Extract the title from a note - first H1 heading or filename
 pub fn extract_title(path: &str, content: &str) -> String {
-    // Track if we're inside frontmatter
-    let mut in_frontmatter = false;
-    let mut frontmatter_started = false;
+    let body = crate::frontmatter::split(content).map_or(content, |(_, body)| body);
 
-    for line in content.lines() {
+    for line in body.lines() {
         let trimmed = line.trim();
 
-        // Handle frontmatter (YAML between --- markers)
-        if trimmed == "---" {
-            if !frontmatter_started {
-                frontmatter_started = true;
-                in_frontmatter = true;
-                continue;
-            } else if in_frontmatter {
-                in_frontmatter = false;
-                continue;
-            }
-        }
-
-        // Skip if inside frontmatter
-        if in_frontmatter {
-            continue;
-        }
-
         // Skip empty lines
         if trimmed.is_empty() {
             continue;
         }
 
-        // Found H1 heading
-        if let Some(title) = trimmed.strip_prefix("# ") {
+        // Found H1 heading (a tab after the # counts too)
+        if let Some(title) = trimmed
+            .strip_prefix("# ")
+            .or_else(|| trimmed.strip_prefix("#\t"))
+        {
             return title.trim().to_string();
         }
 
@@ -196,6 +466,26 @@ pub fn extract_title(path: &str, content: &str) -> String {
         .to_string()
 }
 
+/// Characters (grapheme clusters, really) of context on each side of a match in a snippet
+const SNIPPET_CONTEXT: usize = 50;
+
+/// How much further a snippet can stretch to start and end between words. Text without spaces
+/// (Chinese, Japanese...) just gets cut between characters instead
+const SNIPPET_WORD_SLACK: usize = 15;
+
+/// Where `lower_pos` in `content.to_lowercase()` is in `content` itself. Lowercasing can change
+/// how many bytes a character takes, so they don't always line up
+fn original_offset(content: &str, lower_pos: usize) -> usize {
+    let mut lowered = 0;
+    for (i, c) in content.char_indices() {
+        if lowered >= lower_pos {
+            return i;
+        }
+        lowered += c.to_lowercase().map(char::len_utf8).sum::<usize>();
+    }
+    content.len()
+}
+
 /// Extract a snippet around the first match location
 fn extract_snippet(content: &str, query: &str) -> Option<String> {
     // Simple case-insensitive search for the query
@@ -209,26 +499,41 @@ fn extract_snippet(content: &str, query: &str) -> Option<String> {
         .iter()
         .filter_map(|word| content_lower.find(word))
         .min()?;
-
-    // Extract ~50 chars on each side
-    let context_size = 50;
-    let start = match_pos.saturating_sub(context_size);
-    let end = (match_pos + context_size).min(content.len());
-
-    // Find word boundaries safely (handling multi-byte UTF-8 characters)
-    let start = content[..start]
-        .rfind(char::is_whitespace)
-        .map(|i| {
-            // Advance past the whitespace character (which may be multi-byte)
-            let ws_char = content[i..].chars().next().unwrap();
-            i + ws_char.len_utf8()
-        })
-        .unwrap_or(start);
-
-    let end = content[end..]
+    let match_pos = original_offset(content, match_pos);
+
+    // Counted in grapheme clusters rather than bytes, so a window never splits a character and
+    // is about as wide in any script
+    let before = &content[..match_pos];
+    let start = before
+        .grapheme_indices(true)
+        .rev()
+        .take(SNIPPET_CONTEXT)
+        .last()
+        .map_or(match_pos, |(i, _)| i);
+    let end = content[match_pos..]
+        .grapheme_indices(true)
+        .nth(SNIPPET_CONTEXT)
+        .map_or(content.len(), |(i, _)| match_pos + i);
+
+    // Stretch out to word boundaries, if there are any nearby
+    let slack_start = content[..start]
+        .grapheme_indices(true)
+        .rev()
+        .take(SNIPPET_WORD_SLACK)
+        .last()
+        .map_or(start, |(i, _)| i);
+    let start = content[slack_start..start]
+        .char_indices()
+        .rfind(|(_, c)| c.is_whitespace())
+        .map_or(start, |(i, c)| slack_start + i + c.len_utf8());
+
+    let slack_end = content[end..]
+        .grapheme_indices(true)
+        .nth(SNIPPET_WORD_SLACK)
+        .map_or(content.len(), |(i, _)| end + i);
+    let end = content[end..slack_end]
         .find(char::is_whitespace)
-        .map(|i| end + i)
-        .unwrap_or(end);
+        .map_or(end, |i| end + i);
 
     let mut snippet = content[start..end].to_string();
 
@@ -250,6 +555,46 @@ fn extract_snippet(content: &str, query: &str) -> Option<String> {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_complete_paths() {
+        let mut index = SearchIndex::new();
+        for path in [
+            "Projects/Alpha.md",
+            "Projects/Beta.md",
+            "Archive/Project Ideas.md",
+            "Shared/Projects/Gamma.md",
+            "Home.md",
+        ] {
+            index.upsert(
+                path.to_string(),
+                NoteEntry {
+                    path: path.to_string(),
+                    title: extract_title(path, ""),
+                    content: String::new(),
+                    mtime: 0,
+                },
+            );
+        }
+
+        let (paths, total) = index.complete_paths("proj", "", 10);
+        assert_eq!(
+            paths,
+            vec![
+                "Projects/Alpha.md",
+                "Projects/Beta.md",
+                "Archive/Project Ideas.md",
+                "Shared/Projects/Gamma.md"
+            ]
+        );
+        assert_eq!(total, 4);
+        let (paths, total) = index.complete_paths("PROJ", "", 1);
+        assert_eq!((paths, total), (vec!["Projects/Alpha.md".to_string()], 4));
+        // only under the prefix, matched against the path relative to it
+        let (paths, _) = index.complete_paths("proj", "Shared/", 10);
+        assert_eq!(paths, vec!["Shared/Projects/Gamma.md"]);
+        assert_eq!(index.complete_paths("", "", 10).1, 5);
+    }
+
     #[test]
     fn test_extract_title_from_heading() {
         let content = "# My Great Note\n\nSome content here";
@@ -296,6 +641,75 @@ mod tests {
         assert_eq!(results[0].path, "test.md");
     }
 
+    #[test]
+    fn test_cached_content() {
+        let mut index = SearchIndex::new();
+        index.upsert(
+            "Inbox.md".to_string(),
+            NoteEntry {
+                path: "Inbox.md".to_string(),
+                title: "Inbox".to_string(),
+                content: "- milk".to_string(),
+                mtime: 100,
+            },
+        );
+        assert_eq!(index.cached_content("Inbox.md", 100, 6), Some("- milk"));
+        // the watcher hasn't caught up with an edit yet
+        assert_eq!(index.cached_content("Inbox.md", 200, 6), None);
+        assert_eq!(index.cached_content("Inbox.md", 100, 12), None);
+        assert_eq!(index.cached_content("Other.md", 100, 6), None);
+    }
+
+    #[test]
+    fn test_without_content() {
+        let mut index = SearchIndex::without_content();
+        index.upsert(
+            "Ideas.md".to_string(),
+            NoteEntry {
+                path: "Ideas.md".to_string(),
+                title: "Ideas".to_string(),
+                content: "#someday build a boat".to_string(),
+                mtime: 100,
+            },
+        );
+        assert_eq!(index.notes().next().unwrap().content, "");
+        // tags are worked out before the body goes
+        assert_eq!(index.tag_counts(""), vec![("someday".to_string(), 1)]);
+        assert_eq!(index.search("ideas", SearchOptions::default()).len(), 1);
+        assert!(index.search("boat", SearchOptions::default()).is_empty());
+        assert_eq!(index.cached_content("Ideas.md", 100, 0), None);
+    }
+
+    #[test]
+    fn test_similar_paths() {
+        let mut index = SearchIndex::new();
+        for path in [
+            "Projects/my-project.md",
+            "Projects/other.md",
+            "Shared/Projects/my-project.md",
+        ] {
+            index.upsert(
+                path.to_string(),
+                NoteEntry {
+                    path: path.to_string(),
+                    title: extract_title(path, ""),
+                    content: String::new(),
+                    mtime: 0,
+                },
+            );
+        }
+
+        let similar = index.similar_paths("Projects/myproj.md", 3, "");
+        assert_eq!(similar[0].0, "Projects/my-project.md");
+        assert!(similar.iter().all(|(p, _)| !p.ends_with("other.md")));
+
+        // only under the prefix, matched against the path relative to it
+        let similar = index.similar_paths("projects/myproj.md", 3, "Shared/");
+        assert_eq!(similar.len(), 1);
+        assert_eq!(similar[0].0, "Shared/Projects/my-project.md");
+        assert!(index.similar_paths("nothing like it.md", 3, "").is_empty());
+    }
+
     #[test]
     fn test_extract_snippet() {
         let content = "This is some really long content that contains many words. The word meeting appears somewhere in the middle of this very long text. And then there is much more content after that which goes on and on for quite a while to make sure we have enough text to actually truncate.";
@@ -309,4 +723,130 @@ mod tests {
             content.len()
         );
     }
+
+    #[test]
+    fn test_extract_snippet_without_spaces() {
+        // no spaces to find, and every character is more than one byte
+        let content = "今日は".repeat(40) + "会議の議事録" + &"明日の予定".repeat(40);
+        let snippet = extract_snippet(&content, "議事録").unwrap();
+        assert!(snippet.contains("議事録"));
+        assert!(snippet.starts_with("...") && snippet.ends_with("..."));
+        assert_eq!(
+            snippet.trim_matches('.').chars().count(),
+            2 * SNIPPET_CONTEXT
+        );
+
+        let snippet = extract_snippet("我们今天开会讨论了项目计划", "项目").unwrap();
+        assert_eq!(snippet, "我们今天开会讨论了项目计划");
+
+        // lowercasing İ makes it longer, which used to throw the match position off
+        let content = "İ".repeat(60) + " target";
+        assert!(
+            extract_snippet(&content, "target")
+                .unwrap()
+                .contains("target")
+        );
+    }
+
+    #[test]
+    fn test_extract_title_non_latin() {
+        assert_eq!(extract_title("a.md", "# 会議メモ\n本文"), "会議メモ");
+        assert_eq!(extract_title("a.md", "#\t项目计划\n"), "项目计划");
+        assert_eq!(
+            extract_title("日記/2024年1月1日.md", "本文"),
+            "2024年1月1日"
+        );
+    }
+
+    #[test]
+    fn test_lowercase_ids_are_one_note() {
+        let note = |path: &str| NoteEntry {
+            path: path.to_string(),
+            title: path.to_string(),
+            content: "#idea".to_string(),
+            mtime: 1,
+        };
+        let mut index = SearchIndex::new().with_path_style(PathStyle::Lowercase);
+        index.upsert("Ideas/Plan.md".to_string(), note("Ideas/Plan.md"));
+        assert_eq!(index.cached_content("ideas/PLAN.md", 1, 5), Some("#idea"));
+
+        // the same doc under another case replaces the entry rather than adding one
+        index.upsert("ideas/plan.md".to_string(), note("ideas/plan.md"));
+        assert_eq!(index.len(), 1);
+        assert_eq!(index.tag_counts(""), vec![("idea".to_string(), 1)]);
+
+        // a hard delete only has the lowercased id
+        index.remove("ideas/plan.md");
+        assert!(index.is_empty());
+        assert!(index.tag_counts("").is_empty());
+
+        let mut index = SearchIndex::new();
+        index.upsert("Plan.md".to_string(), note("Plan.md"));
+        index.upsert("plan.md".to_string(), note("plan.md"));
+        assert_eq!(index.len(), 2);
+    }
+
+    #[test]
+    fn test_tag_counts_follow_updates() {
+        let note = |content: &str| NoteEntry {
+            path: String::new(),
+            title: String::new(),
+            content: content.to_string(),
+            mtime: 0,
+        };
+        let mut index = SearchIndex::new();
+        index.upsert("a.md".to_string(), note("#work #idea"));
+        index.upsert("b.md".to_string(), note("#work"));
+        index.upsert("c.md".to_string(), note("---\ntags: [home]\n---\n"));
+        assert_eq!(
+            index.tag_counts(""),
+            vec![
+                ("work".to_string(), 2),
+                ("home".to_string(), 1),
+                ("idea".to_string(), 1),
+            ]
+        );
+
+        index.upsert("a.md".to_string(), note("no tags now"));
+        index.remove("c.md");
+        assert_eq!(index.tag_counts(""), vec![("work".to_string(), 1)]);
+
+        index.upsert("sub/d.md".to_string(), note("#idea #work"));
+        index.upsert("sub/e.md".to_string(), note("#idea"));
+        assert_eq!(
+            index.tag_counts("sub/"),
+            vec![("idea".to_string(), 2), ("work".to_string(), 1)]
+        );
+    }
+
+    #[test]
+    fn test_backfill() {
+        let note = |path: &str, content: &str| NoteEntry {
+            path: path.to_string(),
+            title: path.to_string(),
+            content: content.to_string(),
+            mtime: 0,
+        };
+        let mut index = SearchIndex::new();
+        assert!(index.is_ready());
+        index.start_loading();
+        assert!(!index.is_ready());
+
+        // the watcher gets to these before the snapshot is loaded
+        index.upsert("edited.md".to_string(), note("edited.md", "new"));
+        index.remove("deleted.md");
+
+        index.backfill(vec![note("edited.md", "old"), note("deleted.md", "gone")]);
+        index.backfill(vec![note("other.md", "untouched")]);
+        assert!(!index.is_ready());
+        index.finish_loading();
+        assert!(index.is_ready());
+        assert_eq!(index.len(), 2);
+        assert_eq!(index.cached_content("edited.md", 0, 3), Some("new"));
+        assert!(index.cached_content("deleted.md", 0, 4).is_none());
+
+        // a full resync got there first
+        index.backfill(vec![note("late.md", "")]);
+        assert_eq!(index.len(), 2);
+    }
 }
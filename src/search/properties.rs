@@ -0,0 +1,186 @@
+//! Querying notes by their frontmatter properties, e.g. `status = active` or `priority >= 3`.
+//! The properties are parsed once when a note is indexed, so a query is just a scan of those
+
+use super::SearchIndex;
+use serde_json::Value;
+use std::cmp::Ordering;
+
+/// How a property is compared with the value in a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// The note has the property at all, whatever its value
+    Exists,
+}
+
+impl Comparison {
+    pub fn parse(op: &str) -> Option<Self> {
+        Some(match op.trim().to_lowercase().as_str() {
+            "eq" | "=" | "==" => Self::Eq,
+            "ne" | "!=" => Self::Ne,
+            "lt" | "<" => Self::Lt,
+            "le" | "<=" => Self::Le,
+            "gt" | ">" => Self::Gt,
+            "ge" | ">=" => Self::Ge,
+            "exists" => Self::Exists,
+            _ => return None,
+        })
+    }
+
+    fn matches(self, property: &Value, query: &Value) -> bool {
+        match self {
+            Self::Exists => true,
+            // a list property equals anything in it, the way `tags: [a, b]` has tag a
+            Self::Eq => values(property).any(|v| equal(v, query)),
+            Self::Ne => !values(property).any(|v| equal(v, query)),
+            Self::Lt | Self::Le | Self::Gt | Self::Ge => values(property).any(|v| {
+                compare(v, query).is_some_and(|order| match self {
+                    Self::Lt => order.is_lt(),
+                    Self::Le => order.is_le(),
+                    Self::Gt => order.is_gt(),
+                    _ => order.is_ge(),
+                })
+            }),
+        }
+    }
+}
+
+/// A property's value, or each of its items if it's a list
+fn values(value: &Value) -> Box<dyn Iterator<Item = &Value> + '_> {
+    match value {
+        Value::Array(items) => Box::new(items.iter()),
+        value => Box::new(std::iter::once(value)),
+    }
+}
+
+/// A number, or a string that is one (`priority: "3"` and a query for 3 should meet)
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.trim().to_lowercase()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Numbers compare as numbers, everything else as case-insensitive text
+fn equal(property: &Value, query: &Value) -> bool {
+    if let (Some(a), Some(b)) = (number(property), number(query)) {
+        return a == b;
+    }
+    text(property).is_some_and(|a| text(query) == Some(a))
+}
+
+/// Numbers by value, strings as case-insensitive text (which gets ISO dates right), anything
+/// else not at all
+fn compare(property: &Value, query: &Value) -> Option<Ordering> {
+    if let (Some(a), Some(b)) = (number(property), number(query)) {
+        return a.partial_cmp(&b);
+    }
+    match (property, query) {
+        (Value::String(_), Value::String(_)) => Some(text(property)?.cmp(&text(query)?)),
+        _ => None,
+    }
+}
+
+impl SearchIndex {
+    /// Notes under `prefix` with a `key` property (matched case-insensitively) that compares
+    /// with `value` as asked, as (path, title, the property's value) sorted by path
+    pub fn query_properties(
+        &self,
+        key: &str,
+        comparison: Comparison,
+        value: &Value,
+        prefix: &str,
+    ) -> Vec<(String, String, Value)> {
+        let mut found: Vec<(String, String, Value)> = self
+            .note_properties
+            .iter()
+            .filter(|(path, _)| path.starts_with(prefix))
+            .filter_map(|(path, props)| {
+                let (_, property) = props.iter().find(|(k, _)| k.eq_ignore_ascii_case(key))?;
+                if !comparison.matches(property, value) {
+                    return None;
+                }
+                let title = self.notes.get(path).map(|n| n.title.clone());
+                Some((path.clone(), title.unwrap_or_default(), property.clone()))
+            })
+            .collect();
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::NoteEntry;
+    use serde_json::json;
+
+    fn index(notes: &[(&str, &str)]) -> SearchIndex {
+        let mut index = SearchIndex::new();
+        for (path, content) in notes {
+            index.upsert(
+                path.to_string(),
+                NoteEntry {
+                    path: path.to_string(),
+                    title: path.to_string(),
+                    content: content.to_string(),
+                    mtime: 0,
+                },
+            );
+        }
+        index
+    }
+
+    #[test]
+    fn test_query_properties() {
+        let index = index(&[
+            (
+                "a.md",
+                "---\nStatus: Active\npriority: 3\ndue: 2024-03-01\n---\n",
+            ),
+            (
+                "b.md",
+                "---\nstatus: done\npriority: \"1\"\ntags: [x, y]\n---\n",
+            ),
+            ("c.md", "---\nstatus: [active, waiting]\n---\n"),
+            ("d.md", "no frontmatter here"),
+        ]);
+        let paths = |key: &str, op: &str, value: Value| -> Vec<String> {
+            index
+                .query_properties(key, Comparison::parse(op).unwrap(), &value, "")
+                .into_iter()
+                .map(|(path, _, _)| path)
+                .collect()
+        };
+
+        assert_eq!(paths("status", "eq", json!("active")), vec!["a.md", "c.md"]);
+        assert_eq!(paths("status", "!=", json!("active")), vec!["b.md"]);
+        assert_eq!(paths("priority", ">=", json!(2)), vec!["a.md"]);
+        assert_eq!(paths("priority", "lt", json!("2")), vec!["b.md"]);
+        assert_eq!(paths("priority", "=", json!(1)), vec!["b.md"]);
+        assert_eq!(paths("due", "<", json!("2024-04-01")), vec!["a.md"]);
+        // strings order regardless of case, "Active" isn't before "done" just for the capital
+        assert_eq!(paths("status", ">", json!("BETA")), vec!["b.md", "c.md"]);
+        assert_eq!(paths("status", "<=", json!("ACTIVE")), vec!["a.md", "c.md"]);
+        assert_eq!(paths("tags", "exists", Value::Null), vec!["b.md"]);
+        assert!(Comparison::parse("like").is_none());
+
+        let found = index.query_properties("STATUS", Comparison::Eq, &json!("done"), "");
+        assert_eq!(found[0].2, json!("done"));
+    }
+}
@@ -0,0 +1,225 @@
+//! "More like this": scores notes against each other by the terms they share, weighted with
+//! TF-IDF so that words every note uses don't count for much. The term counts are kept in the
+//! index as notes change, no embeddings needed
+
+use super::SearchIndex;
+use std::collections::{HashMap, HashSet};
+
+/// How many of a note's highest-weighted terms are used to find its relatives
+const SIGNIFICANT_TERMS: usize = 25;
+
+/// Shared terms reported per result
+const SHARED_TERMS_SHOWN: usize = 5;
+
+/// Words too common to say anything about what a note is about
+const STOPWORDS: &[&str] = &[
+    "about", "after", "again", "all", "also", "and", "any", "are", "because", "been", "before",
+    "being", "but", "can", "could", "did", "does", "doing", "done", "each", "for", "from", "had",
+    "has", "have", "her", "here", "him", "his", "how", "into", "its", "just", "like", "more",
+    "most", "not", "now", "only", "other", "our", "out", "over", "same", "she", "should", "some",
+    "such", "than", "that", "the", "their", "them", "then", "there", "these", "they", "this",
+    "those", "through", "too", "under", "until", "very", "was", "were", "what", "when", "where",
+    "which", "while", "who", "why", "will", "with", "would", "you", "your",
+];
+
+/// A note scored against the one related_notes was asked about
+#[derive(Debug, Clone)]
+pub struct RelatedNote {
+    pub path: String,
+    pub title: String,
+    /// Cosine similarity over the source note's significant terms, 0 to 1
+    pub score: f64,
+    /// The terms that contributed most to the score
+    pub shared_terms: Vec<String>,
+}
+
+/// Lowercased words of three or more letters, minus numbers and stopwords
+fn terms(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.chars().count() >= 3 && !w.chars().all(|c| c.is_numeric()))
+        .map(str::to_lowercase)
+        .filter(|w| !STOPWORDS.contains(&w.as_str()))
+}
+
+fn term_counts(text: &str) -> HashMap<String, f64> {
+    let mut counts = HashMap::new();
+    for term in terms(text) {
+        *counts.entry(term).or_default() += 1.0;
+    }
+    counts
+}
+
+impl SearchIndex {
+    /// Counts `content`'s terms for `path`, replacing whatever it had
+    pub(super) fn count_terms(&mut self, path: &str, content: &str) {
+        self.forget_terms(path);
+        let counts = term_counts(content);
+        for term in counts.keys() {
+            *self.term_doc_counts.entry(term.clone()).or_default() += 1;
+        }
+        self.note_terms.insert(path.to_string(), counts);
+    }
+
+    pub(super) fn forget_terms(&mut self, path: &str) {
+        for term in self.note_terms.remove(path).unwrap_or_default().into_keys() {
+            if let Some(count) = self.term_doc_counts.get_mut(&term) {
+                *count -= 1;
+                if *count == 0 {
+                    self.term_doc_counts.remove(&term);
+                }
+            }
+        }
+    }
+
+    /// The notes under `prefix` most similar to `path`, best first. None if the note isn't indexed
+    pub fn related(&self, path: &str, limit: usize, prefix: &str) -> Option<Vec<RelatedNote>> {
+        let path = self.indexed_path(path)?;
+        let counts = &self.note_terms;
+
+        // smoothed so a term in every note still weighs a little rather than nothing
+        let total = self.notes.len() as f64;
+        let idf = |term: &str| {
+            let doc_freq = self.term_doc_counts.get(term).copied().unwrap_or(1);
+            (1.0 + total / doc_freq as f64).ln()
+        };
+
+        let weights = |terms: &HashMap<String, f64>| -> HashMap<String, f64> {
+            terms
+                .iter()
+                .map(|(term, count)| (term.clone(), (1.0 + count.ln()) * idf(term)))
+                .collect()
+        };
+
+        let Some(terms) = counts.get(path) else {
+            return Some(Vec::new());
+        };
+        let mut source: Vec<(String, f64)> = weights(terms).into_iter().collect();
+        source.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        source.truncate(SIGNIFICANT_TERMS);
+        let source_norm = source.iter().map(|(_, w)| w * w).sum::<f64>().sqrt();
+        if source_norm == 0.0 {
+            return Some(Vec::new());
+        }
+        let significant: HashSet<&str> = source.iter().map(|(t, _)| t.as_str()).collect();
+
+        let mut related: Vec<RelatedNote> = counts
+            .iter()
+            .filter(|(other, _)| *other != path && other.starts_with(prefix))
+            .filter_map(|(other, terms)| {
+                if !terms.keys().any(|t| significant.contains(t.as_str())) {
+                    return None;
+                }
+                let other_weights = weights(terms);
+                let other_norm = other_weights.values().map(|w| w * w).sum::<f64>().sqrt();
+
+                let mut shared: Vec<(&str, f64)> = source
+                    .iter()
+                    .filter_map(|(term, w)| Some((term.as_str(), w * other_weights.get(term)?)))
+                    .collect();
+                let dot: f64 = shared.iter().map(|(_, w)| w).sum();
+                shared.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+                let note = self.notes.get(other)?;
+                Some(RelatedNote {
+                    path: note.path.clone(),
+                    title: note.title.clone(),
+                    score: dot / (source_norm * other_norm),
+                    shared_terms: shared
+                        .into_iter()
+                        .take(SHARED_TERMS_SHOWN)
+                        .map(|(t, _)| t.to_string())
+                        .collect(),
+                })
+            })
+            .collect();
+
+        related.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then_with(|| a.path.cmp(&b.path))
+        });
+        related.truncate(limit);
+        Some(related)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::NoteEntry;
+
+    fn index(notes: &[(&str, &str)]) -> SearchIndex {
+        let mut index = SearchIndex::new();
+        for (path, content) in notes {
+            index.upsert(
+                path.to_string(),
+                NoteEntry {
+                    path: path.to_string(),
+                    title: path.to_string(),
+                    content: content.to_string(),
+                    mtime: 0,
+                },
+            );
+        }
+        index
+    }
+
+    #[test]
+    fn test_terms_skip_noise() {
+        let found: Vec<_> = terms("The Rust compiler, and 2024 of it: borrow-checker!").collect();
+        assert_eq!(found, vec!["rust", "compiler", "borrow", "checker"]);
+    }
+
+    #[test]
+    fn test_related_ranks_by_shared_terms() {
+        let index = index(&[
+            ("rust.md", "rust borrow checker lifetimes ownership traits"),
+            (
+                "more-rust.md",
+                "ownership and lifetimes in rust, the borrow checker",
+            ),
+            ("traits.md", "rust traits and generics"),
+            ("cooking.md", "sourdough bread starter hydration"),
+        ]);
+
+        let related = index.related("rust.md", 10, "").unwrap();
+        let paths: Vec<_> = related.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(paths, vec!["more-rust.md", "traits.md"]);
+        assert!(related[0].score > related[1].score);
+        assert!(related[0].shared_terms.contains(&"lifetimes".to_string()));
+
+        assert_eq!(index.related("rust.md", 1, "").unwrap().len(), 1);
+        assert!(index.related("missing.md", 10, "").is_none());
+    }
+
+    #[test]
+    fn test_term_counts_follow_changes() {
+        let mut index = index(&[
+            ("rust.md", "rust borrow checker"),
+            ("other.md", "rust borrow checker"),
+            ("cooking.md", "sourdough bread"),
+        ]);
+        assert_eq!(index.term_doc_counts["rust"], 2);
+
+        let entry = |path: &str, content: &str| NoteEntry {
+            path: path.to_string(),
+            title: path.to_string(),
+            content: content.to_string(),
+            mtime: 0,
+        };
+        index.upsert("other.md".to_string(), entry("other.md", "sourdough bread"));
+        assert_eq!(index.term_doc_counts["rust"], 1);
+        assert_eq!(index.term_doc_counts["bread"], 2);
+        let paths: Vec<_> = index
+            .related("cooking.md", 10, "")
+            .unwrap()
+            .into_iter()
+            .map(|r| r.path)
+            .collect();
+        assert_eq!(paths, vec!["other.md"]);
+
+        index.remove("rust.md");
+        assert!(!index.term_doc_counts.contains_key("rust"));
+        assert!(!index.note_terms.contains_key("rust.md"));
+    }
+}
@@ -0,0 +1,147 @@
+//! Numbers about the vault as a whole: how many notes, how big, where they are. Worked out
+//! from the index on each call, which is cheap next to reading every note from CouchDB
+
+use super::SearchIndex;
+use std::collections::HashMap;
+
+/// An overview of the notes under some prefix
+#[derive(Debug, Clone, PartialEq)]
+pub struct VaultStats {
+    /// Markdown notes
+    pub notes: usize,
+    /// Anything else that's indexed, like attachments
+    pub other_files: usize,
+    /// Notes in each top-level folder under the prefix (subfolders included), most first.
+    /// Notes right at the prefix aren't in any
+    pub folders: Vec<(String, usize)>,
+    /// Distinct tags used by the notes
+    pub tags: usize,
+    /// None if the index only has titles (--index-content=false)
+    pub sizes: Option<SizeStats>,
+}
+
+/// Note sizes, in bytes
+#[derive(Debug, Clone, PartialEq)]
+pub struct SizeStats {
+    pub total: usize,
+    pub average: usize,
+    pub median: usize,
+    /// The biggest notes as (path, size), biggest first
+    pub largest: Vec<(String, usize)>,
+}
+
+impl SearchIndex {
+    /// Stats for the notes under `prefix`, with the `largest` biggest ones
+    pub fn stats(&self, prefix: &str, largest: usize) -> VaultStats {
+        let notes: Vec<_> = self
+            .notes
+            .values()
+            .filter(|note| note.path.starts_with(prefix))
+            .collect();
+        let (markdown, other): (Vec<_>, Vec<_>) = notes
+            .into_iter()
+            .partition(|note| note.path.ends_with(".md"));
+
+        let mut folders: HashMap<&str, usize> = HashMap::new();
+        for note in &markdown {
+            if let Some((folder, _)) = note.path[prefix.len()..].split_once('/') {
+                *folders.entry(folder).or_default() += 1;
+            }
+        }
+        let mut folders: Vec<(String, usize)> = folders
+            .into_iter()
+            .map(|(folder, count)| (format!("{}/", folder), count))
+            .collect();
+        folders.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        let sizes = self.index_content.then(|| {
+            let mut sizes: Vec<(&str, usize)> = markdown
+                .iter()
+                .map(|note| (note.path.as_str(), note.content.len()))
+                .collect();
+            sizes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+            let total = sizes.iter().map(|(_, size)| size).sum();
+            let median = match sizes.len() {
+                0 => 0,
+                n if n % 2 == 1 => sizes[n / 2].1,
+                n => (sizes[n / 2 - 1].1 + sizes[n / 2].1) / 2,
+            };
+            SizeStats {
+                total,
+                average: total.checked_div(sizes.len()).unwrap_or(0),
+                median,
+                largest: sizes
+                    .into_iter()
+                    .take(largest)
+                    .map(|(path, size)| (path.to_string(), size))
+                    .collect(),
+            }
+        });
+
+        VaultStats {
+            notes: markdown.len(),
+            other_files: other.len(),
+            folders,
+            tags: self.tag_counts(prefix).len(),
+            sizes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::search::{NoteEntry, SearchIndex};
+
+    fn note(path: &str, content: &str) -> NoteEntry {
+        NoteEntry {
+            path: path.to_string(),
+            title: path.to_string(),
+            content: content.to_string(),
+            mtime: 0,
+        }
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut index = SearchIndex::new();
+        for (path, content) in [
+            ("Home.md", "#home"),
+            ("Projects/Alpha.md", "#project alpha"),
+            ("Projects/Old/Beta.md", "#project"),
+            ("Daily/2024-01-01.md", "a much longer note than the others"),
+            ("Daily/pic.png", ""),
+        ] {
+            index.upsert(path.to_string(), note(path, content));
+        }
+
+        let stats = index.stats("", 2);
+        assert_eq!(stats.notes, 4);
+        assert_eq!(stats.other_files, 1);
+        assert_eq!(stats.tags, 2);
+        assert_eq!(
+            stats.folders,
+            vec![("Projects/".to_string(), 2), ("Daily/".to_string(), 1)]
+        );
+        let sizes = stats.sizes.unwrap();
+        assert_eq!(sizes.total, 5 + 14 + 8 + 34);
+        assert_eq!(sizes.average, 61 / 4);
+        assert_eq!(sizes.median, (8 + 14) / 2);
+        assert_eq!(
+            sizes.largest,
+            vec![
+                ("Daily/2024-01-01.md".to_string(), 34),
+                ("Projects/Alpha.md".to_string(), 14)
+            ]
+        );
+
+        let stats = index.stats("Projects/", 10);
+        assert_eq!(stats.notes, 2);
+        assert_eq!(stats.folders, vec![("Old/".to_string(), 1)]);
+        assert_eq!(stats.tags, 1);
+
+        let mut index = SearchIndex::without_content();
+        index.upsert("Home.md".to_string(), note("Home.md", "#home"));
+        let stats = index.stats("", 10);
+        assert_eq!((stats.notes, stats.tags, stats.sizes), (1, 1, None));
+    }
+}
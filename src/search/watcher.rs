@@ -1,35 +1,135 @@
-use crate::couchdb::{CouchDbClient, NoteDoc};
+use crate::couchdb::{ChangeEvent, CouchDbClient, CouchDbError, NoteDoc};
 use crate::search::{NoteEntry, SearchIndex, extract_title};
 use anyhow::Result;
-use futures::StreamExt;
+use futures::{FutureExt, StreamExt};
+use rand::Rng;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 use tokio_util::sync::CancellationToken;
 
-/// Change event from CouchDB _changes feed
-#[derive(Debug, serde::Deserialize)]
-pub struct ChangeEvent {
-    pub seq: String,
-    pub id: String,
-    #[serde(default)]
-    pub deleted: bool,
-    pub doc: Option<serde_json::Value>,
+/// How long to wait before reconnecting a dropped changes feed: `initial` after the first
+/// failure, doubling with each one after that up to `max`. Every wait gets up to a quarter
+/// either way of jitter, so a bunch of yamos instances don't all hit couchdb at once when it
+/// comes back
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(5),
+            max: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Backoff {
+    /// The wait after `failures` failed attempts in a row (at least 1), before jitter
+    fn delay(&self, failures: u32) -> Duration {
+        let doublings = failures.saturating_sub(1).min(31);
+        self.initial
+            .saturating_mul(1 << doublings)
+            .min(self.max.max(self.initial))
+    }
+
+    /// How long something has to stay up before its next failure starts over from the first
+    /// wait
+    fn reset_after(&self) -> Duration {
+        self.max.max(self.initial) * 2
+    }
+
+    fn jittered(&self, failures: u32) -> Duration {
+        self.delay(failures)
+            .mul_f64(rand::rng().random_range(0.75..=1.25))
+    }
+}
+
+/// How a changes feed ended, when it wasn't an error
+#[derive(Debug, PartialEq)]
+enum FeedEnd {
+    /// The watcher was told to stop
+    Cancelled,
+    /// CouchDB closed the connection
+    Closed,
+    /// The seq was too old to resume from, so the index was loaded from scratch instead
+    Resynced,
 }
 
 /// Watches CouchDB _changes feed and updates the search index
+#[derive(Clone)]
 pub struct ChangesWatcher {
     db: CouchDbClient,
     index: Arc<RwLock<SearchIndex>>,
+    backoff: Backoff,
 }
 
 impl ChangesWatcher {
     pub fn new(db: CouchDbClient, index: Arc<RwLock<SearchIndex>>) -> Self {
-        Self { db, index }
+        Self {
+            db,
+            index,
+            backoff: Backoff::default(),
+        }
+    }
+
+    /// Waits between reconnects like this instead of a flat 5s
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Runs the watcher in a task of its own and starts it again (after the same backoff as a
+    /// reconnect) if it stops, errors or panics, so a bug can't quietly stop the index updating.
+    /// Returns once `cancel` fires
+    pub async fn supervise(self, cancel: CancellationToken) {
+        self.supervise_with(cancel, |watcher, cancel| async move {
+            watcher.run(cancel).await
+        })
+        .await
+    }
+
+    /// [`ChangesWatcher::supervise`], restarting `run` instead of [`ChangesWatcher::run`]
+    async fn supervise_with<F, Fut>(self, cancel: CancellationToken, run: F)
+    where
+        F: Fn(Self, CancellationToken) -> Fut,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let mut restarts = 0;
+        loop {
+            let started = Instant::now();
+            let result = tokio::spawn(run(self.clone(), cancel.clone())).await;
+            self.index.write().await.watcher_connected = false;
+            if cancel.is_cancelled() {
+                return;
+            }
+            match result {
+                Ok(Ok(())) => tracing::warn!("Changes watcher stopped, restarting it"),
+                Ok(Err(e)) => tracing::error!("Changes watcher failed, restarting it: {}", e),
+                Err(e) => tracing::error!("Changes watcher died, restarting it: {}", e),
+            }
+
+            // a watcher that ran fine for a while before dying starts over from the first wait
+            if started.elapsed() > self.backoff.reset_after() {
+                restarts = 0;
+            }
+            restarts += 1;
+            tokio::select! {
+                _ = tokio::time::sleep(self.backoff.jittered(restarts)) => {}
+                _ = cancel.cancelled() => return,
+            }
+        }
     }
 
     /// Run the changes watcher. Reconnects automatically on errors.
     pub async fn run(&self, cancel: CancellationToken) -> Result<()> {
+        // failed attempts since the feed last worked
+        let mut failures = 0;
         loop {
             // Get current seq to resume from
             let since = {
@@ -42,16 +142,33 @@ impl ChangesWatcher {
 
             tracing::info!("Starting changes watcher from seq: {}", since_param);
 
-            match self.watch_changes(since_param, &cancel).await {
-                Ok(()) => {
-                    // Clean exit (cancelled)
+            match self
+                .watch_changes(since_param, &cancel, &mut failures)
+                .await
+            {
+                Ok(FeedEnd::Cancelled) => {
                     tracing::info!("Changes watcher stopped");
                     break;
                 }
-                Err(e) => {
-                    tracing::warn!("Changes feed error, reconnecting in 5s: {}", e);
+                // carry on from where the resync got to
+                Ok(FeedEnd::Resynced) => {}
+                ended => {
+                    self.index.write().await.watcher_connected = false;
+                    failures += 1;
+                    let wait = self.backoff.jittered(failures);
+                    match ended {
+                        Err(e) => tracing::warn!(
+                            "Changes feed error, reconnecting in {:.1}s: {}",
+                            wait.as_secs_f64(),
+                            e
+                        ),
+                        Ok(_) => tracing::info!(
+                            "CouchDB closed the changes feed, reconnecting in {:.1}s",
+                            wait.as_secs_f64()
+                        ),
+                    }
                     tokio::select! {
-                        _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+                        _ = tokio::time::sleep(wait) => {}
                         _ = cancel.cancelled() => {
                             tracing::info!("Changes watcher cancelled during reconnect wait");
                             break;
@@ -63,118 +180,122 @@ impl ChangesWatcher {
         Ok(())
     }
 
-    async fn watch_changes(&self, since: &str, cancel: &CancellationToken) -> Result<()> {
-        let url = format!(
-            "{}/_changes?feed=continuous&include_docs=true&since={}&heartbeat=30000",
-            self.db.db_url(),
-            urlencoding::encode(since)
-        );
-
-        let response = self.db.get(&url).await?;
-
-        if !response.status().is_success() {
-            let status = response.status();
-            let body = response.text().await.unwrap_or_default();
-
-            // If seq is invalid (too old/compacted), trigger full resync
-            if body.contains("since") || status.as_u16() == 400 {
+    async fn watch_changes(
+        &self,
+        since: &str,
+        cancel: &CancellationToken,
+        failures: &mut u32,
+    ) -> Result<FeedEnd> {
+        let stream = match self.db.changes_stream(since, cancel.clone()).await {
+            Ok(stream) => {
+                self.index.write().await.watcher_connected = true;
+                stream
+            }
+            Err(CouchDbError::InvalidSeq(_)) => {
+                // If seq is invalid (too old/compacted), trigger full resync
                 tracing::warn!("Invalid seq, triggering full resync");
                 self.full_resync().await?;
-                return Ok(());
+                return Ok(FeedEnd::Resynced);
             }
+            Err(e) => return Err(e.into()),
+        };
+        let mut stream = std::pin::pin!(stream);
 
-            return Err(anyhow::anyhow!(
-                "Changes feed request failed: {} - {}",
-                status,
-                body
-            ));
+        // Connecting isn't enough to count as working again, or a couchdb that takes the
+        // connection and then drops it would be retried at the shortest wait forever. It has to
+        // deliver a change or stay up a while
+        let connected = Instant::now();
+        // Ends on cancel or when the server closes the connection
+        while let Some(change) = stream.next().await {
+            let change = match change {
+                Ok(change) => change,
+                Err(e) => {
+                    if connected.elapsed() > self.backoff.reset_after() {
+                        *failures = 0;
+                    }
+                    return Err(e.into());
+                }
+            };
+            *failures = 0;
+            let (seq, id) = (change.seq.clone(), change.id.clone());
+            self.isolate(seq, &id, self.process_change(change)).await;
         }
 
-        let mut stream = response.bytes_stream();
-        let mut buffer = String::new();
+        Ok(if cancel.is_cancelled() {
+            FeedEnd::Cancelled
+        } else {
+            FeedEnd::Closed
+        })
+    }
 
-        loop {
-            tokio::select! {
-                chunk = stream.next() => {
-                    match chunk {
-                        Some(Ok(bytes)) => {
-                            buffer.push_str(&String::from_utf8_lossy(&bytes));
-
-                            // Process complete lines (CouchDB sends one JSON per line)
-                            while let Some(pos) = buffer.find('\n') {
-                                let line = &buffer[..pos];
-                                let line = line.trim();
-
-                                if !line.is_empty()
-                                    && let Err(e) = self.process_change(line).await
-                                {
-                                    tracing::warn!("Error processing change: {}", e);
-                                }
-
-                                buffer = buffer[pos + 1..].to_string();
-                            }
-                        }
-                        Some(Err(e)) => {
-                            return Err(anyhow::anyhow!("Stream error: {}", e));
-                        }
-                        None => {
-                            // Stream ended (server closed connection)
-                            tracing::debug!("Changes stream ended");
-                            return Ok(());
-                        }
-                    }
-                }
-                _ = cancel.cancelled() => {
-                    return Ok(());
-                }
+    /// Runs `work` on the change at `seq`, logging it if that fails. One that panics is
+    /// skipped rather than seen again, since one odd doc shouldn't stop the feed
+    async fn isolate(&self, seq: String, id: &str, work: impl Future<Output = Result<()>>) {
+        match AssertUnwindSafe(work).catch_unwind().await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::warn!("Error processing change: {}", e),
+            Err(_) => {
+                tracing::error!("Panicked processing change to {}, skipping it", id);
+                self.index.write().await.last_seq = Some(seq);
             }
         }
     }
 
-    async fn process_change(&self, line: &str) -> Result<()> {
-        let change: ChangeEvent = serde_json::from_str(line)?;
-
+    async fn process_change(&self, change: ChangeEvent) -> Result<()> {
         // Skip chunk documents (h:*) and system docs (_*)
-        if change.id.starts_with("h:") || change.id.starts_with('_') {
+        if self.db.is_chunk_id(&change.id) || change.id.starts_with('_') {
             // Still update seq
             let mut index = self.index.write().await;
             index.last_seq = Some(change.seq);
             return Ok(());
         }
 
+        let style = self.db.path_style();
         if change.deleted {
-            // Hard-deleted: remove from index and update seq
+            // Hard-deleted: remove from index and update seq. There's no doc left to get the
+            // path from, but the index finds the note by its id whatever the path's case
+            let path = style.note_path(&change.id, None);
             let mut index = self.index.write().await;
-            index.remove(&change.id);
+            index.remove(&path);
             index.last_seq = Some(change.seq);
-            tracing::debug!("Removed from search index: {}", change.id);
+            tracing::debug!("Removed from search index: {}", path);
         } else if let Some(doc_value) = change.doc {
-            // Parse the note document
-            let note_doc: NoteDoc = serde_json::from_value(doc_value)?;
+            // Not everything in a LiveSync database is a note (design docs, plugin config, docs
+            // from newer schemas...). Those aren't errors, there's just nothing to index
+            let note_doc: NoteDoc = match serde_json::from_value(doc_value) {
+                Ok(doc) => doc,
+                Err(e) => {
+                    tracing::debug!("Skipping {}, not a note document: {}", change.id, e);
+                    let mut index = self.index.write().await;
+                    index.last_seq = Some(change.seq);
+                    return Ok(());
+                }
+            };
 
+            let path = style.note_path(&change.id, Some(&note_doc.path));
             if note_doc.deleted == Some(true) {
                 // Soft-deleted: remove from index and update seq
                 let mut index = self.index.write().await;
-                index.remove(&change.id);
+                index.remove(&path);
                 index.last_seq = Some(change.seq);
-                tracing::debug!("Removed soft-deleted from search index: {}", change.id);
+                tracing::debug!("Removed soft-deleted from search index: {}", path);
             } else {
                 // Active note: fetch content (without holding lock), then update index
                 let content = self.db.decode_content(&note_doc).await?;
-                let title = extract_title(&change.id, &content);
+                let title = extract_title(&path, &content);
 
                 let mut index = self.index.write().await;
                 index.upsert(
-                    change.id.clone(),
+                    path.clone(),
                     NoteEntry {
-                        path: change.id.clone(),
+                        path: path.clone(),
                         title,
                         content,
                         mtime: note_doc.mtime,
                     },
                 );
                 index.last_seq = Some(change.seq);
-                tracing::debug!("Updated search index: {}", change.id);
+                tracing::debug!("Updated search index: {}", path);
             }
         } else {
             // No doc included (shouldn't happen with include_docs=true, but handle gracefully)
@@ -185,28 +306,59 @@ impl ChangesWatcher {
         Ok(())
     }
 
+    /// Load the notes into an index that started empty (--no-startup-resync) while the watcher
+    /// keeps it up to date with changes, retrying until it works
+    pub async fn backfill(&self, cancel: CancellationToken) {
+        tracing::info!("Loading the search index in the background");
+        loop {
+            match self.load_in_background().await {
+                Ok(()) => {
+                    let index = self.index.read().await;
+                    tracing::info!("Search index loaded with {} notes", index.len());
+                    return;
+                }
+                Err(e) => {
+                    tracing::warn!("Couldn't load the search index, retrying in 5s: {}", e);
+                    tokio::select! {
+                        _ = tokio::time::sleep(Duration::from_secs(5)) => {}
+                        _ = cancel.cancelled() => return,
+                    }
+                }
+            }
+        }
+    }
+
+    /// One go at [`ChangesWatcher::backfill`]. Notes from a go that failed partway are kept,
+    /// and the next go skips them like anything else the index already has
+    async fn load_in_background(&self) -> Result<()> {
+        let (mut loader, _) = self.db.load_notes().await?;
+        while let Some(batch) = loader.next_batch().await? {
+            let mut index = self.index.write().await;
+            if index.is_ready() {
+                // a full resync got there first
+                return Ok(());
+            }
+            index.backfill(note_entries(batch));
+        }
+        self.index.write().await.finish_loading();
+        Ok(())
+    }
+
     /// Perform a full resync of the index
     async fn full_resync(&self) -> Result<()> {
         tracing::info!("Performing full search index resync");
 
-        let (notes, last_seq) = self.db.get_all_notes_with_content().await?;
-
-        let mut index = self.index.write().await;
-        index.clear();
-
-        for (path, content, mtime) in notes {
-            let title = extract_title(&path, &content);
-            index.upsert(
-                path.clone(),
-                NoteEntry {
-                    path,
-                    title,
-                    content,
-                    mtime,
-                },
-            );
+        let (mut loader, last_seq) = self.db.load_notes().await?;
+        // this also stops a background load, which would only add an older snapshot
+        self.index.write().await.clear();
+        while let Some(batch) = loader.next_batch().await? {
+            let mut index = self.index.write().await;
+            for note in note_entries(batch) {
+                index.upsert(note.path.clone(), note);
+            }
         }
 
+        let mut index = self.index.write().await;
         index.last_seq = last_seq;
 
         tracing::info!("Full resync complete, {} notes indexed", index.len());
@@ -214,3 +366,202 @@ impl ChangesWatcher {
         Ok(())
     }
 }
+
+/// Index entries for a batch from [`crate::couchdb::NoteLoader`]
+pub fn note_entries(batch: Vec<(String, String, u64)>) -> Vec<NoteEntry> {
+    batch
+        .into_iter()
+        .map(|(path, content, mtime)| NoteEntry {
+            title: extract_title(&path, &content),
+            path,
+            content,
+            mtime,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::couchdb::CouchDbConfig;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use wiremock::matchers::{method, path, path_regex};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    const QUICK: Backoff = Backoff {
+        initial: Duration::from_millis(1),
+        max: Duration::from_millis(1),
+    };
+
+    fn watcher(url: &str) -> (ChangesWatcher, Arc<RwLock<SearchIndex>>) {
+        let db = CouchDbClient::new(CouchDbConfig {
+            url: url.to_string(),
+            database: "obsidian".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let index = Arc::new(RwLock::new(SearchIndex::new()));
+        (ChangesWatcher::new(db, index.clone()), index)
+    }
+
+    /// Waits up to a few seconds for `check` to pass
+    async fn eventually(mut check: impl AsyncFnMut() -> bool) {
+        for _ in 0..500 {
+            if check().await {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("gave up waiting");
+    }
+
+    #[tokio::test]
+    async fn test_non_note_docs_are_skipped() {
+        // nothing is listening here, which is fine because nothing should be fetched
+        let (watcher, index) = watcher("http://127.0.0.1:9");
+
+        for (seq, doc) in [
+            (
+                "1-a",
+                serde_json::json!({"_id": "obsydian_livesync_version", "version": 12}),
+            ),
+            (
+                "2-b",
+                serde_json::json!({"_id": "odd.md", "path": "odd.md", "children": "nope"}),
+            ),
+        ] {
+            let change = ChangeEvent {
+                seq: seq.to_string(),
+                id: doc["_id"].as_str().unwrap().to_string(),
+                deleted: false,
+                doc: Some(doc),
+            };
+            watcher.process_change(change).await.unwrap();
+            assert_eq!(index.read().await.last_seq.as_deref(), Some(seq));
+        }
+        assert!(index.read().await.is_empty());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let secs = |backoff: Backoff| -> Vec<u64> {
+            (1..=6).map(|n| backoff.delay(n).as_secs()).collect()
+        };
+        assert_eq!(secs(Backoff::default()), [5, 5, 5, 5, 5, 5]);
+        let exponential = Backoff {
+            initial: Duration::from_secs(2),
+            max: Duration::from_secs(20),
+        };
+        assert_eq!(secs(exponential), [2, 4, 8, 16, 20, 20]);
+        assert_eq!(exponential.delay(u32::MAX), Duration::from_secs(20));
+
+        let wait = exponential.jittered(1);
+        assert!(wait >= Duration::from_millis(1500) && wait <= Duration::from_millis(2500));
+    }
+
+    #[tokio::test]
+    async fn test_panicking_change_is_skipped() {
+        let (watcher, index) = watcher("http://127.0.0.1:9");
+        index.write().await.last_seq = Some("4-d".to_string());
+
+        watcher
+            .isolate("5-e".to_string(), "odd.md", async {
+                Err(anyhow::anyhow!("nope"))
+            })
+            .await;
+        // an error is seen again on the next reconnect
+        assert_eq!(index.read().await.last_seq.as_deref(), Some("4-d"));
+
+        watcher
+            .isolate("5-e".to_string(), "odd.md", async { panic!("bad doc") })
+            .await;
+        assert_eq!(index.read().await.last_seq.as_deref(), Some("5-e"));
+    }
+
+    #[tokio::test]
+    async fn test_supervise_restarts_the_watcher() {
+        let (watcher, index) = watcher("http://127.0.0.1:9");
+        let runs = Arc::new(AtomicUsize::new(0));
+        let cancel = CancellationToken::new();
+        let counter = runs.clone();
+        let task = tokio::spawn(watcher.with_backoff(QUICK).supervise_with(
+            cancel.clone(),
+            move |watcher, _| {
+                let runs = counter.clone();
+                async move {
+                    watcher.index.write().await.watcher_connected = true;
+                    // stopping, failing and panicking all get it started again
+                    match runs.fetch_add(1, Ordering::SeqCst) {
+                        0 => Ok(()),
+                        1 => Err(anyhow::anyhow!("lost couchdb")),
+                        n => panic!("run {}", n),
+                    }
+                }
+            },
+        ));
+
+        eventually(async || runs.load(Ordering::SeqCst) >= 4).await;
+        cancel.cancel();
+        task.await.unwrap();
+        assert!(!index.read().await.watcher_connected);
+    }
+
+    #[tokio::test]
+    async fn test_closed_feed_reconnects() {
+        let server = MockServer::start().await;
+        let change = serde_json::json!({
+            "seq": "1-a",
+            "id": "a.md",
+            "changes": [{"rev": "1-x"}],
+            "doc": {
+                "_id": "a.md", "_rev": "1-x", "path": "a.md", "type": "plain",
+                "children": ["h:a"], "ctime": 0, "mtime": 1, "size": 5,
+            },
+        });
+        // the feed closes after each change, like a proxy timing it out
+        Mock::given(method("GET"))
+            .and(path("/obsidian/_changes"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(format!("{}\n", change)))
+            .mount(&server)
+            .await;
+        // slow enough to catch the watcher connected while it's fetching the chunk
+        Mock::given(method("GET"))
+            .and(path_regex("^/obsidian/h"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({
+                        "_id": "h:a", "_rev": "1-y", "type": "leaf", "data": "hello",
+                    }))
+                    .set_delay(Duration::from_millis(200)),
+            )
+            .mount(&server)
+            .await;
+
+        let (watcher, index) = watcher(&server.uri());
+        let watcher = watcher.with_backoff(QUICK);
+        let cancel = CancellationToken::new();
+        let task = tokio::spawn({
+            let cancel = cancel.clone();
+            async move { watcher.run(cancel).await }
+        });
+
+        eventually(async || index.read().await.watcher_connected).await;
+        eventually(async || index.read().await.last_seq.as_deref() == Some("1-a")).await;
+        eventually(async || {
+            let requests = server.received_requests().await.unwrap();
+            requests
+                .iter()
+                .filter(|r| r.url.path().ends_with("_changes"))
+                .count()
+                >= 2
+        })
+        .await;
+        assert_eq!(
+            index.read().await.cached_content("a.md", 1, 5),
+            Some("hello")
+        );
+
+        cancel.cancel();
+        task.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,45 @@
+//! Vault search as plain JSON over HTTP, for scripts and little dashboards that don't speak
+//! MCP
+
+use crate::server::YamosServer;
+use axum::{
+    Json, Router,
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde::Deserialize;
+
+/// Most results one request can ask for
+const MAX_LIMIT: usize = 200;
+
+pub fn routes(server: YamosServer) -> Router {
+    Router::new()
+        .route("/search", get(search_handler))
+        .with_state(server)
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+    /// default --search-default-limit
+    limit: Option<usize>,
+    /// search note content as well as titles, default --search-default-content
+    content: Option<bool>,
+}
+
+/// GET /search?q=...&limit=...&content=... - the same fuzzy search as the search_notes tool
+async fn search_handler(
+    State(server): State<YamosServer>,
+    Query(query): Query<SearchQuery>,
+) -> Response {
+    if query.q.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "q is required").into_response();
+    }
+    let defaults = server.search_defaults();
+    let limit = query.limit.unwrap_or(defaults.limit).clamp(1, MAX_LIMIT);
+    let content = query.content.unwrap_or(defaults.search_content);
+    let results = server.fuzzy_search(&query.q, limit, content).await;
+    Json(results).into_response()
+}
@@ -1,33 +1,133 @@
-use crate::couchdb::CouchDbClient;
-use crate::search::{SearchIndex, SearchOptions};
+use crate::audit::{AuditEntry, AuditLog, content_hash};
+use crate::auth::{Claims, LegacyAccess, LegacyCaller};
+use crate::client_log::ClientLog;
+use crate::couchdb::{CouchDbClient, CouchDbError, CouchResult, NoteDoc};
+use crate::diff;
+use crate::frontmatter;
+use crate::markdown;
+use crate::search::{Comparison, SearchIndex, SearchOptions, SearchResult};
+use dashmap::DashMap;
 use rmcp::{
-    ErrorData as McpError, ServerHandler,
-    handler::server::{router::tool::ToolRouter, wrapper::Parameters},
+    ErrorData as McpError, RoleServer, ServerHandler,
+    handler::server::{router::tool::ToolRouter, tool::ToolCallContext, wrapper::Parameters},
     model::*,
-    schemars, tool, tool_handler, tool_router,
+    schemars,
+    service::RequestContext,
+    tool, tool_router,
 };
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Instant;
+use tokio::sync::{Mutex, OwnedMutexGuard, RwLock};
+use tracing::Instrument;
+
+/// What the model is told about the server unless the operator says otherwise
+pub const DEFAULT_INSTRUCTIONS: &str = "Obsidian vault access via CouchDB/LiveSync. Use search_notes to find notes by fuzzy matching on titles and content. Use tools to list, read, write, edit, or append notes. For edit_note, include surrounding context in old_string to ensure uniqueness. Batch operations available for multi-note ops.";
+
+/// Near misses listed when a note isn't found
+const PATH_SUGGESTIONS: usize = 3;
+
+/// Past this many note locks, the ones nobody's holding get cleared out
+const MAX_IDLE_NOTE_LOCKS: usize = 1000;
+
+/// Most notes vault_stats will list as the largest
+const MAX_LARGEST_NOTES: usize = 100;
+
+/// Arguments that take a note path, which completion/complete offers paths from the index for
+const PATH_ARGUMENTS: &[&str] = &["path", "from", "to"];
+
+/// Page size for list_notes when the caller doesn't ask for one
+const DEFAULT_LIST_LIMIT: usize = 1000;
+
+/// append_to_log's stamp: a heading per minute
+pub const DEFAULT_LOG_FORMAT: &str = "## {{time}}";
+
+/// Most linked notes read_note_with_context describes, and roughly how many characters of
+/// titles and paragraphs it'll spend on them, so a hub note doesn't flood the context window
+const MAX_LINKED_NOTES: usize = 50;
+const MAX_LINKED_CONTEXT_CHARS: usize = 8000;
+
+/// Longest first paragraph read_note_with_context includes for any one linked note
+const MAX_LINKED_PARAGRAPH_CHARS: usize = 500;
+
+/// Most notes list_recent_notes fetches from CouchDB to sort by mtime itself, when there's no
+/// index on mtime for CouchDB to sort with. A `since` that matches more than this might miss a
+/// few of the newest, and the response says so
+const MAX_RECENT_MATCHES: usize = 10_000;
+
+/// Normalise a note path so every spelling of the same note maps to the same doc id: trims
+/// surrounding whitespace, collapses repeated slashes, and drops `.` segments (so
+/// `./Projects//todo.md` is `Projects/todo.md`). Then validates the result.
+fn normalize_note_path(path: &str, allow_chars: &str) -> Result<String, McpError> {
+    let normalized = path
+        .trim()
+        .split('/')
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .collect::<Vec<_>>()
+        .join("/");
+
+    // a leading slash would have been silently eaten above, but it's still a mistake
+    let check = if path.trim().starts_with('/') {
+        path.trim()
+    } else {
+        &normalized
+    };
+    validate_note_path(check, allow_chars)?;
+    Ok(normalized)
+}
+
+/// Normalise a folder prefix the same way as a note path. It has to end in '/' so that
+/// `Projects/` can't also pick up `Projects2/...`
+pub fn normalize_folder_prefix(folder: &str, allow_chars: &str) -> Result<String, McpError> {
+    let folder = folder.trim();
+    if !folder.ends_with('/') {
+        return Err(invalid_params(format!(
+            "Folder must end with '/' (e.g. '{}/'), so it can't match other folders that start the same way",
+            folder
+        )));
+    }
+    // checked via a note that would live in the folder
+    let placeholder = normalize_note_path(&format!("{}x.md", folder), allow_chars)?;
+    Ok(placeholder
+        .strip_suffix("x.md")
+        .unwrap_or(&placeholder)
+        .to_string())
+}
+
+/// Characters that can't go in a note path by default: the ones Windows/Obsidian won't accept in
+/// a filename, so a note written here would break sync to those devices
+const DENIED_PATH_CHARS: &str = "\\:*?\"<>|";
 
 /// Validate a note path to prevent path traversal and ensure it's a valid Obsidian note path.
-fn validate_note_path(path: &str) -> Result<(), McpError> {
-    let check = |cond: bool, msg: &str| if cond { Err(mcp_error(msg)) } else { Ok(()) };
+/// `allow_chars` lifts individual characters out of [`DENIED_PATH_CHARS`]
+fn validate_note_path(path: &str, allow_chars: &str) -> Result<(), McpError> {
+    let check = |cond: bool, msg: &str| {
+        if cond {
+            Err(invalid_params(msg))
+        } else {
+            Ok(())
+        }
+    };
 
     check(path.is_empty(), "Note path cannot be empty")?;
     check(!path.ends_with(".md"), "Note path must end with .md")?;
     check(path.contains(".."), "Note path cannot contain '..'")?;
     check(path.starts_with('/'), "Note path cannot start with '/'")?;
     check(path.contains('\0'), "Note path cannot contain null bytes")?;
+    check(
+        path.starts_with("h:"),
+        "Note path cannot start with 'h:' (used for chunks)",
+    )?;
 
-    // Allowed: alphanumeric, space, hyphen, underscore, dot, slash, parentheses
+    // Anything goes apart from control characters and the denylist
     let invalid_char = path
         .chars()
-        .find(|c| !c.is_alphanumeric() && !" -_./()'".contains(*c));
+        .find(|c| c.is_control() || (DENIED_PATH_CHARS.contains(*c) && !allow_chars.contains(*c)));
 
     if let Some(c) = invalid_char {
-        return Err(mcp_error(format!(
+        return Err(invalid_params(format!(
             "Note path contains invalid character: '{c}'"
         )));
     }
@@ -35,24 +135,137 @@ fn validate_note_path(path: &str) -> Result<(), McpError> {
     Ok(())
 }
 
+/// Tool-level settings for the MCP server
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Most items a single batch tool call may contain
+    pub max_batch_size: usize,
+    /// Expose tools for poking at the raw LiveSync documents
+    pub debug_tools: bool,
+    /// Normally-forbidden characters to allow in note paths anyway
+    pub path_allow_chars: String,
+    /// What the model is told about this server, instead of [`DEFAULT_INSTRUCTIONS`]
+    pub instructions: Option<String>,
+    /// Advertised server name, instead of the crate name
+    pub server_name: Option<String>,
+    /// Advertised server version, instead of the crate version
+    pub server_version: Option<String>,
+    /// Give up on a tool call after this long (None = wait forever)
+    pub tool_timeout: Option<std::time::Duration>,
+    /// Log a warning for any tool call that takes longer than this (None = never)
+    pub slow_op_threshold: Option<std::time::Duration>,
+    /// Folder (ending in '/') that tool paths are relative to. Nothing outside it can be seen
+    /// or touched
+    pub vault_root: Option<String>,
+    /// Folder (ending in '/', relative to the vault root) that archive_note moves notes into
+    pub archive_folder: String,
+    /// When read_note is given a path that doesn't exist but clearly meant one that does, read
+    /// that instead of failing. Otherwise the near misses are only suggested in the error
+    pub auto_resolve_paths: bool,
+    /// Only offer these tools, if any are given
+    pub enabled_tools: Vec<String>,
+    /// Never offer these tools
+    pub disabled_tools: Vec<String>,
+    /// How append_to_log stamps entries when the call doesn't say, see [`DEFAULT_LOG_FORMAT`]
+    pub log_entry_format: String,
+    /// The limit and search_content that searches use when they don't say
+    pub search_defaults: SearchOptions,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 100,
+            debug_tools: false,
+            path_allow_chars: String::new(),
+            instructions: None,
+            server_name: None,
+            server_version: None,
+            tool_timeout: Some(std::time::Duration::from_secs(300)),
+            slow_op_threshold: None,
+            vault_root: None,
+            archive_folder: "Archive/".to_string(),
+            auto_resolve_paths: false,
+            enabled_tools: Vec::new(),
+            disabled_tools: Vec::new(),
+            log_entry_format: DEFAULT_LOG_FORMAT.to_string(),
+            search_defaults: SearchOptions::default(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct YamosServer {
     db: CouchDbClient,
     search_index: Arc<RwLock<SearchIndex>>,
+    audit: Option<AuditLog>,
+    config: ServerConfig,
+    /// One lock per note path, see [`Self::lock_note`]
+    note_locks: Arc<DashMap<String, Arc<Mutex<()>>>>,
+    #[cfg(feature = "embeddings")]
+    embedder: Option<Arc<dyn crate::search::Embedder>>,
+    /// Databases besides the primary one, by the name tools pick them with
+    vaults: Arc<BTreeMap<String, Vault>>,
+    /// The name of the vault in [`Self::vaults`] this is pointed at, None for the primary
+    vault: Option<String>,
+    /// Where clients that asked for log messages are kept, see [`Self::with_client_log`]
+    client_log: Option<ClientLog>,
+    /// When the server was created, for ping's uptime
+    started: Instant,
     tool_router: ToolRouter<Self>,
 }
 
+/// Another LiveSync database served alongside the primary one, see [`YamosServer::with_vault`]
+#[derive(Clone)]
+struct Vault {
+    db: CouchDbClient,
+    search_index: Arc<RwLock<SearchIndex>>,
+    note_locks: Arc<DashMap<String, Arc<Mutex<()>>>>,
+}
+
 // Request types for tools with parameters
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
 pub struct ListNotesRequest {
     #[schemars(description = "Optional path prefix to filter notes (e.g. 'Projects/')")]
     pub prefix: Option<String>,
+    #[schemars(description = "Maximum number of notes to return (default 1000)")]
+    pub limit: Option<usize>,
+    #[schemars(
+        description = "Cursor from a previous call's 'next_cursor' line, to get the next page"
+    )]
+    pub cursor: Option<String>,
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
 pub struct ReadNoteRequest {
     #[schemars(description = "Path to the note (e.g. 'Todo.md' or 'Projects/myproject.md')")]
     pub path: String,
+    #[schemars(
+        description = "Also return the note's current revision, as a separate 'rev: <rev>' item (default: false)"
+    )]
+    pub include_rev: Option<bool>,
+    #[schemars(
+        description = "Revision from an earlier read. If the note hasn't changed since, just 'not modified' is returned instead of the content"
+    )]
+    pub if_rev: Option<String>,
+    #[schemars(
+        description = "Always reassemble the note from the database rather than using the search index's copy (default: false). Only needed if a read looks stale"
+    )]
+    pub fresh: Option<bool>,
+    #[schemars(
+        description = "Read this older revision of the note instead of the current one (see include_rev). Only works until the database is compacted or the revision's chunks are cleaned up, which usually happens when the note is next saved"
+    )]
+    pub rev: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ReadNoteWithContextRequest {
+    #[schemars(description = "Path to the note (e.g. 'Todo.md' or 'Projects/myproject.md')")]
+    pub path: String,
+    #[schemars(
+        description = "Include the first paragraph of each linked note, not just its title (default: true)"
+    )]
+    pub include_paragraphs: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
@@ -67,8 +280,40 @@ pub struct WriteNoteRequest {
 pub struct AppendNoteRequest {
     #[schemars(description = "Path to the note to append to")]
     pub path: String,
-    #[schemars(description = "Content to append (will be added on a new line)")]
+    #[schemars(description = "Content to append (added after the separator)")]
+    pub content: String,
+    #[schemars(
+        description = "What goes between the existing content and the new content (default: a newline). {{date}} and {{time}} are replaced with the current date and time, e.g. \"\\n\\n## {{date}} {{time}}\\n\""
+    )]
+    pub separator: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct AppendUniqueRequest {
+    #[schemars(description = "Path to the note to append to")]
+    pub path: String,
+    #[schemars(description = "A single line to append, unless the note already has it")]
+    pub line: String,
+    #[schemars(
+        description = "Whether 'Buy milk' and 'buy milk' count as different lines (default true)"
+    )]
+    pub case_sensitive: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct AppendToLogRequest {
+    #[schemars(
+        description = "Path to the log note. {{date}} is replaced with today's date, e.g. 'Daily/{{date}}.md'"
+    )]
+    pub path: String,
+    #[schemars(description = "Content of the entry")]
     pub content: String,
+    #[schemars(
+        description = "How entries are stamped, with {{date}} and {{time}} filled in. A line like '## {{time}}' (the usual default) starts a block that later entries in the same minute go under. With {{content}} in it, e.g. '- [{{time}}] {{content}}', every entry is stamped on its own line"
+    )]
+    pub format: Option<String>,
+    #[schemars(description = "Create the note if it doesn't exist yet (default true)")]
+    pub create: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
@@ -85,412 +330,3985 @@ pub struct EditNoteRequest {
     pub new_string: String,
 }
 
-// Batch operation request types
-
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
-pub struct BatchReadNotesRequest {
-    #[schemars(description = "List of note paths to read")]
-    pub paths: Vec<String>,
+pub struct CopyNoteRequest {
+    #[schemars(description = "Path of the note to copy")]
+    pub from: String,
+    #[schemars(description = "Path to copy it to")]
+    pub to: String,
+    #[schemars(description = "Replace the destination if it already exists (default: false)")]
+    pub overwrite: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
-pub struct BatchWriteOp {
-    #[schemars(description = "Path to the note")]
-    pub path: String,
-    #[schemars(description = "Content to write")]
-    pub content: String,
+pub struct PingRequest {
+    #[schemars(description = "Also check that CouchDB answers (default true)")]
+    pub couchdb: Option<bool>,
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
-pub struct BatchWriteNotesRequest {
-    #[schemars(description = "List of notes to write")]
-    pub notes: Vec<BatchWriteOp>,
+pub struct DiffNotesRequest {
+    #[schemars(description = "The note to diff from (e.g. an archived copy)")]
+    pub from: String,
+    #[schemars(description = "The note to diff to (e.g. the live version)")]
+    pub to: String,
+    #[schemars(description = "Unchanged lines to show around each change (default 3)")]
+    pub context: Option<usize>,
 }
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
-pub struct BatchAppendOp {
-    #[schemars(description = "Path to the note")]
+pub struct RestoreNoteRequest {
+    #[schemars(description = "Path of the deleted note, as shown by list_deleted_notes")]
     pub path: String,
-    #[schemars(description = "Content to append")]
-    pub content: String,
 }
 
-#[derive(Debug, Deserialize, schemars::JsonSchema)]
-pub struct BatchAppendNotesRequest {
-    #[schemars(description = "List of notes to append to")]
-    pub notes: Vec<BatchAppendOp>,
+#[derive(Debug, Serialize)]
+pub struct DeletedNoteResponse {
+    pub path: String,
+    pub deleted_at: String,
 }
 
-// Batch operation result types (for partial success reporting)
-
 #[derive(Debug, Serialize)]
-pub struct BatchReadResult {
-    pub path: String,
-    pub success: bool,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub content: Option<String>,
+pub struct DeletedNotesResponse {
+    pub notes: Vec<DeletedNoteResponse>,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub error: Option<String>,
+    pub next_cursor: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
-pub struct BatchWriteResult {
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ArchiveNoteRequest {
+    #[schemars(description = "Path of the note to archive")]
     pub path: String,
-    pub success: bool,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub error: Option<String>,
+    #[schemars(
+        description = "Add the date and time to the archived note's name, e.g. 'todo 2024-01-05-093000.md', so archiving the same path again doesn't clash (default: false)"
+    )]
+    pub timestamp: Option<bool>,
 }
 
-#[derive(Debug, Serialize)]
-pub struct BatchAppendResult {
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct GetFrontmatterRequest {
+    #[schemars(description = "Path to the note")]
     pub path: String,
-    pub success: bool,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub error: Option<String>,
 }
 
-// Search request/response types
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct SetFrontmatterRequest {
+    #[schemars(description = "Path to the note")]
+    pub path: String,
+    #[schemars(
+        description = "Properties to set, e.g. {\"tags\": [\"project\"], \"status\": \"done\"}. Values can be strings, numbers, booleans or lists of those. A null value removes the property"
+    )]
+    pub properties: serde_json::Map<String, serde_json::Value>,
+    #[schemars(
+        description = "Replace all existing properties with these instead of merging (default: false)"
+    )]
+    pub replace: Option<bool>,
+}
 
 #[derive(Debug, Deserialize, schemars::JsonSchema)]
-pub struct SearchNotesRequest {
-    #[schemars(description = "Search query (fuzzy matching)")]
-    pub query: String,
+pub struct ListRecentNotesRequest {
+    #[schemars(
+        description = "Only notes modified after this, as an RFC 3339 time (e.g. '2024-05-01T09:00:00Z') or a date (e.g. '2024-05-01', midnight UTC)"
+    )]
+    pub since: String,
+    #[schemars(description = "Only notes under this path prefix (e.g. 'Projects/')")]
+    pub prefix: Option<String>,
+    #[schemars(description = "Maximum number of notes to return (default 50)")]
+    pub limit: Option<usize>,
+}
 
-    #[schemars(description = "Search note content in addition to titles (default: true)")]
-    pub search_content: Option<bool>,
+#[derive(Debug, Serialize)]
+pub struct RecentNoteResponse {
+    pub path: String,
+    pub modified: String,
+    pub size: u64,
+}
 
-    #[schemars(description = "Maximum number of results (default: 20)")]
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct QueryFrontmatterRequest {
+    #[schemars(description = "Property name, e.g. 'status' (case-insensitive)")]
+    pub field: String,
+    #[schemars(
+        description = "How to compare: eq, ne, lt, le, gt, ge or exists (default eq). Numbers compare as numbers, other values as case-insensitive text, so ISO dates work with lt/gt. A list property matches if any item does"
+    )]
+    pub op: Option<String>,
+    #[schemars(description = "Value to compare with, e.g. \"active\" or 3. Not needed for exists")]
+    pub value: Option<serde_json::Value>,
+    #[schemars(description = "Only notes under this path prefix (e.g. 'Projects/')")]
+    pub prefix: Option<String>,
+    #[schemars(description = "Maximum number of notes to return (default 100)")]
     pub limit: Option<usize>,
 }
 
 #[derive(Debug, Serialize)]
-pub struct SearchResultResponse {
+pub struct FrontmatterMatch {
     pub path: String,
     pub title: String,
-    pub score: u32,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub snippet: Option<String>,
+    pub value: serde_json::Value,
 }
 
-fn mcp_error(msg: impl Into<String>) -> McpError {
-    McpError {
-        code: ErrorCode::INTERNAL_ERROR,
-        message: Cow::Owned(msg.into()),
-        data: None,
-    }
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct GetOutlineRequest {
+    #[schemars(description = "Path to the note")]
+    pub path: String,
 }
 
-#[tool_router]
-impl YamosServer {
-    pub fn new(db: CouchDbClient, search_index: Arc<RwLock<SearchIndex>>) -> Self {
-        Self {
-            db,
-            search_index,
-            tool_router: Self::tool_router(),
-        }
-    }
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ReadSectionRequest {
+    #[schemars(description = "Path to the note")]
+    pub path: String,
+    #[schemars(description = "Heading text, with or without the #s (e.g. 'Tasks' or '## Tasks')")]
+    pub heading: String,
+}
 
-    #[tool(
-        description = "List all notes in the Obsidian vault, optionally filtered by path prefix"
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ReplaceSectionRequest {
+    #[schemars(description = "Path to the note")]
+    pub path: String,
+    #[schemars(description = "Heading text, with or without the #s (e.g. 'Tasks' or '## Tasks')")]
+    pub heading: String,
+    #[schemars(
+        description = "New content for the section. The heading line itself is kept, so don't include it"
     )]
-    async fn list_notes(
-        &self,
-        Parameters(req): Parameters<ListNotesRequest>,
-    ) -> Result<CallToolResult, McpError> {
-        let notes = self
-            .db
-            .list_notes()
-            .await
-            .map_err(|e| mcp_error(e.to_string()))?;
+    pub content: String,
+}
 
-        let filtered: Vec<_> = match &req.prefix {
-            Some(prefix) => notes
-                .into_iter()
-                .filter(|n| n.starts_with(prefix))
-                .collect(),
-            None => notes,
-        };
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct InsertAfterHeadingRequest {
+    #[schemars(description = "Path to the note")]
+    pub path: String,
+    #[schemars(description = "Heading text, with or without the #s (e.g. 'Log' or '## Log')")]
+    pub heading: String,
+    #[schemars(description = "Content to insert")]
+    pub content: String,
+    #[schemars(
+        description = "'start' to insert right under the heading, or 'end' to add it after the section's existing content, subsections included (default: start)"
+    )]
+    pub position: Option<String>,
+}
 
-        let result = filtered.join("\n");
-        Ok(CallToolResult::success(vec![Content::text(result)]))
-    }
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct RenameHeadingRequest {
+    #[schemars(description = "Path to the note")]
+    pub path: String,
+    #[schemars(
+        description = "Heading to rename, with or without the #s (e.g. 'Goals' or '## Goals')"
+    )]
+    pub heading: String,
+    #[schemars(description = "Its new text, without the #s (the level stays the same)")]
+    pub new_heading: String,
+    #[schemars(
+        description = "Also fix [[Note#Heading]] links to it across the vault, and [[#Heading]] links in the note itself (default: true)"
+    )]
+    pub update_links: Option<bool>,
+}
 
-    #[tool(description = "Read the content of a note from the Obsidian vault")]
-    async fn read_note(
-        &self,
-        Parameters(req): Parameters<ReadNoteRequest>,
-    ) -> Result<CallToolResult, McpError> {
-        validate_note_path(&req.path)?;
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ToggleTaskRequest {
+    #[schemars(description = "Path to the note")]
+    pub path: String,
+    #[schemars(description = "Line number of the task (1-based, as returned by list_tasks)")]
+    pub line: Option<usize>,
+    #[schemars(
+        description = "Text of the task instead of a line number. Must match exactly one task in the note (case-insensitive substring)"
+    )]
+    pub text: Option<String>,
+    #[schemars(
+        description = "Status to set instead of toggling, as the single character between the brackets: ' ' open, 'x' done, or a custom one like '/' or '-'"
+    )]
+    pub status: Option<String>,
+}
 
-        let doc = self
-            .db
-            .get_note(&req.path)
-            .await
-            .map_err(|e| mcp_error(e.to_string()))?;
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ListTasksRequest {
+    #[schemars(
+        description = "Only list tasks in this note. Without it the whole vault is scanned"
+    )]
+    pub path: Option<String>,
+    #[schemars(description = "When scanning the vault, only notes under this path prefix")]
+    pub prefix: Option<String>,
+    #[schemars(description = "Include completed tasks (default: true)")]
+    pub include_done: Option<bool>,
+    #[schemars(description = "Maximum number of tasks to return (default: 500)")]
+    pub limit: Option<usize>,
+}
 
-        let content = self
-            .db
-            .decode_content(&doc)
-            .await
-            .map_err(|e| mcp_error(e.to_string()))?;
+#[derive(Debug, Serialize)]
+pub struct TaskResponse {
+    pub path: String,
+    pub line: usize,
+    pub status: String,
+    pub done: bool,
+    pub text: String,
+}
 
-        Ok(CallToolResult::success(vec![Content::text(content)]))
-    }
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ListTagsRequest {
+    #[schemars(description = "Maximum number of tags to return, most used first (default: all)")]
+    pub limit: Option<usize>,
+}
 
-    #[tool(description = "Create or update a note in the Obsidian vault")]
-    async fn write_note(
-        &self,
-        Parameters(req): Parameters<WriteNoteRequest>,
-    ) -> Result<CallToolResult, McpError> {
-        validate_note_path(&req.path)?;
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct VaultStatsRequest {
+    #[schemars(description = "Only count notes in this folder (e.g. 'Projects/')")]
+    pub folder: Option<String>,
+    #[schemars(description = "How many of the biggest notes to list (default: 10, max: 100)")]
+    pub largest: Option<usize>,
+}
 
-        self.db
-            .save_note(&req.path, &req.content)
-            .await
-            .map_err(|e| mcp_error(e.to_string()))?;
+#[derive(Debug, Serialize)]
+pub struct VaultStatsResponse {
+    pub notes: usize,
+    /// Attachments and anything else that isn't a markdown note
+    pub other_files: usize,
+    /// Distinct tags
+    pub tags: usize,
+    /// Notes in each top-level folder, most first
+    pub folders: Vec<FolderCount>,
+    /// Sizes are in bytes, and left out if note content isn't indexed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub average_size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub median_size: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub largest: Vec<NoteSize>,
+}
 
-        Ok(CallToolResult::success(vec![Content::text(format!(
-            "Successfully wrote to {}",
-            req.path
-        ))]))
-    }
+#[derive(Debug, Serialize)]
+pub struct FolderCount {
+    pub folder: String,
+    pub notes: usize,
+}
 
-    #[tool(description = "Append content to an existing note (adds a newline before the content)")]
-    async fn append_to_note(
-        &self,
-        Parameters(req): Parameters<AppendNoteRequest>,
-    ) -> Result<CallToolResult, McpError> {
-        validate_note_path(&req.path)?;
+#[derive(Debug, Serialize)]
+pub struct NoteSize {
+    pub path: String,
+    pub size: usize,
+}
 
-        self.db
-            .append_to_note(&req.path, &req.content)
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct FindBrokenLinksRequest {
+    #[schemars(description = "Only check notes under this path prefix (e.g. 'Projects/')")]
+    pub prefix: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BrokenLinksResponse {
+    pub path: String,
+    pub links: Vec<BrokenLinkResponse>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BrokenLinkResponse {
+    pub line: usize,
+    pub target: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct ListOrphanNotesRequest {
+    #[schemars(description = "Only list notes under this path prefix (e.g. 'Projects/')")]
+    pub prefix: Option<String>,
+    #[schemars(
+        description = "Path prefixes to leave out, e.g. ['Daily/'] for notes nothing is expected to link to"
+    )]
+    pub exclude: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OrphanNoteResponse {
+    pub path: String,
+    pub title: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagCount {
+    pub tag: String,
+    pub notes: usize,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct MoveFolderRequest {
+    #[schemars(description = "Folder to move, ending in '/' (e.g. 'Projects/Old/')")]
+    pub from: String,
+    #[schemars(description = "Where to move it, ending in '/' (e.g. 'Projects/New/')")]
+    pub to: String,
+    #[schemars(
+        description = "Only report what would be moved, without changing anything (default: true). Set to false to actually move"
+    )]
+    pub dry_run: Option<bool>,
+    #[schemars(
+        description = "Replace notes that already exist at the destination (default: false)"
+    )]
+    pub overwrite: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct DeleteFolderRequest {
+    #[schemars(description = "Folder to delete, ending in '/' (e.g. 'Archive/2019/')")]
+    pub folder: String,
+    #[schemars(
+        description = "Only report what would be deleted, without changing anything (default: true). Set to false to actually delete"
+    )]
+    pub dry_run: Option<bool>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FolderOpResult {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FolderOpResponse {
+    pub dry_run: bool,
+    pub notes: Vec<FolderOpResult>,
+}
+
+// Batch operation request types
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct BatchReadNotesRequest {
+    #[schemars(description = "List of note paths to read")]
+    pub paths: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct BatchWriteOp {
+    #[schemars(description = "Path to the note")]
+    pub path: String,
+    #[schemars(description = "Content to write")]
+    pub content: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct BatchWriteNotesRequest {
+    #[schemars(description = "List of notes to write")]
+    pub notes: Vec<BatchWriteOp>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct BatchAppendOp {
+    #[schemars(description = "Path to the note")]
+    pub path: String,
+    #[schemars(description = "Content to append")]
+    pub content: String,
+    #[schemars(
+        description = "What goes between the existing content and the new content (default: a newline). {{date}} and {{time}} are replaced with the current date and time"
+    )]
+    pub separator: Option<String>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct BatchAppendNotesRequest {
+    #[schemars(description = "List of notes to append to")]
+    pub notes: Vec<BatchAppendOp>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct BatchAppendUniqueOp {
+    #[schemars(description = "Path to the note")]
+    pub path: String,
+    #[schemars(description = "A single line to append, unless the note already has it")]
+    pub line: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct BatchAppendUniqueRequest {
+    #[schemars(description = "List of lines to append and the notes they go in")]
+    pub notes: Vec<BatchAppendUniqueOp>,
+    #[schemars(
+        description = "Whether 'Buy milk' and 'buy milk' count as different lines (default true)"
+    )]
+    pub case_sensitive: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct BatchMoveOp {
+    #[schemars(description = "Note to move")]
+    pub from: String,
+    #[schemars(description = "Where to move it")]
+    pub to: String,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct BatchMoveNotesRequest {
+    #[schemars(description = "List of moves")]
+    pub moves: Vec<BatchMoveOp>,
+    #[schemars(description = "Replace notes that already exist at a destination (default: false)")]
+    pub overwrite: Option<bool>,
+    #[schemars(
+        description = "Rewrite [[wikilinks]] in other notes that point at the moved notes, so they don't break (default: false)"
+    )]
+    pub update_links: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct DebugNoteChunksRequest {
+    #[schemars(description = "Path to the note (e.g. 'Todo.md' or 'Projects/myproject.md')")]
+    pub path: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChunkInfo {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NoteChunksResponse {
+    pub id: String,
+    pub rev: Option<String>,
+    pub path: String,
+    #[serde(rename = "type")]
+    pub doc_type: String,
+    pub ctime: u64,
+    pub mtime: u64,
+    pub size: u64,
+    pub deleted: bool,
+    /// length of the inline `data` field, only non-zero for legacy "notes" docs
+    pub inline_data_size: usize,
+    pub chunk_count: usize,
+    pub chunks: Vec<ChunkInfo>,
+}
+
+// Batch operation result types (for partial success reporting)
+
+#[derive(Debug, Serialize)]
+pub struct BatchReadResult {
+    pub path: String,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchWriteResult {
+    pub path: String,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchAppendResult {
+    pub path: String,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchAppendUniqueResult {
+    pub path: String,
+    pub success: bool,
+    /// false if the note already had the line
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub appended: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchMoveResult {
+    pub from: String,
+    pub to: String,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// Search request/response types
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct SearchNotesRequest {
+    #[schemars(description = "Search query (fuzzy matching)")]
+    pub query: String,
+
+    #[schemars(
+        description = "Search note content in addition to titles (default: the server's, usually true)"
+    )]
+    pub search_content: Option<bool>,
+
+    #[schemars(description = "Maximum number of results (default: the server's, usually 20)")]
+    pub limit: Option<usize>,
+
+    #[schemars(
+        description = "Rank by meaning rather than fuzzy text matching, if the server has embeddings set up. Falls back to fuzzy search otherwise (default: false)"
+    )]
+    pub semantic: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, schemars::JsonSchema)]
+pub struct RelatedNotesRequest {
+    #[schemars(description = "Path of the note to find relatives of")]
+    pub path: String,
+
+    #[schemars(description = "Maximum number of results (default: 10)")]
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LinkedNoteResponse {
+    pub path: String,
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_paragraph: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LinkedContextResponse {
+    pub linked: Vec<LinkedNoteResponse>,
+    /// Link targets that aren't any note
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unresolved: Vec<String>,
+    /// Linked notes left out to keep the response a reasonable size
+    #[serde(skip_serializing_if = "is_zero")]
+    pub omitted: usize,
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+#[derive(Debug, Serialize)]
+pub struct RelatedNoteResponse {
+    pub path: String,
+    pub title: String,
+    pub score: f64,
+    pub shared_terms: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchResultResponse {
+    pub path: String,
+    pub title: String,
+    pub score: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+}
+
+/// What index_status reports about the search index
+#[derive(Debug, Serialize)]
+pub struct IndexStatusResponse {
+    /// false while the index is still loading in the background (--no-startup-resync), when
+    /// search and the tools built on it only know about some of the notes
+    pub ready: bool,
+    pub notes: usize,
+    /// Whether note content is indexed, or just titles and metadata (--index-content=false)
+    pub content_indexed: bool,
+    /// Whether the index is being kept up to date with changes right now. false while the
+    /// changes feed is reconnecting, when search can be behind the vault
+    pub watcher_connected: bool,
+    /// How far through CouchDB's changes feed the index is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seq: Option<String>,
+}
+
+/// What ping reports
+#[derive(Debug, Serialize)]
+pub struct PingResponse {
+    pub server: String,
+    pub version: String,
+    pub uptime_secs: u64,
+    /// Left out if CouchDB wasn't checked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub couchdb_reachable: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub couchdb_error: Option<String>,
+    /// Notes in the search index
+    pub notes: usize,
+}
+
+/// What whoami reports about the caller
+#[derive(Debug, Serialize)]
+pub struct WhoamiResponse {
+    /// "oauth", "legacy" (the static bearer token) or "none"
+    pub auth: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issuer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub issued_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<String>,
+    /// "read" and "write" for a legacy bearer token, depending on which kind it is. OAuth
+    /// tokens aren't scoped yet, so it's empty for them: they can use every tool
+    pub scopes: Vec<String>,
+}
+
+fn whoami(extensions: &Extensions) -> WhoamiResponse {
+    let parts = extensions.get::<axum::http::request::Parts>();
+    let timestamp = |secs: i64| {
+        chrono::DateTime::from_timestamp(secs, 0)
+            .unwrap_or_default()
+            .to_rfc3339()
+    };
+    let mut response = WhoamiResponse {
+        auth: "none",
+        sub: None,
+        issuer: None,
+        issued_at: None,
+        expires_at: None,
+        scopes: Vec::new(),
+    };
+    if let Some(claims) = parts.and_then(|p| p.extensions.get::<Claims>()) {
+        response.auth = "oauth";
+        response.sub = Some(claims.sub.clone());
+        response.issuer = Some(claims.iss.clone());
+        response.issued_at = Some(timestamp(claims.iat));
+        response.expires_at = claims.exp.map(timestamp);
+    } else if let Some(caller) = parts.and_then(|p| p.extensions.get::<LegacyCaller>()) {
+        response.auth = "legacy";
+        response.scopes.push("read".to_string());
+        if caller.access == LegacyAccess::ReadWrite {
+            response.scopes.push("write".to_string());
+        }
+    }
+    response
+}
+
+/// Tools a read-only bearer token can use. Anything not listed here might change the vault,
+/// so new tools are off limits to read-only callers until they're added
+const READ_ONLY_TOOLS: &[&str] = &[
+    "list_notes",
+    "list_deleted_notes",
+    "whoami",
+    "ping",
+    "index_status",
+    "read_note",
+    "read_note_with_context",
+    "diff_notes",
+    "get_frontmatter",
+    "read_section",
+    "get_outline",
+    "list_tasks",
+    "list_tags",
+    "vault_stats",
+    "query_frontmatter",
+    "list_recent_notes",
+    "find_broken_links",
+    "list_orphan_notes",
+    "batch_read_notes",
+    "search_notes",
+    "related_notes",
+    "debug_note_chunks",
+];
+
+/// Whether this call came in with a read-only legacy bearer token
+fn is_read_only(extensions: &Extensions) -> bool {
+    extensions
+        .get::<axum::http::request::Parts>()
+        .and_then(|p| p.extensions.get::<LegacyCaller>())
+        .is_some_and(|c| c.access == LegacyAccess::ReadOnly)
+}
+
+/// JWT subject of whoever made this call. Over HTTP, rmcp puts the request parts in the context
+/// extensions, and the JWT middleware has already stashed the token claims in those
+fn caller_sub(extensions: &Extensions) -> Option<&str> {
+    extensions
+        .get::<axum::http::request::Parts>()
+        .and_then(|p| p.extensions.get::<Claims>())
+        .map(|c| c.sub.as_str())
+}
+
+/// Span for a single tool call, so the couchdb logs it causes can be tied back to it
+fn tool_span(tool: &str, context: &RequestContext<RoleServer>) -> tracing::Span {
+    let request_id = context
+        .extensions
+        .get::<axum::http::request::Parts>()
+        .and_then(|p| p.headers.get("x-request-id"))
+        .and_then(|v| v.to_str().ok());
+
+    tracing::info_span!(
+        "tool_call",
+        tool,
+        request_id = request_id.unwrap_or("-"),
+        sub = caller_sub(&context.extensions).unwrap_or("-"),
+        client = crate::client_log::client_key(&context.peer),
+    )
+}
+
+/// Search results with paths relative to the vault root
+fn search_results(results: Vec<SearchResult>, root: &str) -> Vec<SearchResultResponse> {
+    results
+        .into_iter()
+        .map(|r| SearchResultResponse {
+            path: r.path.strip_prefix(root).unwrap_or(&r.path).to_string(),
+            title: r.title,
+            score: r.score,
+            snippet: r.snippet,
+        })
+        .collect()
+}
+
+fn search_response(results: Vec<SearchResultResponse>) -> Result<CallToolResult, McpError> {
+    let json = serde_json::to_string_pretty(&results).map_err(|e| mcp_error(e.to_string()))?;
+    Ok(CallToolResult::success(vec![Content::text(json)]))
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchSummary {
+    pub total: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchResponse<T> {
+    pub summary: BatchSummary,
+    pub results: Vec<T>,
+}
+
+/// A batch tool's per-note results, with counts up front so the model doesn't have to tally
+fn batch_response<T: Serialize>(
+    results: Vec<T>,
+    succeeded: impl Fn(&T) -> bool,
+) -> Result<CallToolResult, McpError> {
+    let ok = results.iter().filter(|r| succeeded(r)).count();
+    let response = BatchResponse {
+        summary: BatchSummary {
+            total: results.len(),
+            succeeded: ok,
+            failed: results.len() - ok,
+        },
+        results,
+    };
+    let json = serde_json::to_string_pretty(&response).map_err(|e| mcp_error(e.to_string()))?;
+    Ok(CallToolResult::success(vec![Content::text(json)]))
+}
+
+/// Fails tools that work from note bodies in the search index when it's only got titles
+fn require_content(index: &SearchIndex, what: &str) -> Result<(), McpError> {
+    if index.has_content() {
+        return Ok(());
+    }
+    Err(McpError::invalid_request(
+        format!(
+            "{} isn't available: this server only indexes note titles (--index-content=false)",
+            what
+        ),
+        None,
+    ))
+}
+
+/// A new heading for rename_heading: one line, without the characters Obsidian won't link to
+fn check_heading_text(heading: &str) -> Result<&str, McpError> {
+    let heading = heading.trim();
+    if heading.is_empty() {
+        return Err(invalid_params("new_heading cannot be empty"));
+    }
+    if let Some(c) = heading.chars().find(|c| "\n\r#|^[]".contains(*c)) {
+        return Err(invalid_params(format!(
+            "new_heading can't contain {:?}, links to it wouldn't work",
+            c
+        )));
+    }
+    Ok(heading)
+}
+
+/// The line for append_unique, which has to be exactly one non-blank line
+fn check_unique_line(line: &str) -> Result<&str, McpError> {
+    let line = line.trim_end_matches(['\r', '\n']);
+    if line.trim().is_empty() {
+        return Err(invalid_params("line can't be empty"));
+    }
+    if line.contains('\n') {
+        return Err(invalid_params(
+            "line must be a single line - use append_to_note for more",
+        ));
+    }
+    Ok(line)
+}
+
+/// list_recent_notes' `since`, an RFC 3339 time or a date (midnight UTC), as ms since the epoch
+fn parse_since(since: &str) -> Option<i64> {
+    let since = since.trim();
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(since) {
+        return Some(time.timestamp_millis());
+    }
+    let date = chrono::NaiveDate::parse_from_str(since, "%Y-%m-%d").ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc().timestamp_millis())
+}
+
+/// An append separator with its {{date}} and {{time}} filled in. A newline if there isn't one
+fn expand_separator<Tz: chrono::TimeZone>(
+    separator: Option<&str>,
+    now: chrono::DateTime<Tz>,
+) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    separator
+        .unwrap_or("\n")
+        .replace("{{date}}", &now.format("%Y-%m-%d").to_string())
+        .replace("{{time}}", &now.format("%H:%M").to_string())
+}
+
+/// Whether `line` could have come from a log `format` stamp, whatever the date and time. The
+/// {{date}} and {{time}} in it have to be an actual date and time, so a bare "{{time}}" format
+/// doesn't take every line for a stamp
+fn is_log_stamp(line: &str, format: &str) -> bool {
+    let mut rest = line;
+    let mut format = format.trim();
+    loop {
+        let next = [("{{date}}", "%Y-%m-%d", 10), ("{{time}}", "%H:%M", 5)]
+            .into_iter()
+            .filter_map(|(placeholder, shape, len)| {
+                format
+                    .find(placeholder)
+                    .map(|i| (i, placeholder, shape, len))
+            })
+            .min_by_key(|(i, ..)| *i);
+        let Some((i, placeholder, shape, len)) = next else {
+            return rest == format;
+        };
+        let Some(after) = rest.strip_prefix(&format[..i]) else {
+            return false;
+        };
+        let Some(value) = after.get(..len) else {
+            return false;
+        };
+        let parses = match placeholder {
+            "{{date}}" => chrono::NaiveDate::parse_from_str(value, shape).is_ok(),
+            _ => chrono::NaiveTime::parse_from_str(value, shape).is_ok(),
+        };
+        if !parses {
+            return false;
+        }
+        rest = &after[len..];
+        format = &format[i + placeholder.len()..];
+    }
+}
+
+/// `existing` with a log entry added for `now`. A `format` without {{content}} is a stamp line
+/// that's only added when the note's latest block has a different one, so entries in the same
+/// minute share it. With {{content}}, the entry is the format filled in
+fn append_log_entry<Tz: chrono::TimeZone>(
+    existing: &str,
+    content: &str,
+    format: &str,
+    now: chrono::DateTime<Tz>,
+) -> String
+where
+    Tz::Offset: std::fmt::Display,
+{
+    let stamp = expand_separator(Some(format), now);
+    let mut out = existing.to_string();
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    if stamp.contains("{{content}}") {
+        out.push_str(&stamp.replace("{{content}}", content));
+        return out;
+    }
+
+    let stamp = stamp.trim();
+    // walking back from the end, the first stamp (or heading) is the block being added to
+    let same_block = existing
+        .lines()
+        .rev()
+        .map(str::trim)
+        .find(|line| *line == stamp || line.starts_with('#') || is_log_stamp(line, format))
+        .is_some_and(|line| line == stamp);
+    if !same_block {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        out.push_str(stamp);
+        out.push('\n');
+    }
+    out.push_str(content);
+    out
+}
+
+/// The caller got something wrong (a bad path, arguments that don't fit the note...), so
+/// retrying as-is won't help
+fn invalid_params(msg: impl Into<String>) -> McpError {
+    McpError::invalid_params(msg.into(), None)
+}
+
+/// The one path out of some near misses that was clearly meant: the only one, or one that
+/// scores well ahead of the rest
+fn confident_match(candidates: &[(String, u32)]) -> Option<&str> {
+    match candidates {
+        [(path, _)] => Some(path),
+        [(path, best), (_, next), ..] if *best > next.saturating_mul(3) / 2 => Some(path),
+        _ => None,
+    }
+}
+
+/// Picks an error code for a failed CouchDB call, so a client can tell a missing note or a
+/// refused write apart from the server actually having trouble
+fn db_error(e: CouchDbError) -> McpError {
+    let code = match e {
+        CouchDbError::NotFound(_) | CouchDbError::RevisionUnavailable { .. } => {
+            ErrorCode::RESOURCE_NOT_FOUND
+        }
+        CouchDbError::AlreadyExists(_) | CouchDbError::Conflict(_) => ErrorCode::INVALID_REQUEST,
+        CouchDbError::TooLarge { .. }
+        | CouchDbError::TooManyChunks { .. }
+        | CouchDbError::PayloadTooLarge(_) => ErrorCode::INVALID_PARAMS,
+        _ => ErrorCode::INTERNAL_ERROR,
+    };
+    McpError {
+        code,
+        message: Cow::Owned(e.to_string()),
+        data: None,
+    }
+}
+
+fn mcp_error(msg: impl Into<String>) -> McpError {
+    McpError {
+        code: ErrorCode::INTERNAL_ERROR,
+        message: Cow::Owned(msg.into()),
+        data: None,
+    }
+}
+
+#[tool_router]
+impl YamosServer {
+    pub fn new(
+        db: CouchDbClient,
+        search_index: Arc<RwLock<SearchIndex>>,
+        audit: Option<AuditLog>,
+        config: ServerConfig,
+    ) -> Self {
+        let mut tool_router = Self::tool_router();
+        if !config.debug_tools {
+            tool_router.remove_route("debug_note_chunks");
+        }
+        for tool in Self::tool_names() {
+            let enabled = config.enabled_tools.is_empty() || config.enabled_tools.contains(&tool);
+            if !enabled || config.disabled_tools.contains(&tool) {
+                tool_router.remove_route(&tool);
+            }
+        }
+
+        Self {
+            db,
+            search_index,
+            audit,
+            config,
+            note_locks: Arc::new(DashMap::new()),
+            #[cfg(feature = "embeddings")]
+            embedder: None,
+            vaults: Arc::new(BTreeMap::new()),
+            vault: None,
+            client_log: None,
+            started: Instant::now(),
+            tool_router,
+        }
+    }
+
+    /// The name of every tool there is, whether or not it's turned on
+    pub fn tool_names() -> Vec<String> {
+        Self::tool_router()
+            .list_all()
+            .into_iter()
+            .map(|tool| tool.name.to_string())
+            .collect()
+    }
+
+    /// Serves another database too, for tool calls that pass `vault: name`. Its notes get their
+    /// own search index and locks; semantic search and the HTTP endpoints stay on the primary
+    pub fn with_vault(
+        mut self,
+        name: String,
+        db: CouchDbClient,
+        search_index: Arc<RwLock<SearchIndex>>,
+    ) -> Self {
+        let vault = Vault {
+            db,
+            search_index,
+            note_locks: Arc::new(DashMap::new()),
+        };
+        Arc::make_mut(&mut self.vaults).insert(name, vault);
+        self
+    }
+
+    /// Every vault's name and database, primary first
+    pub fn databases(&self) -> Vec<(String, CouchDbClient)> {
+        std::iter::once((self.primary_vault().to_string(), self.db.clone()))
+            .chain(
+                self.vaults
+                    .iter()
+                    .map(|(name, vault)| (name.clone(), vault.db.clone())),
+            )
+            .collect()
+    }
+
+    /// The primary vault's name, which is just its database
+    fn primary_vault(&self) -> &str {
+        self.db.database()
+    }
+
+    /// This server pointed at the named vault, or the primary one if there's no name
+    fn for_vault(&self, name: Option<&str>) -> Result<Self, McpError> {
+        let Some(name) = name.filter(|name| *name != self.primary_vault()) else {
+            return Ok(self.clone());
+        };
+        let vault = self.vaults.get(name).ok_or_else(|| {
+            invalid_params(format!(
+                "Unknown vault '{}'. Available vaults: {}",
+                name,
+                self.vault_names().join(", ")
+            ))
+        })?;
+        Ok(Self {
+            db: vault.db.clone(),
+            search_index: vault.search_index.clone(),
+            note_locks: vault.note_locks.clone(),
+            vault: Some(name.to_string()),
+            ..self.clone()
+        })
+    }
+
+    /// Every vault tools can pick, primary first
+    fn vault_names(&self) -> Vec<&str> {
+        std::iter::once(self.primary_vault())
+            .chain(self.vaults.keys().map(String::as_str))
+            .collect()
+    }
+
+    /// The tool list, with a `vault` parameter added to every tool when there's more than one
+    fn list_tools_with_vaults(&self) -> Vec<Tool> {
+        let mut tools = self.tool_router.list_all();
+        if self.vaults.is_empty() {
+            return tools;
+        }
+        let vault = serde_json::json!({
+            "type": "string",
+            "description": format!(
+                "Which vault to use: one of {} (default {})",
+                self.vault_names().join(", "),
+                self.primary_vault()
+            ),
+        });
+        for tool in &mut tools {
+            let schema = Arc::make_mut(&mut tool.input_schema);
+            let properties = schema
+                .entry("properties")
+                .or_insert_with(|| serde_json::json!({}));
+            if let Some(properties) = properties.as_object_mut() {
+                properties.insert("vault".to_string(), vault.clone());
+            }
+        }
+        tools
+    }
+
+    /// Advertises MCP's logging capability, so clients can ask for log messages via this
+    pub fn with_client_log(mut self, client_log: ClientLog) -> Self {
+        self.client_log = Some(client_log);
+        self
+    }
+
+    /// Lets search_notes do semantic search with this embedder
+    #[cfg(feature = "embeddings")]
+    pub fn with_embedder(mut self, embedder: Arc<dyn crate::search::Embedder>) -> Self {
+        self.embedder = Some(embedder);
+        self
+    }
+
+    pub fn db(&self) -> &CouchDbClient {
+        &self.db
+    }
+
+    /// The --vault-root folder, or "" if tools see the whole vault
+    pub fn root(&self) -> &str {
+        self.config.vault_root.as_deref().unwrap_or("")
+    }
+
+    /// A real note path as the client sees it, i.e. relative to the vault root
+    pub fn display_path<'a>(&self, path: &'a str) -> &'a str {
+        path.strip_prefix(self.root()).unwrap_or(path)
+    }
+
+    /// Normalised and validated version of a note path from a tool call, under the vault root
+    pub fn note_path(&self, path: &str) -> Result<String, McpError> {
+        let path = normalize_note_path(path, &self.config.path_allow_chars)?;
+        Ok(format!("{}{}", self.root(), path))
+    }
+
+    /// Normalised and validated version of a folder prefix from a tool call, under the vault root
+    fn folder_prefix(&self, folder: &str) -> Result<String, McpError> {
+        let folder = normalize_folder_prefix(folder, &self.config.path_allow_chars)?;
+        Ok(format!("{}{}", self.root(), folder))
+    }
+
+    /// Every note under a folder prefix, across however many pages it takes
+    async fn notes_in_folder(&self, prefix: &str) -> Result<Vec<String>, McpError> {
+        let mut notes = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = self
+                .db
+                .list_notes(Some(prefix), cursor.as_deref(), DEFAULT_LIST_LIMIT)
+                .await
+                .map_err(|e| self.db_error(e))?;
+            notes.extend(page.notes);
+            cursor = page.next_cursor;
+            if cursor.is_none() {
+                return Ok(notes);
+            }
+        }
+    }
+
+    /// A listing's prefix and cursor under the vault root. They're relative to it, and so is
+    /// everything listed
+    fn list_range(
+        &self,
+        prefix: Option<String>,
+        cursor: Option<String>,
+    ) -> (Option<String>, Option<String>) {
+        let prefix = format!("{}{}", self.root(), prefix.unwrap_or_default());
+        (
+            (!prefix.is_empty()).then_some(prefix),
+            cursor.map(|c| format!("{}{}", self.root(), c)),
+        )
+    }
+
+    /// Waits for and takes a note's lock. Every tool that changes a note holds it from before
+    /// reading the note until after saving it, so two calls on the same note take turns
+    /// instead of one silently undoing the other.
+    ///
+    /// This only covers this yamos process. Obsidian (or another yamos) writing the same note
+    /// between the read and the save is caught by CouchDB's rev check instead, since the save
+    /// goes against the rev that was read, and the tool fails with a conflict
+    pub async fn lock_note(&self, path: &str) -> OwnedMutexGuard<()> {
+        if self.note_locks.len() > MAX_IDLE_NOTE_LOCKS {
+            self.note_locks
+                .retain(|_, lock| Arc::strong_count(lock) > 1);
+        }
+        // by doc id, so the same note spelt in another case (with lowercased ids) shares a lock
+        let id = self.db.path_style().doc_id(path);
+        let lock = self.note_locks.entry(id).or_default().clone();
+        lock.lock_owned().await
+    }
+
+    /// What a search uses for anything it isn't given (--search-default-limit and
+    /// --search-default-content)
+    pub fn search_defaults(&self) -> &SearchOptions {
+        &self.config.search_defaults
+    }
+
+    /// Fuzzy search over the notes under the vault root, for search_notes and GET /search
+    pub async fn fuzzy_search(
+        &self,
+        query: &str,
+        limit: usize,
+        search_content: bool,
+    ) -> Vec<SearchResultResponse> {
+        let results = self.search_index.read().await.search(
+            query,
+            SearchOptions {
+                limit,
+                search_content,
+                prefix: self.root().to_string(),
+            },
+        );
+        search_results(results, self.root())
+    }
+
+    /// Rejects oversized batches up front, before any db work starts
+    fn check_batch_size(&self, len: usize) -> Result<(), McpError> {
+        if len > self.config.max_batch_size {
+            return Err(invalid_params(format!(
+                "Batch contains {} items, but the maximum is {} - split it into smaller batches",
+                len, self.config.max_batch_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Current content of a note, or None if it doesn't exist (or can't be read)
+    async fn current_content(&self, path: &str) -> Option<String> {
+        let doc = self.db.get_note(path).await.ok()?;
+        self.db.decode_content(&doc).await.ok()
+    }
+
+    /// Semantic search results, or None if there's no embedder, nothing embedded yet, or the
+    /// query couldn't be embedded - in which case the caller falls back to fuzzy search
+    #[cfg(feature = "embeddings")]
+    async fn semantic_search(&self, query: &str, limit: usize) -> Option<Vec<SearchResult>> {
+        let embedder = self.embedder.as_ref()?;
+        if !self.search_index.read().await.has_embeddings() {
+            return None;
+        }
+        let vector = match embedder.embed(&[query.to_string()]).await {
+            Ok(mut vectors) => vectors.pop()?,
+            Err(e) => {
+                tracing::warn!("Couldn't embed search query, using fuzzy search: {}", e);
+                return None;
+            }
+        };
+        let index = self.search_index.read().await;
+        Some(index.semantic_search(query, &vector, limit, self.root()))
+    }
+
+    /// Where archive_note puts a note: the same path under the archive folder, optionally with
+    /// a timestamp before the extension
+    fn archive_path(&self, path: &str, timestamp: Option<&str>) -> Result<String, McpError> {
+        let relative = self.display_path(path);
+        if relative.starts_with(&self.config.archive_folder) {
+            return Err(invalid_params(format!(
+                "{} is already in the archive folder ({})",
+                relative, self.config.archive_folder
+            )));
+        }
+        let relative = match timestamp {
+            Some(ts) => format!("{} {}.md", relative.trim_end_matches(".md"), ts),
+            None => relative.to_string(),
+        };
+        Ok(format!(
+            "{}{}{}",
+            self.root(),
+            self.config.archive_folder,
+            relative
+        ))
+    }
+
+    /// Copies a note to its new path (keeping its ctime) then deletes the old one, auditing
+    /// both halves under `tool`
+    async fn move_note(
+        &self,
+        tool: &str,
+        from: &str,
+        to: &str,
+        overwrite: bool,
+        extensions: &Extensions,
+    ) -> Result<(), McpError> {
+        // always in the same order, so two moves in opposite directions can't deadlock
+        let (first, second) = if from < to { (from, to) } else { (to, from) };
+        let _first = self.lock_note(first).await;
+        let _second = if first != second {
+            Some(self.lock_note(second).await)
+        } else {
+            None
+        };
+
+        self.audited(
+            tool,
+            to,
+            extensions,
+            self.db.copy_note(from, to, overwrite, true),
+        )
+        .await
+        .map_err(|e| self.db_error(e))?;
+        self.audited(tool, from, extensions, self.db.delete_note(from))
+            .await
+            .map_err(|e| {
+                mcp_error(format!(
+                    "copied to {} but couldn't delete the original: {}",
+                    self.display_path(to),
+                    e
+                ))
+            })
+    }
+
+    /// Reads and reassembles a note, for tools that work on its content
+    async fn note_content(&self, path: &str) -> Result<String, McpError> {
+        Ok(self.note_to_edit(path).await?.1)
+    }
+
+    /// A note's doc and content, for tools that change it. Saving with
+    /// [`CouchDbClient::update_note`] against that doc fails with a conflict if the note
+    /// changed in between
+    async fn note_to_edit(&self, path: &str) -> Result<(NoteDoc, String), McpError> {
+        let doc = self.note_result(self.db.get_note(path).await).await?;
+        let content = self
+            .db
+            .decode_content(&doc)
+            .await
+            .map_err(|e| self.db_error(e))?;
+        Ok((doc, content))
+    }
+
+    /// A note's doc and content, or None if there's no such note (or it's been deleted)
+    async fn optional_note_content(
+        &self,
+        path: &str,
+    ) -> Result<Option<(NoteDoc, String)>, McpError> {
+        match self.db.get_note(path).await {
+            Ok(doc) if doc.deleted == Some(true) => Ok(None),
+            Ok(doc) => match self.db.decode_content(&doc).await {
+                Ok(content) => Ok(Some((doc, content))),
+                Err(e) => Err(self.db_error(e)),
+            },
+            Err(CouchDbError::NotFound(_)) => Ok(None),
+            Err(e) => Err(self.db_error(e)),
+        }
+    }
+
+    /// Existing notes whose paths look like `path`, best first
+    async fn similar_paths(&self, path: &str) -> Vec<(String, u32)> {
+        self.search_index.read().await.similar_paths(
+            self.display_path(path),
+            PATH_SUGGESTIONS,
+            self.root(),
+        )
+    }
+
+    /// Like [`db_error`], with the note paths in the message relative to the vault root, the
+    /// way tools take them
+    fn db_error(&self, e: CouchDbError) -> McpError {
+        db_error(e.map_path(|path| self.display_path(&path).to_string()))
+    }
+
+    /// Like [`Self::db_error`], but a missing note's error suggests the notes that were probably
+    /// meant, since a model misremembering a path is the usual reason for one
+    async fn note_result<T>(&self, result: CouchResult<T>) -> Result<T, McpError> {
+        let e = match result {
+            Ok(value) => return Ok(value),
+            Err(e) => e,
+        };
+        let suggestions = match &e {
+            CouchDbError::NotFound(path) => self.similar_paths(path).await,
+            _ => Vec::new(),
+        };
+        let mut error = self.db_error(e);
+        if !suggestions.is_empty() {
+            let paths: Vec<_> = suggestions
+                .iter()
+                .map(|(p, _)| self.display_path(p))
+                .collect();
+            error.message =
+                format!("{} - did you mean {}?", error.message, paths.join(", ")).into();
+        }
+        Err(error)
+    }
+
+    /// Runs a mutating operation on a note and, if auditing is enabled and it succeeded, records
+    /// it with hashes of the content before and after. The extra reads only happen when auditing
+    async fn audited<T, E>(
+        &self,
+        tool: &str,
+        path: &str,
+        extensions: &Extensions,
+        op: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        self.audited_as(tool, path, caller_sub(extensions), op)
+            .await
+    }
+
+    /// [`Self::audited`] for changes that don't come from a tool call, with `sub` as the caller
+    pub async fn audited_as<T, E>(
+        &self,
+        tool: &str,
+        path: &str,
+        sub: Option<&str>,
+        op: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let Some(audit) = &self.audit else {
+            return op.await;
+        };
+
+        let before = self.current_content(path).await;
+        let result = op.await?;
+        let after = self.current_content(path).await;
+
+        audit
+            .record(&AuditEntry {
+                timestamp: chrono::Utc::now(),
+                sub,
+                tool,
+                vault: self.vault.as_deref(),
+                path,
+                before: before.as_deref().map(content_hash),
+                after: after.as_deref().map(content_hash),
+            })
+            .await;
+
+        Ok(result)
+    }
+
+    #[tool(
+        description = "List notes in the Obsidian vault, optionally filtered by path prefix. Returns one path per line; if there are more, the last line is 'next_cursor: <cursor>' - pass that cursor back to get the next page"
+    )]
+    async fn list_notes(
+        &self,
+        Parameters(req): Parameters<ListNotesRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let limit = req.limit.unwrap_or(DEFAULT_LIST_LIMIT).max(1);
+        let (prefix, cursor) = self.list_range(req.prefix, req.cursor);
+        let page = self
+            .db
+            .list_notes(prefix.as_deref(), cursor.as_deref(), limit)
+            .await
+            .map_err(|e| self.db_error(e))?;
+
+        let notes: Vec<_> = page.notes.iter().map(|n| self.display_path(n)).collect();
+        let mut result = notes.join("\n");
+        if let Some(cursor) = page.next_cursor {
+            result.push_str(&format!("\nnext_cursor: {}", self.display_path(&cursor)));
+        }
+        Ok(CallToolResult::success(vec![Content::text(result)]))
+    }
+
+    #[tool(
+        description = "List soft-deleted notes (the trash), which list_notes leaves out, with when each was deleted. Same paging as list_notes. Bring one back with restore_note"
+    )]
+    async fn list_deleted_notes(
+        &self,
+        Parameters(req): Parameters<ListNotesRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let limit = req.limit.unwrap_or(DEFAULT_LIST_LIMIT).max(1);
+        let (prefix, cursor) = self.list_range(req.prefix, req.cursor);
+        let page = self
+            .db
+            .list_deleted_notes(prefix.as_deref(), cursor.as_deref(), limit)
+            .await
+            .map_err(|e| self.db_error(e))?;
+
+        let json = serde_json::to_string_pretty(&DeletedNotesResponse {
+            notes: page
+                .notes
+                .into_iter()
+                .map(|n| DeletedNoteResponse {
+                    path: self.display_path(&n.path).to_string(),
+                    deleted_at: chrono::DateTime::from_timestamp_millis(n.mtime as i64)
+                        .unwrap_or_default()
+                        .to_rfc3339(),
+                })
+                .collect(),
+            next_cursor: page.next_cursor.map(|c| self.display_path(&c).to_string()),
+        })
+        .map_err(|e| mcp_error(e.to_string()))?;
+        Ok(CallToolResult::success(vec![Content::text(json)]))
+    }
+
+    #[tool(description = "Restore a soft-deleted note, so it shows up in the vault again")]
+    async fn restore_note(
+        &self,
+        Parameters(mut req): Parameters<RestoreNoteRequest>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        req.path = self.note_path(&req.path)?;
+        let _lock = self.lock_note(&req.path).await;
+
+        let restored = self
+            .audited(
+                "restore_note",
+                &req.path,
+                &extensions,
+                self.db.restore_note(&req.path),
+            )
+            .await
+            .map_err(|e| self.db_error(e))?;
+        if !restored {
+            return Err(invalid_params(format!(
+                "{} isn't deleted",
+                self.display_path(&req.path)
+            )));
+        }
+
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "Restored {}",
+            self.display_path(&req.path)
+        ))]))
+    }
+
+    #[tool(
+        description = "Show who this server thinks you are: how you authenticated (oauth, legacy bearer token, or none) and, for OAuth, your token's subject, issue and expiry times. Handy for debugging auth problems"
+    )]
+    async fn whoami(&self, extensions: Extensions) -> Result<CallToolResult, McpError> {
+        let json = serde_json::to_string_pretty(&whoami(&extensions))
+            .map_err(|e| mcp_error(e.to_string()))?;
+        Ok(CallToolResult::success(vec![Content::text(json)]))
+    }
+
+    #[tool(
+        description = "Check the server is up and working without touching any notes: returns its version, uptime, whether CouchDB answers and how many notes are indexed. The first thing to try when tools seem broken"
+    )]
+    async fn ping(
+        &self,
+        Parameters(req): Parameters<PingRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let couchdb = if req.couchdb.unwrap_or(true) {
+            Some(self.db.test_connection().await)
+        } else {
+            None
+        };
+        let notes = self
+            .search_index
+            .read()
+            .await
+            .notes()
+            .filter(|note| note.path.starts_with(self.root()))
+            .count();
+        let response = PingResponse {
+            server: self
+                .config
+                .server_name
+                .clone()
+                .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_string()),
+            version: self
+                .config
+                .server_version
+                .clone()
+                .unwrap_or_else(|| env!("CARGO_PKG_VERSION").to_string()),
+            uptime_secs: self.started.elapsed().as_secs(),
+            couchdb_reachable: couchdb.as_ref().map(Result::is_ok),
+            couchdb_error: couchdb.and_then(Result::err).map(|e| e.to_string()),
+            notes,
+        };
+        let json = serde_json::to_string_pretty(&response).map_err(|e| mcp_error(e.to_string()))?;
+        Ok(CallToolResult::success(vec![Content::text(json)]))
+    }
+
+    #[tool(
+        description = "Show whether the search index has finished loading and how many notes are in it. Until it's ready, search results and anything else that scans the vault may be incomplete"
+    )]
+    async fn index_status(&self) -> Result<CallToolResult, McpError> {
+        let index = self.search_index.read().await;
+        let response = IndexStatusResponse {
+            ready: index.is_ready(),
+            notes: index
+                .notes()
+                .filter(|note| note.path.starts_with(self.root()))
+                .count(),
+            content_indexed: index.has_content(),
+            watcher_connected: index.watcher_connected,
+            last_seq: index.last_seq.clone(),
+        };
+        let json = serde_json::to_string_pretty(&response).map_err(|e| mcp_error(e.to_string()))?;
+        Ok(CallToolResult::success(vec![Content::text(json)]))
+    }
+
+    #[tool(
+        description = "Read the content of a note from the Obsidian vault. Pass if_rev with a revision from an earlier read (see include_rev) to skip re-reading an unchanged note"
+    )]
+    async fn read_note(
+        &self,
+        Parameters(mut req): Parameters<ReadNoteRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        req.path = self.note_path(&req.path)?;
+
+        if let Some(rev) = &req.rev {
+            let (_, content) = self
+                .db
+                .get_note_revision(&req.path, rev)
+                .await
+                .map_err(|e| self.db_error(e))?;
+            return Ok(CallToolResult::success(vec![
+                Content::text(content),
+                Content::text(format!("rev: {}", rev)),
+            ]));
+        }
+
+        let mut resolved = None;
+        let doc = match self.db.get_note(&req.path).await {
+            Err(CouchDbError::NotFound(_)) if self.config.auto_resolve_paths => {
+                match confident_match(&self.similar_paths(&req.path).await) {
+                    Some(path) => {
+                        let doc = self.db.get_note(path).await.map_err(|e| self.db_error(e))?;
+                        resolved = Some(path.to_string());
+                        Ok(doc)
+                    }
+                    None => Err(CouchDbError::NotFound(req.path.clone())),
+                }
+            }
+            result => result,
+        };
+        let doc = self.note_result(doc).await?;
+        let rev = doc.rev.clone().unwrap_or_default();
+
+        // unchanged since the caller last saw it, so don't bother reassembling the chunks
+        if req.if_rev.as_deref() == Some(rev.as_str()) {
+            return Ok(CallToolResult::success(vec![Content::text(format!(
+                "not modified (rev: {})",
+                rev
+            ))]));
+        }
+
+        // the index already has the content of any note the watcher has seen, and if the doc's
+        // mtime and size match what it saw, that's the same version we'd get from the chunks
+        let cached = if req.fresh.unwrap_or(false) {
+            None
+        } else {
+            let index = self.search_index.read().await;
+            index
+                .cached_content(
+                    resolved.as_deref().unwrap_or(&req.path),
+                    doc.mtime,
+                    doc.size,
+                )
+                .map(str::to_string)
+        };
+        let content = match cached {
+            Some(content) => content,
+            None => self
+                .db
+                .decode_content(&doc)
+                .await
+                .map_err(|e| self.db_error(e))?,
+        };
+
+        let mut result = vec![Content::text(content)];
+        if req.include_rev.unwrap_or(false) || req.if_rev.is_some() {
+            result.push(Content::text(format!("rev: {}", rev)));
+        }
+        if let Some(path) = resolved {
+            result.push(Content::text(format!(
+                "note: {} doesn't exist, so this is {}",
+                self.display_path(&req.path),
+                self.display_path(&path)
+            )));
+        }
+        Ok(CallToolResult::success(result))
+    }
+
+    #[tool(
+        description = "Read a note along with the titles and first paragraphs of the notes it links to with [[wikilinks]], to save reading each of them. Only direct links are followed, and the linked notes' part is capped in size"
+    )]
+    async fn read_note_with_context(
+        &self,
+        Parameters(mut req): Parameters<ReadNoteWithContextRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        req.path = self.note_path(&req.path)?;
+        let content = self.note_content(&req.path).await?;
+
+        let index = self.search_index.read().await;
+        // without content in the index there are no paragraphs to give, but titles still work
+        let include_paragraphs = req.include_paragraphs.unwrap_or(true) && index.has_content();
+        let (linked, unresolved) = index.linked_notes(&req.path, &content);
+        let linked: Vec<_> = linked
+            .into_iter()
+            .filter(|note| note.path.starts_with(self.root()))
+            .collect();
+
+        let mut response = LinkedContextResponse {
+            linked: Vec::new(),
+            unresolved,
+            omitted: 0,
+        };
+        let mut budget = MAX_LINKED_CONTEXT_CHARS;
+        for note in &linked {
+            let path = self.display_path(&note.path).to_string();
+            let cost = path.chars().count() + note.title.chars().count();
+            if response.linked.len() >= MAX_LINKED_NOTES || cost > budget {
+                response.omitted = linked.len() - response.linked.len();
+                break;
+            }
+            budget -= cost;
+            let first_paragraph = include_paragraphs
+                .then(|| markdown::first_paragraph(&note.content))
+                .flatten()
+                .map(|p| match p.char_indices().nth(MAX_LINKED_PARAGRAPH_CHARS) {
+                    Some((end, _)) => format!("{}...", &p[..end]),
+                    None => p,
+                })
+                .filter(|p| p.chars().count() <= budget);
+            budget -= first_paragraph.as_ref().map_or(0, |p| p.chars().count());
+            response.linked.push(LinkedNoteResponse {
+                path,
+                title: note.title.clone(),
+                first_paragraph,
+            });
+        }
+        drop(index);
+
+        let json = serde_json::to_string_pretty(&response).map_err(|e| mcp_error(e.to_string()))?;
+        Ok(CallToolResult::success(vec![
+            Content::text(content),
+            Content::text(format!("linked notes: {}", json)),
+        ]))
+    }
+
+    #[tool(description = "Create or update a note in the Obsidian vault")]
+    async fn write_note(
+        &self,
+        Parameters(mut req): Parameters<WriteNoteRequest>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        req.path = self.note_path(&req.path)?;
+        let _lock = self.lock_note(&req.path).await;
+
+        self.audited(
+            "write_note",
+            &req.path,
+            &extensions,
+            self.db.save_note(&req.path, &req.content),
+        )
+        .await
+        .map_err(|e| self.db_error(e))?;
+
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "Successfully wrote to {}",
+            self.display_path(&req.path)
+        ))]))
+    }
+
+    #[tool(
+        description = "Append content to an existing note (adds a newline before the content, or a custom separator such as a dated heading)"
+    )]
+    async fn append_to_note(
+        &self,
+        Parameters(mut req): Parameters<AppendNoteRequest>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        req.path = self.note_path(&req.path)?;
+        let _lock = self.lock_note(&req.path).await;
+
+        let result = self
+            .audited(
+                "append_to_note",
+                &req.path,
+                &extensions,
+                self.db.append_to_note(
+                    &req.path,
+                    &req.content,
+                    &expand_separator(req.separator.as_deref(), chrono::Local::now()),
+                ),
+            )
+            .await;
+        self.note_result(result).await?;
+
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "Successfully appended to {}",
+            self.display_path(&req.path)
+        ))]))
+    }
+
+    #[tool(
+        description = "Append a single line to a note, unless the note already has that line (compared with surrounding whitespace trimmed). Safe to re-run: says whether the line was appended or already there"
+    )]
+    async fn append_unique(
+        &self,
+        Parameters(mut req): Parameters<AppendUniqueRequest>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        req.path = self.note_path(&req.path)?;
+        let line = check_unique_line(&req.line)?;
+        let _lock = self.lock_note(&req.path).await;
+
+        let result = self
+            .audited(
+                "append_unique",
+                &req.path,
+                &extensions,
+                self.db
+                    .append_unique(&req.path, line, req.case_sensitive.unwrap_or(true)),
+            )
+            .await;
+        let appended = self.note_result(result).await?;
+
+        Ok(CallToolResult::success(vec![Content::text(if appended {
+            format!("Appended the line to {}", self.display_path(&req.path))
+        } else {
+            format!(
+                "{} already has that line, nothing appended",
+                self.display_path(&req.path)
+            )
+        })]))
+    }
+
+    #[tool(
+        description = "Add a timestamped entry to a log note, such as a daily note: under a time heading that's only started when the minute changes, or as a stamped bullet, depending on the format"
+    )]
+    async fn append_to_log(
+        &self,
+        Parameters(req): Parameters<AppendToLogRequest>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let now = chrono::Local::now();
+        let path = self.note_path(&expand_separator(Some(&req.path), now))?;
+        let format = req
+            .format
+            .as_deref()
+            .unwrap_or(&self.config.log_entry_format);
+        if !format.contains("{{time}}") && !format.contains("{{date}}") {
+            return Err(invalid_params(
+                "format needs a {{time}} or {{date}} to stamp entries with",
+            ));
+        }
+        let _lock = self.lock_note(&path).await;
+
+        let existing = self.optional_note_content(&path).await?;
+        if existing.is_none() && !req.create.unwrap_or(true) {
+            return self.note_result(Err(CouchDbError::NotFound(path))).await;
+        }
+        let content = append_log_entry(
+            existing
+                .as_ref()
+                .map_or("", |(_, content)| content.as_str()),
+            &req.content,
+            format,
+            now,
+        );
+        let save = async {
+            match existing {
+                Some((doc, _)) => self.db.update_note(&path, &content, &doc).await,
+                None => self.db.save_note(&path, &content).await,
+            }
+        };
+        let result = self
+            .audited("append_to_log", &path, &extensions, save)
+            .await;
+        self.note_result(result).await?;
+
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "Logged to {}",
+            self.display_path(&path)
+        ))]))
+    }
+
+    #[tool(
+        description = "Edit a note by replacing old_string with new_string. The old_string must appear exactly once in the note - include enough surrounding context to make it unique. To insert text, include the surrounding lines in both old_string and new_string, with your new content added in new_string. To delete text, include it in old_string with surrounding context, and omit it from new_string."
+    )]
+    async fn edit_note(
+        &self,
+        Parameters(mut req): Parameters<EditNoteRequest>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        req.path = self.note_path(&req.path)?;
+        let _lock = self.lock_note(&req.path).await;
+
+        if req.old_string.is_empty() {
+            return Err(invalid_params(
+                "old_string cannot be empty - include surrounding context to identify where to make changes",
+            ));
+        }
+
+        if req.old_string == req.new_string {
+            return Err(invalid_params("old_string and new_string are identical"));
+        }
+
+        let (doc, content) = self.note_to_edit(&req.path).await?;
+
+        // Find all occurrences of old_string
+        let matches: Vec<_> = content.match_indices(&req.old_string).collect();
+
+        match matches.len() {
+            0 => Err(invalid_params(
+                "old_string not found in note - make sure it matches exactly, including whitespace",
+            )),
+            1 => {
+                let new_content = content.replacen(&req.old_string, &req.new_string, 1);
+                self.audited(
+                    "edit_note",
+                    &req.path,
+                    &extensions,
+                    self.db.update_note(&req.path, &new_content, &doc),
+                )
+                .await
+                .map_err(|e| self.db_error(e))?;
+
+                Ok(CallToolResult::success(vec![Content::text(format!(
+                    "Successfully edited {}",
+                    self.display_path(&req.path)
+                ))]))
+            }
+            n => Err(invalid_params(format!(
+                "old_string appears {} times in the note - include more surrounding context to make it unique",
+                n
+            ))),
+        }
+    }
+
+    #[tool(
+        description = "Copy a note to a new path, e.g. to use it as a starting point. Fails if the destination exists unless overwrite is set."
+    )]
+    async fn copy_note(
+        &self,
+        Parameters(mut req): Parameters<CopyNoteRequest>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        req.from = self.note_path(&req.from)?;
+        req.to = self.note_path(&req.to)?;
+        let _lock = self.lock_note(&req.to).await;
+
+        let result = self
+            .audited(
+                "copy_note",
+                &req.to,
+                &extensions,
+                self.db
+                    .copy_note(&req.from, &req.to, req.overwrite.unwrap_or(false), false),
+            )
+            .await;
+        self.note_result(result).await?;
+
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "Successfully copied {} to {}",
+            self.display_path(&req.from),
+            self.display_path(&req.to)
+        ))]))
+    }
+
+    #[tool(
+        description = "Compare two notes, as a unified diff (like diff -u) of their contents. A note that doesn't exist diffs as empty, so everything shows as added or removed. Useful for reconciling near-duplicates or an archived copy with the live note"
+    )]
+    async fn diff_notes(
+        &self,
+        Parameters(mut req): Parameters<DiffNotesRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        req.from = self.note_path(&req.from)?;
+        req.to = self.note_path(&req.to)?;
+
+        let (from, to) = (
+            self.optional_note_content(&req.from).await?.map(|(_, c)| c),
+            self.optional_note_content(&req.to).await?.map(|(_, c)| c),
+        );
+        if from.is_none() && to.is_none() {
+            return Err(McpError::resource_not_found(
+                format!(
+                    "Neither {} nor {} exists",
+                    self.display_path(&req.from),
+                    self.display_path(&req.to)
+                ),
+                None,
+            ));
+        }
+        let name = |path: &str, content: &Option<String>| match content {
+            Some(_) => self.display_path(path).to_string(),
+            None => "/dev/null".to_string(),
+        };
+
+        let diff = diff::unified(
+            from.as_deref().unwrap_or_default(),
+            to.as_deref().unwrap_or_default(),
+            &name(&req.from, &from),
+            &name(&req.to, &to),
+            req.context.unwrap_or(3),
+        );
+        if diff.is_empty() {
+            return Ok(CallToolResult::success(vec![Content::text(
+                "The notes are identical",
+            )]));
+        }
+        Ok(CallToolResult::success(vec![Content::text(diff)]))
+    }
+
+    #[tool(
+        description = "Archive a note: move it into the archive folder, keeping the rest of its path (e.g. 'Projects/old.md' becomes 'Archive/Projects/old.md'). Unlike deleting, it stays readable and searchable"
+    )]
+    async fn archive_note(
+        &self,
+        Parameters(mut req): Parameters<ArchiveNoteRequest>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        req.path = self.note_path(&req.path)?;
+        let timestamp = req
+            .timestamp
+            .unwrap_or(false)
+            .then(|| chrono::Local::now().format("%Y-%m-%d-%H%M%S").to_string());
+        let dest = self.archive_path(&req.path, timestamp.as_deref())?;
+        validate_note_path(&dest, &self.config.path_allow_chars)?;
+
+        self.move_note("archive_note", &req.path, &dest, false, &extensions)
+            .await?;
+
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "Archived {} to {}",
+            self.display_path(&req.path),
+            self.display_path(&dest)
+        ))]))
+    }
+
+    #[tool(
+        description = "Move every note in a folder to another folder, keeping the layout underneath. Dry run by default - check the report, then call again with dry_run false. Both folders must end in '/'"
+    )]
+    async fn move_folder(
+        &self,
+        Parameters(req): Parameters<MoveFolderRequest>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let from = self.folder_prefix(&req.from)?;
+        let to = self.folder_prefix(&req.to)?;
+        if from == to {
+            return Err(invalid_params("from and to are the same folder"));
+        }
+        let dry_run = req.dry_run.unwrap_or(true);
+        let overwrite = req.overwrite.unwrap_or(false);
+
+        let notes = self.notes_in_folder(&from).await?;
+        self.check_batch_size(notes.len())?;
+
+        let mut results = Vec::with_capacity(notes.len());
+        for path in notes {
+            let dest = format!("{}{}", to, &path[from.len()..]);
+            let outcome = match validate_note_path(&dest, &self.config.path_allow_chars) {
+                Err(e) => Err(e.message.to_string()),
+                Ok(_) if dry_run => Ok(()),
+                Ok(_) => self
+                    .move_note("move_folder", &path, &dest, overwrite, &extensions)
+                    .await
+                    .map_err(|e| e.message.to_string()),
+            };
+            results.push(FolderOpResult {
+                path: self.display_path(&path).to_string(),
+                to: Some(self.display_path(&dest).to_string()),
+                success: outcome.is_ok(),
+                error: outcome.err(),
+            });
+        }
+
+        let json = serde_json::to_string_pretty(&FolderOpResponse {
+            dry_run,
+            notes: results,
+        })
+        .map_err(|e| mcp_error(e.to_string()))?;
+        Ok(CallToolResult::success(vec![Content::text(json)]))
+    }
+
+    #[tool(
+        description = "Delete every note in a folder (and its subfolders). Dry run by default - check the report, then call again with dry_run false. The folder must end in '/'"
+    )]
+    async fn delete_folder(
+        &self,
+        Parameters(req): Parameters<DeleteFolderRequest>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        let folder = self.folder_prefix(&req.folder)?;
+        let dry_run = req.dry_run.unwrap_or(true);
+
+        let notes = self.notes_in_folder(&folder).await?;
+        self.check_batch_size(notes.len())?;
+
+        let mut results = Vec::with_capacity(notes.len());
+        for path in notes {
+            let outcome = if dry_run {
+                Ok(())
+            } else {
+                let _lock = self.lock_note(&path).await;
+                self.audited(
+                    "delete_folder",
+                    &path,
+                    &extensions,
+                    self.db.delete_note(&path),
+                )
+                .await
+                .map_err(|e| self.db_error(e).message.to_string())
+            };
+            results.push(FolderOpResult {
+                path: self.display_path(&path).to_string(),
+                to: None,
+                success: outcome.is_ok(),
+                error: outcome.err(),
+            });
+        }
+
+        let json = serde_json::to_string_pretty(&FolderOpResponse {
+            dry_run,
+            notes: results,
+        })
+        .map_err(|e| mcp_error(e.to_string()))?;
+        Ok(CallToolResult::success(vec![Content::text(json)]))
+    }
+
+    #[tool(
+        description = "Get a note's properties (YAML frontmatter) as a JSON object. Returns {} if the note has none"
+    )]
+    async fn get_frontmatter(
+        &self,
+        Parameters(mut req): Parameters<GetFrontmatterRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        req.path = self.note_path(&req.path)?;
+
+        let content = self.note_content(&req.path).await?;
+        let properties: serde_json::Map<_, _> = frontmatter::get(&content)
+            .map_err(|e| mcp_error(e.to_string()))?
+            .into_iter()
+            .collect();
+
+        let json = serde_json::to_string_pretty(&properties)
+            .map_err(|e| mcp_error(format!("Failed to serialize properties: {}", e)))?;
+        Ok(CallToolResult::success(vec![Content::text(json)]))
+    }
+
+    #[tool(
+        description = "Set a note's properties (YAML frontmatter) without touching the rest of the note. Given properties are merged into the existing ones unless replace is set; null removes a property. Creates the frontmatter block if the note doesn't have one"
+    )]
+    async fn set_frontmatter(
+        &self,
+        Parameters(mut req): Parameters<SetFrontmatterRequest>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        req.path = self.note_path(&req.path)?;
+        let _lock = self.lock_note(&req.path).await;
+
+        let (doc, content) = self.note_to_edit(&req.path).await?;
+        let new_content = frontmatter::set(&content, req.properties, req.replace.unwrap_or(false))
+            .map_err(|e| mcp_error(e.to_string()))?;
+
+        if new_content != content {
+            self.audited(
+                "set_frontmatter",
+                &req.path,
+                &extensions,
+                self.db.update_note(&req.path, &new_content, &doc),
+            )
             .await
+            .map_err(|e| self.db_error(e))?;
+        }
+
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "Successfully updated properties of {}",
+            self.display_path(&req.path)
+        ))]))
+    }
+
+    #[tool(
+        description = "Read the content under a heading, up to the next heading of the same or a higher level (so subsections are included). The heading line itself isn't returned"
+    )]
+    async fn read_section(
+        &self,
+        Parameters(mut req): Parameters<ReadSectionRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        req.path = self.note_path(&req.path)?;
+
+        let content = self.note_content(&req.path).await?;
+        let section = markdown::find_section(&content, &req.heading)
+            .map_err(|e| invalid_params(e.to_string()))?;
+
+        Ok(CallToolResult::success(vec![Content::text(
+            &content[section.heading.end..section.end],
+        )]))
+    }
+
+    #[tool(
+        description = "Get a note's headings as a nested outline of {level, text, line, children}, like a table of contents. Useful on long notes before picking a section to read with read_section"
+    )]
+    async fn get_outline(
+        &self,
+        Parameters(mut req): Parameters<GetOutlineRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        req.path = self.note_path(&req.path)?;
+
+        let content = self.note_content(&req.path).await?;
+        let json = serde_json::to_string_pretty(&markdown::outline(&content))
             .map_err(|e| mcp_error(e.to_string()))?;
+        Ok(CallToolResult::success(vec![Content::text(json)]))
+    }
+
+    #[tool(
+        description = "Replace the content under a heading, up to the next heading of the same or a higher level (subsections included). The heading line is kept. Safer than editing by line number since it doesn't care what's changed elsewhere in the note"
+    )]
+    async fn replace_section(
+        &self,
+        Parameters(mut req): Parameters<ReplaceSectionRequest>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        req.path = self.note_path(&req.path)?;
+        let _lock = self.lock_note(&req.path).await;
+
+        let (doc, content) = self.note_to_edit(&req.path).await?;
+        let new_content = markdown::replace_section(&content, &req.heading, &req.content)
+            .map_err(|e| invalid_params(e.to_string()))?;
+
+        self.audited(
+            "replace_section",
+            &req.path,
+            &extensions,
+            self.db.update_note(&req.path, &new_content, &doc),
+        )
+        .await
+        .map_err(|e| self.db_error(e))?;
 
         Ok(CallToolResult::success(vec![Content::text(format!(
-            "Successfully appended to {}",
-            req.path
+            "Successfully replaced section '{}' in {}",
+            req.heading.trim(),
+            self.display_path(&req.path)
         ))]))
     }
 
     #[tool(
-        description = "Edit a note by replacing old_string with new_string. The old_string must appear exactly once in the note - include enough surrounding context to make it unique. To insert text, include the surrounding lines in both old_string and new_string, with your new content added in new_string. To delete text, include it in old_string with surrounding context, and omit it from new_string."
+        description = "Insert content under a heading, either straight after the heading line or at the end of its section (e.g. add an entry to '## Log'). Returns the line the content starts on"
     )]
-    async fn edit_note(
+    async fn insert_after_heading(
         &self,
-        Parameters(req): Parameters<EditNoteRequest>,
+        Parameters(mut req): Parameters<InsertAfterHeadingRequest>,
+        extensions: Extensions,
     ) -> Result<CallToolResult, McpError> {
-        validate_note_path(&req.path)?;
+        req.path = self.note_path(&req.path)?;
+        let at_end = match req.position.as_deref().map(str::trim) {
+            None | Some("start") => false,
+            Some("end") => true,
+            Some(other) => {
+                return Err(invalid_params(format!(
+                    "position must be 'start' or 'end', not '{}'",
+                    other
+                )));
+            }
+        };
+        let _lock = self.lock_note(&req.path).await;
 
-        if req.old_string.is_empty() {
-            return Err(mcp_error(
-                "old_string cannot be empty - include surrounding context to identify where to make changes",
-            ));
+        let (doc, content) = self.note_to_edit(&req.path).await?;
+        let (new_content, line) =
+            markdown::insert_in_section(&content, &req.heading, &req.content, at_end)
+                .map_err(|e| invalid_params(e.to_string()))?;
+
+        self.audited(
+            "insert_after_heading",
+            &req.path,
+            &extensions,
+            self.db.update_note(&req.path, &new_content, &doc),
+        )
+        .await
+        .map_err(|e| self.db_error(e))?;
+
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "Inserted under '{}' in {} at line {}",
+            req.heading.trim(),
+            self.display_path(&req.path),
+            line
+        ))]))
+    }
+
+    #[tool(
+        description = "Rename a heading in a note, keeping its level, and update [[Note#Heading]] links to it across the vault (plus [[#Heading]] links within the note). Reports which notes had links updated"
+    )]
+    async fn rename_heading(
+        &self,
+        Parameters(mut req): Parameters<RenameHeadingRequest>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        req.path = self.note_path(&req.path)?;
+        let new_heading = check_heading_text(&req.new_heading)?;
+        let update_links = req.update_links.unwrap_or(true);
+        let lock = self.lock_note(&req.path).await;
+
+        let (doc, content) = self.note_to_edit(&req.path).await?;
+        let (mut new_content, old) = markdown::rename_heading(&content, &req.heading, new_heading)
+            .map_err(|e| invalid_params(e.to_string()))?;
+
+        // the other notes whose indexed content has links to the heading, with the targets in
+        // each that point at this note, and the ways this note links to itself
+        let (linking, own) = if update_links {
+            let index = self.search_index.read().await;
+            require_content(&index, "update_links")?;
+            let mut targets = index.links_to(&req.path);
+            let linking: BTreeMap<String, HashSet<String>> = index
+                .notes()
+                .filter(|note| note.path != req.path && note.path.starts_with(self.root()))
+                .filter_map(|note| {
+                    let targets = targets.get(&note.path)?;
+                    let (_, renamed) = markdown::rename_heading_links(
+                        &note.content,
+                        |t| targets.contains(t),
+                        &old.text,
+                        new_heading,
+                    );
+                    (renamed > 0).then(|| (note.path.clone(), targets.clone()))
+                })
+                .collect();
+            (linking, targets.remove(&req.path).unwrap_or_default())
+        } else {
+            (BTreeMap::new(), HashSet::new())
+        };
+
+        let mut link_report = Vec::new();
+        if update_links {
+            let renamed;
+            (new_content, renamed) = markdown::rename_heading_links(
+                &new_content,
+                |t| t.is_empty() || own.contains(t),
+                &old.text,
+                new_heading,
+            );
+            if renamed > 0 {
+                link_report.push(format!("updated links in {}", self.display_path(&req.path)));
+            }
         }
 
-        if req.old_string == req.new_string {
-            return Err(mcp_error("old_string and new_string are identical"));
+        self.audited(
+            "rename_heading",
+            &req.path,
+            &extensions,
+            self.db.update_note(&req.path, &new_content, &doc),
+        )
+        .await
+        .map_err(|e| self.db_error(e))?;
+        drop(lock);
+
+        for (path, targets) in linking {
+            let _lock = self.lock_note(&path).await;
+            let outcome = async {
+                let (doc, content) = self.note_to_edit(&path).await?;
+                let (new_content, renamed) = markdown::rename_heading_links(
+                    &content,
+                    |t| targets.contains(t),
+                    &old.text,
+                    new_heading,
+                );
+                if renamed > 0 {
+                    self.audited(
+                        "rename_heading",
+                        &path,
+                        &extensions,
+                        self.db.update_note(&path, &new_content, &doc),
+                    )
+                    .await
+                    .map_err(|e| self.db_error(e))?;
+                }
+                Ok::<_, McpError>(renamed)
+            }
+            .await;
+            match outcome {
+                Ok(0) => {}
+                Ok(_) => link_report.push(format!("updated links in {}", self.display_path(&path))),
+                Err(e) => link_report.push(format!(
+                    "couldn't update links in {}: {}",
+                    self.display_path(&path),
+                    e.message
+                )),
+            }
         }
 
-        let doc = self
-            .db
-            .get_note(&req.path)
-            .await
+        let mut content = vec![Content::text(format!(
+            "Renamed '{}' to '{}' in {}",
+            old.text,
+            new_heading,
+            self.display_path(&req.path)
+        ))];
+        if update_links {
+            if link_report.is_empty() {
+                link_report.push("no links needed updating".to_string());
+            }
+            content.push(Content::text(link_report.join("\n")));
+        }
+        Ok(CallToolResult::success(content))
+    }
+
+    #[tool(
+        description = "Flip a task's checkbox ('- [ ]' <-> '- [x]'), picked by line number or by its text. Pass status to set a specific marker instead"
+    )]
+    async fn toggle_task(
+        &self,
+        Parameters(mut req): Parameters<ToggleTaskRequest>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        req.path = self.note_path(&req.path)?;
+        let _lock = self.lock_note(&req.path).await;
+
+        let status = match req.status.as_deref() {
+            None => None,
+            Some(s) => {
+                let mut chars = s.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if c != '[' && c != ']' && c != '\n' => Some(c),
+                    _ => {
+                        return Err(invalid_params(
+                            "status must be a single character, e.g. ' ' or 'x'",
+                        ));
+                    }
+                }
+            }
+        };
+
+        let (doc, content) = self.note_to_edit(&req.path).await?;
+        let tasks = markdown::tasks(&content);
+        let task = match (req.line, req.text.as_deref()) {
+            (Some(line), None) => tasks
+                .iter()
+                .find(|t| t.line == line)
+                .ok_or_else(|| invalid_params(format!("line {} isn't a task", line)))?,
+            (None, Some(text)) => {
+                let needle = text.trim().to_lowercase();
+                let matches: Vec<_> = tasks
+                    .iter()
+                    .filter(|t| t.text.to_lowercase().contains(&needle))
+                    .collect();
+                match matches.as_slice() {
+                    [] => return Err(invalid_params("no task matching that text in the note")),
+                    [task] => *task,
+                    many => {
+                        let lines: Vec<_> = many.iter().map(|t| t.line.to_string()).collect();
+                        return Err(invalid_params(format!(
+                            "{} tasks match that text (lines {}) - be more specific or pass a line number",
+                            many.len(),
+                            lines.join(", ")
+                        )));
+                    }
+                }
+            }
+            _ => return Err(invalid_params("pass exactly one of line or text")),
+        };
+
+        let new_status = status.unwrap_or(if task.status == ' ' { 'x' } else { ' ' });
+        let new_content = markdown::set_task_status(&content, task.line, new_status)
             .map_err(|e| mcp_error(e.to_string()))?;
 
-        let content = self
-            .db
-            .decode_content(&doc)
+        if new_content != content {
+            self.audited(
+                "toggle_task",
+                &req.path,
+                &extensions,
+                self.db.update_note(&req.path, &new_content, &doc),
+            )
             .await
-            .map_err(|e| mcp_error(e.to_string()))?;
+            .map_err(|e| self.db_error(e))?;
+        }
 
-        // Find all occurrences of old_string
-        let matches: Vec<_> = content.match_indices(&req.old_string).collect();
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "Line {} of {} is now [{}] {}",
+            task.line,
+            self.display_path(&req.path),
+            new_status,
+            task.text
+        ))]))
+    }
 
-        match matches.len() {
-            0 => Err(mcp_error(
-                "old_string not found in note - make sure it matches exactly, including whitespace",
-            )),
-            1 => {
-                let new_content = content.replacen(&req.old_string, &req.new_string, 1);
-                self.db
-                    .save_note(&req.path, &new_content)
-                    .await
-                    .map_err(|e| mcp_error(e.to_string()))?;
+    #[tool(
+        description = "List checkbox tasks ('- [ ] ...') with their line numbers and status, in one note or across the vault"
+    )]
+    async fn list_tasks(
+        &self,
+        Parameters(req): Parameters<ListTasksRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let include_done = req.include_done.unwrap_or(true);
+        let limit = req.limit.unwrap_or(500);
 
-                Ok(CallToolResult::success(vec![Content::text(format!(
-                    "Successfully edited {}",
-                    req.path
-                ))]))
+        // one note is read fresh, the whole vault comes from the search index
+        let notes = match &req.path {
+            Some(path) => {
+                let path = self.note_path(path)?;
+                let content = self.note_content(&path).await?;
+                vec![(path, content)]
             }
-            n => Err(mcp_error(format!(
-                "old_string appears {} times in the note - include more surrounding context to make it unique",
-                n
-            ))),
+            None => {
+                let prefix = format!("{}{}", self.root(), req.prefix.unwrap_or_default());
+                let index = self.search_index.read().await;
+                require_content(&index, "list_tasks across the vault")?;
+                let mut notes: Vec<_> = index
+                    .notes()
+                    .filter(|n| n.path.starts_with(&prefix))
+                    .map(|n| (n.path.clone(), n.content.clone()))
+                    .collect();
+                notes.sort();
+                notes
+            }
+        };
+
+        let tasks: Vec<TaskResponse> = notes
+            .iter()
+            .flat_map(|(path, content)| {
+                markdown::tasks(content).into_iter().map(|t| TaskResponse {
+                    path: self.display_path(path).to_string(),
+                    line: t.line,
+                    status: t.status.to_string(),
+                    done: t.is_done(),
+                    text: t.text,
+                })
+            })
+            .filter(|t| include_done || !t.done)
+            .take(limit)
+            .collect();
+
+        let json = serde_json::to_string_pretty(&tasks).map_err(|e| mcp_error(e.to_string()))?;
+        Ok(CallToolResult::success(vec![Content::text(json)]))
+    }
+
+    #[tool(
+        description = "List every tag used in the vault (from frontmatter tags and inline #tags) with how many notes use it, most used first. Handy for getting a feel for how the vault is organised"
+    )]
+    async fn list_tags(
+        &self,
+        Parameters(req): Parameters<ListTagsRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let index = self.search_index.read().await;
+        let tags: Vec<TagCount> = index
+            .tag_counts(self.root())
+            .into_iter()
+            .take(req.limit.unwrap_or(usize::MAX))
+            .map(|(tag, notes)| TagCount { tag, notes })
+            .collect();
+
+        let json = serde_json::to_string_pretty(&tags).map_err(|e| mcp_error(e.to_string()))?;
+        Ok(CallToolResult::success(vec![Content::text(json)]))
+    }
+
+    #[tool(
+        description = "Overview of the vault (or a folder): how many notes, their total, average and median size, the biggest ones, notes per top-level folder and how many distinct tags. Good for getting your bearings in an unfamiliar vault"
+    )]
+    async fn vault_stats(
+        &self,
+        Parameters(req): Parameters<VaultStatsRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let prefix = match &req.folder {
+            Some(folder) => self.folder_prefix(folder)?,
+            None => self.root().to_string(),
+        };
+        let largest = req.largest.unwrap_or(10).min(MAX_LARGEST_NOTES);
+
+        let stats = self.search_index.read().await.stats(&prefix, largest);
+        let sizes = stats.sizes.as_ref();
+        let response = VaultStatsResponse {
+            notes: stats.notes,
+            other_files: stats.other_files,
+            tags: stats.tags,
+            folders: stats
+                .folders
+                .into_iter()
+                .map(|(folder, notes)| FolderCount { folder, notes })
+                .collect(),
+            total_size: sizes.map(|s| s.total),
+            average_size: sizes.map(|s| s.average),
+            median_size: sizes.map(|s| s.median),
+            largest: sizes
+                .map(|s| s.largest.as_slice())
+                .unwrap_or_default()
+                .iter()
+                .map(|(path, size)| NoteSize {
+                    path: self.display_path(path).to_string(),
+                    size: *size,
+                })
+                .collect(),
+        };
+
+        let json = serde_json::to_string_pretty(&response).map_err(|e| mcp_error(e.to_string()))?;
+        Ok(CallToolResult::success(vec![Content::text(json)]))
+    }
+
+    #[tool(
+        description = "Find notes by a frontmatter property, e.g. status eq active or priority ge 3. Treats the vault like a small database"
+    )]
+    async fn query_frontmatter(
+        &self,
+        Parameters(req): Parameters<QueryFrontmatterRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let op = req.op.as_deref().unwrap_or("eq");
+        let comparison = Comparison::parse(op).ok_or_else(|| {
+            invalid_params(format!(
+                "Unknown op '{}', expected one of eq, ne, lt, le, gt, ge, exists",
+                op
+            ))
+        })?;
+        let value = req.value.unwrap_or_default();
+        if comparison != Comparison::Exists && value.is_null() {
+            return Err(invalid_params(format!("op '{}' needs a value", op)));
+        }
+        let prefix = format!("{}{}", self.root(), req.prefix.unwrap_or_default());
+
+        let index = self.search_index.read().await;
+        let matches: Vec<FrontmatterMatch> = index
+            .query_properties(&req.field, comparison, &value, &prefix)
+            .into_iter()
+            .take(req.limit.unwrap_or(100))
+            .map(|(path, title, value)| FrontmatterMatch {
+                path: self.display_path(&path).to_string(),
+                title,
+                value,
+            })
+            .collect();
+
+        let json = serde_json::to_string_pretty(&matches).map_err(|e| mcp_error(e.to_string()))?;
+        Ok(CallToolResult::success(vec![Content::text(json)]))
+    }
+
+    #[tool(
+        description = "List notes modified since a time, newest first, with their modification times. Asks CouchDB directly, so it sees every note even while the search index is loading"
+    )]
+    async fn list_recent_notes(
+        &self,
+        Parameters(req): Parameters<ListRecentNotesRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let since = parse_since(&req.since).ok_or_else(|| {
+            invalid_params(format!(
+                "since should be an RFC 3339 time or a YYYY-MM-DD date, not '{}'",
+                req.since
+            ))
+        })?;
+        let limit = req.limit.unwrap_or(50);
+        let prefix = self.db.path_style().doc_id(&format!(
+            "{}{}",
+            self.root(),
+            req.prefix.unwrap_or_default()
+        ));
+
+        let mut selector = serde_json::json!({
+            "mtime": {"$gt": since},
+            "type": {"$in": ["plain", "newnote", "notes"]},
+            "$or": [{"deleted": {"$exists": false}}, {"deleted": false}],
+        });
+        if !prefix.is_empty() {
+            selector["_id"] =
+                serde_json::json!({"$gte": prefix, "$lt": format!("{}\u{fff0}", prefix)});
+        }
+        // couchdb can only sort by mtime with an index on it (see SETUP.md). Without one it's
+        // done here, over as many matches as we're willing to fetch
+        let newest_first = serde_json::json!([{"mtime": "desc"}]);
+        let (notes, complete) = match self
+            .db
+            .find(selector.clone(), Some(newest_first), limit)
+            .await
+        {
+            Ok(notes) => (notes, true),
+            Err(CouchDbError::NoIndex(_)) => {
+                let mut notes = self
+                    .db
+                    .find(selector, None, MAX_RECENT_MATCHES)
+                    .await
+                    .map_err(|e| self.db_error(e))?;
+                notes.sort_by_key(|note| std::cmp::Reverse(note.mtime));
+                let complete = notes.len() < MAX_RECENT_MATCHES;
+                (notes, complete)
+            }
+            Err(e) => return Err(self.db_error(e)),
+        };
+
+        let style = self.db.path_style();
+        let recent: Vec<RecentNoteResponse> = notes
+            .into_iter()
+            .take(limit)
+            .map(|note| RecentNoteResponse {
+                path: self
+                    .display_path(&style.note_path(&note.id, Some(&note.path)))
+                    .to_string(),
+                modified: chrono::DateTime::from_timestamp_millis(note.mtime as i64)
+                    .unwrap_or_default()
+                    .to_rfc3339(),
+                size: note.size,
+            })
+            .collect();
+
+        let json = serde_json::to_string_pretty(&recent).map_err(|e| mcp_error(e.to_string()))?;
+        let mut content = vec![Content::text(json)];
+        if !complete {
+            content.push(Content::text(format!(
+                "incomplete: more than {} notes changed since then and CouchDB has no index on \
+                 mtime to sort them with, so some newer ones may be missing. Use a later since",
+                MAX_RECENT_MATCHES
+            )));
         }
+        Ok(CallToolResult::success(content))
+    }
+
+    #[tool(
+        description = "Find [[wikilinks]] and ![[embeds]] that point at notes which don't exist (renamed or deleted), grouped by the note they're in, with line numbers"
+    )]
+    async fn find_broken_links(
+        &self,
+        Parameters(req): Parameters<FindBrokenLinksRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let prefix = format!("{}{}", self.root(), req.prefix.unwrap_or_default());
+        let index = self.search_index.read().await;
+        require_content(&index, "find_broken_links")?;
+        let broken: Vec<BrokenLinksResponse> = index
+            .broken_links(&prefix)
+            .into_iter()
+            .map(|(path, links)| BrokenLinksResponse {
+                path: self.display_path(&path).to_string(),
+                links: links
+                    .into_iter()
+                    .map(|l| BrokenLinkResponse {
+                        line: l.line,
+                        target: l.target,
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        let json = serde_json::to_string_pretty(&broken).map_err(|e| mcp_error(e.to_string()))?;
+        Ok(CallToolResult::success(vec![Content::text(json)]))
+    }
+
+    #[tool(
+        description = "List notes that no other note links to (no backlinks), to find forgotten notes. Exclude folders like daily notes that are expected to have none"
+    )]
+    async fn list_orphan_notes(
+        &self,
+        Parameters(req): Parameters<ListOrphanNotesRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let prefix = format!("{}{}", self.root(), req.prefix.unwrap_or_default());
+        let exclude: Vec<String> = req
+            .exclude
+            .unwrap_or_default()
+            .iter()
+            .map(|e| format!("{}{}", self.root(), e))
+            .collect();
+
+        let index = self.search_index.read().await;
+        require_content(&index, "list_orphan_notes")?;
+        let orphans: Vec<OrphanNoteResponse> = index
+            .orphans(&prefix, &exclude)
+            .into_iter()
+            .map(|(path, title)| OrphanNoteResponse {
+                path: self.display_path(&path).to_string(),
+                title,
+            })
+            .collect();
+
+        let json = serde_json::to_string_pretty(&orphans).map_err(|e| mcp_error(e.to_string()))?;
+        Ok(CallToolResult::success(vec![Content::text(json)]))
     }
 
     #[tool(
-        description = "Read multiple notes at once. Returns content for each note, with per-note success/failure reporting."
+        description = "Read multiple notes at once. Returns content for each note, with per-note success/failure reporting plus a summary of how many succeeded."
     )]
     async fn batch_read_notes(
         &self,
         Parameters(req): Parameters<BatchReadNotesRequest>,
     ) -> Result<CallToolResult, McpError> {
+        self.check_batch_size(req.paths.len())?;
+
         let mut results = Vec::with_capacity(req.paths.len());
 
         for path in req.paths {
-            let result = match validate_note_path(&path) {
+            let result = match self.note_path(&path) {
                 Err(e) => BatchReadResult {
                     path,
                     success: false,
                     content: None,
                     error: Some(e.message.to_string()),
                 },
-                Ok(()) => match self.db.get_note(&path).await {
+                Ok(id) => match self.db.get_note(&id).await {
                     Err(e) => BatchReadResult {
-                        path,
+                        path: self.display_path(&id).to_string(),
                         success: false,
                         content: None,
                         error: Some(e.to_string()),
                     },
                     Ok(doc) => match self.db.decode_content(&doc).await {
                         Err(e) => BatchReadResult {
-                            path,
+                            path: self.display_path(&id).to_string(),
+                            success: false,
+                            content: None,
+                            error: Some(e.to_string()),
+                        },
+                        Ok(content) => BatchReadResult {
+                            path: self.display_path(&id).to_string(),
+                            success: true,
+                            content: Some(content),
+                            error: None,
+                        },
+                    },
+                },
+            };
+            results.push(result);
+        }
+
+        batch_response(results, |r| r.success)
+    }
+
+    #[tool(
+        description = "Write multiple notes at once. Each note is created or updated independently, with per-note success/failure reporting plus a summary of how many succeeded."
+    )]
+    async fn batch_write_notes(
+        &self,
+        Parameters(req): Parameters<BatchWriteNotesRequest>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        self.check_batch_size(req.notes.len())?;
+
+        let mut results = Vec::with_capacity(req.notes.len());
+
+        for mut note in req.notes {
+            let result = match self.note_path(&note.path) {
+                Err(e) => BatchWriteResult {
+                    path: note.path,
+                    success: false,
+                    error: Some(e.message.to_string()),
+                },
+                Ok(path) => {
+                    note.path = path;
+                    let _lock = self.lock_note(&note.path).await;
+                    match self
+                        .audited(
+                            "batch_write_notes",
+                            &note.path,
+                            &extensions,
+                            self.db.save_note(&note.path, &note.content),
+                        )
+                        .await
+                    {
+                        Err(e) => BatchWriteResult {
+                            path: self.display_path(&note.path).to_string(),
+                            success: false,
+                            error: Some(e.to_string()),
+                        },
+                        Ok(_) => BatchWriteResult {
+                            path: self.display_path(&note.path).to_string(),
+                            success: true,
+                            error: None,
+                        },
+                    }
+                }
+            };
+            results.push(result);
+        }
+
+        batch_response(results, |r| r.success)
+    }
+
+    #[tool(
+        description = "Append content to multiple notes at once. Each append adds a newline before the content. Per-note success/failure reporting plus a summary of how many succeeded."
+    )]
+    async fn batch_append_to_notes(
+        &self,
+        Parameters(req): Parameters<BatchAppendNotesRequest>,
+        extensions: Extensions,
+    ) -> Result<CallToolResult, McpError> {
+        self.check_batch_size(req.notes.len())?;
+
+        let mut results = Vec::with_capacity(req.notes.len());
+
+        for mut note in req.notes {
+            let result = match self.note_path(&note.path) {
+                Err(e) => BatchAppendResult {
+                    path: note.path,
+                    success: false,
+                    error: Some(e.message.to_string()),
+                },
+                Ok(path) => {
+                    note.path = path;
+                    let _lock = self.lock_note(&note.path).await;
+                    match self
+                        .audited(
+                            "batch_append_to_notes",
+                            &note.path,
+                            &extensions,
+                            self.db.append_to_note(
+                                &note.path,
+                                &note.content,
+                                &expand_separator(note.separator.as_deref(), chrono::Local::now()),
+                            ),
+                        )
+                        .await
+                    {
+                        Err(e) => BatchAppendResult {
+                            path: self.display_path(&note.path).to_string(),
                             success: false,
-                            content: None,
                             error: Some(e.to_string()),
                         },
-                        Ok(content) => BatchReadResult {
-                            path,
+                        Ok(_) => BatchAppendResult {
+                            path: self.display_path(&note.path).to_string(),
                             success: true,
-                            content: Some(content),
                             error: None,
                         },
-                    },
-                },
+                    }
+                }
             };
             results.push(result);
         }
 
-        let json = serde_json::to_string_pretty(&results).map_err(|e| mcp_error(e.to_string()))?;
-        Ok(CallToolResult::success(vec![Content::text(json)]))
+        batch_response(results, |r| r.success)
     }
 
     #[tool(
-        description = "Write multiple notes at once. Each note is created or updated independently, with per-note success/failure reporting."
+        description = "Append single lines to multiple notes, skipping any line a note already has. Per-note reporting of whether each line was appended, plus a summary of how many succeeded."
     )]
-    async fn batch_write_notes(
+    async fn batch_append_unique(
         &self,
-        Parameters(req): Parameters<BatchWriteNotesRequest>,
+        Parameters(req): Parameters<BatchAppendUniqueRequest>,
+        extensions: Extensions,
     ) -> Result<CallToolResult, McpError> {
+        self.check_batch_size(req.notes.len())?;
+        let case_sensitive = req.case_sensitive.unwrap_or(true);
+
         let mut results = Vec::with_capacity(req.notes.len());
 
         for note in req.notes {
-            let result = match validate_note_path(&note.path) {
-                Err(e) => BatchWriteResult {
+            let checked = self
+                .note_path(&note.path)
+                .and_then(|path| Ok((path, check_unique_line(&note.line)?)));
+            let result = match checked {
+                Err(e) => BatchAppendUniqueResult {
                     path: note.path,
                     success: false,
+                    appended: None,
                     error: Some(e.message.to_string()),
                 },
-                Ok(()) => match self.db.save_note(&note.path, &note.content).await {
-                    Err(e) => BatchWriteResult {
-                        path: note.path,
-                        success: false,
-                        error: Some(e.to_string()),
-                    },
-                    Ok(_) => BatchWriteResult {
-                        path: note.path,
-                        success: true,
-                        error: None,
-                    },
-                },
+                Ok((path, line)) => {
+                    let _lock = self.lock_note(&path).await;
+                    let result = self
+                        .audited(
+                            "batch_append_unique",
+                            &path,
+                            &extensions,
+                            self.db.append_unique(&path, line, case_sensitive),
+                        )
+                        .await;
+                    let path = self.display_path(&path).to_string();
+                    match result {
+                        Err(e) => BatchAppendUniqueResult {
+                            path,
+                            success: false,
+                            appended: None,
+                            error: Some(e.to_string()),
+                        },
+                        Ok(appended) => BatchAppendUniqueResult {
+                            path,
+                            success: true,
+                            appended: Some(appended),
+                            error: None,
+                        },
+                    }
+                }
             };
             results.push(result);
         }
 
-        let json = serde_json::to_string_pretty(&results).map_err(|e| mcp_error(e.to_string()))?;
-        Ok(CallToolResult::success(vec![Content::text(json)]))
+        batch_response(results, |r| r.success)
     }
 
     #[tool(
-        description = "Append content to multiple notes at once. Each append adds a newline before the content. Per-note success/failure reporting."
+        description = "Move or rename multiple notes at once, given {from, to} pairs. Moves that would land on the same destination all fail rather than overwrite each other. Set update_links to fix [[wikilinks]] to the moved notes. Per-note success/failure reporting plus a summary of how many succeeded."
     )]
-    async fn batch_append_to_notes(
+    async fn batch_move_notes(
         &self,
-        Parameters(req): Parameters<BatchAppendNotesRequest>,
+        Parameters(req): Parameters<BatchMoveNotesRequest>,
+        extensions: Extensions,
     ) -> Result<CallToolResult, McpError> {
-        let mut results = Vec::with_capacity(req.notes.len());
+        self.check_batch_size(req.moves.len())?;
+        let overwrite = req.overwrite.unwrap_or(false);
+        let update_links = req.update_links.unwrap_or(false);
 
-        for note in req.notes {
-            let result = match validate_note_path(&note.path) {
-                Err(e) => BatchAppendResult {
-                    path: note.path,
+        let mut planned: Vec<_> = req
+            .moves
+            .into_iter()
+            .map(|op| {
+                let paths = self
+                    .note_path(&op.from)
+                    .and_then(|from| Ok((from, self.note_path(&op.to)?)))
+                    .map_err(|e| e.message.to_string())
+                    .and_then(|(from, to)| {
+                        if from == to {
+                            Err("from and to are the same note".to_string())
+                        } else {
+                            Ok((from, to))
+                        }
+                    });
+                (op, paths)
+            })
+            .collect();
+
+        // two moves to one place would have the second silently replace (or fail on) the first
+        let mut destinations: HashMap<String, usize> = HashMap::new();
+        for (_, paths) in &planned {
+            if let Ok((_, to)) = paths {
+                *destinations.entry(to.clone()).or_default() += 1;
+            }
+        }
+        for (_, paths) in &mut planned {
+            if let Ok((_, to)) = paths
+                && destinations[to.as_str()] > 1
+            {
+                *paths = Err(format!(
+                    "{} moves in this batch go to {}",
+                    destinations[to.as_str()],
+                    self.display_path(to)
+                ));
+            }
+        }
+
+        let links = if update_links {
+            let moves: HashMap<String, String> = planned
+                .iter()
+                .filter_map(|(_, paths)| paths.clone().ok())
+                .collect();
+            let index = self.search_index.read().await;
+            require_content(&index, "update_links")?;
+            index.moved_links(&moves)
+        } else {
+            BTreeMap::new()
+        };
+
+        let mut results = Vec::with_capacity(planned.len());
+        let mut moved: HashMap<String, String> = HashMap::new();
+        for (op, paths) in planned {
+            let result = match paths {
+                Err(e) => BatchMoveResult {
+                    from: op.from,
+                    to: op.to,
                     success: false,
-                    error: Some(e.message.to_string()),
-                },
-                Ok(()) => match self.db.append_to_note(&note.path, &note.content).await {
-                    Err(e) => BatchAppendResult {
-                        path: note.path,
-                        success: false,
-                        error: Some(e.to_string()),
-                    },
-                    Ok(_) => BatchAppendResult {
-                        path: note.path,
-                        success: true,
-                        error: None,
-                    },
+                    error: Some(e),
                 },
+                Ok((from, to)) => {
+                    let outcome = self
+                        .move_note("batch_move_notes", &from, &to, overwrite, &extensions)
+                        .await;
+                    let result = BatchMoveResult {
+                        from: self.display_path(&from).to_string(),
+                        to: self.display_path(&to).to_string(),
+                        success: outcome.is_ok(),
+                        error: outcome.err().map(|e| e.message.to_string()),
+                    };
+                    if result.success {
+                        moved.insert(from, to);
+                    }
+                    result
+                }
             };
             results.push(result);
         }
 
-        let json = serde_json::to_string_pretty(&results).map_err(|e| mcp_error(e.to_string()))?;
-        Ok(CallToolResult::success(vec![Content::text(json)]))
+        let mut link_report = Vec::new();
+        for (path, links) in links {
+            // only notes the caller could have touched themselves, and only links to notes that
+            // did actually move
+            if !path.starts_with(self.root())
+                || !links.values().any(|l| moved.contains_key(&l.from))
+            {
+                continue;
+            }
+            let path = moved.get(&path).cloned().unwrap_or(path);
+            let _lock = self.lock_note(&path).await;
+            let outcome = async {
+                let (doc, content) = self.note_to_edit(&path).await?;
+                let new_content = markdown::retarget_links(&content, |target| {
+                    let link = links.get(target)?;
+                    moved.contains_key(&link.from).then(|| link.target.clone())
+                });
+                if new_content != content {
+                    self.audited(
+                        "batch_move_notes",
+                        &path,
+                        &extensions,
+                        self.db.update_note(&path, &new_content, &doc),
+                    )
+                    .await
+                    .map_err(|e| self.db_error(e))?;
+                }
+                Ok::<_, McpError>(())
+            }
+            .await;
+            link_report.push(match outcome {
+                Ok(()) => format!("updated links in {}", self.display_path(&path)),
+                Err(e) => format!(
+                    "couldn't update links in {}: {}",
+                    self.display_path(&path),
+                    e.message
+                ),
+            });
+        }
+
+        let mut response = batch_response(results, |r| r.success)?;
+        if update_links {
+            if link_report.is_empty() {
+                link_report.push("no links needed updating".to_string());
+            }
+            response.content.push(Content::text(link_report.join("\n")));
+        }
+        Ok(response)
     }
 
     #[tool(
-        description = "Fuzzy search notes by title and/or content. Returns ranked results with relevance scores. Use this to find notes when you don't know the exact path."
+        description = "Fuzzy search notes by title and/or content. Returns ranked results with relevance scores. Use this to find notes when you don't know the exact path. Set semantic to search by meaning instead, where available."
     )]
     async fn search_notes(
         &self,
         Parameters(req): Parameters<SearchNotesRequest>,
     ) -> Result<CallToolResult, McpError> {
-        let index = self.search_index.read().await;
+        let defaults = self.search_defaults();
+        let limit = req.limit.unwrap_or(defaults.limit);
+        let search_content = req.search_content.unwrap_or(defaults.search_content);
 
-        let results = index.search(
-            &req.query,
-            SearchOptions {
-                limit: req.limit.unwrap_or(20),
-                search_content: req.search_content.unwrap_or(true),
-            },
-        );
+        #[cfg(feature = "embeddings")]
+        if req.semantic.unwrap_or(false)
+            && let Some(results) = self.semantic_search(&req.query, limit).await
+        {
+            return search_response(search_results(results, self.root()));
+        }
+        #[cfg(not(feature = "embeddings"))]
+        if req.semantic.unwrap_or(false) {
+            tracing::debug!("Semantic search asked for, but built without embeddings support");
+        }
+
+        let results = self.fuzzy_search(&req.query, limit, search_content).await;
+        let mut response = search_response(results)?;
+        if !self.search_index.read().await.is_ready() {
+            response.content.push(Content::text(
+                "The search index is still loading, so these results may be incomplete. \
+                 index_status says when it's done",
+            ));
+        }
+        Ok(response)
+    }
 
-        let response: Vec<SearchResultResponse> = results
+    #[tool(
+        description = "Find notes similar to a given note, scored by the distinctive terms they share (TF-IDF). Returns the most related first, with the terms that matched"
+    )]
+    async fn related_notes(
+        &self,
+        Parameters(mut req): Parameters<RelatedNotesRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        req.path = self.note_path(&req.path)?;
+
+        let index = self.search_index.read().await;
+        require_content(&index, "related_notes")?;
+        let related = index
+            .related(&req.path, req.limit.unwrap_or(10), self.root())
+            .ok_or_else(|| {
+                McpError::resource_not_found(
+                    format!("{} isn't in the search index", self.display_path(&req.path)),
+                    None,
+                )
+            })?;
+
+        let response: Vec<RelatedNoteResponse> = related
             .into_iter()
-            .map(|r| SearchResultResponse {
-                path: r.path,
+            .map(|r| RelatedNoteResponse {
+                path: self.display_path(&r.path).to_string(),
                 title: r.title,
-                score: r.score,
-                snippet: r.snippet,
+                // nobody needs 16 decimal places of similarity
+                score: (r.score * 1000.0).round() / 1000.0,
+                shared_terms: r.shared_terms,
+            })
+            .collect();
+
+        let json = serde_json::to_string_pretty(&response).map_err(|e| mcp_error(e.to_string()))?;
+        Ok(CallToolResult::success(vec![Content::text(json)]))
+    }
+
+    // only registered with --debug-tools, see new()
+    #[tool(
+        description = "Debugging: show a note's raw LiveSync metadata and the id and size of each chunk, without reassembling it"
+    )]
+    async fn debug_note_chunks(
+        &self,
+        Parameters(mut req): Parameters<DebugNoteChunksRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        req.path = self.note_path(&req.path)?;
+
+        let doc = self
+            .db
+            .get_note(&req.path)
+            .await
+            .map_err(|e| self.db_error(e))?;
+
+        let chunks: Vec<ChunkInfo> = self
+            .db
+            .chunk_sizes(&doc)
+            .await
+            .into_iter()
+            .map(|(id, size)| match size {
+                Ok(size) => ChunkInfo {
+                    id,
+                    size: Some(size),
+                    error: None,
+                },
+                Err(e) => ChunkInfo {
+                    id,
+                    size: None,
+                    error: Some(e.to_string()),
+                },
             })
             .collect();
 
+        let response = NoteChunksResponse {
+            id: doc.id,
+            rev: doc.rev,
+            path: doc.path,
+            doc_type: doc.doc_type,
+            ctime: doc.ctime,
+            mtime: doc.mtime,
+            size: doc.size,
+            deleted: doc.deleted == Some(true),
+            inline_data_size: doc.data.len(),
+            chunk_count: chunks.len(),
+            chunks,
+        };
+
         let json = serde_json::to_string_pretty(&response).map_err(|e| mcp_error(e.to_string()))?;
         Ok(CallToolResult::success(vec![Content::text(json)]))
     }
 }
 
-#[tool_handler]
 impl ServerHandler for YamosServer {
+    // these two are what #[tool_handler] would generate, plus the tracing span
+    async fn call_tool(
+        &self,
+        request: CallToolRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        if is_read_only(&context.extensions) && !READ_ONLY_TOOLS.contains(&request.name.as_ref()) {
+            return Err(McpError::invalid_request(
+                format!(
+                    "{} can change the vault, and this bearer token is read-only",
+                    request.name
+                ),
+                None,
+            ));
+        }
+
+        let mut request = request;
+        let vault = match request
+            .arguments
+            .as_mut()
+            .and_then(|args| args.remove("vault"))
+        {
+            None | Some(serde_json::Value::Null) => None,
+            Some(serde_json::Value::String(name)) => Some(name),
+            Some(_) => return Err(invalid_params("vault must be a string")),
+        };
+        let server = self.for_vault(vault.as_deref())?;
+
+        let span = tool_span(&request.name, &context);
+        let tool = request.name.clone();
+        let tcc = ToolCallContext::new(&server, request, context);
+        let call = server.tool_router.call(tcc).instrument(span);
+
+        let started = std::time::Instant::now();
+        let result = match self.config.tool_timeout {
+            None => call.await,
+            // dropping the call on timeout stops it wherever it's got to, which for a write could
+            // be before or after couchdb accepted the note - so say so rather than claiming it
+            // failed
+            Some(limit) => tokio::time::timeout(limit, call).await.unwrap_or_else(|_| {
+                tracing::warn!("Tool call {} timed out after {:?}", tool, limit);
+                Err(mcp_error(format!(
+                    "{} timed out after {}s. If it was changing notes, some changes may or may \
+                     not have been applied - read them back to check before retrying",
+                    tool,
+                    limit.as_secs()
+                )))
+            }),
+        };
+
+        let elapsed = started.elapsed();
+        if self.config.slow_op_threshold.is_some_and(|t| elapsed >= t) {
+            tracing::warn!(
+                "Slow tool call: {} took {}ms{}",
+                tool,
+                elapsed.as_millis(),
+                if result.is_err() { " and failed" } else { "" }
+            );
+        }
+        result
+    }
+
+    async fn list_tools(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        context: RequestContext<RoleServer>,
+    ) -> Result<ListToolsResult, McpError> {
+        let mut tools = self.list_tools_with_vaults();
+        if is_read_only(&context.extensions) {
+            tools.retain(|t| READ_ONLY_TOOLS.contains(&t.name.as_ref()));
+        }
+        Ok(ListToolsResult {
+            tools,
+            meta: None,
+            next_cursor: None,
+        })
+    }
+
+    async fn set_level(
+        &self,
+        request: SetLevelRequestParam,
+        context: RequestContext<RoleServer>,
+    ) -> Result<(), McpError> {
+        let Some(client_log) = &self.client_log else {
+            return Err(McpError::method_not_found::<SetLevelRequestMethod>());
+        };
+        client_log.set_level(context.peer, request.level);
+        Ok(())
+    }
+
+    /// Completes note paths (and vault names) as they're typed. MCP only defines completion for
+    /// prompt and resource arguments, but clients that complete tool arguments send the same
+    /// request, so this goes by the argument's name whatever the reference
+    async fn complete(
+        &self,
+        request: CompleteRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<CompleteResult, McpError> {
+        let typed = &request.argument.value;
+        let (values, total) = match request.argument.name.as_str() {
+            "vault" => {
+                let names: Vec<String> = self
+                    .vault_names()
+                    .into_iter()
+                    .filter(|name| name.starts_with(typed.as_str()))
+                    .map(str::to_string)
+                    .collect();
+                let total = names.len();
+                (names, total)
+            }
+            name if PATH_ARGUMENTS.contains(&name) => {
+                let vault = request
+                    .context
+                    .as_ref()
+                    .and_then(|c| c.arguments.as_ref())
+                    .and_then(|args| args.get("vault"));
+                let server = self.for_vault(vault.map(String::as_str))?;
+                let (paths, total) = server.search_index.read().await.complete_paths(
+                    typed,
+                    server.root(),
+                    CompletionInfo::MAX_VALUES,
+                );
+                let paths = paths
+                    .iter()
+                    .map(|p| server.display_path(p).to_string())
+                    .collect();
+                (paths, total)
+            }
+            _ => (Vec::new(), 0),
+        };
+        let has_more = total > values.len();
+        let completion = CompletionInfo::with_pagination(values, Some(total as u32), has_more)
+            .map_err(mcp_error)?;
+        Ok(CompleteResult { completion })
+    }
+
     fn get_info(&self) -> ServerInfo {
+        let mut server_info = Implementation::from_build_env();
+        if let Some(name) = &self.config.server_name {
+            server_info.name = name.clone();
+        }
+        if let Some(version) = &self.config.server_version {
+            server_info.version = version.clone();
+        }
+
         ServerInfo {
             protocol_version: ProtocolVersion::V_2024_11_05,
-            capabilities: ServerCapabilities::builder().enable_tools().build(),
-            server_info: Implementation::from_build_env(),
+            capabilities: match self.client_log {
+                Some(_) => ServerCapabilities::builder()
+                    .enable_tools()
+                    .enable_logging()
+                    .enable_completions()
+                    .build(),
+                None => ServerCapabilities::builder()
+                    .enable_tools()
+                    .enable_completions()
+                    .build(),
+            },
+            server_info,
             instructions: Some(
-                "Obsidian vault access via CouchDB/LiveSync. Use search_notes to find notes by fuzzy matching on titles and content. Use tools to list, read, write, edit, or append notes. For edit_note, include surrounding context in old_string to ensure uniqueness. Batch operations available for multi-note ops.".to_string(),
+                self.config
+                    .instructions
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_INSTRUCTIONS.to_string()),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::couchdb::CouchDbConfig;
+
+    fn test_server(vault_root: Option<&str>) -> YamosServer {
+        let db = CouchDbClient::new(CouchDbConfig {
+            url: "http://localhost:5984".to_string(),
+            database: "obsidian".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        YamosServer::new(
+            db,
+            Arc::new(RwLock::new(SearchIndex::new())),
+            None,
+            ServerConfig {
+                vault_root: vault_root.map(str::to_string),
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_parse_since() {
+        assert_eq!(parse_since("1970-01-02"), Some(86_400_000));
+        assert_eq!(parse_since("1970-01-01T00:00:01+00:00"), Some(1000));
+        assert_eq!(parse_since(" 1970-01-01T01:00:00+01:00 "), Some(0));
+        assert_eq!(parse_since("yesterday"), None);
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let code = |e: CouchDbError| db_error(e).code;
+        assert_eq!(
+            code(CouchDbError::NotFound("a.md".into())),
+            ErrorCode::RESOURCE_NOT_FOUND
+        );
+        assert_eq!(
+            code(CouchDbError::RevisionUnavailable {
+                path: "a.md".into(),
+                rev: "1-x".into(),
+                reason: "gone",
+            }),
+            ErrorCode::RESOURCE_NOT_FOUND
+        );
+        assert_eq!(
+            code(CouchDbError::AlreadyExists("a.md".into())),
+            ErrorCode::INVALID_REQUEST
+        );
+        assert_eq!(
+            code(CouchDbError::Http {
+                status: reqwest::StatusCode::BAD_GATEWAY,
+                body: String::new(),
+            }),
+            ErrorCode::INTERNAL_ERROR
+        );
+        assert_eq!(
+            code(CouchDbError::PayloadTooLarge(String::new())),
+            ErrorCode::INVALID_PARAMS
+        );
+        assert_eq!(
+            normalize_note_path("../x.md", "").unwrap_err().code,
+            ErrorCode::INVALID_PARAMS
+        );
+    }
+
+    #[tokio::test]
+    async fn test_errors_hide_vault_root() {
+        let server = test_server(Some("Shared/"));
+        let message = |e: CouchDbError| server.db_error(e).message.to_string();
+        assert_eq!(
+            message(CouchDbError::NotFound("Shared/a.md".into())),
+            "Note not found: a.md"
+        );
+        assert_eq!(
+            message(CouchDbError::Conflict("Shared/a.md".into())),
+            "a.md changed since it was read - read it again and redo the change"
+        );
+        assert!(message(CouchDbError::AlreadyExists("Shared/b/c.md".into())).contains(": b/c.md"));
+        assert_eq!(
+            message(CouchDbError::RevisionUnavailable {
+                path: "Shared/a.md".into(),
+                rev: "1-x".into(),
+                reason: "gone",
+            }),
+            "Revision 1-x of a.md can't be read: gone"
+        );
+
+        let err = server
+            .note_result::<()>(Err(CouchDbError::NotFound("Shared/a.md".into())))
+            .await
+            .unwrap_err();
+        assert_eq!(err.message, "Note not found: a.md");
+    }
+
+    #[test]
+    fn test_vault_root_paths() {
+        let server = test_server(Some("Shared/"));
+        assert_eq!(server.note_path("./a//b.md").unwrap(), "Shared/a/b.md");
+        assert_eq!(server.folder_prefix("Old/").unwrap(), "Shared/Old/");
+        assert_eq!(server.display_path("Shared/a/b.md"), "a/b.md");
+        // no way back out of the root
+        assert!(server.note_path("../Private/x.md").is_err());
+        assert!(server.note_path("/Private/x.md").is_err());
+
+        let server = test_server(None);
+        assert_eq!(server.note_path("a/b.md").unwrap(), "a/b.md");
+        assert_eq!(server.display_path("a/b.md"), "a/b.md");
+    }
+
+    #[test]
+    fn test_whoami() {
+        // the request parts an HTTP call would carry, with whatever the auth middleware added
+        let over_http = |auth: axum::http::Extensions| {
+            let (mut parts, _) = axum::http::Request::new(()).into_parts();
+            parts.extensions = auth;
+            let mut extensions = Extensions::new();
+            extensions.insert(parts);
+            extensions
+        };
+
+        assert_eq!(whoami(&Extensions::new()).auth, "none");
+        let mut auth = axum::http::Extensions::new();
+        assert_eq!(whoami(&over_http(auth.clone())).auth, "none");
+        auth.insert(LegacyCaller {
+            access: LegacyAccess::ReadWrite,
+        });
+        let legacy = over_http(auth.clone());
+        assert_eq!(whoami(&legacy).auth, "legacy");
+        assert_eq!(whoami(&legacy).scopes, vec!["read", "write"]);
+        assert!(!is_read_only(&legacy));
+        auth.insert(LegacyCaller {
+            access: LegacyAccess::ReadOnly,
+        });
+        let read_only = over_http(auth);
+        assert_eq!(whoami(&read_only).scopes, vec!["read"]);
+        assert!(is_read_only(&read_only));
+
+        let mut auth = axum::http::Extensions::new();
+        auth.insert(Claims {
+            sub: "claude".to_string(),
+            iat: 1_700_000_000,
+            exp: Some(1_700_003_600),
+            nbf: None,
+            jti: "x".to_string(),
+            iss: "yamos".to_string(),
+        });
+        let oauth = whoami(&over_http(auth));
+        assert_eq!(oauth.auth, "oauth");
+        assert_eq!(oauth.sub.as_deref(), Some("claude"));
+        assert_eq!(
+            oauth.expires_at.as_deref(),
+            Some("2023-11-14T23:13:20+00:00")
+        );
+    }
+
+    #[test]
+    fn test_enabled_and_disabled_tools() {
+        let server = |enabled: &[&str], disabled: &[&str]| {
+            let server = YamosServer::new(
+                test_server(None).db,
+                Arc::new(RwLock::new(SearchIndex::new())),
+                None,
+                ServerConfig {
+                    enabled_tools: enabled.iter().map(|t| t.to_string()).collect(),
+                    disabled_tools: disabled.iter().map(|t| t.to_string()).collect(),
+                    ..Default::default()
+                },
+            );
+            let mut names: Vec<_> = server
+                .list_tools_with_vaults()
+                .into_iter()
+                .map(|t| t.name.to_string())
+                .collect();
+            names.sort();
+            names
+        };
+
+        assert_eq!(
+            server(
+                &["read_note", "search_notes", "list_notes"],
+                &["list_notes"]
             ),
+            vec!["read_note", "search_notes"]
+        );
+        let all = server(&[], &["delete_folder"]);
+        assert!(all.contains(&"write_note".to_string()));
+        assert!(!all.contains(&"delete_folder".to_string()));
+        // --debug-tools still decides about the debug ones
+        assert!(server(&["debug_note_chunks"], &[]).is_empty());
+    }
+
+    #[test]
+    fn test_read_only_tools_exist() {
+        let server = YamosServer::new(
+            test_server(None).db,
+            Arc::new(RwLock::new(SearchIndex::new())),
+            None,
+            ServerConfig {
+                debug_tools: true,
+                ..Default::default()
+            },
+        );
+        let tools: Vec<_> = server.tool_router.list_all();
+        for name in READ_ONLY_TOOLS {
+            assert!(tools.iter().any(|t| t.name == *name), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_note_locks() {
+        let server = test_server(None);
+        let wait = std::time::Duration::from_millis(20);
+
+        let held = server.lock_note("a.md").await;
+        // other notes aren't held up, the same one is until it's let go
+        assert!(
+            tokio::time::timeout(wait, server.lock_note("b.md"))
+                .await
+                .is_ok()
+        );
+        assert!(
+            tokio::time::timeout(wait, server.lock_note("a.md"))
+                .await
+                .is_err()
+        );
+        drop(held);
+        assert!(
+            tokio::time::timeout(wait, server.lock_note("a.md"))
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_expand_separator() {
+        let now = chrono::DateTime::parse_from_rfc3339("2024-01-05T09:30:00+00:00").unwrap();
+        assert_eq!(expand_separator(None, now), "\n");
+        assert_eq!(
+            expand_separator(Some("\n\n## {{date}} {{time}}\n"), now),
+            "\n\n## 2024-01-05 09:30\n"
+        );
+        assert_eq!(expand_separator(Some("\n---\n"), now), "\n---\n");
+    }
+
+    #[test]
+    fn test_append_log_entry() {
+        let at = |time: &str| {
+            chrono::DateTime::parse_from_rfc3339(&format!("2024-01-05T{}:00+00:00", time)).unwrap()
+        };
+        let heading = "## {{time}}";
+        let log = append_log_entry("", "first", heading, at("09:30"));
+        assert_eq!(log, "## 09:30\nfirst");
+        // same minute goes under the same heading, a new one starts a block
+        let log = append_log_entry(&log, "second", heading, at("09:30"));
+        assert_eq!(log, "## 09:30\nfirst\nsecond");
+        let log = append_log_entry(&log, "third", heading, at("09:31"));
+        assert_eq!(log, "## 09:30\nfirst\nsecond\n\n## 09:31\nthird");
+        // an older block with the same stamp doesn't count, only the latest
+        let log = append_log_entry(&log, "fourth", heading, at("09:30"));
+        assert!(log.ends_with("third\n\n## 09:30\nfourth"));
+        // nor does one with some other heading after it
+        let log = append_log_entry("## 09:30\na\n# Notes\n", "b", heading, at("09:30"));
+        assert_eq!(log, "## 09:30\na\n# Notes\n\n## 09:30\nb");
+
+        let bold = "**{{date}} {{time}}**";
+        let log = append_log_entry("intro", "a", bold, at("09:30"));
+        let log = append_log_entry(&log, "b", bold, at("09:30"));
+        assert_eq!(log, "intro\n\n**2024-01-05 09:30**\na\nb");
+
+        let bullet = "- [{{time}}] {{content}}";
+        let log = append_log_entry("# Log\n", "a", bullet, at("09:30"));
+        let log = append_log_entry(&log, "b", bullet, at("09:30"));
+        assert_eq!(log, "# Log\n- [09:30] a\n- [09:30] b");
+
+        // a bare time is only a stamp when the line really is one
+        let log = append_log_entry("", "a", "{{time}}", at("09:30"));
+        let log = append_log_entry(&log, "b", "{{time}}", at("09:30"));
+        assert_eq!(log, "09:30\na\nb");
+    }
+
+    #[test]
+    fn test_is_log_stamp() {
+        assert!(is_log_stamp("## 14:32", "## {{time}}"));
+        assert!(!is_log_stamp("## Notes", "**{{time}}**"));
+        assert!(is_log_stamp(
+            "**2024-01-05 09:30**",
+            "**{{date}} {{time}}**"
+        ));
+        assert!(!is_log_stamp("**bold** text", "**{{date}} {{time}}**"));
+        assert!(is_log_stamp("14:32", "{{time}}"));
+        assert!(!is_log_stamp("bought milk", "{{time}}"));
+        assert!(!is_log_stamp("## 99:99", "## {{time}}"));
+    }
+
+    #[test]
+    fn test_confident_match() {
+        let candidates = |scores: &[u32]| -> Vec<(String, u32)> {
+            scores
+                .iter()
+                .enumerate()
+                .map(|(i, s)| (format!("{}.md", i), *s))
+                .collect()
+        };
+        assert_eq!(confident_match(&candidates(&[])), None);
+        assert_eq!(confident_match(&candidates(&[40])), Some("0.md"));
+        assert_eq!(confident_match(&candidates(&[100, 50, 10])), Some("0.md"));
+        assert_eq!(confident_match(&candidates(&[100, 90])), None);
+    }
+
+    #[test]
+    fn test_archive_path() {
+        let server = test_server(Some("Shared/"));
+        assert_eq!(
+            server.archive_path("Shared/Projects/old.md", None).unwrap(),
+            "Shared/Archive/Projects/old.md"
+        );
+        assert_eq!(
+            server
+                .archive_path("Shared/todo.md", Some("2024-01-05-093000"))
+                .unwrap(),
+            "Shared/Archive/todo 2024-01-05-093000.md"
+        );
+        assert!(server.archive_path("Shared/Archive/old.md", None).is_err());
+    }
+
+    #[test]
+    fn test_normalize_folder_prefix() {
+        assert_eq!(
+            normalize_folder_prefix(" ./Projects//Old/ ", "").unwrap(),
+            "Projects/Old/"
+        );
+        for bad in [
+            "Projects",
+            "Projects/Old",
+            "/",
+            "/Projects/",
+            "../Projects/",
+            "",
+        ] {
+            assert!(normalize_folder_prefix(bad, "").is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_normalize_note_path() {
+        let cases = [
+            ("Projects/note.md", "Projects/note.md"),
+            ("Projects//note.md", "Projects/note.md"),
+            ("Projects///deep//note.md", "Projects/deep/note.md"),
+            ("./note.md", "note.md"),
+            ("Projects/./note.md", "Projects/note.md"),
+            ("  Projects/note.md\n", "Projects/note.md"),
+            ("Projects/note.md/", "Projects/note.md"),
+            // dots inside names are left alone
+            ("v1.2/notes.v2.md", "v1.2/notes.v2.md"),
+            ("Spaced out/my note.md", "Spaced out/my note.md"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(
+                normalize_note_path(input, "").unwrap(),
+                expected,
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_normalize_note_path_still_rejects() {
+        for bad in [
+            "",
+            "   ",
+            "./",
+            "/note.md",
+            " /note.md",
+            "../note.md",
+            "Projects/../note.md",
+            "note.txt",
+        ] {
+            assert!(normalize_note_path(bad, "").is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn test_real_world_filenames_allowed() {
+        for name in [
+            "C# notes.md",
+            "Q&A.md",
+            "C++ tips.md",
+            "Done!.md",
+            "@mentions.md",
+            "Meeting, 2024-01-05.md",
+            "50% off.md",
+            "[draft] plan.md",
+            "Café — résumé.md",
+            "日本語/ノート.md",
+            "«quoted» and “smart”.md",
+            "emoji 🦀.md",
+            "it's fine.md",
+        ] {
+            assert!(normalize_note_path(name, "").is_ok(), "{name:?}");
+        }
+    }
+
+    #[test]
+    fn test_denied_path_chars() {
+        for bad in [
+            "a:b.md",
+            "what?.md",
+            "star*.md",
+            "pipe|.md",
+            "back\\slash.md",
+            "\"quoted\".md",
+            "<tag>.md",
+            "tab\there.md",
+            "h:abc.md",
+        ] {
+            assert!(normalize_note_path(bad, "").is_err(), "{bad:?}");
+        }
+
+        // the override lifts individual characters, but never the structural checks
+        assert!(normalize_note_path("10:30 standup.md", ":").is_ok());
+        assert!(normalize_note_path("what?.md", ":").is_err());
+        assert!(normalize_note_path("h:abc.md", ":").is_err());
+        assert!(normalize_note_path("../x.md", ":").is_err());
+    }
+
+    #[test]
+    fn test_check_unique_line() {
+        assert_eq!(check_unique_line("- [ ] milk\n").unwrap(), "- [ ] milk");
+        assert_eq!(check_unique_line("  indented").unwrap(), "  indented");
+        assert!(check_unique_line("   ").is_err());
+        assert!(check_unique_line("one\ntwo").is_err());
+    }
+
+    #[test]
+    fn test_check_heading_text() {
+        assert_eq!(check_heading_text(" Aims ").unwrap(), "Aims");
+        assert!(check_heading_text("").is_err());
+        assert!(check_heading_text("Q1 #goals").is_err());
+        assert!(check_heading_text("see [[Plan]]").is_err());
+        assert!(check_heading_text("one\ntwo").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_vaults() {
+        let server = test_server(None);
+        assert_eq!(
+            server.for_vault(Some("obsidian")).unwrap().db.database(),
+            "obsidian"
+        );
+        // a single vault doesn't advertise the parameter
+        let tools = server.list_tools_with_vaults();
+        assert!(
+            tools
+                .iter()
+                .all(|t| t.input_schema["properties"].get("vault").is_none())
+        );
+
+        let work = CouchDbClient::new(CouchDbConfig {
+            url: "http://localhost:5984".to_string(),
+            database: "work-notes".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        let work_index = Arc::new(RwLock::new(SearchIndex::new()));
+        let server = server.with_vault("work".to_string(), work, work_index.clone());
+
+        assert_eq!(server.for_vault(None).unwrap().db.database(), "obsidian");
+        assert_eq!(server.for_vault(None).unwrap().vault, None);
+        let work = server.for_vault(Some("work")).unwrap();
+        assert_eq!(work.db.database(), "work-notes");
+        // what audit entries say the note was in
+        assert_eq!(work.vault.as_deref(), Some("work"));
+        assert!(Arc::ptr_eq(&work.search_index, &work_index));
+        let Err(err) = server.for_vault(Some("play")) else {
+            panic!("unknown vault was accepted");
+        };
+        assert_eq!(err.code, ErrorCode::INVALID_PARAMS);
+        assert!(err.message.contains("obsidian, work"));
+
+        let tools = server.list_tools_with_vaults();
+        assert!(!tools.is_empty());
+        for tool in tools {
+            let vault = &tool.input_schema["properties"]["vault"];
+            assert_eq!(vault["type"], "string", "{}", tool.name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_recent_notes_without_an_mtime_index() {
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, Request, ResponseTemplate};
+
+        // no index to sort with, and more matches than get fetched. Pages go by bookmark
+        let couch = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/obsidian/_find"))
+            .respond_with(|req: &Request| {
+                let body: serde_json::Value = req.body_json().unwrap();
+                if body.get("sort").is_some() {
+                    return ResponseTemplate::new(400).set_body_json(serde_json::json!({
+                        "error": "no_usable_index",
+                        "reason": "No index exists for this sort",
+                    }));
+                }
+                let page = body["bookmark"].as_str().map_or(0, |b| b.parse().unwrap());
+                let size = body["limit"].as_u64().unwrap();
+                let docs: Vec<_> = (0..size)
+                    .map(|i| {
+                        let n = page * size + i;
+                        serde_json::json!({
+                            "_id": format!("{:05}.md", n), "path": format!("{:05}.md", n),
+                            "mtime": n, "size": 1,
+                        })
+                    })
+                    .collect();
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                    "docs": docs,
+                    "bookmark": (page + 1).to_string(),
+                }))
+            })
+            .mount(&couch)
+            .await;
+        let server = test_server(None);
+        let server = YamosServer {
+            db: CouchDbClient::new(CouchDbConfig {
+                url: couch.uri(),
+                database: "obsidian".to_string(),
+                ..Default::default()
+            })
+            .unwrap(),
+            ..server
+        };
+
+        let result = server
+            .list_recent_notes(Parameters(ListRecentNotesRequest {
+                since: "1970-01-01".to_string(),
+                prefix: None,
+                limit: Some(2),
+            }))
+            .await
+            .unwrap();
+        let recent: serde_json::Value =
+            serde_json::from_str(&result.content[0].as_text().unwrap().text).unwrap();
+        // sorted here instead, newest first
+        assert_eq!(
+            recent[0]["path"],
+            format!("{:05}.md", MAX_RECENT_MATCHES - 1)
+        );
+        assert_eq!(
+            recent[1]["path"],
+            format!("{:05}.md", MAX_RECENT_MATCHES - 2)
+        );
+        let note = &result.content[1].as_text().unwrap().text;
+        assert!(note.starts_with("incomplete: more than 10000 notes changed since then"));
+        assert!(note.contains("CouchDB has no index on mtime to sort them with"));
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let server = test_server(None);
+        let result = server
+            .ping(Parameters(PingRequest {
+                couchdb: Some(false),
+            }))
+            .await
+            .unwrap();
+        let text = &result.content[0].as_text().unwrap().text;
+        let response: serde_json::Value = serde_json::from_str(text).unwrap();
+        assert_eq!(response["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(response["notes"], 0);
+        assert!(response.get("couchdb_reachable").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_search_defaults() {
+        let server = test_server(None);
+        let server = YamosServer {
+            config: ServerConfig {
+                search_defaults: SearchOptions {
+                    limit: 1,
+                    search_content: false,
+                    ..Default::default()
+                },
+                ..server.config.clone()
+            },
+            ..server
+        };
+        for (path, content) in [
+            ("Boats.md", ""),
+            ("Boat ideas.md", ""),
+            ("Inbox.md", "boat"),
+        ] {
+            server.search_index.write().await.upsert(
+                path.to_string(),
+                crate::search::NoteEntry {
+                    path: path.to_string(),
+                    title: path.trim_end_matches(".md").to_string(),
+                    content: content.to_string(),
+                    mtime: 0,
+                },
+            );
         }
+        let search = |limit, search_content| {
+            server.search_notes(Parameters(SearchNotesRequest {
+                query: "boat".to_string(),
+                search_content,
+                limit,
+                semantic: None,
+            }))
+        };
+        let count = |result: CallToolResult| -> usize {
+            let text = &result.content[0].as_text().unwrap().text;
+            let response: serde_json::Value = serde_json::from_str(text).unwrap();
+            response.as_array().unwrap().len()
+        };
+
+        assert_eq!(count(search(None, None).await.unwrap()), 1);
+        // titles only, unless the call asks for content
+        assert_eq!(count(search(Some(10), None).await.unwrap()), 2);
+        assert_eq!(count(search(Some(10), Some(true)).await.unwrap()), 3);
     }
 }
@@ -0,0 +1,210 @@
+//! Whole-vault export and import over plain HTTP, for backups and moving vaults around
+
+use crate::archive::{TAR_END, read_tar, tar_entry};
+use crate::auth::{Claims, LegacyAccess, LegacyCaller};
+use crate::couchdb::CouchDbClient;
+use crate::server::{BatchWriteResult, YamosServer};
+use axum::{
+    Extension, Json, Router,
+    body::{Body, Bytes},
+    extract::{DefaultBodyLimit, Query, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+/// Notes fetched per list_notes call while walking the vault
+const EXPORT_PAGE_SIZE: usize = 100;
+
+/// Biggest archive /import will take. It's held in memory while it's unpacked
+const IMPORT_MAX_BYTES: usize = 256 * 1024 * 1024;
+
+pub fn routes(server: YamosServer) -> Router {
+    Router::new()
+        .route("/export", get(export_handler))
+        .route(
+            "/import",
+            post(import_handler).layer(DefaultBodyLimit::max(IMPORT_MAX_BYTES)),
+        )
+        .with_state(server)
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportParams {
+    /// only export notes under this folder (relative to the vault root, like tool paths)
+    #[serde(default)]
+    prefix: String,
+}
+
+/// GET /export - every note (or every one under `?prefix=`) as a tar, streamed out one note at a
+/// time so memory use doesn't grow with the vault
+async fn export_handler(
+    State(server): State<YamosServer>,
+    Query(params): Query<ExportParams>,
+) -> Response {
+    let db = server.db().clone();
+    let root = server.root().to_string();
+    let prefix = format!("{}{}", root, params.prefix);
+    // a couple of notes of buffer is plenty, couchdb is the slow bit
+    let (tx, rx) = mpsc::channel::<std::io::Result<Vec<u8>>>(4);
+
+    tokio::spawn(async move {
+        if let Err(e) = write_vault(&db, &root, &prefix, &tx).await {
+            tracing::error!("Vault export failed: {}", e);
+            // erroring the body makes axum cut the connection, so the client can tell the
+            // archive is incomplete rather than getting a tar that just stops early
+            let _ = tx.send(Err(std::io::Error::other(e.to_string()))).await;
+        }
+    });
+
+    let filename = format!("vault-{}.tar", chrono::Utc::now().format("%Y%m%d-%H%M%S"));
+    (
+        [
+            (header::CONTENT_TYPE, "application/x-tar".to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", filename),
+            ),
+        ],
+        Body::from_stream(ReceiverStream::new(rx)),
+    )
+        .into_response()
+}
+
+/// Every note under `prefix` (or all of them), with paths in the archive relative to `root`
+async fn write_vault(
+    db: &CouchDbClient,
+    root: &str,
+    prefix: &str,
+    tx: &mpsc::Sender<std::io::Result<Vec<u8>>>,
+) -> anyhow::Result<()> {
+    let mut cursor = None;
+    let mut exported = 0;
+
+    loop {
+        let page = db
+            .list_notes(
+                (!prefix.is_empty()).then_some(prefix),
+                cursor.as_deref(),
+                EXPORT_PAGE_SIZE,
+            )
+            .await?;
+
+        for path in page.notes {
+            // one broken note shouldn't sink the whole backup
+            let doc = match db.get_note(&path).await {
+                Ok(doc) => doc,
+                Err(e) => {
+                    tracing::warn!("Skipping {} in export: {}", path, e);
+                    continue;
+                }
+            };
+            let content = match db.decode_content(&doc).await {
+                Ok(content) => content,
+                Err(e) => {
+                    tracing::warn!("Skipping {} in export: {}", path, e);
+                    continue;
+                }
+            };
+
+            let name = path.strip_prefix(root).unwrap_or(&path);
+            let entry = tar_entry(name, content.as_bytes(), doc.mtime / 1000);
+            if tx.send(Ok(entry)).await.is_err() {
+                tracing::info!("Export client went away after {} notes", exported);
+                return Ok(());
+            }
+            exported += 1;
+        }
+
+        cursor = page.next_cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+
+    let _ = tx.send(Ok(TAR_END.to_vec())).await;
+    tracing::info!("Exported {} notes", exported);
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportParams {
+    /// replace notes that already exist instead of reporting them as failures
+    #[serde(default)]
+    overwrite: bool,
+}
+
+/// POST /import - writes every file in a tar body as a note, reporting per entry like the batch
+/// tools do. Entries go through the same path checks as tool calls
+async fn import_handler(
+    State(server): State<YamosServer>,
+    Query(params): Query<ImportParams>,
+    caller: Option<Extension<LegacyCaller>>,
+    claims: Option<Extension<Claims>>,
+    body: Bytes,
+) -> Response {
+    if caller.is_some_and(|Extension(c)| c.access == LegacyAccess::ReadOnly) {
+        return (StatusCode::FORBIDDEN, "this bearer token is read-only").into_response();
+    }
+
+    let files = match read_tar(&body) {
+        Ok(files) => files,
+        Err(e) => {
+            return (StatusCode::BAD_REQUEST, format!("not a valid tar: {}", e)).into_response();
+        }
+    };
+
+    let mut results = Vec::with_capacity(files.len());
+    for file in files {
+        let sub = claims.as_ref().map(|Extension(c)| c.sub.as_str());
+        let result =
+            match import_file(&server, &file.path, file.content, params.overwrite, sub).await {
+                Ok(path) => BatchWriteResult {
+                    path,
+                    success: true,
+                    error: None,
+                },
+                Err(e) => BatchWriteResult {
+                    path: file.path,
+                    success: false,
+                    error: Some(e),
+                },
+            };
+        results.push(result);
+    }
+
+    let imported = results.iter().filter(|r| r.success).count();
+    tracing::info!("Imported {} of {} notes", imported, results.len());
+    Json(results).into_response()
+}
+
+/// Writes one archive entry, returning the path it ended up at. It holds the note's lock like
+/// a tool call would, and goes in the audit log as the "import" tool
+async fn import_file(
+    server: &YamosServer,
+    path: &str,
+    content: Vec<u8>,
+    overwrite: bool,
+    sub: Option<&str>,
+) -> Result<String, String> {
+    let path = server.note_path(path).map_err(|e| e.message.to_string())?;
+    let content = String::from_utf8(content).map_err(|_| "content isn't utf-8 text".to_string())?;
+    let db = server.db();
+    let _lock = server.lock_note(&path).await;
+
+    if !overwrite
+        && let Ok(existing) = db.get_note(&path).await
+        && existing.deleted != Some(true)
+    {
+        return Err("note already exists (pass overwrite=true to replace it)".to_string());
+    }
+
+    server
+        .audited_as("import", &path, sub, db.save_note(&path, &content))
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(server.display_path(&path).to_string())
+}
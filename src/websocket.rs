@@ -0,0 +1,87 @@
+//! MCP over a WebSocket, for hosts that would rather hold one socket open than speak streamable
+//! HTTP. Each text frame is one JSON-RPC message, and each connection is its own MCP session
+
+use crate::server::YamosServer;
+use axum::{
+    Router,
+    extract::{
+        State,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+    },
+    http::request::Parts,
+    response::Response,
+    routing::get,
+};
+use futures::{SinkExt, StreamExt};
+use rmcp::ServiceExt;
+use rmcp::model::{ClientJsonRpcMessage, GetExtensions, ServerJsonRpcMessage};
+
+/// Offered to clients that ask for it. Clients that don't send a subprotocol still get in
+pub const SUBPROTOCOL: &str = "mcp";
+
+pub fn routes(server: YamosServer) -> Router {
+    Router::new()
+        .route("/ws", get(upgrade_handler))
+        .with_state(server)
+}
+
+/// GET /ws - upgrades to a WebSocket and runs an MCP session over it
+async fn upgrade_handler(
+    State(server): State<YamosServer>,
+    parts: Parts,
+    ws: WebSocketUpgrade,
+) -> Response {
+    ws.protocols([SUBPROTOCOL])
+        .on_upgrade(move |socket| serve_socket(server, socket, parts))
+}
+
+async fn serve_socket(server: YamosServer, socket: WebSocket, parts: Parts) {
+    let (sink, stream) = socket.split();
+
+    let sink = sink.with(|message: ServerJsonRpcMessage| async move {
+        let json = serde_json::to_string(&message).map_err(axum::Error::new)?;
+        Ok::<_, axum::Error>(Message::Text(json.into()))
+    });
+
+    // the upgrade request's parts go on every message, the same as the HTTP transport does,
+    // so tools can see who's calling and the request id
+    let stream = stream
+        .take_while(|frame| std::future::ready(!matches!(frame, Err(_) | Ok(Message::Close(_)))))
+        .filter_map(move |frame| {
+            let parts = parts.clone();
+            async move {
+                let Ok(Message::Text(text)) = frame else {
+                    // pings are answered by axum, binary frames aren't part of the protocol
+                    return None;
+                };
+                let mut message: ClientJsonRpcMessage = match serde_json::from_str(&text) {
+                    Ok(message) => message,
+                    Err(e) => {
+                        tracing::warn!("Ignoring malformed WebSocket message: {}", e);
+                        return None;
+                    }
+                };
+                match &mut message {
+                    ClientJsonRpcMessage::Request(req) => {
+                        req.request.extensions_mut().insert(parts);
+                    }
+                    ClientJsonRpcMessage::Notification(not) => {
+                        not.notification.extensions_mut().insert(parts);
+                    }
+                    _ => {}
+                }
+                Some(message)
+            }
+        });
+
+    tracing::info!("WebSocket MCP session started");
+    match server.serve((Box::pin(sink), Box::pin(stream))).await {
+        Ok(service) => {
+            if let Err(e) = service.waiting().await {
+                tracing::warn!("WebSocket MCP session failed: {}", e);
+            }
+        }
+        Err(e) => tracing::warn!("WebSocket MCP session failed to initialise: {}", e),
+    }
+    tracing::info!("WebSocket MCP session ended");
+}